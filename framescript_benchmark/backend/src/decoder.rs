@@ -1,26 +1,51 @@
+mod ranges;
+pub mod source;
+
 use std::{
-    collections::{HashMap, HashSet},
+    collections::HashMap,
     sync::{
         Arc, LazyLock, Mutex, RwLock,
-        atomic::{AtomicUsize, Ordering},
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use tokio::time::timeout;
+use serde::Serialize;
+
+use tokio::{
+    sync::{OnceCell, mpsc},
+    time::timeout,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
 
-use crate::{ffmpeg::hw_decoder, future::SharedManualFuture};
+use crate::{
+    decoder::{
+        ranges::FrameRanges,
+        source::{FrameSource, extract_frame, frame_source_from_env},
+    },
+    ffmpeg::{
+        DecodeOptions, command::CANCELED, hw_decoder::DecodeBackend, hwaccel::HwAccel,
+        probe_video_frames, scale::ScaleQuality,
+    },
+    future::SharedManualFuture,
+};
 
-pub static DECODER: LazyLock<Decoder> = LazyLock::new(|| Decoder::new());
+pub static DECODER: LazyLock<Decoder> =
+    LazyLock::new(|| Decoder::with_source(frame_source_from_env(), *WAIT_POLICY));
 
 pub struct Decoder {
     map: Mutex<HashMap<DecoderKey, CachedDecoder>>,
+    source: Arc<dyn FrameSource>,
+    wait_policy: WaitPolicy,
 }
 
 impl Decoder {
-    fn new() -> Self {
+    pub fn with_source(source: Arc<dyn FrameSource>, wait_policy: WaitPolicy) -> Self {
         Self {
             map: Mutex::new(HashMap::new()),
+            source,
+            wait_policy,
         }
     }
 
@@ -33,9 +58,10 @@ impl Decoder {
             .entry(key.clone())
             .or_insert_with(|| {
                 generated = true;
-                CachedDecoder::new(key)
+                CachedDecoder::new(key, self.source.clone(), self.wait_policy)
             })
             .clone();
+        decoder.touch();
 
         if generated {
             decoder.schedule_gc().await;
@@ -44,7 +70,10 @@ impl Decoder {
         decoder
     }
 
-    pub async fn clear(&self) {
+    /// Cancel every decoder and wait up to the clear timeout for their decode tasks.
+    /// Tasks still running after the timeout are abandoned; they only touch the
+    /// detached decoders, never the fresh map.
+    pub async fn clear(&self) -> ClearOutcome {
         let map_clone = {
             let mut map = self.map.lock().unwrap();
 
@@ -54,30 +83,240 @@ impl Decoder {
             temp
         };
 
-        loop {
+        // 実行中のデコードはキャンセルし、ffmpeg も kill する
+        for decoder in map_clone.values() {
+            decoder.close();
+        }
+
+        let deadline = Instant::now() + *CLEAR_TIMEOUT;
+        let outcome = loop {
             // await decode task
-            let mut finished = true;
-            for decoder in map_clone.values() {
-                if decoder.inner.running_decode_tasks.load(Ordering::Relaxed) > 0 {
-                    finished = false;
-                    break;
-                }
+            let pending = map_clone
+                .iter()
+                .filter(|(_, decoder)| {
+                    decoder.inner.running_decode_tasks.load(Ordering::Relaxed) > 0
+                })
+                .map(|(key, _)| key.clone())
+                .collect::<Vec<_>>();
+
+            if pending.is_empty() {
+                break ClearOutcome::Clean;
             }
 
-            if finished {
-                break;
+            if Instant::now() >= deadline {
+                for key in &pending {
+                    warn!(
+                        path = %key.path,
+                        width = key.width,
+                        height = key.height,
+                        "abandoned decoder with running decode tasks"
+                    );
+                }
+                break ClearOutcome::Forced { abandoned: pending };
             }
 
             tokio::time::sleep(Duration::from_millis(50)).await;
-        }
+        };
 
         ENTIRE_CACHE_SIZE.store(0, Ordering::Relaxed);
+
+        outcome
+    }
+
+    /// Remove decoders that have not been requested for longer than `ttl`,
+    /// releasing their cached frames and stopping their GC tasks.
+    pub fn evict_idle(&self, ttl: Duration) {
+        let evicted = {
+            let mut map = self.map.lock().unwrap();
+            let now = elapsed_ms();
+            let ttl_ms = ttl.as_millis() as u64;
+
+            let idle_keys = map
+                .iter()
+                .filter(|(_, decoder)| {
+                    decoder.inner.running_decode_tasks.load(Ordering::Relaxed) == 0
+                        && now.saturating_sub(decoder.inner.last_access_ms.load(Ordering::Relaxed))
+                            > ttl_ms
+                })
+                .map(|(key, _)| key.clone())
+                .collect::<Vec<_>>();
+
+            idle_keys
+                .into_iter()
+                .filter_map(|key| map.remove(&key).map(|decoder| (key, decoder)))
+                .collect::<Vec<_>>()
+        };
+
+        for (key, decoder) in evicted {
+            decoder.close();
+            info!(path = %key.path, width = key.width, height = key.height, "evicted idle decoder");
+        }
+    }
+
+    /// Recompute the cache size from the frame maps of every decoder.
+    pub fn recompute_cache_size(&self) -> usize {
+        let decoders = self
+            .map
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+
+        decoders.iter().map(|decoder| decoder.cached_bytes()).sum()
+    }
+
+    /// Compare the accounted cache size against the maps and correct any drift.
+    pub fn reconcile_cache_size(&self) {
+        let recomputed = self.recompute_cache_size();
+        let accounted = ENTIRE_CACHE_SIZE.swap(recomputed, Ordering::Relaxed);
+
+        if accounted != recomputed {
+            warn!(
+                accounted,
+                recomputed,
+                drift = accounted as i64 - recomputed as i64,
+                "cache size drift detected, corrected"
+            );
+        }
+    }
+
+    pub fn decoder_stats(&self) -> Vec<DecoderStats> {
+        let decoders = self
+            .map
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+
+        decoders.iter().map(|decoder| decoder.stats()).collect()
     }
 }
 
+/// Periodically evict idle decoders and reconcile the accounted cache size with the frame maps.
+pub fn spawn_cache_maintenance() {
+    tokio::spawn(async {
+        loop {
+            tokio::time::sleep(CACHE_MAINTENANCE_INTERVAL).await;
+            DECODER.evict_idle(*DECODER_IDLE_TTL);
+            DECODER.reconcile_cache_size();
+        }
+    });
+}
+
+/// What `get_frame` serves when a frame never arrives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FallbackPolicy {
+    PreviousFrame,
+    Empty,
+    Error,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct WaitPolicy {
+    /// How long a single wait on a pending frame lasts before re-checking.
+    pub wait: Duration,
+    /// Total time a request may wait while a decode covering it is still running.
+    pub budget: Duration,
+    pub fallback: FallbackPolicy,
+}
+
+fn env_millis(name: &str, default: u64) -> Duration {
+    let millis = std::env::var(name)
+        .ok()
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .unwrap_or(default);
+    Duration::from_millis(millis)
+}
+
+/// Configured by `FRAMESCRIPT_FRAME_WAIT_MS`, `FRAMESCRIPT_FRAME_WAIT_BUDGET_MS`
+/// and `FRAMESCRIPT_FRAME_FALLBACK` (`previous`, `empty` or `error`).
+static WAIT_POLICY: LazyLock<WaitPolicy> = LazyLock::new(|| {
+    let fallback = match std::env::var("FRAMESCRIPT_FRAME_FALLBACK")
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+        .as_str()
+    {
+        "empty" => FallbackPolicy::Empty,
+        "error" => FallbackPolicy::Error,
+        _ => FallbackPolicy::PreviousFrame,
+    };
+
+    WaitPolicy {
+        wait: env_millis("FRAMESCRIPT_FRAME_WAIT_MS", 1000).max(Duration::from_millis(1)),
+        budget: env_millis("FRAMESCRIPT_FRAME_WAIT_BUDGET_MS", 60_000),
+        fallback,
+    }
+});
+
+#[derive(Debug)]
+pub enum ClearOutcome {
+    Clean,
+    Forced { abandoned: Vec<DecoderKey> },
+}
+
+/// Upper bound for `Decoder::clear`. Override with `FRAMESCRIPT_CLEAR_TIMEOUT_MS`.
+static CLEAR_TIMEOUT: LazyLock<Duration> =
+    LazyLock::new(|| env_millis("FRAMESCRIPT_CLEAR_TIMEOUT_MS", 3000));
+
+const CACHE_MAINTENANCE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Idle time after which a decoder is dropped. Override with `FRAMESCRIPT_DECODER_IDLE_TTL_SECS`.
+static DECODER_IDLE_TTL: LazyLock<Duration> = LazyLock::new(|| {
+    let secs = std::env::var("FRAMESCRIPT_DECODER_IDLE_TTL_SECS")
+        .ok()
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .unwrap_or(600);
+    Duration::from_secs(secs.max(1))
+});
+
+static EPOCH: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+fn elapsed_ms() -> u64 {
+    EPOCH.elapsed().as_millis() as u64
+}
+
 static ENTIRE_CACHE_SIZE: AtomicUsize = AtomicUsize::new(0);
 static MAX_CACHE_SIZE: AtomicUsize = AtomicUsize::new(1024 * 1024 * 1024 * 4); // Default: 4GiB
 
+/// Decoded frames in flight between ffmpeg and the cache; keeps a slow consumer
+/// from letting a whole window pile up in memory.
+const FRAME_CHANNEL_CAPACITY: usize = 4;
+
+/// Estimated fixed overhead of one cached frame beyond its pixel buffer.
+const FRAME_ENTRY_OVERHEAD: usize =
+    // slot in `frames`
+    size_of::<(u32, SharedManualFuture<Vec<u8>>)>()
+        // Arc counters + mutex state of the shared future
+        + 2 * size_of::<usize>()
+        + size_of::<Mutex<(Option<Arc<Vec<u8>>>, Vec<usize>)>>()
+        // Arc<Vec<u8>> around the pixel buffer
+        + 2 * size_of::<usize>()
+        + size_of::<Vec<u8>>();
+
+/// Bytes a cached frame is accounted as, including allocation slack and entry overhead.
+fn frame_cost(frame: &Arc<Vec<u8>>) -> usize {
+    frame.capacity() + FRAME_ENTRY_OVERHEAD
+}
+
+fn account_frame(frame: &Arc<Vec<u8>>) {
+    ENTIRE_CACHE_SIZE.fetch_add(frame_cost(frame), Ordering::Relaxed);
+}
+
+fn release_frame(frame: &Arc<Vec<u8>>) {
+    let cost = frame_cost(frame);
+    let previous = ENTIRE_CACHE_SIZE
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |size| {
+            Some(size.saturating_sub(cost))
+        })
+        .unwrap_or_default();
+    if previous < cost {
+        warn!(previous, cost, "cache size underflow, clamped to zero");
+    }
+}
+
 pub fn set_max_cache_size(bytes: usize) {
     MAX_CACHE_SIZE.store(bytes.max(1024 * 1024), Ordering::Relaxed);
 }
@@ -94,6 +333,21 @@ pub struct DecoderKey {
     pub path: String,
     pub width: u32,
     pub height: u32,
+    pub hwaccel: HwAccel,
+    pub quality: ScaleQuality,
+    pub tonemap: bool,
+}
+
+impl DecoderKey {
+    fn options(&self) -> DecodeOptions {
+        DecodeOptions {
+            width: self.width,
+            height: self.height,
+            hwaccel: self.hwaccel,
+            quality: self.quality,
+            tonemap: self.tonemap,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -101,237 +355,677 @@ pub struct CachedDecoder {
     inner: Arc<Inner>,
 }
 
+/// Counters kept per decoder. They live as long as the decoder itself,
+/// so frame eviction does not reset them but `/reset` does.
+#[derive(Debug, Default)]
+struct DecoderMetrics {
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    frames_decoded: AtomicU64,
+    bytes_decoded: AtomicU64,
+    fallback_frames: AtomicU64,
+    decode_time_us: AtomicU64,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct DecoderMetricsSnapshot {
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub frames_decoded: u64,
+    pub bytes_decoded: u64,
+    pub fallback_frames: u64,
+    pub decode_time_us: u64,
+}
+
+impl DecoderMetrics {
+    fn record_decode(&self, frames: usize, bytes: usize, elapsed: Duration) {
+        self.frames_decoded
+            .fetch_add(frames as u64, Ordering::Relaxed);
+        self.bytes_decoded
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.decode_time_us
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> DecoderMetricsSnapshot {
+        DecoderMetricsSnapshot {
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            cache_misses: self.cache_misses.load(Ordering::Relaxed),
+            frames_decoded: self.frames_decoded.load(Ordering::Relaxed),
+            bytes_decoded: self.bytes_decoded.load(Ordering::Relaxed),
+            fallback_frames: self.fallback_frames.load(Ordering::Relaxed),
+            decode_time_us: self.decode_time_us.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl DecoderMetricsSnapshot {
+    pub fn merge(&mut self, other: &DecoderMetricsSnapshot) {
+        self.cache_hits += other.cache_hits;
+        self.cache_misses += other.cache_misses;
+        self.frames_decoded += other.frames_decoded;
+        self.bytes_decoded += other.bytes_decoded;
+        self.fallback_frames += other.fallback_frames;
+        self.decode_time_us += other.decode_time_us;
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DecoderStats {
+    pub path: String,
+    pub width: u32,
+    pub height: u32,
+    pub hwaccel: HwAccel,
+    pub quality: ScaleQuality,
+    pub tonemap: bool,
+    pub cached_frames: usize,
+    pub cached_bytes: usize,
+    pub waiting_frames: usize,
+    pub claimed_ranges: usize,
+    /// Backend that decoded the most recent window.
+    pub backend: Option<DecodeBackend>,
+    pub metrics: DecoderMetricsSnapshot,
+}
+
+/// The std locks below only guard short critical sections that copy data out;
+/// none of them may be held across an `.await`. When several are needed at once
+/// they are taken in declaration order.
 #[derive(Debug)]
 struct Inner {
     path: String,
-    width: u32,
-    height: u32,
+    options: DecodeOptions,
+    source: Arc<dyn FrameSource>,
+    wait_policy: WaitPolicy,
+    frame_count: OnceCell<Option<u32>>,
+    /// The background frame count has been started.
+    counting: AtomicBool,
     frames: RwLock<HashMap<u32, SharedManualFuture<Vec<u8>>>>,
-    frame_states: RwLock<HashMap<u32, FrameState>>,
-    decoding_frames: Mutex<HashSet<u32>>,
+    /// Number of requests currently waiting on each frame. Entries are removed
+    /// as soon as the last waiter finishes, so this is bounded by in-flight requests.
+    waiting: Mutex<HashMap<u32, usize>>,
+    /// Every frame that has been handed to a decode task at some point.
+    decoding_frames: Mutex<FrameRanges>,
+    /// Windows whose decode task is still running.
+    pending_windows: Mutex<FrameRanges>,
+    /// Frames that had to be decoded again after leaving the cache.
+    revisited: Mutex<FrameRanges>,
+    /// Frames whose cached copy came from the hardware decoder; the rest were decoded
+    /// in software.
+    hardware_frames: Mutex<FrameRanges>,
     running_decode_tasks: AtomicUsize,
+    last_backend: Mutex<Option<DecodeBackend>>,
+    metrics: DecoderMetrics,
+    last_access_ms: AtomicU64,
+    closed: AtomicBool,
+    cancel: CancellationToken,
+}
+
+/// Registers a waiter on a frame for the lifetime of the guard.
+struct WaitGuard<'a> {
+    inner: &'a Inner,
+    frame_index: u32,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
-enum FrameState {
-    None,
-    Wait,
-    Drop,
+impl<'a> WaitGuard<'a> {
+    fn new(inner: &'a Inner, frame_index: u32) -> Self {
+        *inner
+            .waiting
+            .lock()
+            .unwrap()
+            .entry(frame_index)
+            .or_default() += 1;
+        Self { inner, frame_index }
+    }
+}
+
+impl Drop for WaitGuard<'_> {
+    fn drop(&mut self) {
+        let mut waiting = self.inner.waiting.lock().unwrap();
+        if let Some(count) = waiting.get_mut(&self.frame_index) {
+            *count -= 1;
+            if *count == 0 {
+                waiting.remove(&self.frame_index);
+            }
+        }
+    }
 }
 
 impl CachedDecoder {
-    fn new(key: DecoderKey) -> Self {
+    fn new(key: DecoderKey, source: Arc<dyn FrameSource>, wait_policy: WaitPolicy) -> Self {
         let inner = Inner {
+            options: key.options(),
             path: key.path,
-            width: key.width,
-            height: key.height,
+            source,
+            wait_policy,
+            frame_count: OnceCell::new(),
+            counting: AtomicBool::new(false),
             frames: RwLock::new(HashMap::new()),
-            frame_states: RwLock::new(HashMap::new()),
-            decoding_frames: Mutex::new(HashSet::new()),
+            waiting: Mutex::new(HashMap::new()),
+            decoding_frames: Mutex::new(FrameRanges::default()),
+            pending_windows: Mutex::new(FrameRanges::default()),
+            revisited: Mutex::new(FrameRanges::default()),
+            hardware_frames: Mutex::new(FrameRanges::default()),
             running_decode_tasks: AtomicUsize::new(0),
+            last_backend: Mutex::new(None),
+            metrics: DecoderMetrics::default(),
+            last_access_ms: AtomicU64::new(elapsed_ms()),
+            closed: AtomicBool::new(false),
+            cancel: CancellationToken::new(),
         };
         Self {
             inner: Arc::new(inner),
         }
     }
 
+    fn touch(&self) {
+        self.inner
+            .last_access_ms
+            .store(elapsed_ms(), Ordering::Relaxed);
+    }
+
+    /// Cancel in-flight decodes, stop background tasks and release every cached frame.
+    fn close(&self) {
+        self.inner.closed.store(true, Ordering::Relaxed);
+        self.inner.cancel.cancel();
+
+        let frames = std::mem::take(&mut *self.inner.frames.write().unwrap());
+        for frame in frames.values().filter_map(|future| future.get_now()) {
+            release_frame(&frame);
+        }
+        self.inner.decoding_frames.lock().unwrap().clear();
+        self.inner.pending_windows.lock().unwrap().clear();
+        self.inner.revisited.lock().unwrap().clear();
+        self.inner.hardware_frames.lock().unwrap().clear();
+    }
+
+    /// Backend that decoded the most recent window, `None` before the first decode.
+    pub fn backend(&self) -> Option<DecodeBackend> {
+        *self.inner.last_backend.lock().unwrap()
+    }
+
+    fn cached_bytes(&self) -> usize {
+        self.inner
+            .frames
+            .read()
+            .unwrap()
+            .values()
+            .filter_map(|future| future.get_now())
+            .map(|frame| frame_cost(&frame))
+            .sum()
+    }
+
+    fn stats(&self) -> DecoderStats {
+        DecoderStats {
+            path: self.inner.path.clone(),
+            width: self.inner.options.width,
+            height: self.inner.options.height,
+            hwaccel: self.inner.options.hwaccel,
+            quality: self.inner.options.quality,
+            tonemap: self.inner.options.tonemap,
+            cached_frames: self.inner.frames.read().unwrap().len(),
+            cached_bytes: self.cached_bytes(),
+            waiting_frames: self.inner.waiting.lock().unwrap().len(),
+            claimed_ranges: self.inner.decoding_frames.lock().unwrap().range_count(),
+            backend: self.backend(),
+            metrics: self.inner.metrics.snapshot(),
+        }
+    }
+
     async fn schedule_gc(&self) {
         let self_clone = self.clone();
 
         tokio::spawn(async move {
             loop {
+                if self_clone.inner.closed.load(Ordering::Relaxed) {
+                    break;
+                }
+
                 if ENTIRE_CACHE_SIZE.load(Ordering::Relaxed)
                     >= MAX_CACHE_SIZE.load(Ordering::Relaxed)
                 {
-                    let mut frames = self_clone.inner.frames.write().unwrap();
+                    self_clone.evict_for_pressure();
+                }
+
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        });
+    }
 
-                    let all_frame_index = frames.keys().cloned().collect::<Vec<_>>();
+    /// Drop completed, unwatched frames until the cache is below its limit.
+    /// Candidates are collected under read locks; each removal takes the write
+    /// locks only for that single entry.
+    fn evict_for_pressure(&self) {
+        let mut candidates = {
+            let frames = self.inner.frames.read().unwrap();
+            let waiting = self.inner.waiting.lock().unwrap();
 
-                    for frame_index in all_frame_index.into_iter().rev() {
-                        let future = frames.get(&frame_index).unwrap();
-                        let mut frame_states = self_clone.inner.frame_states.write().unwrap();
-                        let frame_state = frame_states
-                            .get(&frame_index)
-                            .cloned()
-                            .unwrap_or(FrameState::None);
+            frames
+                .iter()
+                .filter(|(frame_index, future)| {
+                    future.is_completed() && !waiting.contains_key(frame_index)
+                })
+                .map(|(frame_index, _)| *frame_index)
+                .collect::<Vec<_>>()
+        };
+        candidates.sort_unstable_by(|a, b| b.cmp(a));
 
-                        if future.is_completed() && frame_state == FrameState::None {
-                            let future = frames.remove(&frame_index).unwrap();
-                            frame_states.insert(frame_index, FrameState::Drop);
+        for frame_index in candidates {
+            if ENTIRE_CACHE_SIZE.load(Ordering::Relaxed) < MAX_CACHE_SIZE.load(Ordering::Relaxed) {
+                break;
+            }
 
-                            ENTIRE_CACHE_SIZE
-                                .fetch_sub(future.get_now().unwrap().len(), Ordering::Relaxed);
+            // lock order: frames -> waiting
+            let removed = {
+                let mut frames = self.inner.frames.write().unwrap();
+                let waiting = self.inner.waiting.lock().unwrap();
 
-                            if ENTIRE_CACHE_SIZE.load(Ordering::Relaxed)
-                                < MAX_CACHE_SIZE.load(Ordering::Relaxed)
-                            {
-                                break;
-                            }
-                        }
+                match frames.get(&frame_index).and_then(|future| future.get_now()) {
+                    Some(frame) if !waiting.contains_key(&frame_index) => {
+                        frames.remove(&frame_index);
+                        Some(frame)
                     }
+                    _ => None,
                 }
+            };
 
-                tokio::time::sleep(Duration::from_secs(5)).await;
+            if let Some(frame) = removed {
+                release_frame(&frame);
             }
-        });
+        }
     }
 
-    pub async fn get_frame(&self, frame_index: u32) -> Arc<Vec<u8>> {
+    /// Frames in the source, probed once per decoder. `None` if it cannot be determined.
+    async fn frame_count(&self) -> Option<u32> {
+        *self
+            .inner
+            .frame_count
+            .get_or_init(|| async {
+                let path = self.inner.path.clone();
+                tokio::task::spawn_blocking(move || probe_video_frames(&path))
+                    .await
+                    .ok()
+                    .and_then(Result::ok)
+                    .map(|count| count.frames.min(u32::MAX as u64) as u32)
+            })
+            .await
+    }
+
+    /// The frame count if it has been probed already. The first call starts the probe
+    /// in the background, so frame requests never wait for it.
+    fn known_frame_count(&self) -> Option<u32> {
+        if let Some(count) = self.inner.frame_count.get() {
+            return *count;
+        }
+        if !self.inner.counting.swap(true, Ordering::Relaxed) {
+            let decoder = self.clone();
+            tokio::spawn(async move {
+                tokio::select! {
+                    _ = decoder.inner.cancel.cancelled() => {}
+                    _ = decoder.frame_count() => {}
+                }
+            });
+        }
+        None
+    }
+
+    /// Claim the window starting at `frame_index` and return its exclusive end,
+    /// or `None` if it was already claimed.
+    fn claim_window(&self, frame_index: u32) -> Option<u32> {
+        const DECODE_CHUNK: u32 = 120;
+
+        let mut decoding_frames = self.inner.decoding_frames.lock().unwrap();
+
+        if decoding_frames.contains(frame_index) {
+            return None;
+        }
+
+        let mut chunk_end = frame_index.saturating_add(DECODE_CHUNK);
+        if let Some(count) = self.inner.frame_count.get().copied().flatten() {
+            chunk_end = chunk_end.min(count).max(frame_index.saturating_add(1));
+        }
+        let end_frame = match decoding_frames.next_start_after(frame_index) {
+            Some(next) if next < chunk_end => next,
+            _ => chunk_end,
+        };
+
+        decoding_frames.insert(frame_index, end_frame);
+        self.inner
+            .pending_windows
+            .lock()
+            .unwrap()
+            .insert(frame_index, end_frame);
+
+        Some(end_frame)
+    }
+
+    fn release_window(&self, start_frame: u32, end_frame: u32) {
+        self.inner
+            .decoding_frames
+            .lock()
+            .unwrap()
+            .remove(start_frame, end_frame);
+    }
+
+    /// Decode a single evicted frame and put it back into the cache. When the source
+    /// no longer produces it, the wait policy's fallback is served instead.
+    async fn redecode_frame(
+        &self,
+        frame_index: u32,
+    ) -> Result<(Arc<Vec<u8>>, Option<DecodeBackend>), String> {
+        self.inner
+            .metrics
+            .cache_misses
+            .fetch_add(1, Ordering::Relaxed);
+
+        let inner = self.inner.clone();
+        let started = Instant::now();
+        let result = extract_frame(
+            inner.source.as_ref(),
+            &inner.path,
+            frame_index as _,
+            inner.options,
+            &inner.cancel,
+        )
+        .await?;
+
+        let Some((result, backend)) = result else {
+            self.inner.metrics.record_decode(0, 0, started.elapsed());
+            return Ok((self.fallback_frame(frame_index)?, None));
+        };
+
+        self.inner
+            .metrics
+            .record_decode(1, result.len(), started.elapsed());
+
+        let frame = Arc::new(result);
+        self.cache_redecoded(frame_index, frame.clone(), backend)
+            .await;
+
+        Ok((frame, Some(backend)))
+    }
+
+    /// Re-decoded frames are requested repeatedly (looping, scrubbing back), so they
+    /// stay cached after being sent and are only dropped by the GC under pressure.
+    async fn cache_redecoded(&self, frame_index: u32, frame: Arc<Vec<u8>>, backend: DecodeBackend) {
+        if self.inner.closed.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let cost = frame_cost(&frame);
+        if ENTIRE_CACHE_SIZE.load(Ordering::Relaxed) + cost > MAX_CACHE_SIZE.load(Ordering::Relaxed)
         {
-            let mut decoding_frames = self.inner.decoding_frames.lock().unwrap();
+            return;
+        }
 
-            const DECODE_CHUNK: u32 = 120;
+        let future = {
+            let mut frames = self.inner.frames.write().unwrap();
+            frames.entry(frame_index).or_default().clone()
+        };
+        self.inner
+            .revisited
+            .lock()
+            .unwrap()
+            .insert(frame_index, frame_index.saturating_add(1));
 
-            if !decoding_frames.contains(&frame_index) {
-                let mut last_frame = frame_index;
-                for frame_index in frame_index..(frame_index + DECODE_CHUNK) {
-                    if decoding_frames.contains(&frame_index) {
-                        break;
-                    }
-                    last_frame = frame_index;
-                }
+        if future.complete(frame.clone()).await {
+            self.record_backend(frame_index, backend);
+            account_frame(&frame);
+        }
+    }
 
-                for frame_index in frame_index..=last_frame {
-                    decoding_frames.insert(frame_index);
-                }
+    /// Whether `frame_index` was decoded before but is no longer cached and
+    /// no running decode will produce it again (evicted or already sent).
+    fn needs_redecode(&self, frame_index: u32) -> bool {
+        let cached = self
+            .inner
+            .frames
+            .read()
+            .unwrap()
+            .get(&frame_index)
+            .is_some_and(|future| future.is_completed());
+
+        !cached
+            && !self
+                .inner
+                .pending_windows
+                .lock()
+                .unwrap()
+                .contains(frame_index)
+    }
 
-                self.inner
-                    .running_decode_tasks
-                    .fetch_add(1, Ordering::Relaxed);
+    fn spawn_decode(&self, frame_index: u32, end_frame: u32) {
+        self.inner
+            .running_decode_tasks
+            .fetch_add(1, Ordering::Relaxed);
 
-                let self_clone = self.clone();
+        let self_clone = self.clone();
 
-                tokio::spawn(async move {
-                    let result = hw_decoder::extract_frame_window_hw_rgba(
-                        &self_clone.inner.path,
+        tokio::spawn(async move {
+            let started = Instant::now();
+            let (tx, mut rx) = mpsc::channel(FRAME_CHANNEL_CAPACITY);
+            let inner = &self_clone.inner;
+            // tx はこのブロックと一緒に破棄され、受信ループが終わる
+            let extract = async move {
+                inner
+                    .source
+                    .extract_window(
+                        &inner.path,
                         frame_index as _,
-                        last_frame as _,
-                        self_clone.inner.width,
-                        self_clone.inner.height,
+                        end_frame as _,
+                        inner.options,
+                        &inner.cancel,
+                        &tx,
+                    )
+                    .await
+            };
+            // フレームが届くたびに待機者を起こす
+            let deliver = async {
+                let mut delivered = (0usize, 0usize);
+                let mut next_missing = frame_index;
+                while let Some((index, frame, backend)) = rx.recv().await {
+                    let index = index as u32;
+                    delivered.0 += 1;
+                    delivered.1 += frame.len();
+                    next_missing = next_missing.max(index.saturating_add(1));
+                    self_clone.complete_frame(index, frame, backend).await;
+                }
+                (delivered, next_missing)
+            };
+            let (result, ((frames, bytes), next_missing)) = tokio::join!(extract, deliver);
+
+            self_clone
+                .inner
+                .metrics
+                .record_decode(frames, bytes, started.elapsed());
+
+            if let Ok(extracted) = &result {
+                *self_clone.inner.last_backend.lock().unwrap() = Some(extracted.backend);
+            }
+            if let Err(err) = result {
+                // 届かなかった範囲は再リクエストでデコードし直せるようにする
+                self_clone.release_window(next_missing, end_frame);
+
+                if !self_clone.inner.cancel.is_cancelled() {
+                    error!(
+                        path = %self_clone.inner.path,
+                        frame_index,
+                        end_frame,
+                        "failed to decode frame window: {err}"
                     );
+                }
+            }
 
-                    match result {
-                        Ok(result) => {
-                            let futures = {
-                                let mut frames = self_clone.inner.frames.write().unwrap();
-
-                                let mut futures = Vec::new();
-                                for (frame_index, _) in result.iter() {
-                                    let future = frames
-                                        .entry(*frame_index as _)
-                                        .or_insert_with(|| SharedManualFuture::new())
-                                        .clone();
-                                    futures.push(future);
-                                }
-
-                                futures
-                            };
-
-                            for (future, (_, frame)) in futures.into_iter().zip(result.into_iter())
-                            {
-                                ENTIRE_CACHE_SIZE.fetch_add(frame.len(), Ordering::Relaxed);
-                                future.complete(Arc::new(frame)).await;
-                            }
-                        }
-                        Err(_) => todo!(),
-                    }
+            self_clone
+                .inner
+                .pending_windows
+                .lock()
+                .unwrap()
+                .remove(frame_index, end_frame);
+            self_clone
+                .inner
+                .running_decode_tasks
+                .fetch_sub(1, Ordering::Relaxed);
+        });
+    }
 
-                    self_clone
-                        .inner
-                        .running_decode_tasks
-                        .fetch_sub(1, Ordering::Relaxed);
-                });
+    async fn complete_frame(&self, frame_index: u32, frame: Vec<u8>, backend: DecodeBackend) {
+        let future = {
+            let mut frames = self.inner.frames.write().unwrap();
+            frames.entry(frame_index).or_default().clone()
+        };
+
+        // ロックを解放してから待機者を起こす
+        let frame = Arc::new(frame);
+        if future.complete(frame.clone()).await {
+            self.record_backend(frame_index, backend);
+            // 切り離されたデコーダは全体のキャッシュサイズに計上しない
+            if !self.inner.closed.load(Ordering::Relaxed) {
+                account_frame(&frame);
             }
         }
+    }
 
-        {
-            let frame_state = {
-                let mut frame_states = self.inner.frame_states.write().unwrap();
+    /// Remember which backend produced the cached copy of `frame_index`.
+    fn record_backend(&self, frame_index: u32, backend: DecodeBackend) {
+        let mut hardware_frames = self.inner.hardware_frames.lock().unwrap();
+        let end = frame_index.saturating_add(1);
+        match backend {
+            DecodeBackend::Hardware => hardware_frames.insert(frame_index, end),
+            DecodeBackend::Software => hardware_frames.remove(frame_index, end),
+        }
+    }
 
-                let frame_state = frame_states
-                    .get(&frame_index)
-                    .cloned()
-                    .unwrap_or(FrameState::None);
+    fn frame_backend(&self, frame_index: u32) -> DecodeBackend {
+        if self
+            .inner
+            .hardware_frames
+            .lock()
+            .unwrap()
+            .contains(frame_index)
+        {
+            DecodeBackend::Hardware
+        } else {
+            DecodeBackend::Software
+        }
+    }
 
-                frame_states.insert(frame_index, FrameState::Wait);
+    /// Closest decoded frame before `frame_index`, looked up under a single read lock.
+    fn previous_frame(&self, frame_index: u32) -> Option<Arc<Vec<u8>>> {
+        let frames = self.inner.frames.read().unwrap();
 
-                frame_state
-            };
+        frames
+            .iter()
+            .filter(|(index, _)| **index < frame_index)
+            .filter_map(|(index, future)| future.get_now().map(|frame| (*index, frame)))
+            .max_by_key(|(index, _)| *index)
+            .map(|(_, frame)| frame)
+    }
 
-            if let FrameState::Drop | FrameState::Wait = frame_state {
-                let result = hw_decoder::extract_frame_hw_rgba(
-                    &self.inner.path,
-                    frame_index as _,
-                    self.inner.width,
-                    self.inner.height,
-                );
+    /// What the wait policy serves in place of a frame the source never produced.
+    /// Fallback frames are never cached.
+    fn fallback_frame(&self, frame_index: u32) -> Result<Arc<Vec<u8>>, String> {
+        let empty = || {
+            Arc::new(generate_empty_frame(
+                self.inner.options.width,
+                self.inner.options.height,
+            ))
+        };
 
-                match result {
-                    Ok(result) => {
-                        return Arc::new(result);
-                    }
-                    Err(_) => todo!(),
-                }
+        let frame = match self.inner.wait_policy.fallback {
+            FallbackPolicy::PreviousFrame => self.previous_frame(frame_index).unwrap_or_else(empty),
+            FallbackPolicy::Empty => empty(),
+            FallbackPolicy::Error => {
+                return Err(format!(
+                    "frame {frame_index} was not produced by the decoder"
+                ));
             }
+        };
+        self.inner
+            .metrics
+            .fallback_frames
+            .fetch_add(1, Ordering::Relaxed);
+
+        Ok(frame)
+    }
+
+    pub async fn get_frame(&self, frame_index: u32) -> Result<Arc<Vec<u8>>, String> {
+        self.get_frame_with_backend(frame_index)
+            .await
+            .map(|(frame, _)| frame)
+    }
+
+    /// Like `get_frame`, also returning the backend that decoded the frame. `None` when
+    /// the wait policy served a fallback in its place.
+    pub async fn get_frame_with_backend(
+        &self,
+        frame_index: u32,
+    ) -> Result<(Arc<Vec<u8>>, Option<DecodeBackend>), String> {
+        if self.inner.cancel.is_cancelled() {
+            return Err(CANCELED.to_string());
+        }
+
+        // 末尾を越えたフレームは最終フレームで代用する（静止画は常にフレーム0）。
+        // 数え終わるまではクランプせず、越えた分は待機ポリシーの代替に任せる
+        let frame_index = match self.known_frame_count() {
+            Some(count) if count > 0 => frame_index.min(count - 1),
+            _ => frame_index,
+        };
+
+        let claimed = self.claim_window(frame_index);
+        if let Some(end_frame) = claimed {
+            self.spawn_decode(frame_index, end_frame);
+        }
+
+        let _wait_guard = WaitGuard::new(&self.inner, frame_index);
+
+        if claimed.is_none() && self.needs_redecode(frame_index) {
+            return self.redecode_frame(frame_index).await;
         }
 
         let future = {
             let mut frames = self.inner.frames.write().unwrap();
 
-            frames
-                .entry(frame_index)
-                .or_insert_with(|| SharedManualFuture::new())
-                .clone()
+            frames.entry(frame_index).or_default().clone()
         };
 
-        let frame;
+        let counter = match future.is_completed() {
+            true => &self.inner.metrics.cache_hits,
+            false => &self.inner.metrics.cache_misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
 
-        loop {
-            match timeout(Duration::from_secs(1), future.get()).await {
-                Ok(result) => {
-                    frame = result;
-                    break;
-                }
-                Err(_) => match self.inner.running_decode_tasks.load(Ordering::Relaxed) > 0 {
-                    true => continue,
-                    false => {
-                        // 多分ドロップフレーム
-                        // frame_indexに穴がある場合
-                        // 直前のフレームを持ってくる
-                        let mut frame_index = frame_index;
-                        loop {
-                            match frame_index.checked_sub(1) {
-                                Some(new_index) => {
-                                    frame_index = new_index;
-
-                                    let frames = self.inner.frames.read().unwrap();
-
-                                    match frames.get(&frame_index) {
-                                        Some(future) => match future.get_now() {
-                                            Some(result) => {
-                                                frame = result;
-                                                break;
-                                            }
-                                            None => continue,
-                                        },
-                                        None => continue,
-                                    }
-                                }
-                                None => {
-                                    frame = Arc::new(generate_empty_frame(
-                                        self.inner.width,
-                                        self.inner.height,
-                                    ));
-                                    break;
-                                }
-                            }
-                        }
+        let policy = &self.inner.wait_policy;
+        let deadline = Instant::now() + policy.budget;
 
-                        break;
-                    }
-                },
+        let (frame, backend) = loop {
+            // a wait that times out as the decoder closes must not fall back
+            let waited = tokio::select! {
+                biased;
+                _ = self.inner.cancel.cancelled() => return Err(CANCELED.to_string()),
+                waited = timeout(policy.wait, future.get()) => waited,
+            };
+
+            if let Ok(result) = waited {
+                break (result, Some(self.frame_backend(frame_index)));
             }
-        }
+            if let Some(result) = future.get_now() {
+                break (result, Some(self.frame_backend(frame_index)));
+            }
+
+            let pending = self
+                .inner
+                .pending_windows
+                .lock()
+                .unwrap()
+                .contains(frame_index);
+            if pending && Instant::now() < deadline {
+                continue;
+            }
+
+            // 多分ドロップフレーム
+            // frame_indexに穴がある場合、ポリシーに従ってフォールバックする
+            break (self.fallback_frame(frame_index)?, None);
+        };
 
         {
             // 送信が終わったフレームは解放する。
@@ -339,14 +1033,20 @@ impl CachedDecoder {
             // frame_index = 0のリクエストが複数飛んでくる。
             // 0の場合に解放してしまうと、後方のレスポンスが帰らずに無限に待たせてしまう。
             // おそらく、もっと良いロジックがあるが、一旦は0のみ解放しないことで実装する。
-            if frame_index != 0 {
-                ENTIRE_CACHE_SIZE.fetch_sub(frame.len(), Ordering::Relaxed);
-
-                self.inner.frames.write().unwrap().remove(&frame_index);
+            // 再デコードしたフレームは繰り返し参照されるのでキャッシュに残す。
+            let revisited = self.inner.revisited.lock().unwrap().contains(frame_index);
+            if frame_index != 0 && !revisited {
+                let removed = self.inner.frames.write().unwrap().remove(&frame_index);
+
+                // フォールバックで返したフレームは計上されていないので、
+                // 実際にマップから外れた完了済みフレームだけを差し引く。
+                if let Some(cached) = removed.and_then(|future| future.get_now()) {
+                    release_frame(&cached);
+                }
             }
         }
 
-        frame
+        Ok((frame, backend))
     }
 }
 
@@ -371,3 +1071,493 @@ pub fn generate_empty_frame(width: u32, height: u32) -> Vec<u8> {
 
     buf
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::source::SyntheticFrameSource;
+
+    const WIDTH: u32 = 4;
+    const HEIGHT: u32 = 2;
+
+    /// The cache size and its limit are process-wide, so decoder tests run one at a time.
+    static SERIAL: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+    const FAST_POLICY: WaitPolicy = WaitPolicy {
+        wait: Duration::from_millis(20),
+        budget: Duration::from_secs(5),
+        fallback: FallbackPolicy::PreviousFrame,
+    };
+
+    fn decoder(source: SyntheticFrameSource, policy: WaitPolicy) -> Decoder {
+        Decoder::with_source(Arc::new(source), policy)
+    }
+
+    fn source(latency_ms: u64, total_frames: usize, drop_every: usize) -> SyntheticFrameSource {
+        SyntheticFrameSource::new(
+            Duration::from_millis(latency_ms),
+            total_frames,
+            drop_every,
+            0,
+        )
+    }
+
+    fn key(width: u32, height: u32) -> DecoderKey {
+        DecoderKey {
+            path: "synthetic.mp4".to_string(),
+            width,
+            height,
+            hwaccel: HwAccel::None,
+            quality: ScaleQuality::default(),
+            tonemap: false,
+        }
+    }
+
+    fn expected(frame_index: usize) -> Vec<u8> {
+        SyntheticFrameSource::frame(frame_index, WIDTH, HEIGHT)
+    }
+
+    /// Wait until every decode task of `decoder` has finished.
+    async fn settle(decoder: &CachedDecoder) {
+        while decoder.inner.running_decode_tasks.load(Ordering::Relaxed) > 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    /// Evict every unwatched frame by pushing the cache over a minimal limit.
+    fn evict_all(decoder: &CachedDecoder) {
+        const OVERFLOW: usize = 2 * 1024 * 1024;
+
+        let max = get_cache_usage().1;
+        set_max_cache_size(0);
+        ENTIRE_CACHE_SIZE.fetch_add(OVERFLOW, Ordering::Relaxed);
+        decoder.evict_for_pressure();
+        ENTIRE_CACHE_SIZE.fetch_sub(OVERFLOW, Ordering::Relaxed);
+        set_max_cache_size(max);
+    }
+
+    #[tokio::test]
+    async fn one_request_decodes_a_whole_chunk() {
+        let _serial = SERIAL.lock().await;
+        let decoders = decoder(source(0, 1000, 0), FAST_POLICY);
+        let decoder = decoders.cached_decoder(key(WIDTH, HEIGHT)).await;
+
+        assert_eq!(*decoder.get_frame(0).await.unwrap(), expected(0));
+        settle(&decoder).await;
+
+        for index in 1..120 {
+            assert_eq!(
+                *decoder.get_frame(index).await.unwrap(),
+                expected(index as usize)
+            );
+        }
+        let stats = decoder.stats();
+        assert_eq!(stats.metrics.frames_decoded, 120);
+        assert_eq!(stats.claimed_ranges, 1);
+
+        decoders.clear().await;
+    }
+
+    #[tokio::test]
+    async fn claimed_windows_stop_at_the_next_claim_and_coalesce() {
+        let _serial = SERIAL.lock().await;
+        let decoder = CachedDecoder::new(
+            key(WIDTH, HEIGHT),
+            Arc::new(source(0, 1000, 0)),
+            FAST_POLICY,
+        );
+
+        assert_eq!(decoder.claim_window(200), Some(320));
+        assert_eq!(decoder.claim_window(100), Some(200));
+        assert_eq!(decoder.claim_window(150), None);
+        assert_eq!(decoder.claim_window(319), None);
+        assert_eq!(decoder.stats().claimed_ranges, 1);
+
+        assert_eq!(decoder.claim_window(320), Some(440));
+        assert_eq!(decoder.stats().claimed_ranges, 1);
+    }
+
+    #[tokio::test]
+    async fn pressure_evicts_unwatched_frames_from_the_back() {
+        let _serial = SERIAL.lock().await;
+        let (width, height) = (256, 256);
+        let decoders = decoder(source(0, 16, 0), FAST_POLICY);
+        let decoder = decoders.cached_decoder(key(width, height)).await;
+
+        decoder.get_frame(0).await.unwrap();
+        settle(&decoder).await;
+        let before = decoder.stats();
+        assert_eq!(before.cached_frames, 16);
+
+        let max = get_cache_usage().1;
+        set_max_cache_size(1024 * 1024);
+        decoder.evict_for_pressure();
+        let (used, limit) = get_cache_usage();
+        set_max_cache_size(max);
+
+        assert!(used < limit, "{used} >= {limit}");
+        let mut kept = decoder
+            .inner
+            .frames
+            .read()
+            .unwrap()
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+        kept.sort_unstable();
+        assert!(kept.len() > 1 && kept.len() < 16, "{kept:?}");
+        // the highest indices go first
+        assert_eq!(kept, (0..kept.len() as u32).collect::<Vec<_>>());
+
+        decoders.clear().await;
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests_for_one_frame_share_a_single_decode() {
+        let _serial = SERIAL.lock().await;
+        let decoders = decoder(source(50, 1000, 0), FAST_POLICY);
+        let decoder = decoders.cached_decoder(key(WIDTH, HEIGHT)).await;
+
+        let results = futures::future::join_all((0..8).map(|_| decoder.get_frame(10))).await;
+
+        let first = results[0].as_ref().unwrap();
+        assert_eq!(**first, expected(10));
+        for result in &results {
+            assert!(Arc::ptr_eq(first, result.as_ref().unwrap()));
+        }
+        settle(&decoder).await;
+        assert_eq!(decoder.stats().metrics.frames_decoded, 120);
+
+        decoders.clear().await;
+    }
+
+    #[tokio::test]
+    async fn frames_do_not_wait_for_the_frame_count() {
+        let _serial = SERIAL.lock().await;
+        let decoders = decoder(source(0, 100, 0), FAST_POLICY);
+        let decoder = decoders.cached_decoder(key(WIDTH, HEIGHT)).await;
+
+        assert_eq!(*decoder.get_frame(3).await.unwrap(), expected(3));
+        // the count is probed in the background, once
+        assert!(decoder.inner.counting.load(Ordering::Relaxed));
+        decoder.frame_count().await;
+        assert!(decoder.inner.frame_count.initialized());
+
+        decoders.clear().await;
+    }
+
+    #[tokio::test]
+    async fn dropped_frame_falls_back_to_the_previous_frame() {
+        let _serial = SERIAL.lock().await;
+        let decoders = decoder(source(10, 100, 5), FAST_POLICY);
+        let decoder = decoders.cached_decoder(key(WIDTH, HEIGHT)).await;
+        decoder.frame_count().await;
+
+        let (first, dropped) = tokio::join!(decoder.get_frame(1), decoder.get_frame(5));
+
+        assert_eq!(*first.unwrap(), expected(1));
+        assert_eq!(*dropped.unwrap(), expected(4));
+        assert_eq!(decoder.stats().metrics.fallback_frames, 1);
+
+        decoders.clear().await;
+    }
+
+    #[tokio::test]
+    async fn dropped_frame_follows_the_empty_and_error_policies() {
+        let _serial = SERIAL.lock().await;
+        for fallback in [FallbackPolicy::Empty, FallbackPolicy::Error] {
+            let policy = WaitPolicy {
+                fallback,
+                ..FAST_POLICY
+            };
+            let decoders = decoder(source(0, 100, 5), policy);
+            let decoder = decoders.cached_decoder(key(WIDTH, HEIGHT)).await;
+
+            let result = decoder.get_frame(5).await;
+            match fallback {
+                FallbackPolicy::Empty => {
+                    assert_eq!(*result.unwrap(), generate_empty_frame(WIDTH, HEIGHT))
+                }
+                _ => assert_eq!(
+                    result.unwrap_err(),
+                    "frame 5 was not produced by the decoder"
+                ),
+            }
+
+            decoders.clear().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn clear_cancels_pending_requests() {
+        let _serial = SERIAL.lock().await;
+        let decoders = Arc::new(decoder(source(10_000, 1000, 0), FAST_POLICY));
+        let decoder = decoders.cached_decoder(key(WIDTH, HEIGHT)).await;
+
+        let request = tokio::spawn({
+            let decoder = decoder.clone();
+            async move { decoder.get_frame(3).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let started = Instant::now();
+        assert!(matches!(decoders.clear().await, ClearOutcome::Clean));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(request.await.unwrap().unwrap_err(), CANCELED);
+        assert_eq!(decoder.get_frame(3).await.unwrap_err(), CANCELED);
+        assert!(decoders.decoder_stats().is_empty());
+    }
+
+    #[tokio::test]
+    async fn metrics_follow_a_scripted_request_sequence() {
+        let _serial = SERIAL.lock().await;
+        let decoders = decoder(source(0, 1000, 0), FAST_POLICY);
+        let decoder = decoders.cached_decoder(key(WIDTH, HEIGHT)).await;
+        let frame_bytes = (WIDTH * HEIGHT * 4) as u64;
+
+        // cold request: miss, then the whole chunk is decoded
+        decoder.get_frame(0).await.unwrap();
+        settle(&decoder).await;
+        let metrics = decoder.stats().metrics;
+        assert_eq!((metrics.cache_hits, metrics.cache_misses), (0, 1));
+        assert_eq!(metrics.frames_decoded, 120);
+        assert_eq!(metrics.bytes_decoded, 120 * frame_bytes);
+
+        // cached frames are hits; frame 1 leaves the cache once sent
+        decoder.get_frame(1).await.unwrap();
+        decoder.get_frame(2).await.unwrap();
+        let metrics = decoder.stats().metrics;
+        assert_eq!((metrics.cache_hits, metrics.cache_misses), (2, 1));
+
+        // asking for it again re-decodes a single frame
+        decoder.get_frame(1).await.unwrap();
+        let metrics = decoder.stats().metrics;
+        assert_eq!((metrics.cache_hits, metrics.cache_misses), (2, 2));
+        assert_eq!(metrics.frames_decoded, 121);
+        assert_eq!(metrics.bytes_decoded, 121 * frame_bytes);
+        assert_eq!(metrics.fallback_frames, 0);
+
+        // evicting frames keeps the counters
+        evict_all(&decoder);
+        assert_eq!(decoder.stats().cached_frames, 0);
+        assert_eq!(decoder.stats().metrics.frames_decoded, 121);
+
+        // /reset drops the decoder and with it the counters
+        decoders.clear().await;
+        let decoder = decoders.cached_decoder(key(WIDTH, HEIGHT)).await;
+        let metrics = decoder.stats().metrics;
+        assert_eq!((metrics.cache_hits, metrics.cache_misses), (0, 0));
+        assert_eq!(metrics.frames_decoded, 0);
+
+        decoders.clear().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_requests_gc_and_clear_do_not_deadlock() {
+        let _serial = SERIAL.lock().await;
+        let decoders = Arc::new(decoder(source(1, 2000, 7), FAST_POLICY));
+        let max = get_cache_usage().1;
+        set_max_cache_size(1024 * 1024);
+
+        let stress = async {
+            for round in 0..5u32 {
+                let decoder = decoders.cached_decoder(key(64, 64)).await;
+                let requests = (0..8u32).map(|socket| {
+                    let decoder = decoder.clone();
+                    tokio::spawn(async move {
+                        for step in 0..40 {
+                            let index = (socket * 97 + step * 13 + round * 31) % 2000;
+                            match decoder.get_frame(index).await {
+                                Ok(frame) => assert_eq!(frame.len(), 64 * 64 * 4),
+                                Err(error) => assert_eq!(error, CANCELED),
+                            }
+                        }
+                    })
+                });
+                let gc = tokio::spawn({
+                    let decoder = decoder.clone();
+                    async move {
+                        for _ in 0..20 {
+                            decoder.evict_for_pressure();
+                            tokio::task::yield_now().await;
+                        }
+                    }
+                });
+                let requests = tokio::spawn(futures::future::join_all(requests));
+
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                decoders.clear().await;
+                for request in requests.await.unwrap() {
+                    request.unwrap();
+                }
+                gc.await.unwrap();
+            }
+        };
+        let finished = timeout(Duration::from_secs(30), stress).await;
+        set_max_cache_size(max);
+
+        assert!(finished.is_ok(), "decoder deadlocked under concurrent load");
+        assert!(decoders.decoder_stats().is_empty());
+    }
+
+    #[tokio::test]
+    async fn scrubbing_a_long_range_keeps_state_bounded() {
+        let _serial = SERIAL.lock().await;
+        let decoders = decoder(source(0, 500_000, 0), FAST_POLICY);
+        let decoder = decoders.cached_decoder(key(WIDTH, HEIGHT)).await;
+
+        // forward across ~33 minutes at 60 fps, one window every 250 frames
+        for index in (0..120_000).step_by(250) {
+            decoder.get_frame(index).await.unwrap();
+        }
+        settle(&decoder).await;
+        evict_all(&decoder);
+
+        // back across the first windows, re-decoding every evicted frame
+        for index in (0..1_000).rev() {
+            decoder.get_frame(index).await.unwrap();
+        }
+
+        // state grows with windows, not with frames
+        let stats = decoder.stats();
+        assert_eq!(stats.waiting_frames, 0);
+        assert!(stats.claimed_ranges <= 480, "{}", stats.claimed_ranges);
+        assert_eq!(
+            decoder.inner.pending_windows.lock().unwrap().range_count(),
+            0
+        );
+        assert_eq!(decoder.inner.revisited.lock().unwrap().range_count(), 4);
+
+        decoders.clear().await;
+    }
+
+    /// Decodes whole windows but never produces a frame asked for on its own.
+    #[derive(Debug)]
+    struct NoSingleFrames(SyntheticFrameSource);
+
+    impl FrameSource for NoSingleFrames {
+        fn extract_window<'a>(
+            &'a self,
+            path: &'a str,
+            start_frame: usize,
+            end_frame: usize,
+            options: DecodeOptions,
+            cancel: &'a CancellationToken,
+            frames: &'a crate::ffmpeg::FrameSender,
+        ) -> futures::future::BoxFuture<'a, Result<crate::ffmpeg::hw_decoder::Extracted, String>>
+        {
+            let end_frame = match end_frame - start_frame {
+                1 => start_frame,
+                _ => end_frame,
+            };
+            self.0
+                .extract_window(path, start_frame, end_frame, options, cancel, frames)
+        }
+    }
+
+    #[tokio::test]
+    async fn missing_redecoded_frame_follows_the_policy_and_is_not_cached() {
+        let _serial = SERIAL.lock().await;
+        for fallback in [FallbackPolicy::PreviousFrame, FallbackPolicy::Error] {
+            let policy = WaitPolicy {
+                fallback,
+                ..FAST_POLICY
+            };
+            let decoders =
+                Decoder::with_source(Arc::new(NoSingleFrames(source(0, 1000, 0))), policy);
+            let decoder = decoders.cached_decoder(key(WIDTH, HEIGHT)).await;
+
+            decoder.get_frame(0).await.unwrap();
+            settle(&decoder).await;
+            // sending frame 3 drops it from the cache
+            assert_eq!(*decoder.get_frame(3).await.unwrap(), expected(3));
+
+            for _ in 0..2 {
+                let result = decoder.get_frame(3).await;
+                match fallback {
+                    FallbackPolicy::PreviousFrame => {
+                        assert_eq!(*result.unwrap(), expected(2))
+                    }
+                    _ => assert_eq!(
+                        result.unwrap_err(),
+                        "frame 3 was not produced by the decoder"
+                    ),
+                }
+            }
+
+            assert!(!decoder.inner.frames.read().unwrap().contains_key(&3));
+            assert!(!decoder.inner.revisited.lock().unwrap().contains(3));
+            let expected_fallbacks = match fallback {
+                FallbackPolicy::PreviousFrame => 2,
+                _ => 0,
+            };
+            assert_eq!(decoder.stats().metrics.fallback_frames, expected_fallbacks);
+
+            decoders.clear().await;
+        }
+    }
+
+    /// Reports every frame of the wrapped source as decoded in hardware.
+    #[derive(Debug)]
+    struct HardwareFrames(SyntheticFrameSource);
+
+    impl FrameSource for HardwareFrames {
+        fn extract_window<'a>(
+            &'a self,
+            path: &'a str,
+            start_frame: usize,
+            end_frame: usize,
+            options: DecodeOptions,
+            cancel: &'a CancellationToken,
+            frames: &'a crate::ffmpeg::FrameSender,
+        ) -> futures::future::BoxFuture<'a, Result<crate::ffmpeg::hw_decoder::Extracted, String>>
+        {
+            Box::pin(async move {
+                let (tx, mut rx) = mpsc::channel(1);
+                let (result, ()) = tokio::join!(
+                    async move {
+                        self.0
+                            .extract_window(path, start_frame, end_frame, options, cancel, &tx)
+                            .await
+                    },
+                    async {
+                        while let Some((index, frame, _)) = rx.recv().await {
+                            let _ = frames.send((index, frame, DecodeBackend::Hardware)).await;
+                        }
+                    }
+                );
+                result
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn frames_report_the_backend_that_decoded_them() {
+        let _serial = SERIAL.lock().await;
+        let policy = WaitPolicy {
+            fallback: FallbackPolicy::Empty,
+            ..FAST_POLICY
+        };
+        let decoders = Decoder::with_source(Arc::new(HardwareFrames(source(0, 100, 5))), policy);
+        let decoder = decoders.cached_decoder(key(WIDTH, HEIGHT)).await;
+        decoder.frame_count().await;
+
+        let (frame, backend) = decoder.get_frame_with_backend(3).await.unwrap();
+        assert_eq!(*frame, expected(3));
+        assert_eq!(backend, Some(DecodeBackend::Hardware));
+        settle(&decoder).await;
+        // sent frames leave the cache; this one comes back from a single-frame decode
+        let (_, backend) = decoder.get_frame_with_backend(3).await.unwrap();
+        assert_eq!(backend, Some(DecodeBackend::Hardware));
+        // dropped by the source: a fallback, decoded by neither
+        let (_, backend) = decoder.get_frame_with_backend(5).await.unwrap();
+        assert_eq!(backend, None);
+        decoders.clear().await;
+
+        let decoders = Decoder::with_source(Arc::new(source(0, 100, 0)), policy);
+        let software = decoders.cached_decoder(key(WIDTH, HEIGHT)).await;
+        let (_, backend) = software.get_frame_with_backend(3).await.unwrap();
+        assert_eq!(backend, Some(DecodeBackend::Software));
+        decoders.clear().await;
+    }
+}
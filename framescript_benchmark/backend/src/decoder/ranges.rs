@@ -0,0 +1,85 @@
+use std::collections::BTreeMap;
+
+/// Set of frame indices stored as disjoint, non-adjacent half-open ranges `[start, end)`.
+/// Memory grows with the number of gaps, not with the number of frames.
+#[derive(Debug, Default)]
+pub(crate) struct FrameRanges {
+    ranges: BTreeMap<u32, u32>,
+}
+
+impl FrameRanges {
+    pub(crate) fn contains(&self, index: u32) -> bool {
+        self.ranges
+            .range(..=index)
+            .next_back()
+            .is_some_and(|(_, end)| *end > index)
+    }
+
+    /// First index after `index` that is in the set.
+    pub(crate) fn next_start_after(&self, index: u32) -> Option<u32> {
+        let next = index.checked_add(1)?;
+        self.ranges.range(next..).next().map(|(start, _)| *start)
+    }
+
+    pub(crate) fn insert(&mut self, start: u32, end: u32) {
+        if end <= start {
+            return;
+        }
+
+        let mut start = start;
+        let mut end = end;
+
+        // merge with a range that overlaps or touches from the left
+        if let Some((&prev_start, &prev_end)) = self.ranges.range(..=start).next_back()
+            && prev_end >= start
+        {
+            start = prev_start;
+            end = end.max(prev_end);
+        }
+
+        // absorb every range that overlaps or touches from the right
+        let absorbed = self
+            .ranges
+            .range(start..=end)
+            .map(|(s, e)| (*s, *e))
+            .collect::<Vec<_>>();
+        for (s, e) in absorbed {
+            self.ranges.remove(&s);
+            end = end.max(e);
+        }
+
+        self.ranges.insert(start, end);
+    }
+
+    pub(crate) fn remove(&mut self, start: u32, end: u32) {
+        if end <= start {
+            return;
+        }
+
+        let affected = self
+            .ranges
+            .range(..end)
+            .filter(|(_, e)| **e > start)
+            .map(|(s, e)| (*s, *e))
+            .collect::<Vec<_>>();
+
+        for (s, e) in affected {
+            self.ranges.remove(&s);
+            if s < start {
+                self.ranges.insert(s, start);
+            }
+            if e > end {
+                self.ranges.insert(end, e);
+            }
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.ranges.clear();
+    }
+
+    /// Number of disjoint ranges held.
+    pub(crate) fn range_count(&self) -> usize {
+        self.ranges.len()
+    }
+}
@@ -0,0 +1,210 @@
+use std::{
+    fmt::Debug,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use futures::future::BoxFuture;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+use crate::ffmpeg::{
+    DecodeOptions, FrameSender,
+    command::CANCELED,
+    hw_decoder::{self, DecodeBackend, Extracted},
+};
+
+/// Where decoded RGBA frames come from.
+pub trait FrameSource: Debug + Send + Sync {
+    /// Decode the half-open window `[start_frame, end_frame)` as described by `options`.
+    /// Frames are sent to `frames` as they are decoded; frames that do not exist in the
+    /// source are simply never sent. Resolves to the number of frames delivered and the
+    /// backend that decoded them.
+    fn extract_window<'a>(
+        &'a self,
+        path: &'a str,
+        start_frame: usize,
+        end_frame: usize,
+        options: DecodeOptions,
+        cancel: &'a CancellationToken,
+        frames: &'a FrameSender,
+    ) -> BoxFuture<'a, Result<Extracted, String>>;
+}
+
+/// Decode only `frame_index`, with the backend that produced it. `None` if the source
+/// has no such frame.
+pub async fn extract_frame(
+    source: &dyn FrameSource,
+    path: &str,
+    frame_index: usize,
+    options: DecodeOptions,
+    cancel: &CancellationToken,
+) -> Result<Option<(Vec<u8>, DecodeBackend)>, String> {
+    let (tx, mut rx) = mpsc::channel(1);
+    let (result, frame) = tokio::join!(
+        async move {
+            source
+                .extract_window(path, frame_index, frame_index + 1, options, cancel, &tx)
+                .await
+        },
+        async {
+            let mut found = None;
+            while let Some((index, frame, backend)) = rx.recv().await {
+                if index == frame_index {
+                    found.get_or_insert((frame, backend));
+                }
+            }
+            found
+        }
+    );
+    result?;
+
+    Ok(frame)
+}
+
+/// Decodes through ffmpeg, trying hardware acceleration first.
+#[derive(Debug, Default)]
+pub struct FfmpegFrameSource;
+
+impl FrameSource for FfmpegFrameSource {
+    fn extract_window<'a>(
+        &'a self,
+        path: &'a str,
+        start_frame: usize,
+        end_frame: usize,
+        options: DecodeOptions,
+        cancel: &'a CancellationToken,
+        frames: &'a FrameSender,
+    ) -> BoxFuture<'a, Result<Extracted, String>> {
+        Box::pin(hw_decoder::extract_frame_window_rgba(
+            path,
+            start_frame,
+            end_frame,
+            options,
+            cancel,
+            frames,
+        ))
+    }
+}
+
+/// In-memory source producing solid frames whose color encodes the frame index.
+/// Latency, dropped frames and failures are controllable, which makes it possible
+/// to exercise the cache and transport without ffmpeg or real media.
+#[derive(Debug)]
+pub struct SyntheticFrameSource {
+    /// Delay per extracted window.
+    pub latency: Duration,
+    /// Number of frames in the synthetic source.
+    pub total_frames: usize,
+    /// Omit every frame whose index is a multiple of this value (0 disables).
+    pub drop_every: usize,
+    /// Fail every Nth extraction call (0 disables).
+    pub fail_every: usize,
+    calls: AtomicUsize,
+}
+
+impl SyntheticFrameSource {
+    pub fn new(
+        latency: Duration,
+        total_frames: usize,
+        drop_every: usize,
+        fail_every: usize,
+    ) -> Self {
+        Self {
+            latency,
+            total_frames,
+            drop_every,
+            fail_every,
+            calls: AtomicUsize::new(0),
+        }
+    }
+
+    /// Built from `FRAMESCRIPT_SYNTHETIC_LATENCY_MS`, `FRAMESCRIPT_SYNTHETIC_FRAMES`,
+    /// `FRAMESCRIPT_SYNTHETIC_DROP_EVERY` and `FRAMESCRIPT_SYNTHETIC_FAIL_EVERY`.
+    pub fn from_env() -> Self {
+        let read = |name: &str, default: usize| {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.trim().parse::<usize>().ok())
+                .unwrap_or(default)
+        };
+
+        Self::new(
+            Duration::from_millis(read("FRAMESCRIPT_SYNTHETIC_LATENCY_MS", 0) as u64),
+            read("FRAMESCRIPT_SYNTHETIC_FRAMES", usize::MAX),
+            read("FRAMESCRIPT_SYNTHETIC_DROP_EVERY", 0),
+            read("FRAMESCRIPT_SYNTHETIC_FAIL_EVERY", 0),
+        )
+    }
+
+    pub fn frame(frame_index: usize, width: u32, height: u32) -> Vec<u8> {
+        let bytes = (frame_index as u32).to_le_bytes();
+        let pixel = [bytes[0], bytes[1], bytes[2], 255];
+        pixel.repeat((width as usize) * (height as usize))
+    }
+}
+
+impl FrameSource for SyntheticFrameSource {
+    fn extract_window<'a>(
+        &'a self,
+        _path: &'a str,
+        start_frame: usize,
+        end_frame: usize,
+        options: DecodeOptions,
+        cancel: &'a CancellationToken,
+        frames: &'a FrameSender,
+    ) -> BoxFuture<'a, Result<Extracted, String>> {
+        Box::pin(async move {
+            let call = self.calls.fetch_add(1, Ordering::Relaxed) + 1;
+
+            tokio::select! {
+                _ = cancel.cancelled() => return Err(CANCELED.to_string()),
+                _ = tokio::time::sleep(self.latency) => {}
+            }
+
+            if self.fail_every > 0 && call.is_multiple_of(self.fail_every) {
+                return Err(format!("synthetic failure on call {call}"));
+            }
+
+            let mut delivered = 0;
+            for index in start_frame..end_frame.min(self.total_frames) {
+                if self.drop_every > 0 && index.is_multiple_of(self.drop_every) {
+                    continue;
+                }
+                if frames
+                    .send((
+                        index,
+                        Self::frame(index, options.width, options.height),
+                        DecodeBackend::Software,
+                    ))
+                    .await
+                    .is_err()
+                {
+                    return Err(CANCELED.to_string());
+                }
+                delivered += 1;
+            }
+
+            Ok(Extracted {
+                frames: delivered,
+                backend: DecodeBackend::Software,
+            })
+        })
+    }
+}
+
+/// Source selected by `FRAMESCRIPT_FRAME_SOURCE` (`ffmpeg` or `synthetic`).
+pub fn frame_source_from_env() -> Arc<dyn FrameSource> {
+    match std::env::var("FRAMESCRIPT_FRAME_SOURCE")
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+        .as_str()
+    {
+        "synthetic" => Arc::new(SyntheticFrameSource::from_env()),
+        _ => Arc::new(FfmpegFrameSource),
+    }
+}
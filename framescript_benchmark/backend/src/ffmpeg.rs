@@ -1,10 +1,55 @@
+pub mod audio;
 pub mod hw_decoder;
-pub mod sw_decoder;
+pub mod hwaccel;
+pub(crate) mod remote;
+pub mod scale;
+pub mod sequence;
+pub mod tonemap;
 pub(crate) mod command;
-pub(crate) mod bin;
+pub mod bin;
+pub mod version;
+#[cfg(test)]
+pub(crate) mod fixtures;
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::process::Command;
+use std::sync::{LazyLock, Mutex};
+
+/// How frames are decoded and scaled by an extraction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DecodeOptions {
+    pub width: u32,
+    pub height: u32,
+    pub hwaccel: hwaccel::HwAccel,
+    pub quality: scale::ScaleQuality,
+    /// Tone-map HDR sources to SDR.
+    pub tonemap: bool,
+}
+
+/// Receives decoded `(frame_index, rgba, backend)` as extraction produces them; `backend`
+/// is the decoder path that produced that particular frame.
+pub type FrameSender = tokio::sync::mpsc::Sender<(usize, Vec<u8>, hw_decoder::DecodeBackend)>;
+
+/// Per-file stream properties the extraction filter chain depends on.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct StreamHints {
+    /// `None` when the stream does not report a usable rate.
+    pub fps: Option<f64>,
+    /// Frame timing varies, so a frame index does not map to a seek time.
+    pub vfr: bool,
+    /// Clockwise display rotation in degrees: 0, 90, 180 or 270.
+    pub rotation: u32,
+    /// The stream carries real transparency.
+    pub alpha: bool,
+    /// Decoder that has to be forced to get the alpha plane (VP8/VP9 in WebM).
+    pub alpha_decoder: Option<&'static str>,
+    /// PQ or HLG transfer, i.e. an HDR signal.
+    pub hdr: bool,
+}
+
+static HINTS_CACHE: LazyLock<Mutex<HashMap<(String, String), StreamHints>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
 
 #[derive(Debug, Deserialize)]
 struct FfprobeFormat {
@@ -13,10 +58,39 @@ struct FfprobeFormat {
 
 #[derive(Debug, Deserialize)]
 struct FfprobeStream {
+    codec_type: Option<String>,
     duration: Option<String>,
     avg_frame_rate: Option<String>,
     r_frame_rate: Option<String>,
     nb_frames: Option<String>,
+    nb_read_frames: Option<String>,
+    nb_read_packets: Option<String>,
+    codec_name: Option<String>,
+    pix_fmt: Option<String>,
+    color_transfer: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    sample_rate: Option<String>,
+    channels: Option<u32>,
+    disposition: Option<HashMap<String, i64>>,
+    side_data_list: Option<Vec<FfprobeSideData>>,
+    tags: Option<HashMap<String, String>>,
+}
+
+impl FfprobeStream {
+    fn is(&self, codec_type: &str) -> bool {
+        self.codec_type.as_deref() == Some(codec_type)
+            // cover art in audio files shows up as a one-frame video stream
+            && self
+                .disposition
+                .as_ref()
+                .is_none_or(|disposition| disposition.get("attached_pic") != Some(&1))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct FfprobeSideData {
+    rotation: Option<f64>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -25,30 +99,47 @@ struct FfprobeOutput {
     streams: Option<Vec<FfprobeStream>>,
 }
 
-fn run_ffprobe(path: &str, select_streams: Option<&str>, entries: &str) -> Result<FfprobeOutput, String> {
-    let ffprobe = bin::ffprobe_path()?;
+fn run_ffprobe(path: &str, select_streams: Option<&str>, entries: &str) -> Result<FfprobeOutput, ProbeError> {
+    let mut args = vec!["-show_entries", entries];
+    if let Some(select_streams) = select_streams {
+        args.extend(["-select_streams", select_streams]);
+    }
+    ffprobe_json(path, &args)
+}
+
+fn ffprobe_json(path: &str, args: &[&str]) -> Result<FfprobeOutput, ProbeError> {
+    let ffprobe = bin::ffprobe_path().map_err(|stderr| ProbeError::ToolFailed { stderr })?;
     let mut cmd = Command::new(ffprobe);
     cmd.arg("-v")
         .arg("error")
         .arg("-print_format")
         .arg("json")
-        .arg("-show_entries")
-        .arg(entries);
-    if let Some(select_streams) = select_streams {
-        cmd.arg("-select_streams").arg(select_streams);
-    }
-    cmd.arg(path);
+        .args(args)
+        .args(command::EXTRA_ARGS.iter());
+    match sequence::image_sequence(path) {
+        Some(sequence) => cmd.args(sequence.input_args(0, *sequence::SEQUENCE_FPS)),
+        None => cmd.args(remote::input_args(path)).arg(path),
+    };
 
-    let output = cmd
-        .output()
-        .map_err(|error| format!("failed to run ffprobe: {error}"))?;
+    let output = command::output_with_timeout(&mut cmd, *command::PROBE_TIMEOUT)
+        .map_err(|stderr| ProbeError::ToolFailed { stderr })?;
     if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        return Err(format!("ffprobe failed: {}", stderr.trim()));
+        let stderr = command::stderr_excerpt(&output.stderr);
+        tracing::warn!(command = %command::command_line(&cmd), stderr = %stderr, "ffprobe failed with status {}", output.status);
+        return Err(ProbeError::ToolFailed { stderr });
     }
 
-    serde_json::from_slice::<FfprobeOutput>(&output.stdout)
-        .map_err(|error| format!("failed to parse ffprobe json: {error}"))
+    parse_ffprobe_json(&output.stdout)
+}
+
+/// Some builds print warnings to stdout ahead of the document, so parsing starts at the first `{`.
+fn parse_ffprobe_json(stdout: &[u8]) -> Result<FfprobeOutput, ProbeError> {
+    let start = stdout
+        .iter()
+        .position(|byte| *byte == b'{')
+        .ok_or_else(|| ProbeError::Unparseable("no JSON in ffprobe output".to_string()))?;
+    serde_json::from_slice::<FfprobeOutput>(&stdout[start..])
+        .map_err(|error| ProbeError::Unparseable(format!("failed to parse ffprobe json: {error}")))
 }
 
 fn parse_duration_seconds(value: Option<&str>) -> Option<f64> {
@@ -91,83 +182,706 @@ fn parse_ratio(value: Option<&str>) -> Option<f64> {
     }
 }
 
-/// Return video duration in milliseconds using ffprobe metadata.
-pub fn probe_video_duration_ms(path: &str) -> Result<u64, String> {
-    let output = run_ffprobe(path, Some("v:0"), "format=duration:stream=duration")?;
-    let stream_duration = output
-        .streams
-        .as_ref()
-        .and_then(|streams| streams.first())
-        .and_then(|stream| parse_duration_seconds(stream.duration.as_deref()));
+#[derive(Debug)]
+pub enum ProbeError {
+    /// The file has no stream of the requested kind.
+    NoSuchStream(&'static str),
+    /// ffprobe ran but its output could not be read.
+    Unparseable(String),
+    /// ffprobe could not be run or exited with an error.
+    ToolFailed { stderr: String },
+    /// The stream exists but the named value is missing or nonsensical (`N/A`, 0, ...).
+    InvalidValue(&'static str),
+}
+
+impl ProbeError {
+    /// Stable identifier for API error bodies.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::NoSuchStream(_) => "no_such_stream",
+            Self::Unparseable(_) => "unparseable",
+            Self::ToolFailed { .. } => "tool_failed",
+            Self::InvalidValue(_) => "invalid_value",
+        }
+    }
+
+    /// Whether the media itself is at fault rather than ffprobe or the backend.
+    pub fn is_media_problem(&self) -> bool {
+        matches!(self, Self::NoSuchStream(_) | Self::InvalidValue(_))
+    }
+}
+
+impl std::fmt::Display for ProbeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoSuchStream(kind) => write!(f, "no {kind} stream"),
+            Self::Unparseable(error) => f.write_str(error),
+            Self::ToolFailed { stderr } => write!(f, "ffprobe failed: {stderr}"),
+            Self::InvalidValue(name) => write!(f, "failed to read {name}"),
+        }
+    }
+}
+
+impl From<ProbeError> for String {
+    fn from(error: ProbeError) -> Self {
+        error.to_string()
+    }
+}
+
+/// Everything the backend needs to know about a media file, from one ffprobe run.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MediaInfo {
+    pub video: Option<VideoInfo>,
+    pub audio: Option<AudioInfo>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VideoInfo {
+    /// Seconds. The stream duration is preferred over the container's.
+    pub duration: Option<f64>,
+    pub fps: FpsInfo,
+    /// `nb_frames` (exact), else stream duration × average fps (estimated).
+    pub frames: Option<FrameCount>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub codec: Option<String>,
+    pub pix_fmt: Option<String>,
+    /// PQ or HLG transfer, i.e. an HDR signal.
+    pub hdr: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AudioInfo {
+    /// Seconds. The first of stream and container duration that is positive
+    /// and below a sanity cap, since some containers report bogus global durations.
+    pub duration: Option<f64>,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u32>,
+    pub codec: Option<String>,
+}
+
+fn seconds_to_ms(seconds: f64) -> u64 {
+    (seconds * 1000.0).round().max(0.0) as u64
+}
+
+impl VideoInfo {
+    pub fn duration_ms(&self) -> Option<u64> {
+        self.duration.map(seconds_to_ms)
+    }
+}
+
+impl AudioInfo {
+    pub fn duration_ms(&self) -> Option<u64> {
+        self.duration.map(seconds_to_ms)
+    }
+}
+
+pub fn probe_media_info(path: &str) -> Result<MediaInfo, ProbeError> {
+    probe_media_info_with_fps(path, None)
+}
+
+/// Like `probe_media_info`, but image sequences and still images are timed at `image_fps`
+/// (default `FRAMESCRIPT_SEQUENCE_FPS`). A sequence has one frame per file, a still
+/// image a single frame lasting `FRAMESCRIPT_STILL_DURATION_MS`.
+pub fn probe_media_info_with_fps(
+    path: &str,
+    image_fps: Option<f64>,
+) -> Result<MediaInfo, ProbeError> {
+    let output = ffprobe_json(path, &["-show_format", "-show_streams"])?;
+    let mut info = media_info_from_output(&output);
+
+    let Some(video) = info.video.as_mut() else {
+        return Ok(info);
+    };
+    let fps = image_fps
+        .filter(|fps| fps.is_finite() && *fps > 0.0)
+        .unwrap_or(*sequence::SEQUENCE_FPS);
+    let timing = if let Some(sequence) = sequence::image_sequence(path) {
+        Some((sequence.duration(fps), sequence.frames))
+    } else if sequence::is_still_image(path) {
+        Some((*sequence::STILL_DURATION_MS as f64 / 1000.0, 1))
+    } else {
+        None
+    };
+    if let Some((duration, frames)) = timing {
+        video.fps = FpsInfo::new(Some(fps), Some(fps));
+        video.duration = Some(duration);
+        video.frames = Some(FrameCount { frames, exact: true });
+    }
+
+    Ok(info)
+}
+
+fn media_info_from_output(output: &FfprobeOutput) -> MediaInfo {
     let format_duration = output
         .format
         .as_ref()
         .and_then(|format| parse_duration_seconds(format.duration.as_deref()));
+    let streams = output.streams.as_deref().unwrap_or_default();
+
+    let video = streams.iter().find(|stream| stream.is("video")).map(|stream| {
+        let stream_duration = parse_duration_seconds(stream.duration.as_deref());
+        let fps = FpsInfo::new(
+            parse_ratio(stream.avg_frame_rate.as_deref()),
+            parse_ratio(stream.r_frame_rate.as_deref()),
+        );
+        let frames = stream
+            .nb_frames
+            .as_deref()
+            .and_then(|value| value.parse::<u64>().ok())
+            .filter(|frames| *frames > 0)
+            .map(|frames| FrameCount { frames, exact: true })
+            .or_else(|| {
+                let (duration, fps) = (stream_duration?, fps.avg?);
+                Some(FrameCount {
+                    frames: (duration * fps).round().max(0.0) as u64,
+                    exact: false,
+                })
+            });
+
+        VideoInfo {
+            duration: stream_duration.or(format_duration),
+            fps,
+            frames,
+            width: stream.width,
+            height: stream.height,
+            codec: stream.codec_name.clone(),
+            pix_fmt: stream.pix_fmt.clone(),
+            hdr: is_hdr_transfer(stream.color_transfer.as_deref()),
+        }
+    });
+
+    let audio = streams.iter().find(|stream| stream.is("audio")).map(|stream| AudioInfo {
+        duration: audio_duration(parse_duration_seconds(stream.duration.as_deref()), format_duration),
+        sample_rate: stream.sample_rate.as_deref().and_then(|value| value.trim().parse().ok()),
+        channels: stream.channels,
+        codec: stream.codec_name.clone(),
+    });
+
+    MediaInfo { video, audio }
+}
+
+fn audio_duration(stream_duration: Option<f64>, format_duration: Option<f64>) -> Option<f64> {
+    const MAX_REASONABLE_DURATION_MS: u64 = 1000 * 60 * 60 * 24 * 7; // 7 days
+
+    [stream_duration, format_duration].into_iter().flatten().find(|duration| {
+        let duration_ms = seconds_to_ms(*duration);
+        duration_ms > 0 && duration_ms <= MAX_REASONABLE_DURATION_MS
+    })
+}
+
+fn probe_video_info(path: &str) -> Result<VideoInfo, ProbeError> {
+    probe_media_info(path)?.video.ok_or(ProbeError::NoSuchStream("video"))
+}
+
+/// Return video duration in milliseconds using ffprobe metadata.
+pub fn probe_video_duration_ms(path: &str) -> Result<u64, ProbeError> {
+    probe_video_info(path)?
+        .duration_ms()
+        .ok_or(ProbeError::InvalidValue("duration"))
+}
+
+/// Number of video frames and whether it was counted or estimated from duration × fps.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct FrameCount {
+    pub frames: u64,
+    pub exact: bool,
+}
+
+static FRAME_COUNT_CACHE: LazyLock<Mutex<HashMap<(String, String), FrameCount>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Decode every frame instead of counting packets (`FRAMESCRIPT_COUNT_FRAMES=1`). Exact
+/// even for codecs with packed frames, but reads the whole file.
+static COUNT_DECODED_FRAMES: LazyLock<bool> = LazyLock::new(|| {
+    matches!(
+        std::env::var("FRAMESCRIPT_COUNT_FRAMES").as_deref().map(str::trim),
+        Ok("1" | "true")
+    )
+});
+
+/// Frame count cached per path and source version (mtime, or ETag for URLs). Containers without `nb_frames`
+/// (MKV, WebM) are counted with ffprobe instead of trusting duration × fps.
+pub fn probe_video_frames(path: &str) -> Result<FrameCount, ProbeError> {
+    if let Some(sequence) = sequence::image_sequence(path) {
+        return Ok(FrameCount {
+            frames: sequence.frames,
+            exact: true,
+        });
+    }
+    if sequence::is_still_image(path) {
+        return Ok(FrameCount {
+            frames: 1,
+            exact: true,
+        });
+    }
+
+    let key = (path.to_string(), remote::source_version(path));
+    if let Some(count) = FRAME_COUNT_CACHE.lock().unwrap().get(&key) {
+        return Ok(*count);
+    }
+
+    let header = probe_video_info(path)?.frames;
+    let count = match header {
+        Some(count) if count.exact => count,
+        estimate => match count_video_frames(path) {
+            Ok(frames) => FrameCount { frames, exact: true },
+            Err(error) => {
+                tracing::warn!(path, "failed to count frames, using the estimate: {error}");
+                estimate.ok_or(ProbeError::InvalidValue("frames"))?
+            }
+        },
+    };
 
-    let seconds = stream_duration.or(format_duration).ok_or_else(|| "failed to read duration".to_string())?;
-    Ok((seconds * 1000.0).round().max(0.0) as u64)
+    FRAME_COUNT_CACHE.lock().unwrap().insert(key, count);
+    Ok(count)
 }
 
-pub fn probe_video_frames(path: &str) -> Result<u64, String> {
-    let output = run_ffprobe(path, Some("v:0"), "stream=nb_frames,duration,avg_frame_rate")?;
+fn count_video_frames(path: &str) -> Result<u64, ProbeError> {
+    let (flag, entry) = if *COUNT_DECODED_FRAMES {
+        ("-count_frames", "stream=nb_read_frames")
+    } else {
+        ("-count_packets", "stream=nb_read_packets")
+    };
+    let output = ffprobe_json(path, &[flag, "-select_streams", "v:0", "-show_entries", entry])?;
     let stream = output
         .streams
         .as_ref()
         .and_then(|streams| streams.first())
-        .ok_or_else(|| "failed to read frames".to_string())?;
+        .ok_or(ProbeError::NoSuchStream("video"))?;
+
+    stream
+        .nb_read_frames
+        .as_deref()
+        .or(stream.nb_read_packets.as_deref())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .filter(|frames| *frames > 0)
+        .ok_or(ProbeError::InvalidValue("frame count"))
+}
+
+/// Frame rates reported for a video stream.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct FpsInfo {
+    /// `avg_frame_rate`: frames / duration.
+    pub avg: Option<f64>,
+    /// `r_frame_rate`: the lowest rate all timestamps fit, often a timebase for VFR.
+    pub nominal: Option<f64>,
+    /// The two disagree, i.e. frame timing is variable.
+    pub is_vfr: bool,
+}
+
+impl FpsInfo {
+    /// Above this the nominal rate is a timebase rather than a frame rate.
+    const MAX_PLAUSIBLE_FPS: f64 = 240.0;
 
-    if let Some(frames) = stream.nb_frames.as_deref().and_then(|value| value.parse::<u64>().ok()) {
-        if frames > 0 {
-            return Ok(frames);
+    fn new(avg: Option<f64>, nominal: Option<f64>) -> Self {
+        let is_vfr = match (avg, nominal) {
+            (Some(avg), Some(nominal)) => {
+                nominal > Self::MAX_PLAUSIBLE_FPS || (avg - nominal).abs() / nominal > 0.01
+            }
+            _ => false,
+        };
+        Self {
+            avg,
+            nominal,
+            is_vfr,
         }
     }
 
-    let duration = parse_duration_seconds(stream.duration.as_deref());
-    let fps = parse_ratio(stream.avg_frame_rate.as_deref());
-    if let (Some(duration), Some(fps)) = (duration, fps) {
-        return Ok((duration * fps).round().max(0.0) as u64);
+    /// Single rate for frame math: average when known, else a plausible nominal rate.
+    pub fn fps(&self) -> Option<f64> {
+        self.avg
+            .or(self.nominal.filter(|fps| *fps <= Self::MAX_PLAUSIBLE_FPS))
     }
+}
+
+pub fn probe_video_fps_info(path: &str) -> Result<FpsInfo, ProbeError> {
+    Ok(probe_video_info(path)?.fps)
+}
+
+pub fn probe_video_fps(path: &str) -> Result<f64, ProbeError> {
+    probe_video_fps_info(path)?
+        .fps()
+        .ok_or(ProbeError::InvalidValue("fps"))
+}
 
-    Err("failed to read frames".to_string())
+/// Stream hints probed once per path and source version. Probe failures yield the defaults.
+pub(crate) fn stream_hints(path: &str) -> StreamHints {
+    let key = (path.to_string(), remote::source_version(path));
+    if let Some(hints) = HINTS_CACHE.lock().unwrap().get(&key) {
+        return *hints;
+    }
+
+    // probe outside the lock; a racing probe of the same path is harmless
+    let hints = probe_stream_hints(path).unwrap_or_default();
+    HINTS_CACHE.lock().unwrap().insert(key, hints);
+    hints
 }
 
-pub fn probe_video_fps(path: &str) -> Result<f64, String> {
-    let output = run_ffprobe(path, Some("v:0"), "stream=avg_frame_rate,r_frame_rate")?;
+fn probe_stream_hints(path: &str) -> Result<StreamHints, ProbeError> {
+    let output = run_ffprobe(
+        path,
+        Some("v:0"),
+        "stream=avg_frame_rate,r_frame_rate,codec_name,pix_fmt,color_transfer:stream_side_data=rotation:stream_tags=rotate,alpha_mode",
+    )?;
     let stream = output
         .streams
         .as_ref()
         .and_then(|streams| streams.first())
-        .ok_or_else(|| "Not video!".to_string())?;
+        .ok_or(ProbeError::NoSuchStream("video"))?;
 
-    let fps = parse_ratio(stream.avg_frame_rate.as_deref())
-        .or_else(|| parse_ratio(stream.r_frame_rate.as_deref()))
-        .ok_or_else(|| "failed to read fps".to_string())?;
+    // WebM VP8/VP9 keep alpha in side data; only libvpx decodes it and
+    // the stream itself reports a plain yuv420p
+    let webm_alpha = stream
+        .tags
+        .iter()
+        .flatten()
+        .any(|(key, value)| key.eq_ignore_ascii_case("alpha_mode") && value.trim() == "1");
+    let alpha_decoder = match stream.codec_name.as_deref() {
+        Some("vp8") if webm_alpha => Some("libvpx"),
+        Some("vp9") if webm_alpha => Some("libvpx-vp9"),
+        _ => None,
+    };
 
-    Ok(fps)
+    let fps = FpsInfo::new(
+        parse_ratio(stream.avg_frame_rate.as_deref()),
+        parse_ratio(stream.r_frame_rate.as_deref()),
+    );
+    Ok(StreamHints {
+        fps: fps.fps(),
+        vfr: fps.is_vfr,
+        rotation: stream_rotation(stream),
+        alpha: alpha_decoder.is_some() || stream.pix_fmt.as_deref().is_some_and(pix_fmt_has_alpha),
+        alpha_decoder,
+        hdr: is_hdr_transfer(stream.color_transfer.as_deref()),
+    })
 }
 
-/// Return audio duration in milliseconds using ffprobe metadata.
-pub fn probe_audio_duration_ms(path: &str) -> Result<u64, String> {
-    // Some containers report bogus global duration; prefer audio stream duration when available.
-    const MAX_REASONABLE_DURATION_MS: u64 = 1000 * 60 * 60 * 24 * 7; // 7 days
+fn is_hdr_transfer(color_transfer: Option<&str>) -> bool {
+    matches!(color_transfer, Some("smpte2084" | "arib-std-b67"))
+}
 
-    let output = run_ffprobe(path, Some("a:0"), "format=duration:stream=duration")?;
-    let stream_duration = output
-        .streams
-        .as_ref()
-        .and_then(|streams| streams.first())
-        .and_then(|stream| parse_duration_seconds(stream.duration.as_deref()));
-    let format_duration = output
-        .format
+fn pix_fmt_has_alpha(pix_fmt: &str) -> bool {
+    pix_fmt.starts_with("yuva")
+        || pix_fmt.starts_with("gbrap")
+        || pix_fmt.starts_with("ya")
+        || pix_fmt.starts_with("rgba")
+        || pix_fmt.starts_with("bgra")
+        || pix_fmt.starts_with("argb")
+        || pix_fmt.starts_with("abgr")
+        || pix_fmt.starts_with("ayuv")
+}
+
+/// The legacy `rotate` tag is clockwise, the display matrix rotation counterclockwise.
+fn stream_rotation(stream: &FfprobeStream) -> u32 {
+    let tag = stream
+        .tags
         .as_ref()
-        .and_then(|format| parse_duration_seconds(format.duration.as_deref()));
+        .and_then(|tags| tags.get("rotate"))
+        .and_then(|value| value.trim().parse::<f64>().ok());
+    let matrix = stream
+        .side_data_list
+        .iter()
+        .flatten()
+        .find_map(|side_data| side_data.rotation)
+        .map(|rotation| -rotation);
+
+    let Some(degrees) = tag.or(matrix) else {
+        return 0;
+    };
+    let quarter_turns = (degrees / 90.0).round() as i64;
+    (quarter_turns.rem_euclid(4) * 90) as u32
+}
+
+/// Return audio duration in milliseconds using ffprobe metadata.
+pub fn probe_audio_duration_ms(path: &str) -> Result<u64, ProbeError> {
+    probe_media_info(path)?
+        .audio
+        .ok_or(ProbeError::NoSuchStream("audio"))?
+        .duration_ms()
+        .ok_or(ProbeError::InvalidValue("audio duration"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn first_stream(json: &str) -> FfprobeStream {
+        parse_ffprobe_json(json.as_bytes())
+            .unwrap()
+            .streams
+            .unwrap()
+            .remove(0)
+    }
+
+    #[test]
+    fn rotation_comes_from_the_tag_or_the_display_matrix() {
+        let cases = [
+            (r#"{"codec_type": "video"}"#, 0),
+            (r#"{"codec_type": "video", "tags": {"rotate": "90"}}"#, 90),
+            (r#"{"codec_type": "video", "tags": {"rotate": "-90"}}"#, 270),
+            (
+                r#"{"codec_type": "video", "side_data_list": [{"side_data_type": "Display Matrix", "rotation": -90}]}"#,
+                90,
+            ),
+            (
+                r#"{"codec_type": "video", "side_data_list": [{"side_data_type": "Display Matrix", "rotation": 180}]}"#,
+                180,
+            ),
+            (
+                r#"{"codec_type": "video", "side_data_list": [{"side_data_type": "Display Matrix", "rotation": 90.0}]}"#,
+                270,
+            ),
+            // the tag wins over the matrix
+            (
+                r#"{"codec_type": "video", "tags": {"rotate": "180"}, "side_data_list": [{"rotation": -90}]}"#,
+                180,
+            ),
+        ];
+
+        for (stream, expected) in cases {
+            let json = format!(r#"{{"streams": [{stream}]}}"#);
+            assert_eq!(stream_rotation(&first_stream(&json)), expected, "{stream}");
+        }
+    }
 
-    for duration in [stream_duration, format_duration].into_iter().flatten() {
-        let duration_ms = (duration * 1000.0).round().max(0.0) as u64;
-        if duration_ms > 0 && duration_ms <= MAX_REASONABLE_DURATION_MS {
-            return Ok(duration_ms);
+    #[test]
+    fn alpha_pix_fmts_are_recognized() {
+        for pix_fmt in ["yuva420p", "yuva444p", "rgba", "bgra", "argb", "gbrap", "ya8"] {
+            assert!(pix_fmt_has_alpha(pix_fmt), "{pix_fmt}");
         }
+        for pix_fmt in ["yuv420p", "yuv444p", "rgb24", "gbrp", "nv12", "gray"] {
+            assert!(!pix_fmt_has_alpha(pix_fmt), "{pix_fmt}");
+        }
+    }
+
+    fn media_info(json: &str) -> MediaInfo {
+        media_info_from_output(&parse_ffprobe_json(json.as_bytes()).unwrap())
+    }
+
+    #[test]
+    fn video_prefers_the_stream_duration() {
+        let info = media_info(
+            r#"{
+                "format": {"duration": "12.500000"},
+                "streams": [{"codec_type": "video", "duration": "10.000000", "avg_frame_rate": "30/1"}]
+            }"#,
+        );
+        let video = info.video.unwrap();
+        assert_eq!(video.duration_ms(), Some(10_000));
+        // no nb_frames: estimated from the stream duration
+        let frames = video.frames.unwrap();
+        assert_eq!((frames.frames, frames.exact), (300, false));
+
+        let info = media_info(
+            r#"{
+                "format": {"duration": "12.500000"},
+                "streams": [{"codec_type": "video", "duration": "N/A", "nb_frames": "375"}]
+            }"#,
+        );
+        let video = info.video.unwrap();
+        assert_eq!(video.duration_ms(), Some(12_500));
+        let frames = video.frames.unwrap();
+        assert_eq!((frames.frames, frames.exact), (375, true));
     }
 
-    Err("failed to read audio duration".to_string())
+    #[test]
+    fn audio_duration_is_capped_at_seven_days() {
+        let audio = |stream: &str, format: &str| {
+            media_info(&format!(
+                r#"{{"format": {{"duration": "{format}"}}, "streams": [{{"codec_type": "audio", "duration": "{stream}"}}]}}"#
+            ))
+            .audio
+            .unwrap()
+            .duration_ms()
+        };
+
+        assert_eq!(audio("3.000000", "4.000000"), Some(3_000));
+        // a bogus stream duration falls back to the container's
+        assert_eq!(audio("700000.000000", "4.000000"), Some(4_000));
+        assert_eq!(audio("N/A", "4.000000"), Some(4_000));
+        assert_eq!(audio("604800.000000", "N/A"), Some(604_800_000));
+        assert_eq!(audio("604801.000000", "999999999"), None);
+        assert_eq!(audio("0", "0"), None);
+    }
+
+    #[test]
+    fn unusable_rates_are_ignored() {
+        for value in ["0/0", "N/A", "", "30/0", "0/1", "-30/1", "abc"] {
+            assert_eq!(parse_ratio(Some(value)), None, "{value:?}");
+        }
+        assert_eq!(parse_ratio(Some("30000/1001")), Some(30000.0 / 1001.0));
+        assert_eq!(parse_ratio(Some("25")), Some(25.0));
+
+        let fps = FpsInfo::new(parse_ratio(Some("0/0")), parse_ratio(Some("N/A")));
+        assert_eq!((fps.fps(), fps.is_vfr), (None, false));
+    }
+
+    #[test]
+    fn a_timebase_rate_marks_the_stream_vfr() {
+        // screen recording: 23.9 fps on average, timestamps on a 1 ms grid
+        let fps = FpsInfo::new(Some(23.9), parse_ratio(Some("1000/1")));
+        assert!(fps.is_vfr);
+        assert_eq!(fps.fps(), Some(23.9));
+
+        // without an average, a timebase is no frame rate at all
+        let fps = FpsInfo::new(parse_ratio(Some("0/0")), parse_ratio(Some("1000/1")));
+        assert_eq!(fps.fps(), None);
+
+        let fps = FpsInfo::new(Some(29.97), Some(30000.0 / 1001.0));
+        assert!(!fps.is_vfr);
+
+        let fps = FpsInfo::new(Some(24.0), Some(60.0));
+        assert!(fps.is_vfr);
+    }
+
+    #[test]
+    fn hdr_comes_from_the_transfer() {
+        let info = media_info(
+            r#"{"streams": [{"codec_type": "video", "color_transfer": "smpte2084"}]}"#,
+        );
+        assert!(info.video.unwrap().hdr);
+        let info = media_info(r#"{"streams": [{"codec_type": "video", "color_transfer": "bt709"}]}"#);
+        assert!(!info.video.unwrap().hdr);
+    }
+
+
+    /// `ffprobe -v error -print_format json -show_format -show_streams` of an H.264/AAC
+    /// MP4, trimmed to the fields the backend reads, behind the warnings some builds
+    /// print to stdout.
+    const CAPTURED_MP4: &str = r#"[mov,mp4,m4a,3gp,3g2,mj2 @ 0x55d5c8e0c840] stream 1, timescale not set
+[h264 @ 0x55d5c8e1a2c0] mmco: unref short failure
+{
+    "streams": [
+        {
+            "index": 0,
+            "codec_name": "h264",
+            "codec_type": "video",
+            "width": 1920,
+            "height": 1080,
+            "pix_fmt": "yuv420p",
+            "color_transfer": "bt709",
+            "r_frame_rate": "30000/1001",
+            "avg_frame_rate": "30000/1001",
+            "time_base": "1/30000",
+            "duration": "10.010000",
+            "nb_frames": "300",
+            "tags": {
+                "language": "und",
+                "handler_name": "VideoHandler"
+            }
+        },
+        {
+            "index": 1,
+            "codec_name": "aac",
+            "codec_type": "audio",
+            "sample_rate": "48000",
+            "channels": 2,
+            "channel_layout": "stereo",
+            "r_frame_rate": "0/0",
+            "avg_frame_rate": "0/0",
+            "duration": "10.005333",
+            "nb_frames": "470"
+        }
+    ],
+    "format": {
+        "filename": "clip.mp4",
+        "nb_streams": 2,
+        "format_name": "mov,mp4,m4a,3gp,3g2,mj2",
+        "duration": "10.010000",
+        "size": "5242880",
+        "bit_rate": "4190242"
+    }
+}
+"#;
+
+    /// The same for an MP3 renamed to `.mp4`: no video stream at all.
+    const CAPTURED_MP3: &str = r#"{
+    "streams": [
+        {
+            "index": 0,
+            "codec_name": "mp3",
+            "codec_type": "audio",
+            "sample_rate": "44100",
+            "channels": 2,
+            "r_frame_rate": "0/0",
+            "avg_frame_rate": "0/0",
+            "duration": "183.823673"
+        }
+    ],
+    "format": {
+        "filename": "song.mp4",
+        "format_name": "mp3",
+        "duration": "183.823673"
+    }
+}"#;
+
+    #[test]
+    fn captured_output_parses_past_leading_noise() {
+        let info = media_info(CAPTURED_MP4);
+
+        let video = info.video.unwrap();
+        assert_eq!((video.width, video.height), (Some(1920), Some(1080)));
+        assert_eq!(video.codec.as_deref(), Some("h264"));
+        assert_eq!(video.duration, Some(10.01));
+        assert!((video.fps.avg.unwrap() - 29.97).abs() < 0.001);
+        assert!(!video.fps.is_vfr);
+        let frames = video.frames.unwrap();
+        assert_eq!((frames.frames, frames.exact), (300, true));
+        assert!(!video.hdr);
+
+        let audio = info.audio.unwrap();
+        assert_eq!(audio.codec.as_deref(), Some("aac"));
+        assert_eq!((audio.sample_rate, audio.channels), (Some(48000), Some(2)));
+        assert_eq!(audio.duration, Some(10.005333));
+    }
+
+    #[test]
+    fn an_audio_only_file_is_a_missing_video_stream() {
+        let info = media_info(CAPTURED_MP3);
+        assert_eq!(info.audio.unwrap().codec.as_deref(), Some("mp3"));
+
+        let error = info.video.ok_or(ProbeError::NoSuchStream("video")).unwrap_err();
+        assert_eq!(error.code(), "no_such_stream");
+        assert_eq!(error.to_string(), "no video stream");
+        assert!(error.is_media_problem());
+    }
+
+    #[test]
+    fn output_without_a_document_is_unparseable() {
+        for stdout in [
+            "",
+            "clip.mp4: Invalid data found when processing input\n",
+            r#"{"streams": [{"codec_type": "video""#,
+        ] {
+            let error = parse_ffprobe_json(stdout.as_bytes()).unwrap_err();
+            assert!(matches!(error, ProbeError::Unparseable(_)), "{stdout:?}: {error}");
+            assert_eq!(error.code(), "unparseable");
+            assert!(!error.is_media_problem());
+        }
+
+        // an empty document is fine: the file simply has nothing to report
+        let info = media_info("{}");
+        assert!(info.video.is_none() && info.audio.is_none());
+    }
+
+    #[test]
+    fn errors_split_into_media_and_tool_problems() {
+        let cases = [
+            (ProbeError::NoSuchStream("audio"), "no_such_stream", true),
+            (ProbeError::InvalidValue("fps"), "invalid_value", true),
+            (ProbeError::Unparseable(String::new()), "unparseable", false),
+            (
+                ProbeError::ToolFailed {
+                    stderr: "No such file or directory".to_string(),
+                },
+                "tool_failed",
+                false,
+            ),
+        ];
+        for (error, code, media) in cases {
+            assert_eq!(error.code(), code);
+            assert_eq!(error.is_media_problem(), media, "{error}");
+        }
+    }
 }
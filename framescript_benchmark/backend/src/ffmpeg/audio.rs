@@ -0,0 +1,250 @@
+use std::process::Stdio;
+use std::time::Instant;
+
+use tokio::io::AsyncReadExt;
+use tokio::process::Command;
+use tracing::{debug, warn};
+
+use crate::ffmpeg::bin::ffmpeg_path;
+use crate::ffmpeg::command::{
+    EXTRA_ARGS, STALL_TIMEOUT, command_line, spawn_stderr_tail, stall_error,
+};
+use crate::ffmpeg::{probe_media_info, remote};
+
+/// Decode `duration_sec` seconds of audio from `start_sec` to interleaved f32 PCM.
+///
+/// The result always holds exactly `round(duration_sec * sample_rate) * channels`
+/// samples: a range past the end of the stream, or a source without audio, is
+/// padded with silence; any extra samples from ffmpeg are dropped.
+pub async fn extract_audio_pcm(
+    path: &str,
+    start_sec: f64,
+    duration_sec: f64,
+    sample_rate: u32,
+    channels: u16,
+) -> Result<Vec<f32>, String> {
+    if !start_sec.is_finite() || start_sec < 0.0 || !duration_sec.is_finite() || duration_sec < 0.0
+    {
+        return Err(format!(
+            "invalid audio range: start {start_sec}s, duration {duration_sec}s"
+        ));
+    }
+    if sample_rate == 0 || channels == 0 {
+        return Err("sample rate and channels must be positive".to_string());
+    }
+
+    let expected = (duration_sec * sample_rate as f64).round() as usize * channels as usize;
+    if expected == 0 {
+        return Ok(Vec::new());
+    }
+
+    let probe_path = path.to_string();
+    let has_audio = tokio::task::spawn_blocking(move || probe_media_info(&probe_path))
+        .await
+        .map_err(|error| format!("failed to probe audio: {error}"))?
+        .map_err(String::from)?
+        .audio
+        .is_some();
+    if !has_audio {
+        return Ok(vec![0.0; expected]);
+    }
+
+    let ffmpeg = ffmpeg_path()?;
+    let mut cmd = Command::new(ffmpeg);
+    cmd.arg("-hide_banner")
+        .arg("-loglevel")
+        .arg("error")
+        .arg("-nostdin")
+        .arg("-ss")
+        .arg(format!("{start_sec:.6}"))
+        .arg("-t")
+        .arg(format!("{duration_sec:.6}"))
+        .args(remote::input_args(path))
+        .arg("-i")
+        .arg(path)
+        .arg("-map")
+        .arg("0:a:0")
+        .arg("-vn")
+        .arg("-ac")
+        .arg(channels.to_string())
+        .arg("-ar")
+        .arg(sample_rate.to_string())
+        .args(EXTRA_ARGS.iter())
+        .arg("-f")
+        .arg("f32le")
+        .arg("pipe:1");
+    cmd.stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+
+    let started = Instant::now();
+    let mut child = cmd
+        .spawn()
+        .map_err(|error| format!("failed to spawn ffmpeg: {error}"))?;
+    let mut stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| "failed to open ffmpeg stdout".to_string())?;
+    let stderr_tail = child.stderr.take().map(spawn_stderr_tail);
+
+    let mut bytes = Vec::with_capacity(expected * 4);
+    let mut chunk = vec![0u8; 64 * 1024];
+    // Err(None) は出力が止まったまま STALL_TIMEOUT を過ぎた場合
+    let read: Result<(), Option<std::io::Error>> = loop {
+        match tokio::time::timeout(*STALL_TIMEOUT, stdout.read(&mut chunk)).await {
+            Ok(Ok(0)) => break Ok(()),
+            Ok(Ok(read)) => bytes.extend_from_slice(&chunk[..read]),
+            Ok(Err(error)) => break Err(Some(error)),
+            Err(_) => {
+                let _ = child.kill().await;
+                break Err(None);
+            }
+        }
+    };
+    let status = child
+        .wait()
+        .await
+        .map_err(|error| format!("failed to wait on ffmpeg: {error}"))?;
+    let stderr = match stderr_tail {
+        Some(handle) => handle.await.unwrap_or_default(),
+        None => String::new(),
+    };
+    match read {
+        Err(None) => return Err(stall_error(&stderr)),
+        Err(Some(error)) => return Err(format!("failed to read ffmpeg output: {error}: {stderr}")),
+        Ok(()) => {}
+    }
+    debug!(command = %command_line(cmd.as_std()), elapsed_ms = started.elapsed().as_millis() as u64, "{status}");
+    if !status.success() {
+        warn!(command = %command_line(cmd.as_std()), stderr = %stderr, "ffmpeg failed with status {status}");
+        return Err(format!("ffmpeg failed with status: {status}: {stderr}"));
+    }
+    if bytes.len() % (4 * channels as usize) != 0 {
+        return Err(format!(
+            "ffmpeg returned {} bytes, not a whole number of {channels}-channel f32 samples",
+            bytes.len()
+        ));
+    }
+
+    let mut samples = bytes
+        .chunks_exact(4)
+        .map(|sample| f32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]]))
+        .collect::<Vec<_>>();
+    samples.resize(expected, 0.0);
+
+    Ok(samples)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffmpeg::fixtures::{self, Fixture};
+
+    const RATE: u32 = 48_000;
+
+    /// One second of a 1 kHz sine at ffmpeg's default amplitude of 1/8.
+    fn sine() -> Option<Fixture> {
+        fixtures::generate(
+            "sine.wav",
+            &[
+                "-f",
+                "lavfi",
+                "-i",
+                "sine=frequency=1000:sample_rate=48000:duration=1",
+            ],
+        )
+    }
+
+    fn peak(samples: &[f32]) -> f32 {
+        samples
+            .iter()
+            .fold(0.0, |peak, sample| peak.max(sample.abs()))
+    }
+
+    #[tokio::test]
+    async fn invalid_ranges_are_rejected_before_running_ffmpeg() {
+        assert!(
+            extract_audio_pcm("unused.wav", -1.0, 1.0, RATE, 1)
+                .await
+                .is_err()
+        );
+        assert!(
+            extract_audio_pcm("unused.wav", 0.0, f64::NAN, RATE, 1)
+                .await
+                .is_err()
+        );
+        assert!(
+            extract_audio_pcm("unused.wav", 0.0, 1.0, 0, 1)
+                .await
+                .is_err()
+        );
+        assert!(
+            extract_audio_pcm("unused.wav", 0.0, 1.0, RATE, 0)
+                .await
+                .is_err()
+        );
+        assert_eq!(
+            extract_audio_pcm("unused.wav", 0.0, 0.0, RATE, 1).await,
+            Ok(Vec::new())
+        );
+    }
+
+    #[tokio::test]
+    async fn a_range_decodes_to_the_sine() {
+        let Some(sine) = sine() else {
+            return;
+        };
+
+        let mono = extract_audio_pcm(&sine.path, 0.25, 0.5, RATE, 1)
+            .await
+            .unwrap();
+        assert_eq!(mono.len(), 24_000);
+        assert!((0.1..0.15).contains(&peak(&mono)), "peak {}", peak(&mono));
+        // two zero crossings per period: ~1000 in half a second of 1 kHz
+        let crossings = mono
+            .windows(2)
+            .filter(|pair| (pair[0] < 0.0) != (pair[1] < 0.0))
+            .count();
+        assert!(
+            (980..=1020).contains(&crossings),
+            "{crossings} zero crossings"
+        );
+
+        let stereo = extract_audio_pcm(&sine.path, 0.25, 0.5, RATE, 2)
+            .await
+            .unwrap();
+        assert_eq!(stereo.len(), 48_000);
+        assert!(stereo.chunks_exact(2).all(|frame| frame[0] == frame[1]));
+    }
+
+    #[tokio::test]
+    async fn ranges_past_the_end_are_padded_with_silence() {
+        let Some(sine) = sine() else {
+            return;
+        };
+
+        let tail = extract_audio_pcm(&sine.path, 0.75, 0.5, RATE, 1)
+            .await
+            .unwrap();
+        assert_eq!(tail.len(), 24_000);
+        assert!(peak(&tail[..11_000]) > 0.1);
+        assert_eq!(peak(&tail[13_000..]), 0.0);
+
+        let beyond = extract_audio_pcm(&sine.path, 5.0, 0.5, RATE, 1)
+            .await
+            .unwrap();
+        assert_eq!(beyond, vec![0.0; 24_000]);
+    }
+
+    #[tokio::test]
+    async fn a_source_without_audio_is_silence() {
+        let Some(video) = fixtures::test_video("silent.mp4", 30, 30) else {
+            return;
+        };
+
+        let samples = extract_audio_pcm(&video.path, 0.0, 1.0, RATE, 2)
+            .await
+            .unwrap();
+        assert_eq!(samples, vec![0.0; 96_000]);
+    }
+}
@@ -1,9 +1,20 @@
+use std::ffi::OsStr;
 use std::io;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::sync::{Mutex, OnceLock};
 
-static FFMPEG_PATH: OnceLock<Mutex<Option<String>>> = OnceLock::new();
-static FFPROBE_PATH: OnceLock<Mutex<Option<String>>> = OnceLock::new();
+use super::version::FfmpegVersion;
+
+/// A resolved binary and, when resolving it already ran `-version`, that output.
+#[derive(Debug, Clone, PartialEq)]
+struct Resolved {
+    path: String,
+    version_output: Option<String>,
+}
+
+static FFMPEG_PATH: OnceLock<Mutex<Option<Resolved>>> = OnceLock::new();
+static FFPROBE_PATH: OnceLock<Mutex<Option<Resolved>>> = OnceLock::new();
 
 fn read_env_path(env_var: &str) -> Option<String> {
     let value = std::env::var(env_var).ok()?;
@@ -15,41 +26,215 @@ fn read_env_path(env_var: &str) -> Option<String> {
     }
 }
 
+/// Stdout of `program -version`, `None` unless it ran and succeeded.
+fn version_output(program: &Path) -> Option<String> {
+    Command::new(program)
+        .arg("-version")
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn exe_dir() -> Option<PathBuf> {
+    std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(Path::to_path_buf))
+}
+
+/// Locations a packaged app ships the binaries in, relative to the executable's directory.
+fn bundled_candidates(exe_dir: &Path, name: &str) -> Vec<PathBuf> {
+    let file_name = format!("{name}{}", std::env::consts::EXE_SUFFIX);
+    vec![
+        exe_dir.join(&file_name),
+        exe_dir.join("bin").join(&file_name),
+        exe_dir.join("resources").join(&file_name),
+        exe_dir.join("..").join("Resources").join(&file_name),
+        exe_dir.join("..").join("resources").join(&file_name),
+    ]
+}
+
+/// Resolution order: `override_path` > `name` on PATH (`search_path` when given) >
+/// binaries bundled next to `exe_dir` that actually run. `Ok(None)` when nothing is found.
+fn resolve(
+    name: &str,
+    override_path: Option<String>,
+    search_path: Option<&OsStr>,
+    exe_dir: Option<&Path>,
+) -> Result<Option<Resolved>, String> {
+    if let Some(path) = override_path {
+        return Ok(Some(Resolved {
+            path,
+            version_output: None,
+        }));
+    }
+
+    let mut on_path = Command::new(name);
+    if let Some(search_path) = search_path {
+        on_path.env("PATH", search_path);
+    }
+    match on_path.arg("-version").output() {
+        Ok(output) => Ok(Some(Resolved {
+            path: name.to_string(),
+            version_output: Some(String::from_utf8_lossy(&output.stdout).into_owned()),
+        })),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(exe_dir.and_then(|exe_dir| {
+            bundled_candidates(exe_dir, name)
+                .into_iter()
+                .find_map(|candidate| {
+                    let output = version_output(&candidate)?;
+                    Some(Resolved {
+                        path: candidate.to_string_lossy().into_owned(),
+                        version_output: Some(output),
+                    })
+                })
+        })),
+        Err(error) => Err(format!("failed to run {name}: {error}")),
+    }
+}
+
 fn resolve_with_cache(
-    cache: &OnceLock<Mutex<Option<String>>>,
+    cache: &OnceLock<Mutex<Option<Resolved>>>,
     name: &str,
     env_var: &str,
-) -> Result<String, String> {
+) -> Result<Resolved, String> {
     let lock = cache.get_or_init(|| Mutex::new(None));
     let mut cached = lock.lock().unwrap();
-    if let Some(path) = cached.as_ref() {
-        return Ok(path.clone());
+    if let Some(resolved) = cached.as_ref() {
+        return Ok(resolved.clone());
     }
 
-    match Command::new(name).arg("-version").output() {
-        Ok(_) => {
-            let path = name.to_string();
-            *cached = Some(path.clone());
-            Ok(path)
+    match resolve(name, read_env_path(env_var), None, exe_dir().as_deref())? {
+        Some(resolved) => {
+            *cached = Some(resolved.clone());
+            Ok(resolved)
         }
-        Err(error) if error.kind() == io::ErrorKind::NotFound => {
-            if let Some(path) = read_env_path(env_var) {
-                *cached = Some(path.clone());
-                Ok(path)
-            } else {
-                Err(format!(
-                    "{name} not found on PATH and {env_var} is not set"
-                ))
-            }
-        }
-        Err(error) => Err(format!("failed to run {name}: {error}")),
+        None => Err(format!(
+            "{name} not found: {env_var} is not set, it is not on PATH and no bundled copy was found"
+        )),
     }
 }
 
 pub(crate) fn ffmpeg_path() -> Result<String, String> {
     resolve_with_cache(&FFMPEG_PATH, "ffmpeg", "FRAMESCRIPT_FFMPEG_PATH")
+        .map(|resolved| resolved.path)
 }
 
 pub(crate) fn ffprobe_path() -> Result<String, String> {
     resolve_with_cache(&FFPROBE_PATH, "ffprobe", "FRAMESCRIPT_FFPROBE_PATH")
+        .map(|resolved| resolved.path)
+}
+
+static FFMPEG_VERSION: OnceLock<Option<FfmpegVersion>> = OnceLock::new();
+
+/// Oldest supported ffmpeg (`FRAMESCRIPT_FFMPEG_MIN_VERSION`, default 4.3).
+pub fn min_ffmpeg_version() -> FfmpegVersion {
+    FfmpegVersion::minimum()
+}
+
+/// Version of the resolved ffmpeg, `None` if it could not be run or parsed. Reuses the
+/// `-version` output from resolution; only an env override has to be run here.
+pub fn ffmpeg_version() -> Option<FfmpegVersion> {
+    *FFMPEG_VERSION.get_or_init(|| {
+        let resolved =
+            resolve_with_cache(&FFMPEG_PATH, "ffmpeg", "FRAMESCRIPT_FFMPEG_PATH").ok()?;
+        let output = match resolved.version_output {
+            Some(output) => output,
+            None => version_output(Path::new(&resolved.path))?,
+        };
+        FfmpegVersion::parse(&output)
+    })
+}
+
+/// Fails when ffmpeg is older than the minimum. Unparseable versions are let through.
+pub fn check_ffmpeg_version() -> Result<(), String> {
+    let required = min_ffmpeg_version();
+    match ffmpeg_version() {
+        Some(found) if found < required => Err(format!(
+            "ffmpeg {found} found, but {required} or newer is required; install a newer ffmpeg or point FRAMESCRIPT_FFMPEG_PATH at one"
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    const NAME: &str = "framescript-fake-ffmpeg";
+
+    /// Shell script at `dir/name` printing a version line and exiting with `code`.
+    fn fake_executable(dir: &Path, code: i32) -> PathBuf {
+        fs::create_dir_all(dir).unwrap();
+        let path = dir.join(NAME);
+        fs::write(
+            &path,
+            format!("#!/bin/sh\necho 'ffmpeg version 5.1.2'\nexit {code}\n"),
+        )
+        .unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    #[test]
+    fn override_wins_over_path_and_bundled() {
+        let root = tempfile::tempdir().unwrap();
+        let path_dir = root.path().join("path");
+        let exe_dir = root.path().join("app");
+        fake_executable(&path_dir, 0);
+        fake_executable(&exe_dir, 0);
+
+        let resolved = resolve(
+            NAME,
+            Some("/opt/ffmpeg/bin/ffmpeg".to_string()),
+            Some(path_dir.as_os_str()),
+            Some(&exe_dir),
+        );
+        assert_eq!(
+            resolved.unwrap().map(|resolved| resolved.path).as_deref(),
+            Some("/opt/ffmpeg/bin/ffmpeg")
+        );
+    }
+
+    #[test]
+    fn path_wins_over_bundled() {
+        let root = tempfile::tempdir().unwrap();
+        let path_dir = root.path().join("path");
+        let exe_dir = root.path().join("app");
+        fake_executable(&path_dir, 0);
+        fake_executable(&exe_dir, 0);
+
+        let resolved = resolve(NAME, None, Some(path_dir.as_os_str()), Some(&exe_dir));
+        assert_eq!(
+            resolved.unwrap().map(|resolved| resolved.path).as_deref(),
+            Some(NAME)
+        );
+    }
+
+    #[test]
+    fn bundled_copies_are_used_when_not_on_path() {
+        let root = tempfile::tempdir().unwrap();
+        let empty = root.path().join("empty");
+        fs::create_dir_all(&empty).unwrap();
+        let exe_dir = root.path().join("app").join("MacOS");
+        // ./bin has a copy that does not run; ../Resources has a working one
+        fake_executable(&exe_dir.join("bin"), 1);
+        let bundled = fake_executable(&exe_dir.join("..").join("Resources"), 0);
+
+        let resolved = resolve(NAME, None, Some(empty.as_os_str()), Some(&exe_dir))
+            .unwrap()
+            .unwrap();
+        assert_eq!(resolved.path, bundled.to_string_lossy());
+        // the probe's output is kept, so the version check does not run it again
+        assert_eq!(
+            FfmpegVersion::parse(resolved.version_output.as_deref().unwrap()),
+            FfmpegVersion::parse("ffmpeg version 5.1.2")
+        );
+
+        let resolved = resolve(NAME, None, Some(empty.as_os_str()), Some(&empty));
+        assert_eq!(resolved.unwrap(), None);
+    }
 }
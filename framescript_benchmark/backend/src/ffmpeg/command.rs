@@ -1,18 +1,317 @@
 use std::io::{self, Read};
-use std::process::{Command, Stdio};
+use std::process::{Command as StdCommand, Output, Stdio};
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
 
-use crate::ffmpeg::bin::ffmpeg_path;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::process::{ChildStderr, Command};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
 
-pub(crate) fn extract_frames_rgba(
+use crate::ffmpeg::{
+    DecodeOptions, FrameSender, StreamHints,
+    bin::ffmpeg_path,
+    hw_decoder::DecodeBackend,
+    hwaccel::HwAccel,
+    remote,
+    sequence::{SEQUENCE_FPS, image_sequence, is_still_image},
+    stream_hints,
+    tonemap::tonemap_filter,
+};
+
+pub(crate) const CANCELED: &str = "decode canceled";
+
+/// How much of ffmpeg's stderr is kept for error messages.
+const STDERR_TAIL_BYTES: usize = 8 * 1024;
+
+fn read_secs(name: &str, default: u64) -> Duration {
+    let secs = std::env::var(name)
+        .ok()
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .filter(|secs| *secs > 0)
+        .unwrap_or(default);
+    Duration::from_secs(secs)
+}
+
+/// How long ffmpeg may go without writing to stdout before it is killed
+/// (`FRAMESCRIPT_FFMPEG_STALL_SECS`, default 30). Guards against reads from a
+/// network mount that hang forever.
+pub(crate) static STALL_TIMEOUT: LazyLock<Duration> =
+    LazyLock::new(|| read_secs("FRAMESCRIPT_FFMPEG_STALL_SECS", 30));
+
+/// Limit for a whole ffprobe run (`FRAMESCRIPT_PROBE_TIMEOUT_SECS`, default 120).
+pub(crate) static PROBE_TIMEOUT: LazyLock<Duration> =
+    LazyLock::new(|| read_secs("FRAMESCRIPT_PROBE_TIMEOUT_SECS", 120));
+
+/// Options `FRAMESCRIPT_FFMPEG_EXTRA_ARGS` may use, with the number of values each takes.
+/// Everything here tunes logging, threading or demuxing; nothing adds an input or output.
+const EXTRA_ARG_OPTIONS: &[(&str, usize)] = &[
+    ("-loglevel", 1),
+    ("-v", 1),
+    ("-report", 0),
+    ("-benchmark", 0),
+    ("-stats", 0),
+    ("-nostats", 0),
+    ("-debug_ts", 0),
+    ("-xerror", 0),
+    ("-threads", 1),
+    ("-filter_threads", 1),
+    ("-sws_flags", 1),
+    ("-fflags", 1),
+    ("-err_detect", 1),
+    ("-probesize", 1),
+    ("-analyzeduration", 1),
+];
+
+/// Tokens from `FRAMESCRIPT_FFMPEG_EXTRA_ARGS`, split like a shell would (quotes and
+/// backslashes). For debugging only: they are inserted at one fixed place, right
+/// before the output options of frame decodes and right before the input of ffprobe
+/// runs. Only `EXTRA_ARG_OPTIONS` with their values are accepted, so a list cannot
+/// add inputs or outputs; any other token makes the whole list ignored.
+pub(crate) static EXTRA_ARGS: LazyLock<Vec<String>> = LazyLock::new(|| {
+    let Ok(value) = std::env::var("FRAMESCRIPT_FFMPEG_EXTRA_ARGS") else {
+        return Vec::new();
+    };
+    match parse_extra_args(&value) {
+        Ok(args) => args,
+        Err(error) => {
+            warn!("FRAMESCRIPT_FFMPEG_EXTRA_ARGS {error}, ignoring it");
+            Vec::new()
+        }
+    }
+});
+
+fn parse_extra_args(value: &str) -> Result<Vec<String>, String> {
+    let args = split_args(value).ok_or("has an unterminated quote")?;
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        let Some((_, values)) = EXTRA_ARG_OPTIONS.iter().find(|(option, _)| option == arg) else {
+            return Err(format!("may not contain {arg:?}"));
+        };
+        for _ in 0..*values {
+            rest.next()
+                .ok_or_else(|| format!("is missing the value of {arg:?}"))?;
+        }
+    }
+    Ok(args)
+}
+
+fn split_args(value: &str) -> Option<Vec<String>> {
+    let mut args = Vec::new();
+    let mut current: Option<String> = None;
+    let mut quote = None;
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('"'), '\\') | (None, '\\') => {
+                current.get_or_insert_default().push(chars.next()?);
+            }
+            (Some(_), c) => current.get_or_insert_default().push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                current.get_or_insert_default();
+            }
+            (None, c) if c.is_whitespace() => args.extend(current.take()),
+            (None, c) => current.get_or_insert_default().push(c),
+        }
+    }
+    if quote.is_some() {
+        return None;
+    }
+    args.extend(current);
+    Some(args)
+}
+
+pub(crate) fn stall_error(stderr: &str) -> String {
+    format!(
+        "ffmpeg stalled: no output for {}s: {stderr}",
+        STALL_TIMEOUT.as_secs()
+    )
+}
+
+/// Program and arguments joined for logging.
+pub(crate) fn command_line(cmd: &StdCommand) -> String {
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Last `STDERR_TAIL_BYTES` of `stderr` as trimmed text.
+pub(crate) fn stderr_excerpt(stderr: &[u8]) -> String {
+    let start = stderr.len().saturating_sub(STDERR_TAIL_BYTES);
+    String::from_utf8_lossy(&stderr[start..]).trim().to_string()
+}
+
+/// `Command::output` for blocking callers; the child is killed once `timeout` passes.
+pub(crate) fn output_with_timeout(
+    cmd: &mut StdCommand,
+    timeout: Duration,
+) -> Result<Output, String> {
+    let started = Instant::now();
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|error| {
+            format!(
+                "failed to run {}: {error}",
+                cmd.get_program().to_string_lossy()
+            )
+        })?;
+
+    let drain = |pipe: Option<Box<dyn Read + Send>>| {
+        std::thread::spawn(move || {
+            let mut buf = Vec::new();
+            if let Some(mut pipe) = pipe {
+                let _ = pipe.read_to_end(&mut buf);
+            }
+            buf
+        })
+    };
+    let stdout = drain(child.stdout.take().map(|pipe| Box::new(pipe) as _));
+    let stderr = drain(child.stderr.take().map(|pipe| Box::new(pipe) as _));
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if Instant::now() < deadline => std::thread::sleep(Duration::from_millis(20)),
+            Ok(None) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                let stderr = stderr.join().unwrap_or_default();
+                return Err(format!(
+                    "{} timed out after {}s: {}",
+                    command_line(cmd),
+                    timeout.as_secs(),
+                    stderr_excerpt(&stderr)
+                ));
+            }
+            Err(error) => {
+                let _ = child.kill();
+                return Err(format!("failed to wait on {}: {error}", command_line(cmd)));
+            }
+        }
+    };
+
+    debug!(command = %command_line(cmd), elapsed_ms = started.elapsed().as_millis() as u64, "{status}");
+    Ok(Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
+/// Drains stderr on its own task so a chatty ffmpeg never blocks on a full pipe
+/// while we are reading stdout. Only the tail is retained.
+pub(crate) fn spawn_stderr_tail(mut stderr: ChildStderr) -> JoinHandle<String> {
+    tokio::spawn(async move {
+        let mut tail = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            match stderr.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(read) => {
+                    tail.extend_from_slice(&buf[..read]);
+                    if tail.len() > STDERR_TAIL_BYTES * 2 {
+                        tail.drain(..tail.len() - STDERR_TAIL_BYTES);
+                    }
+                }
+            }
+        }
+        stderr_excerpt(&tail)
+    })
+}
+
+/// Decode `[start_frame, end_frame)` and send each frame to `frames` as soon as it is read.
+/// Returns the number of frames delivered.
+pub(crate) async fn extract_frames_rgba(
     path: &str,
     start_frame: usize,
     end_frame: usize,
-    dst_width: u32,
-    dst_height: u32,
-    use_hwaccel: bool,
-) -> Result<Vec<Vec<u8>>, String> {
-    if end_frame < start_frame {
-        return Ok(Vec::new());
+    options: DecodeOptions,
+    cancel: &CancellationToken,
+    frames: &FrameSender,
+) -> Result<usize, String> {
+    extract_rgba(path, start_frame, end_frame, None, options, cancel, frames).await
+}
+
+/// Frames per `select` expression; longer lists are split over several ffmpeg runs.
+const SELECT_CHUNK: usize = 64;
+
+/// Channel capacity used while collecting `extract_frames_select_rgba` output.
+const FRAME_BATCH: usize = 4;
+
+/// Decode the frames at `indices` (any order, duplicates allowed) with one ffmpeg run
+/// per `SELECT_CHUNK` frames instead of one per frame. Returns `(index, rgba)` in
+/// ascending order; indices past the end of the source are left out.
+pub(crate) async fn extract_frames_select_rgba(
+    path: &str,
+    indices: &[usize],
+    options: DecodeOptions,
+    cancel: &CancellationToken,
+) -> Result<Vec<(usize, Vec<u8>)>, String> {
+    let mut indices = indices.to_vec();
+    indices.sort_unstable();
+    indices.dedup();
+
+    let mut decoded = Vec::with_capacity(indices.len());
+    for chunk in indices.chunks(SELECT_CHUNK) {
+        let (start_frame, end_frame) = (chunk[0], chunk[chunk.len() - 1] + 1);
+        let (tx, mut rx) = mpsc::channel(FRAME_BATCH);
+        let (result, ()) = tokio::join!(
+            async move {
+                extract_rgba(
+                    path,
+                    start_frame,
+                    end_frame,
+                    Some(chunk),
+                    options,
+                    cancel,
+                    &tx,
+                )
+                .await
+            },
+            async {
+                while let Some((index, frame, _)) = rx.recv().await {
+                    decoded.push((index, frame));
+                }
+            }
+        );
+        result?;
+    }
+
+    Ok(decoded)
+}
+
+/// With `select`, only those frames of `[start_frame, end_frame)` are decoded and
+/// sent, in ascending order; `select` must be sorted and within the window.
+async fn extract_rgba(
+    path: &str,
+    start_frame: usize,
+    end_frame: usize,
+    select: Option<&[usize]>,
+    options: DecodeOptions,
+    cancel: &CancellationToken,
+    frames: &FrameSender,
+) -> Result<usize, String> {
+    let DecodeOptions {
+        width: dst_width,
+        height: dst_height,
+        ..
+    } = options;
+    if cancel.is_cancelled() {
+        return Err(CANCELED.to_string());
+    }
+
+    if end_frame <= start_frame {
+        return Ok(0);
     }
     let frame_size = (dst_width as usize)
         .saturating_mul(dst_height as usize)
@@ -21,35 +320,101 @@ pub(crate) fn extract_frames_rgba(
         return Err("invalid output size".to_string());
     }
 
-    let filter = format!(
-        "trim=start_frame={}:end_frame={},scale={}x{}",
-        start_frame, end_frame, dst_width, dst_height
-    );
+    let probe_path = path.to_string();
+    let (hints, sequence, still) = tokio::task::spawn_blocking(move || {
+        (
+            stream_hints(&probe_path),
+            image_sequence(&probe_path),
+            is_still_image(&probe_path),
+        )
+    })
+    .await
+    .unwrap_or_default();
+    // hw のデコード経路ではアルファが落ちるのでソフトウェアに固定する。画像も hw では読めない
+    let hwaccel = if hints.alpha || sequence.is_some() || still {
+        HwAccel::None
+    } else {
+        options.hwaccel
+    };
+    let backend = if hwaccel.is_hardware() {
+        DecodeBackend::Hardware
+    } else {
+        DecodeBackend::Software
+    };
+
+    // -ss before -i seeks to the nearest keyframe and drops frames up to the target,
+    // instead of decoding everything from frame 0. Seeking half a frame early keeps
+    // timestamp rounding from skipping the first requested frame.
+    // image sequences start at the right file instead of seeking, and VFR streams
+    // take the exact trim path since start_frame / fps is not where the frame is
+    let seek_seconds = if start_frame > 0 && sequence.is_none() && !hints.vfr {
+        hints
+            .fps
+            .map(|fps| (start_frame as f64 - 0.5).max(0.0) / fps)
+    } else {
+        None
+    };
+    // after a seek, or from the first file of a sequence, n counts from the window start
+    let trim = if seek_seconds.is_some() || sequence.is_some() {
+        format!("trim=end_frame={}", end_frame - start_frame)
+    } else {
+        format!("trim=start_frame={start_frame}:end_frame={end_frame}")
+    };
+    // select の n は trim 後の先頭からの番号
+    let trim = match select {
+        Some(indices) => {
+            let expression = indices
+                .iter()
+                .map(|index| format!("eq(n,{})", index - start_frame))
+                .collect::<Vec<_>>()
+                .join("+");
+            format!("{trim},select='{expression}'")
+        }
+        None => trim,
+    };
+    let tonemap = if options.tonemap && hints.hdr {
+        tonemap_filter().await
+    } else {
+        None
+    };
+    let filter = filter_chain(trim, &hints, tonemap, &options);
 
     let ffmpeg = ffmpeg_path()?;
     let mut cmd = Command::new(ffmpeg);
     cmd.arg("-hide_banner")
         .arg("-loglevel")
         .arg("error")
-        .arg("-nostdin");
-    if use_hwaccel {
-        cmd.arg("-hwaccel").arg("auto");
+        .arg("-nostdin")
+        // rotation is applied by our own filter chain
+        .arg("-noautorotate");
+    cmd.args(hwaccel.input_args());
+    if let Some(seek_seconds) = seek_seconds {
+        cmd.arg("-ss").arg(format!("{seek_seconds:.6}"));
+    }
+    if let Some(decoder) = hints.alpha_decoder {
+        cmd.arg("-c:v").arg(decoder);
     }
-    cmd.arg("-i")
-        .arg(path)
-        .arg("-vf")
+    match &sequence {
+        Some(sequence) => cmd.args(sequence.input_args(start_frame, *SEQUENCE_FPS)),
+        None => cmd.args(remote::input_args(path)).arg("-i").arg(path),
+    };
+    cmd.arg("-vf")
         .arg(filter)
         .arg("-an")
         .arg("-vsync")
         .arg("0")
+        .args(EXTRA_ARGS.iter())
         .arg("-f")
         .arg("rawvideo")
         .arg("-pix_fmt")
         .arg("rgba")
         .arg("pipe:1");
 
-    cmd.stdout(Stdio::piped()).stderr(Stdio::inherit());
+    cmd.stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
 
+    let started = Instant::now();
     let mut child = cmd
         .spawn()
         .map_err(|error| format!("failed to run ffmpeg: {error}"))?;
@@ -57,35 +422,480 @@ pub(crate) fn extract_frames_rgba(
         .stdout
         .take()
         .ok_or_else(|| "failed to open ffmpeg stdout".to_string())?;
+    let stderr_tail = child.stderr.take().map(spawn_stderr_tail);
+    let collect_stderr = async move || match stderr_tail {
+        Some(handle) => handle.await.unwrap_or_default(),
+        None => String::new(),
+    };
 
-    let max_frames = end_frame - start_frame + 1;
-    let mut frames = Vec::new();
+    let max_frames = select.map_or(end_frame - start_frame, <[usize]>::len);
     let mut index = 0usize;
 
     loop {
         let mut frame = vec![0u8; frame_size];
-        match stdout.read_exact(&mut frame) {
-            Ok(()) => {
+        let read = tokio::select! {
+            _ = cancel.cancelled() => break,
+            read = tokio::time::timeout(*STALL_TIMEOUT, read_frame(&mut stdout, &mut frame)) => read,
+        };
+        let Ok(read) = read else {
+            let _ = child.kill().await;
+            let stderr = collect_stderr().await;
+            warn!(command = %command_line(cmd.as_std()), stderr = %stderr, "ffmpeg stalled, killed it");
+            return Err(stall_error(&stderr));
+        };
+        match read {
+            Ok(true) => {
                 if index < max_frames {
-                    frames.push(frame);
+                    let frame_index = select.map_or(start_frame + index, |indices| indices[index]);
+                    if frames.send((frame_index, frame, backend)).await.is_err() {
+                        // nobody is listening anymore
+                        let _ = child.kill().await;
+                        return Err(CANCELED.to_string());
+                    }
                 }
                 index = index.saturating_add(1);
             }
+            Ok(false) => break,
             Err(error) => {
-                if error.kind() == io::ErrorKind::UnexpectedEof {
-                    break;
-                }
-                return Err(format!("failed to read ffmpeg output: {error}"));
+                let _ = child.kill().await;
+                let stderr = collect_stderr().await;
+                warn!(command = %command_line(cmd.as_std()), stderr = %stderr, "ffmpeg output read failed");
+                return Err(format!("failed to read ffmpeg output: {error}: {stderr}"));
             }
         }
     }
 
+    if cancel.is_cancelled() {
+        let _ = child.kill().await;
+        return Err(CANCELED.to_string());
+    }
+
     let status = child
         .wait()
+        .await
         .map_err(|error| format!("failed to wait on ffmpeg: {error}"))?;
+    let stderr = collect_stderr().await;
+    debug!(command = %command_line(cmd.as_std()), elapsed_ms = started.elapsed().as_millis() as u64, frames = index, "{status}");
     if !status.success() {
-        return Err(format!("ffmpeg failed with status: {status}"));
+        warn!(command = %command_line(cmd.as_std()), stderr = %stderr, "ffmpeg failed with status {status}");
+        return Err(format!("ffmpeg failed with status: {status}: {stderr}"));
+    }
+
+    Ok(index.min(max_frames))
+}
+
+/// trim → (tonemap) → rotation → (alpha) → scale. The requested size is the upright
+/// display size, so rotating before scaling needs no width/height swap.
+fn filter_chain(
+    trim: String,
+    hints: &StreamHints,
+    tonemap: Option<&str>,
+    options: &DecodeOptions,
+) -> String {
+    let mut filters = vec![trim];
+    if let Some(tonemap) = tonemap {
+        filters.push(tonemap.to_string());
+    }
+    match hints.rotation {
+        90 => filters.push("transpose=clock".to_string()),
+        180 => filters.push("hflip,vflip".to_string()),
+        270 => filters.push("transpose=cclock".to_string()),
+        _ => {}
+    }
+    if hints.alpha {
+        // convert before scaling so the alpha plane is scaled with the color planes
+        filters.push("format=rgba".to_string());
+    }
+    filters.push(format!(
+        "scale={}x{}:flags={}",
+        options.width,
+        options.height,
+        options.quality.flags()
+    ));
+    filters.join(",")
+}
+
+/// Fill `frame` completely. `Ok(false)` on a clean end of stream.
+async fn read_frame(reader: &mut (impl AsyncRead + Unpin), frame: &mut [u8]) -> io::Result<bool> {
+    match reader.read_exact(frame).await {
+        Ok(_) => Ok(true),
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(error) => Err(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffmpeg::fixtures;
+
+    #[tokio::test]
+    async fn fast_seek_matches_decoding_from_the_start() {
+        // one keyframe for the whole file, so the seek has to decode up to the target
+        let Some(video) = fixtures::test_video("long.mp4", 1800, 1800) else {
+            return;
+        };
+        let options = fixtures::options(64, 36);
+
+        let started = Instant::now();
+        let seeked = fixtures::extract(&video.path, 1500, 1503, options)
+            .await
+            .unwrap();
+        let seek_time = started.elapsed();
+
+        // a window starting at 0 takes the exact path through every frame
+        let started = Instant::now();
+        let from_start = fixtures::extract(&video.path, 0, 1503, options)
+            .await
+            .unwrap();
+        let slow_time = started.elapsed();
+
+        assert_eq!(seeked, from_start[1500..]);
+        eprintln!("fast seek {seek_time:?}, decoding from frame 0 {slow_time:?}");
+    }
+
+    #[tokio::test]
+    async fn selected_frames_match_single_extractions() {
+        let Some(video) = fixtures::test_video("strip.mp4", 200, 30) else {
+            return;
+        };
+        let options = fixtures::options(64, 36);
+
+        // unsorted, duplicated, more than one select chunk and one past the end
+        let mut indices: Vec<usize> = (0..SELECT_CHUNK + 4).map(|i| i * 2 % 199).collect();
+        indices.extend([150, 3, 250]);
+        let selected =
+            extract_frames_select_rgba(&video.path, &indices, options, &CancellationToken::new())
+                .await
+                .unwrap();
+
+        let mut expected_indices = indices.clone();
+        expected_indices.retain(|&index| index < 200);
+        expected_indices.sort_unstable();
+        expected_indices.dedup();
+        assert_eq!(
+            selected.iter().map(|(index, _)| *index).collect::<Vec<_>>(),
+            expected_indices
+        );
+        for (index, frame) in &selected {
+            let single = fixtures::extract(&video.path, *index, index + 1, options)
+                .await
+                .unwrap();
+            assert_eq!(single, [(*index, frame.clone())], "frame {index}");
+        }
+    }
+
+    #[tokio::test]
+    async fn vfr_windows_match_decoding_from_the_start() {
+        // 30 frames at 30 fps, then the rest at 10 fps: a seek by start_frame / fps
+        // would land far past the frame
+        let Some(video) = fixtures::generate(
+            "vfr.mp4",
+            &[
+                "-f",
+                "lavfi",
+                "-i",
+                "testsrc2=size=64x36:rate=30:duration=3",
+                "-vf",
+                "setpts='if(lt(N,30),N,30+(N-30)*3)/(30*TB)'",
+                "-vsync",
+                "0",
+                "-pix_fmt",
+                "yuv420p",
+            ],
+        ) else {
+            return;
+        };
+        let path = video.path.clone();
+        let hints = tokio::task::spawn_blocking(move || stream_hints(&path))
+            .await
+            .unwrap();
+        assert!(hints.vfr, "fixture is not VFR: {hints:?}");
+        let options = fixtures::options(64, 36);
+
+        let window = fixtures::extract(&video.path, 60, 63, options)
+            .await
+            .unwrap();
+        let from_start = fixtures::extract(&video.path, 0, 63, options)
+            .await
+            .unwrap();
+
+        assert_eq!(window.len(), 3);
+        assert_eq!(window, from_start[60..]);
+    }
+
+    // single-threaded, like a busy ws connection: a blocking read would stall the ticker
+    #[tokio::test]
+    async fn heavy_decodes_leave_the_runtime_responsive() {
+        let Some(video) = fixtures::test_video("heavy.mp4", 300, 30) else {
+            return;
+        };
+        let options = fixtures::options(1280, 720);
+
+        let done = CancellationToken::new();
+        let ticker = async {
+            let mut worst = Duration::ZERO;
+            while !done.is_cancelled() {
+                let started = Instant::now();
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                worst = worst.max(started.elapsed());
+            }
+            worst
+        };
+        let decodes = async {
+            let windows =
+                (0..4).map(|i| fixtures::extract(&video.path, i * 60, i * 60 + 60, options));
+            let results = futures::future::join_all(windows).await;
+            done.cancel();
+            results
+        };
+        let (worst, results) = tokio::join!(ticker, decodes);
+
+        for frames in results {
+            assert_eq!(frames.unwrap().len(), 60);
+        }
+        assert!(
+            worst < Duration::from_millis(250),
+            "runtime stalled for {worst:?}"
+        );
+    }
+
+    #[test]
+    fn rotation_goes_between_trim_and_scale() {
+        let options = fixtures::options(32, 64);
+        let scale = format!("scale=32x64:flags={}", options.quality.flags());
+        for (rotation, filter) in [
+            (0, None),
+            (90, Some("transpose=clock")),
+            (180, Some("hflip,vflip")),
+            (270, Some("transpose=cclock")),
+        ] {
+            let hints = StreamHints {
+                rotation,
+                ..Default::default()
+            };
+            let expected = ["trim=end_frame=1", filter.unwrap_or_default(), &scale]
+                .into_iter()
+                .filter(|part| !part.is_empty())
+                .collect::<Vec<_>>()
+                .join(",");
+            assert_eq!(
+                filter_chain("trim=end_frame=1".to_string(), &hints, None, &options),
+                expected
+            );
+        }
     }
 
-    Ok(frames)
+    /// `frame` turned `degrees` clockwise.
+    fn rotate_clockwise(frame: &[u8], width: usize, height: usize, degrees: u32) -> Vec<u8> {
+        let mut rotated = vec![0u8; frame.len()];
+        for y in 0..height {
+            for x in 0..width {
+                let (dst_x, dst_y, dst_width) = match degrees {
+                    90 => (height - 1 - y, x, height),
+                    180 => (width - 1 - x, height - 1 - y, width),
+                    270 => (y, width - 1 - x, height),
+                    _ => (x, y, width),
+                };
+                let src = (y * width + x) * 4;
+                let dst = (dst_y * dst_width + dst_x) * 4;
+                rotated[dst..dst + 4].copy_from_slice(&frame[src..src + 4]);
+            }
+        }
+        rotated
+    }
+
+    #[tokio::test]
+    async fn rotation_metadata_turns_frames_upright() {
+        // 4:4:4 so the rotation is an exact pixel permutation
+        let Some(plain) = fixtures::generate(
+            "plain.mp4",
+            &[
+                "-f",
+                "lavfi",
+                "-i",
+                "testsrc2=size=64x32:rate=30",
+                "-frames:v",
+                "1",
+                "-c:v",
+                "libx264",
+                "-pix_fmt",
+                "yuv444p",
+            ],
+        ) else {
+            return;
+        };
+        let (_, reference) = fixtures::extract(&plain.path, 0, 1, fixtures::options(64, 32))
+            .await
+            .unwrap()
+            .remove(0);
+
+        for degrees in [90, 180, 270] {
+            let Some(rotated) = fixtures::rotated_copy(&plain, degrees) else {
+                return;
+            };
+            // the requested size is the upright one
+            let (width, height) = match degrees {
+                180 => (64, 32),
+                _ => (32, 64),
+            };
+            let (_, frame) =
+                fixtures::extract(&rotated.path, 0, 1, fixtures::options(width, height))
+                    .await
+                    .unwrap()
+                    .remove(0);
+
+            let expected = rotate_clockwise(&reference, 64, 32, degrees);
+            let worst = frame
+                .iter()
+                .zip(&expected)
+                .map(|(a, b)| a.abs_diff(*b))
+                .max()
+                .unwrap();
+            assert!(worst <= 2, "{degrees}°: pixels differ by up to {worst}");
+        }
+    }
+
+    #[test]
+    fn alpha_is_kept_through_scaling() {
+        let options = fixtures::options(64, 32);
+        let hints = StreamHints {
+            alpha: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            filter_chain("trim=end_frame=1".to_string(), &hints, None, &options),
+            format!(
+                "trim=end_frame=1,format=rgba,scale=64x32:flags={}",
+                options.quality.flags()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn transparent_pixels_stay_transparent() {
+        // left half fully transparent, right half opaque
+        let Some(video) = fixtures::generate(
+            "alpha.mkv",
+            &[
+                "-f",
+                "lavfi",
+                "-i",
+                "color=c=red@0:size=32x32:rate=30,format=yuva420p[a];\
+                 color=c=blue:size=32x32:rate=30,format=yuva420p[b];\
+                 [a][b]hstack[out0]",
+                "-frames:v",
+                "1",
+                "-c:v",
+                "ffv1",
+                "-pix_fmt",
+                "yuva420p",
+            ],
+        ) else {
+            return;
+        };
+
+        let (_, frame) = fixtures::extract(&video.path, 0, 1, fixtures::options(64, 32))
+            .await
+            .unwrap()
+            .remove(0);
+
+        for (i, pixel) in frame.chunks_exact(4).enumerate() {
+            let x = i % 64;
+            // the scaler may blend the two columns at the seam
+            match x {
+                0..30 => assert!(pixel[3] <= 8, "pixel {i} alpha {}", pixel[3]),
+                34.. => assert!(pixel[3] >= 247, "pixel {i} alpha {}", pixel[3]),
+                _ => {}
+            }
+        }
+    }
+
+    /// Executable shell script with `body`, standing in for ffmpeg or ffprobe.
+    #[cfg(unix)]
+    fn fake_tool(dir: &tempfile::TempDir, body: &str) -> StdCommand {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = dir.path().join("ffprobe");
+        std::fs::write(&path, format!("#!/bin/sh\n{body}\n")).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        StdCommand::new(path)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_hanging_tool_is_killed_at_the_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        // exec, so killing the script kills the sleep holding the pipes open
+        let mut cmd = fake_tool(
+            &dir,
+            "echo 'opening smb://share/clip.mov' >&2\nexec sleep 30",
+        );
+
+        let started = Instant::now();
+        let error = output_with_timeout(&mut cmd, Duration::from_millis(300)).unwrap_err();
+        assert!(
+            started.elapsed() < Duration::from_secs(10),
+            "{:?}",
+            started.elapsed()
+        );
+        assert!(error.contains("timed out"), "{error}");
+        assert!(error.contains("opening smb://share/clip.mov"), "{error}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_tool_finishing_in_time_returns_its_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut cmd = fake_tool(&dir, "echo '{}'\necho warning >&2\nexit 3");
+
+        let output = output_with_timeout(&mut cmd, Duration::from_secs(10)).unwrap();
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(output.stdout, b"{}\n");
+        assert_eq!(output.stderr, b"warning\n");
+    }
+
+    #[test]
+    fn extra_args_split_like_a_shell() {
+        let split = |value: &str| split_args(value).unwrap();
+        assert_eq!(split("  -threads   2 "), ["-threads", "2"]);
+        assert_eq!(
+            split(r#"-sws_flags "bicubic+accurate_rnd""#),
+            ["-sws_flags", "bicubic+accurate_rnd"]
+        );
+        assert_eq!(split("-fflags 'a b' c\\ d"), ["-fflags", "a b", "c d"]);
+        assert_eq!(split(r#"-v "say \"hi\"" ''"#), ["-v", r#"say "hi""#, ""]);
+        assert_eq!(split_args("-v 'open"), None);
+        assert_eq!(split_args("-v \\"), None);
+    }
+
+    #[test]
+    fn extra_args_only_take_known_options_and_their_values() {
+        assert_eq!(
+            parse_extra_args("-threads 2 -report -loglevel debug"),
+            Ok(vec![
+                "-threads".to_string(),
+                "2".to_string(),
+                "-report".to_string(),
+                "-loglevel".to_string(),
+                "debug".to_string(),
+            ])
+        );
+        assert_eq!(parse_extra_args(""), Ok(Vec::new()));
+
+        // each of these would add an input or an output
+        for value in [
+            "-an /tmp/x.mp4",
+            "-map 0 out.mkv",
+            "-report /tmp/x.mp4",
+            "-i other.mp4",
+            "-y -f null -",
+            "pipe:1",
+            "-threads 2 out.mkv",
+        ] {
+            assert!(parse_extra_args(value).is_err(), "{value:?} was accepted");
+        }
+        assert!(parse_extra_args("-threads").is_err());
+        assert!(parse_extra_args("-v 'open").is_err());
+    }
 }
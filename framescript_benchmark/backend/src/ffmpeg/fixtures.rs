@@ -0,0 +1,129 @@
+//! Media generated with ffmpeg's lavfi sources for tests. Helpers return `None`
+//! when ffmpeg or ffprobe cannot be run, and the calling test skips.
+
+use std::process::Command;
+
+use tempfile::TempDir;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+use crate::ffmpeg::{
+    DecodeOptions,
+    bin::{ffmpeg_path, ffprobe_path},
+    command::extract_frames_rgba,
+    hwaccel::HwAccel,
+    scale::ScaleQuality,
+};
+
+/// A generated file inside a temp dir that is removed on drop.
+pub(crate) struct Fixture {
+    _dir: TempDir,
+    pub path: String,
+}
+
+fn tools_available() -> bool {
+    let available = ffmpeg_path().is_ok() && ffprobe_path().is_ok();
+    if !available {
+        eprintln!("skipping: ffmpeg/ffprobe not available");
+    }
+    available
+}
+
+/// Run ffmpeg with `args` followed by the output file `name` in a fresh temp dir.
+pub(crate) fn generate(name: &str, args: &[&str]) -> Option<Fixture> {
+    if !tools_available() {
+        return None;
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join(name).to_string_lossy().into_owned();
+    let output = Command::new(ffmpeg_path().unwrap())
+        .args(["-hide_banner", "-loglevel", "error", "-y"])
+        .args(args)
+        .arg(&path)
+        .output()
+        .unwrap();
+    if !output.status.success() {
+        // e.g. an encoder missing from this build
+        eprintln!(
+            "skipping: could not generate {name}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        return None;
+    }
+
+    Some(Fixture { _dir: dir, path })
+}
+
+/// `testsrc2` video of `frames` frames at 30 fps, with a keyframe only every `gop` frames.
+pub(crate) fn test_video(name: &str, frames: usize, gop: usize) -> Option<Fixture> {
+    let source = format!(
+        "testsrc2=size=64x36:rate=30:duration={}",
+        frames as f64 / 30.0
+    );
+    let gop = gop.to_string();
+    generate(
+        name,
+        &[
+            "-f", "lavfi", "-i", &source, "-g", &gop, "-pix_fmt", "yuv420p",
+        ],
+    )
+}
+
+pub(crate) fn options(width: u32, height: u32) -> DecodeOptions {
+    DecodeOptions {
+        width,
+        height,
+        hwaccel: HwAccel::None,
+        quality: ScaleQuality::default(),
+        tonemap: false,
+    }
+}
+
+/// Every frame `extract_frames_rgba` delivers for `[start_frame, end_frame)`.
+pub(crate) async fn extract(
+    path: &str,
+    start_frame: usize,
+    end_frame: usize,
+    options: DecodeOptions,
+) -> Result<Vec<(usize, Vec<u8>)>, String> {
+    let cancel = CancellationToken::new();
+    let (tx, mut rx) = mpsc::channel(4);
+    let (result, frames) = tokio::join!(
+        async move { extract_frames_rgba(path, start_frame, end_frame, options, &cancel, &tx).await },
+        async {
+            let mut frames = Vec::new();
+            while let Some((index, frame, _)) = rx.recv().await {
+                frames.push((index, frame));
+            }
+            frames
+        }
+    );
+    result?;
+    Ok(frames)
+}
+
+/// Stream copy of `source` whose display matrix says to turn it `degrees` clockwise.
+pub(crate) fn rotated_copy(source: &Fixture, degrees: u32) -> Option<Fixture> {
+    let name = format!("rotated_{degrees}.mp4");
+    // -display_rotation is counterclockwise; older builds only know the rotate tag
+    let counterclockwise = format!("-{degrees}");
+    let tag = format!("rotate={degrees}");
+    generate(
+        &name,
+        &[
+            "-display_rotation",
+            &counterclockwise,
+            "-i",
+            &source.path,
+            "-c",
+            "copy",
+        ],
+    )
+    .or_else(|| {
+        generate(
+            &name,
+            &["-i", &source.path, "-c", "copy", "-metadata:s:v:0", &tag],
+        )
+    })
+}
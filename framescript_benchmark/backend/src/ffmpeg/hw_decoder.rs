@@ -1,60 +1,223 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
 use crate::decoder::generate_empty_frame;
 use crate::ffmpeg::command::extract_frames_rgba;
+use crate::ffmpeg::hwaccel::HwAccel;
+use crate::ffmpeg::{DecodeOptions, FrameSender};
+
+/// Decoder path that produced a window of frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DecodeBackend {
+    Hardware,
+    Software,
+}
+
+/// Frames delivered by one extraction and the path that decoded them.
+#[derive(Debug, Clone, Copy)]
+pub struct Extracted {
+    pub frames: usize,
+    pub backend: DecodeBackend,
+}
+
+/// Decode everything in software regardless of `FRAMESCRIPT_HWACCEL`, for drivers that
+/// produce corrupted frames. Starts from `FRAMESCRIPT_DECODE_BACKEND=software`.
+static FORCE_SOFTWARE: LazyLock<AtomicBool> = LazyLock::new(|| {
+    let forced = std::env::var("FRAMESCRIPT_DECODE_BACKEND")
+        .is_ok_and(|value| value.trim().eq_ignore_ascii_case("software"));
+    AtomicBool::new(forced)
+});
+
+pub fn software_forced() -> bool {
+    FORCE_SOFTWARE.load(Ordering::Relaxed)
+}
+
+pub fn force_software(forced: bool) {
+    FORCE_SOFTWARE.store(forced, Ordering::Relaxed);
+}
+
+/// Files whose hardware decode failed, decoded in software until the entry expires.
+static HW_FAILED: LazyLock<Mutex<HashMap<String, Instant>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+const HW_FAILED_LIMIT: usize = 256;
+
+/// How long a file stays on software decoding (`FRAMESCRIPT_HW_RETRY_SECS`, default 600).
+static HW_RETRY_AFTER: LazyLock<Duration> = LazyLock::new(|| {
+    let secs = std::env::var("FRAMESCRIPT_HW_RETRY_SECS")
+        .ok()
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .unwrap_or(600);
+    Duration::from_secs(secs)
+});
+
+fn hw_failed_recently(path: &str) -> bool {
+    let mut failed = HW_FAILED.lock().unwrap();
+    match failed.get(path) {
+        Some(at) if at.elapsed() < *HW_RETRY_AFTER => true,
+        Some(_) => {
+            failed.remove(path);
+            false
+        }
+        None => false,
+    }
+}
+
+fn remember_hw_failure(path: &str, error: &str) {
+    let mut failed = HW_FAILED.lock().unwrap();
+    if failed.len() >= HW_FAILED_LIMIT
+        && !failed.contains_key(path)
+        && let Some(oldest) = failed
+            .iter()
+            .min_by_key(|(_, at)| **at)
+            .map(|(path, _)| path.clone())
+    {
+        failed.remove(&oldest);
+    }
+    if failed.insert(path.to_string(), Instant::now()).is_none() {
+        info!(
+            path,
+            "hardware decode failed, using software for this file: {error}"
+        );
+    }
+}
 
-pub fn extract_frame_window_hw_rgba(
+/// Give every file another chance at hardware decoding.
+pub fn forget_hw_failures() {
+    HW_FAILED.lock().unwrap().clear();
+}
+
+/// Decode `[start_frame, end_frame)` into `frames`, trying `options.hwaccel` first unless it
+/// is `None` or software decoding is forced. A window that yields nothing produces a single
+/// empty frame at `start_frame`.
+pub async fn extract_frame_window_rgba(
     path: &str,
     start_frame: usize,
     end_frame: usize,
-    dst_width: u32,
-    dst_height: u32,
-) -> Result<Vec<(usize, Vec<u8>)>, String> {
-    let end_exclusive = end_frame.saturating_add(1);
-    let frames = match extract_frames_rgba(
-        path,
-        start_frame,
-        end_exclusive,
-        dst_width,
-        dst_height,
-        true,
-    ) {
-        Ok(frames) => frames,
-        Err(hw_err) => extract_frames_rgba(
-            path,
-            start_frame,
-            end_exclusive,
-            dst_width,
-            dst_height,
-            false,
-        )
-        .map_err(|sw_err| format!("hwaccel failed: {hw_err}; software failed: {sw_err}"))?,
+    options: DecodeOptions,
+    cancel: &CancellationToken,
+    frames: &FrameSender,
+) -> Result<Extracted, String> {
+    let use_hardware =
+        options.hwaccel.is_hardware() && !software_forced() && !hw_failed_recently(path);
+    let hw_result = if use_hardware {
+        extract_frames_rgba(path, start_frame, end_frame, options, cancel, frames).await
+    } else {
+        Err("hardware decoding disabled".to_string())
     };
-
-    if frames.is_empty() {
-        return Ok(vec![(
-            start_frame,
-            generate_empty_frame(dst_width, dst_height),
-        )]);
+    if use_hardware
+        && let Err(hw_err) = &hw_result
+        && !cancel.is_cancelled()
+    {
+        remember_hw_failure(path, hw_err);
     }
+    let software = DecodeOptions {
+        hwaccel: HwAccel::None,
+        ..options
+    };
+    let extracted = match hw_result {
+        Ok(frames) => Extracted {
+            frames,
+            backend: DecodeBackend::Hardware,
+        },
+        Err(hw_err) if cancel.is_cancelled() => return Err(hw_err),
+        // hw が途中で落ちた場合も窓全体をやり直す。届け済みのフレームは受け手が無視する
+        Err(hw_err) => Extracted {
+            frames: extract_frames_rgba(path, start_frame, end_frame, software, cancel, frames)
+                .await
+                .map_err(|sw_err| {
+                    if use_hardware {
+                        format!("hwaccel failed: {hw_err}; software failed: {sw_err}")
+                    } else {
+                        sw_err
+                    }
+                })?,
+            backend: DecodeBackend::Software,
+        },
+    };
 
-    let mut results = Vec::with_capacity(frames.len());
-    for (idx, frame) in frames.into_iter().enumerate() {
-        results.push((start_frame + idx, frame));
+    if extracted.frames == 0 {
+        let _ = frames
+            .send((
+                start_frame,
+                generate_empty_frame(options.width, options.height),
+                extracted.backend,
+            ))
+            .await;
+        return Ok(Extracted {
+            frames: 1,
+            ..extracted
+        });
     }
 
-    Ok(results)
+    Ok(extracted)
 }
 
-pub fn extract_frame_hw_rgba(
-    path: &str,
-    target_frame: usize,
-    dst_width: u32,
-    dst_height: u32,
-) -> Result<Vec<u8>, String> {
-    let frames =
-        extract_frame_window_hw_rgba(path, target_frame, target_frame + 1, dst_width, dst_height)?;
-    if let Some((_, data)) = frames.into_iter().next() {
-        Ok(data)
-    } else {
-        Ok(generate_empty_frame(dst_width, dst_height))
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::ffmpeg::fixtures;
+
+    /// Every frame `extract_frame_window_rgba` delivers for `[start_frame, end_frame)`.
+    async fn window(
+        path: &str,
+        start_frame: usize,
+        end_frame: usize,
+        options: DecodeOptions,
+    ) -> Vec<(usize, Vec<u8>)> {
+        let cancel = CancellationToken::new();
+        let (tx, mut rx) = mpsc::channel(4);
+        let (result, frames) = tokio::join!(
+            async move {
+                extract_frame_window_rgba(path, start_frame, end_frame, options, &cancel, &tx).await
+            },
+            async {
+                let mut frames = Vec::new();
+                while let Some((index, frame, _)) = rx.recv().await {
+                    frames.push((index, frame));
+                }
+                frames
+            }
+        );
+        result.unwrap();
+        frames
+    }
+
+    fn indices(frames: &[(usize, Vec<u8>)]) -> Vec<usize> {
+        frames.iter().map(|(index, _)| *index).collect()
+    }
+
+    #[tokio::test]
+    async fn windows_are_half_open_at_chunk_edges_and_the_last_frame() {
+        let Some(video) = fixtures::test_video("edges.mp4", 30, 10) else {
+            return;
+        };
+        let options = fixtures::options(64, 36);
+        let all = window(&video.path, 0, 30, options).await;
+        assert_eq!(indices(&all), (0..30).collect::<Vec<_>>());
+
+        // adjacent chunks neither overlap nor leave a gap, on and off keyframes
+        for (start, end) in [(0, 10), (10, 20), (20, 30), (9, 11), (19, 21)] {
+            let chunk = window(&video.path, start, end, options).await;
+            assert_eq!(chunk, all[start..end], "[{start}, {end})");
+        }
+
+        // the exact last frame, alone and in a window running past the end
+        assert_eq!(window(&video.path, 29, 30, options).await, all[29..]);
+        assert_eq!(window(&video.path, 25, 40, options).await, all[25..]);
+
+        // nothing past the end: a single placeholder at the start of the window
+        let past_end = window(&video.path, 30, 31, options).await;
+        assert_eq!(indices(&past_end), [30]);
+        assert_eq!(past_end[0].1, generate_empty_frame(64, 36));
     }
 }
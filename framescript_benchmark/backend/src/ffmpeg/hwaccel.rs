@@ -0,0 +1,204 @@
+use std::process::Stdio;
+use std::sync::LazyLock;
+
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use tokio::sync::OnceCell;
+use tracing::{info, warn};
+
+use crate::ffmpeg::bin::ffmpeg_path;
+use crate::ffmpeg::command::PROBE_TIMEOUT;
+
+/// Hardware decoding backend handed to ffmpeg's `-hwaccel`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HwAccel {
+    Auto,
+    Vaapi,
+    Cuda,
+    Qsv,
+    D3d11va,
+    Videotoolbox,
+    /// Software decoding only.
+    None,
+}
+
+impl HwAccel {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "auto" => Some(Self::Auto),
+            "vaapi" => Some(Self::Vaapi),
+            "cuda" => Some(Self::Cuda),
+            "qsv" => Some(Self::Qsv),
+            "d3d11va" => Some(Self::D3d11va),
+            "videotoolbox" => Some(Self::Videotoolbox),
+            "none" | "off" | "software" => Some(Self::None),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Auto => "auto",
+            Self::Vaapi => "vaapi",
+            Self::Cuda => "cuda",
+            Self::Qsv => "qsv",
+            Self::D3d11va => "d3d11va",
+            Self::Videotoolbox => "videotoolbox",
+            Self::None => "none",
+        }
+    }
+
+    pub fn is_hardware(self) -> bool {
+        self != Self::None
+    }
+
+    /// Input options for ffmpeg. Empty for software decoding.
+    pub(crate) fn input_args(self) -> Vec<String> {
+        if !self.is_hardware() {
+            return Vec::new();
+        }
+
+        let mut args = vec!["-hwaccel".to_string(), self.as_str().to_string()];
+        // auto は ffmpeg にデバイス選択を任せる
+        if self != Self::Auto
+            && let Some(device) = HWACCEL_DEVICE.as_deref()
+        {
+            args.push("-hwaccel_device".to_string());
+            args.push(device.to_string());
+        }
+        args
+    }
+}
+
+/// Backend from `FRAMESCRIPT_HWACCEL` (default `auto`).
+pub static HWACCEL: LazyLock<HwAccel> = LazyLock::new(|| {
+    let Ok(value) = std::env::var("FRAMESCRIPT_HWACCEL") else {
+        return HwAccel::Auto;
+    };
+    HwAccel::parse(&value).unwrap_or_else(|| {
+        warn!("unknown FRAMESCRIPT_HWACCEL={value:?}, using auto");
+        HwAccel::Auto
+    })
+});
+
+/// Device from `FRAMESCRIPT_HWACCEL_DEVICE`, e.g. `/dev/dri/renderD128` or a CUDA index.
+pub static HWACCEL_DEVICE: LazyLock<Option<String>> = LazyLock::new(|| {
+    std::env::var("FRAMESCRIPT_HWACCEL_DEVICE")
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+});
+
+/// First backend that decoded the probe clip, `None` when none did.
+static DETECTED: OnceCell<HwAccel> = OnceCell::const_new();
+
+impl HwAccel {
+    /// `Auto` becomes the detected backend so ffmpeg does not have to guess per chunk.
+    pub async fn resolve(self) -> HwAccel {
+        match self {
+            Self::Auto => detected_hwaccel().await,
+            other => other,
+        }
+    }
+}
+
+/// Detected backend, if detection has already finished.
+pub fn detected_hwaccel_now() -> Option<HwAccel> {
+    DETECTED.get().copied()
+}
+
+pub async fn detected_hwaccel() -> HwAccel {
+    *DETECTED.get_or_init(detect).await
+}
+
+fn candidates() -> &'static [HwAccel] {
+    if cfg!(target_os = "macos") {
+        &[HwAccel::Videotoolbox]
+    } else if cfg!(windows) {
+        &[HwAccel::Cuda, HwAccel::D3d11va, HwAccel::Qsv]
+    } else {
+        &[HwAccel::Cuda, HwAccel::Vaapi, HwAccel::Qsv]
+    }
+}
+
+async fn detect() -> HwAccel {
+    let Ok(ffmpeg) = ffmpeg_path() else {
+        return HwAccel::None;
+    };
+
+    let listed = match Command::new(&ffmpeg)
+        .args(["-hide_banner", "-hwaccels"])
+        .stdin(Stdio::null())
+        .output()
+        .await
+    {
+        Ok(output) => String::from_utf8_lossy(&output.stdout).into_owned(),
+        Err(error) => {
+            warn!("failed to list hwaccels: {error}");
+            return HwAccel::None;
+        }
+    };
+    let listed = listed
+        .lines()
+        .skip(1)
+        .filter_map(HwAccel::parse)
+        .collect::<Vec<_>>();
+
+    let clip = std::env::temp_dir().join(format!("framescript-hwprobe-{}.mp4", std::process::id()));
+    let clip_path = clip.to_string_lossy().into_owned();
+    let generated = run_quiet(
+        &ffmpeg,
+        &[
+            "-f",
+            "lavfi",
+            "-i",
+            "testsrc=size=256x256:rate=30:duration=0.2",
+            "-pix_fmt",
+            "yuv420p",
+            "-y",
+            &clip_path,
+        ],
+    )
+    .await;
+
+    let mut detected = HwAccel::None;
+    if generated {
+        for candidate in candidates().iter().filter(|c| listed.contains(c)) {
+            let mut args = vec!["-nostdin".to_string()];
+            args.extend(candidate.input_args());
+            args.extend(["-i", &clip_path, "-frames:v", "1", "-f", "null", "-"].map(String::from));
+            let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+            if run_quiet(&ffmpeg, &args).await {
+                detected = *candidate;
+                break;
+            }
+        }
+    } else {
+        warn!("failed to generate hwaccel probe clip");
+    }
+    let _ = tokio::fs::remove_file(&clip).await;
+
+    info!(
+        listed = ?listed,
+        "hwaccel detection picked {}",
+        detected.as_str()
+    );
+    detected
+}
+
+/// A broken driver can hang the test decode, so it is given `PROBE_TIMEOUT`.
+async fn run_quiet(ffmpeg: &str, args: &[&str]) -> bool {
+    let status = Command::new(ffmpeg)
+        .args(["-hide_banner", "-loglevel", "error"])
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .status();
+    matches!(
+        tokio::time::timeout(*PROBE_TIMEOUT, status).await,
+        Ok(Ok(status)) if status.success()
+    )
+}
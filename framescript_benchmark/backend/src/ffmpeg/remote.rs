@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::ffmpeg::command::STALL_TIMEOUT;
+use crate::util::is_remote_url;
+
+/// Input options for an `http(s)://` source: reconnect on dropped connections and
+/// give up on a read that stalls for `STALL_TIMEOUT`. Empty for local files.
+pub(crate) fn input_args(path: &str) -> Vec<String> {
+    if !is_remote_url(path) {
+        return Vec::new();
+    }
+    vec![
+        "-reconnect".to_string(),
+        "1".to_string(),
+        "-reconnect_streamed".to_string(),
+        "1".to_string(),
+        "-reconnect_delay_max".to_string(),
+        "5".to_string(),
+        // microseconds
+        "-rw_timeout".to_string(),
+        STALL_TIMEOUT.as_micros().to_string(),
+    ]
+}
+
+/// What probe caches are keyed on besides the path: the ETag (or Last-Modified) of a
+/// URL, the modification time of a local file. Changes when the source does.
+pub(crate) fn source_version(path: &str) -> String {
+    if is_remote_url(path) {
+        return remote_version(path).unwrap_or_default();
+    }
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map(|since_epoch| since_epoch.as_nanos().to_string())
+        .unwrap_or_default()
+}
+
+type VersionCache = HashMap<String, (Instant, Option<String>)>;
+
+/// Versions of URLs looked up recently, so probe cache hits do not each cost a request.
+static REMOTE_VERSIONS: LazyLock<Mutex<VersionCache>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+const REMOTE_VERSIONS_LIMIT: usize = 256;
+
+/// How long a looked-up version is trusted before asking the server again.
+const REMOTE_VERSION_TTL: Duration = Duration::from_secs(30);
+
+/// Shared by every lookup. Only touched from the lookup threads, since a blocking
+/// client must not be built or used on a runtime thread.
+static CLIENT: LazyLock<Option<reqwest::blocking::Client>> = LazyLock::new(|| {
+    reqwest::blocking::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .ok()
+});
+
+fn remote_version(url: &str) -> Option<String> {
+    if let Some((at, version)) = REMOTE_VERSIONS.lock().unwrap().get(url)
+        && at.elapsed() < REMOTE_VERSION_TTL
+    {
+        return version.clone();
+    }
+
+    let owned = url.to_string();
+    // reqwest::blocking must not run on a runtime thread, so it gets its own
+    let version = std::thread::spawn(move || {
+        let response = CLIENT.as_ref()?.head(&owned).send().ok()?;
+        let headers = response.headers();
+        headers
+            .get(reqwest::header::ETAG)
+            .or_else(|| headers.get(reqwest::header::LAST_MODIFIED))
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    })
+    .join()
+    .ok()
+    .flatten();
+
+    let mut versions = REMOTE_VERSIONS.lock().unwrap();
+    if versions.len() >= REMOTE_VERSIONS_LIMIT && !versions.contains_key(url) {
+        versions.retain(|_, (at, _)| at.elapsed() < REMOTE_VERSION_TTL);
+        if versions.len() >= REMOTE_VERSIONS_LIMIT {
+            versions.clear();
+        }
+    }
+    versions.insert(url.to_string(), (Instant::now(), version.clone()));
+    version
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    /// Answers every request with `ETag: "v1"` and counts them.
+    fn etag_server() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/clip.mp4", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { break };
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                while reader.read_line(&mut line).is_ok_and(|read| read > 2) {
+                    line.clear();
+                }
+                counter.fetch_add(1, Ordering::SeqCst);
+                let _ = stream.write_all(
+                    b"HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                );
+            }
+        });
+        (url, requests)
+    }
+
+    #[test]
+    fn remote_versions_are_cached_between_lookups() {
+        let (url, requests) = etag_server();
+
+        assert_eq!(source_version(&url), "\"v1\"");
+        assert_eq!(source_version(&url), "\"v1\"");
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        // an expired entry is looked up again
+        REMOTE_VERSIONS.lock().unwrap().get_mut(&url).unwrap().0 -= REMOTE_VERSION_TTL;
+        assert_eq!(source_version(&url), "\"v1\"");
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,77 @@
+use std::sync::LazyLock;
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+/// Scaler used when resizing decoded frames to the requested size.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScaleQuality {
+    /// bilinear
+    Fast,
+    /// bicubic
+    #[default]
+    Good,
+    /// lanczos
+    Best,
+}
+
+impl ScaleQuality {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "fast" | "bilinear" => Some(Self::Fast),
+            "good" | "bicubic" => Some(Self::Good),
+            "best" | "lanczos" => Some(Self::Best),
+            _ => None,
+        }
+    }
+
+    /// swscale flags for the `scale` filter.
+    pub fn flags(self) -> &'static str {
+        match self {
+            Self::Fast => "bilinear",
+            Self::Good => "bicubic",
+            Self::Best => "lanczos",
+        }
+    }
+}
+
+/// Default for interactive preview from `FRAMESCRIPT_SCALE_QUALITY` (default `good`).
+/// Thumbnail-style requests should ask for `fast` explicitly.
+pub static SCALE_QUALITY: LazyLock<ScaleQuality> = LazyLock::new(|| {
+    let Ok(value) = std::env::var("FRAMESCRIPT_SCALE_QUALITY") else {
+        return ScaleQuality::default();
+    };
+    ScaleQuality::parse(&value).unwrap_or_else(|| {
+        warn!("unknown FRAMESCRIPT_SCALE_QUALITY={value:?}, using good");
+        ScaleQuality::default()
+    })
+});
+
+/// How much proxy frames are shrunk in each dimension (`FRAMESCRIPT_PROXY_SCALE`:
+/// `half`, `quarter` or a divisor; default `half`).
+pub static PROXY_DIVISOR: LazyLock<u32> = LazyLock::new(|| {
+    let Ok(value) = std::env::var("FRAMESCRIPT_PROXY_SCALE") else {
+        return 2;
+    };
+    match value.trim().to_ascii_lowercase().as_str() {
+        "half" => 2,
+        "quarter" => 4,
+        other => other
+            .parse::<u32>()
+            .ok()
+            .filter(|divisor| *divisor > 0)
+            .unwrap_or_else(|| {
+                warn!("unknown FRAMESCRIPT_PROXY_SCALE={value:?}, using half");
+                2
+            }),
+    }
+});
+
+/// Decode size for a proxy of a `width`×`height` request.
+pub fn proxy_size(width: u32, height: u32) -> (u32, u32) {
+    (
+        (width / *PROXY_DIVISOR).max(1),
+        (height / *PROXY_DIVISOR).max(1),
+    )
+}
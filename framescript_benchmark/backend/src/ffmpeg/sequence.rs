@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::LazyLock;
+
+use crate::util::is_remote_url;
+
+/// Frame rate assumed for image sequences and still images when the caller gives none
+/// (`FRAMESCRIPT_SEQUENCE_FPS`, default 30).
+pub static SEQUENCE_FPS: LazyLock<f64> = LazyLock::new(|| {
+    std::env::var("FRAMESCRIPT_SEQUENCE_FPS")
+        .ok()
+        .and_then(|value| value.trim().parse::<f64>().ok())
+        .filter(|fps| fps.is_finite() && *fps > 0.0)
+        .unwrap_or(30.0)
+});
+
+/// Duration reported for a still image (`FRAMESCRIPT_STILL_DURATION_MS`, default 0).
+pub static STILL_DURATION_MS: LazyLock<u64> = LazyLock::new(|| {
+    std::env::var("FRAMESCRIPT_STILL_DURATION_MS")
+        .ok()
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .unwrap_or(0)
+});
+
+const IMAGE_EXTENSIONS: &[&str] = &[
+    "png", "jpg", "jpeg", "exr", "tif", "tiff", "bmp", "webp", "dpx", "tga",
+];
+
+/// Numbered images read through ffmpeg's image2 demuxer. Frame `n` of the source
+/// is the file numbered `start_number + n`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageSequence {
+    /// printf-style input for ffmpeg, e.g. `/shots/shot_%04d.png`.
+    pub pattern: String,
+    pub start_number: u64,
+    /// Contiguous files from `start_number`; image2 stops at the first gap.
+    pub frames: u64,
+}
+
+impl ImageSequence {
+    /// Input arguments reading the sequence from `first_frame` at `fps`.
+    pub fn input_args(&self, first_frame: usize, fps: f64) -> Vec<String> {
+        vec![
+            "-f".to_string(),
+            "image2".to_string(),
+            "-framerate".to_string(),
+            format!("{fps}"),
+            "-start_number".to_string(),
+            (self.start_number + first_frame as u64).to_string(),
+            "-i".to_string(),
+            self.pattern.clone(),
+        ]
+    }
+
+    pub fn duration(&self, fps: f64) -> f64 {
+        self.frames as f64 / fps
+    }
+}
+
+/// A single image file, served as a one-frame video whatever frame is asked for.
+pub fn is_still_image(path: &str) -> bool {
+    if is_remote_url(path) {
+        return false;
+    }
+    let path = Path::new(path);
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            IMAGE_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str())
+        })
+        && path.is_file()
+}
+
+/// `path` as an image sequence: a printf pattern (`shot_%04d.png`), a glob with a
+/// single `*` (`shot_*.png`), or a directory of numbered images. `None` for anything
+/// else, including patterns that match no files.
+pub fn image_sequence(path: &str) -> Option<ImageSequence> {
+    if is_remote_url(path) {
+        return None;
+    }
+    let path = Path::new(path);
+    if path.is_dir() {
+        return directory_sequence(path);
+    }
+
+    let name = path.file_name()?.to_str()?;
+    let (prefix, width, suffix) = parse_pattern(name)?;
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty())?;
+    let numbers = numbered_files(dir, prefix, suffix, width);
+    let width = width.unwrap_or_else(|| padding_width(&numbers));
+    build_sequence(dir, prefix, width, suffix, numbers)
+}
+
+/// Split a file name around `%d`, `%0Nd` or `*` into prefix, zero-pad width and suffix.
+fn parse_pattern(name: &str) -> Option<(&str, Option<usize>, &str)> {
+    if let Some(star) = name.find('*') {
+        let (prefix, suffix) = (&name[..star], &name[star + 1..]);
+        return (!suffix.contains('*')).then_some((prefix, None, suffix));
+    }
+
+    let percent = name.find('%')?;
+    let rest = &name[percent + 1..];
+    let d = rest.find('d')?;
+    let spec = &rest[..d];
+    if !spec.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let width = if spec.is_empty() {
+        0
+    } else {
+        spec.parse::<usize>().ok()?
+    };
+    Some((&name[..percent], Some(width), &rest[d + 1..]))
+}
+
+/// Numbers of the files in `dir` named `prefix<digits>suffix`.
+fn numbered_files(
+    dir: &Path,
+    prefix: &str,
+    suffix: &str,
+    width: Option<usize>,
+) -> Vec<(u64, usize)> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+
+    entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            let digits = name.strip_prefix(prefix)?.strip_suffix(suffix)?;
+            if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+                return None;
+            }
+            // %04d matches 0001 and 12345 but not 001
+            if width.is_some_and(|width| digits.len() < width) {
+                return None;
+            }
+            Some((digits.parse::<u64>().ok()?, digits.len()))
+        })
+        .collect()
+}
+
+/// Zero-pad width of the files: the shortest name when any number is padded
+/// (`0001` … `12345` is `%04d`), otherwise 0 for plain `%d`.
+fn padding_width(numbers: &[(u64, usize)]) -> usize {
+    let padded = numbers
+        .iter()
+        .any(|(number, len)| number.to_string().len() < *len);
+    if padded {
+        numbers.iter().map(|(_, len)| *len).min().unwrap_or(0)
+    } else {
+        0
+    }
+}
+
+/// The largest group of `<prefix><digits>.<image extension>` files in `dir`.
+fn directory_sequence(dir: &Path) -> Option<ImageSequence> {
+    let entries = std::fs::read_dir(dir).ok()?;
+    let mut groups: HashMap<(String, String), Vec<(u64, usize)>> = HashMap::new();
+    for entry in entries.flatten() {
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        let Some((stem, extension)) = name.rsplit_once('.') else {
+            continue;
+        };
+        if !IMAGE_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str()) {
+            continue;
+        }
+        let prefix = stem.trim_end_matches(|c: char| c.is_ascii_digit());
+        let digits = &stem[prefix.len()..];
+        let Ok(number) = digits.parse::<u64>() else {
+            continue;
+        };
+        groups
+            .entry((prefix.to_string(), format!(".{extension}")))
+            .or_default()
+            .push((number, digits.len()));
+    }
+
+    let ((prefix, suffix), numbers) = groups
+        .into_iter()
+        .max_by(|(a_key, a), (b_key, b)| a.len().cmp(&b.len()).then_with(|| b_key.cmp(a_key)))?;
+    let width = padding_width(&numbers);
+    build_sequence(dir, &prefix, width, &suffix, numbers)
+}
+
+fn build_sequence(
+    dir: &Path,
+    prefix: &str,
+    width: usize,
+    suffix: &str,
+    numbers: Vec<(u64, usize)>,
+) -> Option<ImageSequence> {
+    let mut numbers = numbers
+        .into_iter()
+        .map(|(number, _)| number)
+        .collect::<Vec<_>>();
+    numbers.sort_unstable();
+    numbers.dedup();
+
+    let start_number = *numbers.first()?;
+    let frames = numbers
+        .iter()
+        .zip(start_number..)
+        .take_while(|(number, expected)| **number == *expected)
+        .count() as u64;
+
+    let spec = if width > 1 {
+        format!("%0{width}d")
+    } else {
+        "%d".to_string()
+    };
+    // ffmpeg treats a literal % in the name as the start of a pattern
+    let escape = |part: &str| part.replace('%', "%%");
+    let pattern = dir.join(format!("{}{spec}{}", escape(prefix), escape(suffix)));
+
+    Some(ImageSequence {
+        pattern: pattern.to_string_lossy().into_owned(),
+        start_number,
+        frames,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffmpeg::{fixtures, probe_media_info_with_fps};
+
+    fn path(dir: &Path, name: &str) -> String {
+        dir.join(name).to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn patterns_globs_and_directories_find_the_same_sequence() {
+        let dir = tempfile::tempdir().unwrap();
+        for number in (1..=50).chain([52]) {
+            std::fs::write(dir.path().join(format!("shot_{number:04}.png")), []).unwrap();
+        }
+        std::fs::write(dir.path().join("notes.txt"), []).unwrap();
+
+        let expected = ImageSequence {
+            pattern: path(dir.path(), "shot_%04d.png"),
+            start_number: 1,
+            // image2 stops at the gap before 0052
+            frames: 50,
+        };
+        for source in ["shot_%04d.png", "shot_*.png", ""] {
+            assert_eq!(
+                image_sequence(&path(dir.path(), source)),
+                Some(expected.clone()),
+                "{source:?}"
+            );
+        }
+        assert_eq!(image_sequence(&path(dir.path(), "take_%04d.png")), None);
+        assert!(is_still_image(&path(dir.path(), "shot_0001.png")));
+    }
+
+    #[tokio::test]
+    async fn a_png_sequence_probes_and_decodes_frame_by_frame() {
+        let Some(shots) = fixtures::generate(
+            "shot_%04d.png",
+            &[
+                "-f",
+                "lavfi",
+                "-i",
+                "testsrc2=size=64x36:rate=30",
+                "-frames:v",
+                "50",
+                "-start_number",
+                "1",
+            ],
+        ) else {
+            return;
+        };
+
+        let video = probe_media_info_with_fps(&shots.path, Some(25.0))
+            .unwrap()
+            .video
+            .unwrap();
+        assert_eq!(video.frames.unwrap().frames, 50);
+        assert_eq!(video.duration, Some(2.0));
+
+        let options = fixtures::options(64, 36);
+        let all = fixtures::extract(&shots.path, 0, 50, options)
+            .await
+            .unwrap();
+        assert_eq!(
+            all.iter().map(|(index, _)| *index).collect::<Vec<_>>(),
+            (0..50).collect::<Vec<_>>()
+        );
+        // a window starts at the right file and stops at the last one
+        let tail = fixtures::extract(&shots.path, 45, 60, options)
+            .await
+            .unwrap();
+        assert_eq!(tail, all[45..]);
+    }
+}
@@ -0,0 +1,78 @@
+use std::process::Stdio;
+use std::sync::LazyLock;
+
+use tokio::process::Command;
+use tokio::sync::OnceCell;
+use tracing::{info, warn};
+
+use crate::ffmpeg::bin::ffmpeg_path;
+
+/// Whether HDR sources are tone-mapped to SDR (`FRAMESCRIPT_TONEMAP`, default on).
+pub static TONEMAP: LazyLock<bool> = LazyLock::new(|| {
+    !matches!(
+        std::env::var("FRAMESCRIPT_TONEMAP")
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase()
+            .as_str(),
+        "0" | "false" | "off" | "no"
+    )
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TonemapFilter {
+    Zscale,
+    Libplacebo,
+    Unavailable,
+}
+
+static FILTER: OnceCell<TonemapFilter> = OnceCell::const_new();
+
+async fn detect_filter() -> TonemapFilter {
+    let Ok(ffmpeg) = ffmpeg_path() else {
+        return TonemapFilter::Unavailable;
+    };
+    let listed = match Command::new(ffmpeg)
+        .args(["-hide_banner", "-filters"])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .await
+    {
+        Ok(output) => String::from_utf8_lossy(&output.stdout).into_owned(),
+        Err(error) => {
+            warn!("failed to list ffmpeg filters: {error}");
+            return TonemapFilter::Unavailable;
+        }
+    };
+    let has = |name: &str| {
+        listed
+            .lines()
+            .any(|line| line.split_whitespace().nth(1) == Some(name))
+    };
+
+    let filter = if has("zscale") && has("tonemap") {
+        TonemapFilter::Zscale
+    } else if has("libplacebo") {
+        TonemapFilter::Libplacebo
+    } else {
+        warn!("neither zscale nor libplacebo is available, HDR sources will not be tone-mapped");
+        TonemapFilter::Unavailable
+    };
+    info!("HDR tone mapping via {filter:?}");
+    filter
+}
+
+/// Filters converting a PQ/HLG BT.2020 signal to SDR BT.709, if ffmpeg has them.
+pub(crate) async fn tonemap_filter() -> Option<&'static str> {
+    match *FILTER.get_or_init(detect_filter).await {
+        TonemapFilter::Zscale => Some(
+            "zscale=t=linear:npl=100,format=gbrpf32le,zscale=p=bt709,\
+             tonemap=tonemap=hable:desat=0,zscale=t=bt709:m=bt709:r=tv,format=yuv420p",
+        ),
+        TonemapFilter::Libplacebo => Some(
+            "libplacebo=tonemapping=hable:colorspace=bt709:color_primaries=bt709:color_trc=bt709",
+        ),
+        TonemapFilter::Unavailable => None,
+    }
+}
@@ -0,0 +1,93 @@
+//! ffmpeg release numbers. The render crate includes this file as well, so both
+//! binaries agree on parsing and on the minimum supported version.
+
+/// `major.minor.patch` parsed from the first line of `ffmpeg -version`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FfmpegVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl FfmpegVersion {
+    /// Accepts `ffmpeg version 6.1.1-3ubuntu5 ...` and `n6.0`-style tags. Git snapshot
+    /// builds (`N-112233-g...`) carry no release number and yield `None`.
+    pub fn parse(output: &str) -> Option<Self> {
+        let version = output
+            .lines()
+            .next()?
+            .split_whitespace()
+            .skip_while(|word| *word != "version")
+            .nth(1)?;
+        Self::parse_number(version.trim_start_matches('n'))
+    }
+
+    fn parse_number(value: &str) -> Option<Self> {
+        let numeric = value
+            .split(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .next()?;
+        let mut parts = numeric.split('.').map(|part| part.parse::<u32>().ok());
+        Some(Self {
+            major: parts.next()??,
+            minor: parts.next().flatten().unwrap_or(0),
+            patch: parts.next().flatten().unwrap_or(0),
+        })
+    }
+
+    /// Oldest supported ffmpeg (`FRAMESCRIPT_FFMPEG_MIN_VERSION`, default 4.3).
+    pub fn minimum() -> Self {
+        std::env::var("FRAMESCRIPT_FFMPEG_MIN_VERSION")
+            .ok()
+            .and_then(|value| Self::parse_number(value.trim()))
+            .unwrap_or(Self {
+                major: 4,
+                minor: 3,
+                patch: 0,
+            })
+    }
+}
+
+impl std::fmt::Display for FfmpegVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(major: u32, minor: u32, patch: u32) -> FfmpegVersion {
+        FfmpegVersion {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    #[test]
+    fn release_and_distro_versions_parse() {
+        let parse = |line: &str| FfmpegVersion::parse(&format!("{line}\nbuilt with gcc 13"));
+        assert_eq!(
+            parse("ffmpeg version 6.1.1-3ubuntu5 Copyright (c) 2000-2023"),
+            Some(version(6, 1, 1))
+        );
+        assert_eq!(
+            parse("ffmpeg version n6.0 Copyright"),
+            Some(version(6, 0, 0))
+        );
+        assert_eq!(
+            parse("ffmpeg version 4.3 Copyright"),
+            Some(version(4, 3, 0))
+        );
+        assert_eq!(parse("ffmpeg version N-112233-gabcdef Copyright"), None);
+        assert_eq!(FfmpegVersion::parse(""), None);
+    }
+
+    #[test]
+    fn versions_order_numerically() {
+        assert!(version(4, 2, 9) < version(4, 3, 0));
+        assert!(version(4, 10, 0) > version(4, 9, 0));
+        assert_eq!(version(7, 0, 1).to_string(), "7.0.1");
+    }
+}
@@ -5,9 +5,11 @@ use std::{
 
 use manual_future::{ManualFuture, ManualFutureCompleter};
 
+type SharedState<T> = (Option<Arc<T>>, Vec<ManualFutureCompleter<Arc<T>>>);
+
 #[derive(Debug)]
 pub struct SharedManualFuture<T: Send> {
-    value: Arc<Mutex<(Option<Arc<T>>, Vec<ManualFutureCompleter<Arc<T>>>)>>,
+    value: Arc<Mutex<SharedState<T>>>,
 }
 
 impl<T: Send> SharedManualFuture<T> {
@@ -44,12 +46,13 @@ impl<T: Send> SharedManualFuture<T> {
         }
     }
 
-    pub async fn complete(&self, complete_value: Arc<T>) {
+    /// Completes the future. Returns `false` if it was already completed.
+    pub async fn complete(&self, complete_value: Arc<T>) -> bool {
         let (arc_complete_value, completers) = {
             let mut value = self.value.lock().unwrap();
 
             if value.0.is_some() {
-                return;
+                return false;
             }
 
             value.0 = Some(complete_value.clone());
@@ -63,6 +66,14 @@ impl<T: Send> SharedManualFuture<T> {
         for completer in completers {
             completer.complete(arc_complete_value.clone()).await;
         }
+
+        true
+    }
+}
+
+impl<T: Send> Default for SharedManualFuture<T> {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
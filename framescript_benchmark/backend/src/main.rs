@@ -3,7 +3,12 @@ pub mod ffmpeg;
 pub mod future;
 pub mod util;
 
-use std::{net::SocketAddr, ops::Bound, sync::atomic::AtomicBool};
+use std::{
+    net::SocketAddr,
+    ops::Bound,
+    sync::atomic::AtomicBool,
+    time::{Duration, Instant},
+};
 
 use axum::{
     Router,
@@ -13,7 +18,7 @@ use axum::{
         ws::{Message, WebSocket, WebSocketUpgrade},
     },
     http::{HeaderMap, HeaderValue, StatusCode, header},
-    response::{IntoResponse, Json},
+    response::{IntoResponse, Json, Redirect, Response},
     routing::{get, post},
     serve,
 };
@@ -24,17 +29,46 @@ use std::sync::atomic::{AtomicUsize, Ordering};
 use tokio::io::{AsyncReadExt, AsyncSeekExt, SeekFrom};
 use tokio::net::TcpListener;
 use tokio_util::io::ReaderStream;
-use tracing::{error, info};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
 
 use crate::{
-    decoder::{DECODER, DecoderKey, set_max_cache_size},
-    ffmpeg::{probe_audio_duration_ms, probe_video_duration_ms, probe_video_fps},
-    util::resolve_path_to_string,
+    decoder::{
+        ClearOutcome, DECODER, DecoderKey, DecoderMetricsSnapshot, DecoderStats,
+        get_cache_usage, set_max_cache_size, spawn_cache_maintenance,
+    },
+    ffmpeg::{
+        DecodeOptions,
+        bin::{check_ffmpeg_version, ffmpeg_version, min_ffmpeg_version},
+        command::extract_frames_select_rgba,
+        hw_decoder::{DecodeBackend, force_software, forget_hw_failures, software_forced},
+        hwaccel::{HWACCEL, HWACCEL_DEVICE, HwAccel, detected_hwaccel, detected_hwaccel_now},
+        ProbeError, probe_audio_duration_ms, probe_media_info_with_fps,
+        scale::{SCALE_QUALITY, ScaleQuality, proxy_size},
+        tonemap::TONEMAP,
+    },
+    util::{is_remote_url, resolve_path_to_string},
 };
 
 #[derive(Deserialize)]
 struct VideoQuery {
     path: String,
+    /// Frame rate of an image sequence (`shot_%04d.png`, `shot_*.png` or a directory)
+    /// or still image.
+    #[serde(default)]
+    fps: Option<f64>,
+}
+
+#[derive(Deserialize)]
+struct ThumbnailQuery {
+    path: String,
+    width: u32,
+    height: u32,
+    /// Comma-separated frame indices, e.g. `0,431,862`.
+    frames: String,
+    /// Scaler; defaults to `fast`.
+    #[serde(default)]
+    quality: Option<ScaleQuality>,
 }
 
 #[derive(Deserialize)]
@@ -51,6 +85,16 @@ struct FrameRequest {
     width: u32,
     height: u32,
     frame: u32,
+    /// Overrides `FRAMESCRIPT_HWACCEL` for this request (debugging).
+    #[serde(default)]
+    hwaccel: Option<HwAccel>,
+    /// Scaler; `fast` for thumbnails, defaults to `FRAMESCRIPT_SCALE_QUALITY`.
+    #[serde(default)]
+    quality: Option<ScaleQuality>,
+    /// Decode at `FRAMESCRIPT_PROXY_SCALE` of the size for fast scrubbing. The reply
+    /// header carries the actual size; the client scales it up.
+    #[serde(default)]
+    proxy: bool,
 }
 
 #[derive(Deserialize)]
@@ -62,12 +106,55 @@ struct CacheSizeRequest {
 struct ProgressRequest {
     completed: Option<usize>,
     total: Option<usize>,
+    canceled: Option<bool>,
+    /// Sent with `frames_per_second` and `eta_seconds` by renderers that report
+    /// stages; older ones and the UI leave all three out.
+    stage: Option<String>,
+    frames_per_second: Option<f64>,
+    eta_seconds: Option<f64>,
+    /// Milliseconds since the renderer started, sent with every update.
+    heartbeat_ms: Option<u64>,
+    /// Id of the `--jobs` entry the update is for.
+    job: Option<String>,
+    /// Percent done of the concat or mux, from ffmpeg's own progress.
+    stage_percent: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct CacheStatsResponse {
+    accounted_bytes: usize,
+    recomputed_bytes: usize,
+    max_bytes: usize,
+    cached_frames: usize,
+    totals: DecoderMetricsSnapshot,
+    decoders: Vec<DecoderStats>,
 }
 
 #[derive(Serialize)]
 struct ProgressResponse {
     completed: usize,
     total: usize,
+    /// The render stopped after a cancel and produced no output.
+    canceled: bool,
+    #[serde(flatten)]
+    stage: RenderStage,
+    heartbeat_ms: Option<u64>,
+    /// A render is running but its renderer has not sent an update for
+    /// `FRAMESCRIPT_RENDER_STALL_SECS`; it has most likely been killed.
+    stalled: bool,
+}
+
+/// rendering, encoding, concat, mux or finalizing, with the frame rate and ETA
+/// measured by the renderer and the job it is rendering, if it was given a job
+/// list. Fields are null until a renderer reports them; `stage_percent` only
+/// during concat and mux.
+#[derive(Serialize, Clone, Default)]
+struct RenderStage {
+    stage: Option<String>,
+    frames_per_second: Option<f64>,
+    eta_seconds: Option<f64>,
+    job: Option<String>,
+    stage_percent: Option<f64>,
 }
 
 #[derive(Deserialize, Clone)]
@@ -87,12 +174,46 @@ struct AudioSegment {
     source_start_frame: i64,
     #[serde(rename = "durationFrames")]
     duration_frames: i64,
+    #[serde(default)]
+    gain: Option<f64>,
+    #[serde(rename = "fadeInFrames", default)]
+    fade_in_frames: Option<i64>,
+    #[serde(rename = "fadeOutFrames", default)]
+    fade_out_frames: Option<i64>,
+    #[serde(rename = "playbackRate", default)]
+    playback_rate: Option<f64>,
 }
 
 #[derive(Deserialize, Clone)]
 struct AudioPlanRequest {
     fps: f64,
     segments: Vec<AudioSegment>,
+    #[serde(default)]
+    mastering: Option<AudioMastering>,
+    #[serde(rename = "loudnessTarget", default)]
+    loudness_target: Option<f64>,
+    #[serde(rename = "channelLayout", default)]
+    channel_layout: Option<ChannelLayout>,
+}
+
+/// Stage the renderer puts on the final audio mix.
+#[derive(Deserialize, Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum AudioMastering {
+    Off,
+    Limiter,
+    Loudnorm,
+}
+
+/// Channels of the renderer's audio mix.
+#[derive(Deserialize, Serialize, Clone, Copy)]
+enum ChannelLayout {
+    #[serde(rename = "mono")]
+    Mono,
+    #[serde(rename = "stereo")]
+    Stereo,
+    #[serde(rename = "5.1")]
+    Surround51,
 }
 
 #[derive(Serialize, Clone)]
@@ -112,29 +233,81 @@ struct AudioSegmentResolved {
     source_start_frame: i64,
     #[serde(rename = "durationFrames")]
     duration_frames: i64,
+    /// Left out when the segment plays at its own volume.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gain: Option<f64>,
+    /// Clamped to `durationFrames`; left out when there is no fade.
+    #[serde(rename = "fadeInFrames", skip_serializing_if = "Option::is_none")]
+    fade_in_frames: Option<i64>,
+    #[serde(rename = "fadeOutFrames", skip_serializing_if = "Option::is_none")]
+    fade_out_frames: Option<i64>,
+    /// Left out at normal speed.
+    #[serde(rename = "playbackRate", skip_serializing_if = "Option::is_none")]
+    playback_rate: Option<f64>,
 }
 
 #[derive(Serialize, Clone)]
 struct AudioPlanResolved {
     fps: f64,
     segments: Vec<AudioSegmentResolved>,
+    /// Left out to use the renderer's default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mastering: Option<AudioMastering>,
+    /// LUFS for `loudnorm`, within what it accepts (-70 to -5).
+    #[serde(rename = "loudnessTarget", skip_serializing_if = "Option::is_none")]
+    loudness_target: Option<f64>,
+    /// Left out to mix in stereo, the renderer's default.
+    #[serde(rename = "channelLayout", skip_serializing_if = "Option::is_none")]
+    channel_layout: Option<ChannelLayout>,
 }
 
-static RENDER_AUDIO_PLAN: std::sync::LazyLock<std::sync::Mutex<Option<AudioPlanResolved>>> =
+/// Bits of the `flags` word in ws frame packets.
+/// The frame was decoded in hardware; unset means software.
+const FRAME_FLAG_HARDWARE: u32 = 1;
+/// The decoder never produced the frame; this is the wait policy's fallback.
+const FRAME_FLAG_FALLBACK: u32 = 1 << 1;
+/// Software decoding is forced through `/decode_backend`.
+const FRAME_FLAG_FORCE_SOFTWARE: u32 = 1 << 2;
+
+type SharedAudioPlan = std::sync::Mutex<Option<AudioPlanResolved>>;
+
+static RENDER_AUDIO_PLAN: std::sync::LazyLock<SharedAudioPlan> =
     std::sync::LazyLock::new(|| std::sync::Mutex::new(None));
 
 static RENDER_COMPLETED: AtomicUsize = AtomicUsize::new(0);
 static RENDER_TOTAL: AtomicUsize = AtomicUsize::new(0);
 static RENDER_CANCEL: AtomicBool = AtomicBool::new(false);
+/// Reported by the renderer once a canceled render has cleaned up and exited.
+static RENDER_CANCEL_FINALIZED: AtomicBool = AtomicBool::new(false);
+static RENDER_STAGE: std::sync::LazyLock<std::sync::Mutex<RenderStage>> =
+    std::sync::LazyLock::new(Default::default);
+/// When the last renderer heartbeat arrived and its value; `None` outside a render.
+static RENDER_HEARTBEAT: std::sync::Mutex<Option<(Instant, u64)>> = std::sync::Mutex::new(None);
+
+/// Silence after which a running render counts as stalled
+/// (`FRAMESCRIPT_RENDER_STALL_SECS`, default 15).
+static RENDER_STALL_AFTER: std::sync::LazyLock<Duration> = std::sync::LazyLock::new(|| {
+    let secs = std::env::var("FRAMESCRIPT_RENDER_STALL_SECS")
+        .ok()
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .filter(|secs| *secs > 0)
+        .unwrap_or(15);
+    Duration::from_secs(secs)
+});
 
 #[tokio::main]
 async fn main() {
-    unsafe {
-        std::env::set_var("LIBVA_DRIVER_NAME", "radeonsi");
-    };
-
     tracing_subscriber::fmt::init();
 
+    spawn_cache_maintenance();
+    if let Err(error) = check_ffmpeg_version() {
+        warn!("{error}");
+    }
+    if *HWACCEL == HwAccel::Auto {
+        // 最初のリクエストで待たされないよう先に検出しておく
+        tokio::spawn(detected_hwaccel());
+    }
+
     let app_state = AppState;
     let app = Router::new()
         .route("/ws", get(ws_handler))
@@ -143,6 +316,10 @@ async fn main() {
             "/video/meta",
             get(video_meta_handler).options(options_handler),
         )
+        .route(
+            "/video/thumbnails",
+            get(thumbnails_handler).options(options_handler),
+        )
         .route("/audio", get(audio_handler).options(options_handler))
         .route(
             "/audio/meta",
@@ -152,6 +329,10 @@ async fn main() {
             "/set_cache_size",
             post(set_cache_size_handler).options(options_handler),
         )
+        .route(
+            "/cache_stats",
+            get(cache_stats_handler).options(options_handler),
+        )
         .route(
             "/render_progress",
             post(set_progress_handler)
@@ -173,6 +354,12 @@ async fn main() {
             "/is_canceled",
             get(is_canceled_handler).options(options_handler),
         )
+        .route(
+            "/decode_backend",
+            post(set_decode_backend_handler)
+                .get(get_decode_backend_handler)
+                .options(options_handler),
+        )
         .route("/healthz", get(healthz_handler).options(options_handler))
         .with_state(app_state);
 
@@ -190,10 +377,14 @@ async fn ws_handler(ws: WebSocketUpgrade, State(state): State<AppState>) -> impl
 
 async fn video_handler(
     State(_state): State<AppState>,
-    Query(VideoQuery { path }): Query<VideoQuery>,
+    Query(VideoQuery { path, .. }): Query<VideoQuery>,
     range: Option<TypedHeader<Range>>,
 ) -> Result<impl IntoResponse, StatusCode> {
     let resolved_path = resolve_path_to_string(&path).map_err(|_| StatusCode::BAD_REQUEST)?;
+    // リモートの素材はブラウザに直接取りに行かせる
+    if is_remote_url(&resolved_path) {
+        return Ok(Redirect::temporary(&resolved_path).into_response());
+    }
     let mut file = tokio::fs::File::open(&resolved_path)
         .await
         .map_err(|_| StatusCode::NOT_FOUND)?;
@@ -280,6 +471,10 @@ async fn audio_handler(
     range: Option<TypedHeader<Range>>,
 ) -> Result<impl IntoResponse, StatusCode> {
     let resolved_path = resolve_path_to_string(&path).map_err(|_| StatusCode::BAD_REQUEST)?;
+    // リモートの素材はブラウザに直接取りに行かせる
+    if is_remote_url(&resolved_path) {
+        return Ok(Redirect::temporary(&resolved_path).into_response());
+    }
     let mut file = tokio::fs::File::open(&resolved_path)
         .await
         .map_err(|_| StatusCode::NOT_FOUND)?;
@@ -363,26 +558,136 @@ async fn audio_handler(
 async fn healthz_handler() -> impl IntoResponse {
     let mut headers = HeaderMap::new();
     apply_cors(&mut headers);
-    (headers, StatusCode::OK)
+    (
+        headers,
+        Json(serde_json::json!({
+            "hwaccel": HWACCEL.as_str(),
+            "hwaccel_device": HWACCEL_DEVICE.as_deref(),
+            "hwaccel_detected": detected_hwaccel_now().map(HwAccel::as_str),
+            "force_software": software_forced(),
+            "ffmpeg_version": ffmpeg_version().map(|version| version.to_string()),
+            "ffmpeg_min_version": min_ffmpeg_version().to_string(),
+            "ffmpeg_version_ok": check_ffmpeg_version().is_ok(),
+        })),
+    )
 }
 
 #[derive(Serialize)]
 struct VideoMetadataResponse {
     duration_ms: u64,
     fps: f64,
+    avg_fps: Option<f64>,
+    nominal_fps: Option<f64>,
+    is_vfr: bool,
+    is_hdr: bool,
+    frames: Option<u64>,
+    frames_exact: bool,
+}
+
+/// `{"error": code, "message": ...}` with 422 when the media is at fault
+/// (missing stream, unusable value) and 500 when ffprobe is.
+fn probe_error_response(error: ProbeError) -> Response {
+    let status = if error.is_media_problem() {
+        StatusCode::UNPROCESSABLE_ENTITY
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+    let body = serde_json::json!({
+        "error": error.code(),
+        "message": error.to_string(),
+    });
+    let mut resp = (status, Json(body)).into_response();
+    apply_cors(resp.headers_mut());
+    resp
 }
 
 async fn video_meta_handler(
     State(_state): State<AppState>,
-    Query(VideoQuery { path }): Query<VideoQuery>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let resolved_path = resolve_path_to_string(&path).map_err(|_| StatusCode::BAD_REQUEST)?;
-    let duration_ms =
-        probe_video_duration_ms(&resolved_path).map_err(|_| StatusCode::BAD_REQUEST)?;
+    Query(VideoQuery { path, fps }): Query<VideoQuery>,
+) -> Result<Response, Response> {
+    let resolved_path =
+        resolve_path_to_string(&path).map_err(|_| StatusCode::BAD_REQUEST.into_response())?;
+    // one ffprobe run, off the executor
+    let video = tokio::task::spawn_blocking(move || probe_media_info_with_fps(&resolved_path, fps))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?
+        .and_then(|info| info.video.ok_or(ProbeError::NoSuchStream("video")))
+        .map_err(probe_error_response)?;
+    let duration_ms = video
+        .duration_ms()
+        .ok_or_else(|| probe_error_response(ProbeError::InvalidValue("duration")))?;
+    let fps_info = video.fps;
+    let fps = fps_info
+        .fps()
+        .ok_or_else(|| probe_error_response(ProbeError::InvalidValue("fps")))?;
+
+    let frame_count = video.frames;
+
+    let mut resp = Json(VideoMetadataResponse {
+        duration_ms,
+        fps,
+        avg_fps: fps_info.avg,
+        nominal_fps: fps_info.nominal,
+        is_vfr: fps_info.is_vfr,
+        is_hdr: video.hdr,
+        frames: frame_count.map(|count| count.frames),
+        frames_exact: frame_count.is_some_and(|count| count.exact),
+    })
+    .into_response();
+    apply_cors(resp.headers_mut());
+    Ok(resp)
+}
+
+/// Frames of a thumbnail strip or sprite sheet, decoded with one ffmpeg run per
+/// batch instead of one per frame. Replies `[width][height][count]` followed by
+/// `[frame_index][rgba...]` for each frame in ascending order; frames past the end
+/// of the video are left out.
+async fn thumbnails_handler(
+    State(_state): State<AppState>,
+    Query(query): Query<ThumbnailQuery>,
+) -> Result<Response, Response> {
+    let resolved_path =
+        resolve_path_to_string(&query.path).map_err(|_| StatusCode::BAD_REQUEST.into_response())?;
+    let indices = query
+        .frames
+        .split(',')
+        .map(|index| index.trim().parse::<usize>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| StatusCode::BAD_REQUEST.into_response())?;
+    if query.width == 0 || query.height == 0 {
+        return Err(StatusCode::BAD_REQUEST.into_response());
+    }
 
-    let fps = probe_video_fps(&resolved_path).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let options = DecodeOptions {
+        width: query.width,
+        height: query.height,
+        hwaccel: HWACCEL.resolve().await,
+        quality: query.quality.unwrap_or(ScaleQuality::Fast),
+        tonemap: *TONEMAP,
+    };
+    let frames =
+        extract_frames_select_rgba(&resolved_path, &indices, options, &CancellationToken::new())
+            .await
+            .map_err(|e| {
+                error!("failed to extract thumbnails: {e}");
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            })?;
+
+    let frame_size = frames.first().map_or(0, |(_, frame)| frame.len());
+    let mut packet = Vec::with_capacity(12 + frames.len() * (4 + frame_size));
+    packet.extend_from_slice(&query.width.to_le_bytes());
+    packet.extend_from_slice(&query.height.to_le_bytes());
+    packet.extend_from_slice(&(frames.len() as u32).to_le_bytes());
+    for (index, frame) in &frames {
+        packet.extend_from_slice(&(*index as u32).to_le_bytes());
+        packet.extend_from_slice(frame);
+    }
 
-    let mut resp = Json(VideoMetadataResponse { duration_ms, fps }).into_response();
+    let mut resp = packet.into_response();
+    resp.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/octet-stream"),
+    );
     apply_cors(resp.headers_mut());
     Ok(resp)
 }
@@ -395,10 +700,10 @@ struct AudioMetadataResponse {
 async fn audio_meta_handler(
     State(_state): State<AppState>,
     Query(AudioQuery { path }): Query<AudioQuery>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let resolved_path = resolve_path_to_string(&path).map_err(|_| StatusCode::BAD_REQUEST)?;
-    let duration_ms =
-        probe_audio_duration_ms(&resolved_path).map_err(|_| StatusCode::BAD_REQUEST)?;
+) -> Result<Response, Response> {
+    let resolved_path =
+        resolve_path_to_string(&path).map_err(|_| StatusCode::BAD_REQUEST.into_response())?;
+    let duration_ms = probe_audio_duration_ms(&resolved_path).map_err(probe_error_response)?;
 
     let mut resp = Json(AudioMetadataResponse { duration_ms }).into_response();
     apply_cors(resp.headers_mut());
@@ -427,9 +732,18 @@ async fn handle_socket(mut socket: WebSocket, _state: AppState) {
                     }
                 };
 
-                let width = req.width;
-                let height = req.height;
+                let (width, height) = if req.proxy {
+                    proxy_size(req.width, req.height)
+                } else {
+                    (req.width, req.height)
+                };
                 let target_frame = req.frame;
+                // プロキシは速度優先なので既定の拡縮も軽いものにする
+                let default_quality = if req.proxy {
+                    ScaleQuality::Fast
+                } else {
+                    *SCALE_QUALITY
+                };
 
                 let path = resolve_path_to_string(&req.video).unwrap_or_default();
 
@@ -438,15 +752,45 @@ async fn handle_socket(mut socket: WebSocket, _state: AppState) {
                         path,
                         width,
                         height,
+                        hwaccel: req.hwaccel.unwrap_or(*HWACCEL).resolve().await,
+                        quality: req.quality.unwrap_or(default_quality),
+                        tonemap: *TONEMAP,
                     })
                     .await;
-                let frame_rgba = decoder.get_frame(target_frame).await;
+                let result = decoder.get_frame_with_backend(target_frame).await;
+                let (frame_rgba, backend) = match result {
+                    Ok(frame) => frame,
+                    Err(e) => {
+                        error!("failed to get frame {target_frame}: {e}");
+                        let reply = serde_json::json!({
+                            "frame": target_frame,
+                            "error": e,
+                            "backend": decoder.backend(),
+                            "force_software": software_forced(),
+                        });
+                        if let Err(e) = socket.send(Message::Text(reply.to_string().into())).await {
+                            error!("failed to send error: {e}");
+                            break;
+                        }
+                        continue;
+                    }
+                };
 
-                // into [width][height][frame_index][rgba...] packet
-                let mut packet = Vec::with_capacity(12 + frame_rgba.len());
+                // into [width][height][frame_index][flags][rgba...] packet
+                let mut flags = 0u32;
+                match backend {
+                    Some(DecodeBackend::Hardware) => flags |= FRAME_FLAG_HARDWARE,
+                    Some(DecodeBackend::Software) => {}
+                    None => flags |= FRAME_FLAG_FALLBACK,
+                }
+                if software_forced() {
+                    flags |= FRAME_FLAG_FORCE_SOFTWARE;
+                }
+                let mut packet = Vec::with_capacity(16 + frame_rgba.len());
                 packet.extend_from_slice(&width.to_le_bytes());
                 packet.extend_from_slice(&height.to_le_bytes());
                 packet.extend_from_slice(&target_frame.to_le_bytes());
+                packet.extend_from_slice(&flags.to_le_bytes());
                 packet.extend_from_slice(&frame_rgba);
 
                 let bytes = Bytes::from(packet);
@@ -484,13 +828,36 @@ async fn set_cache_size_handler(
     let mut headers = HeaderMap::new();
     apply_cors(&mut headers);
 
-    let gib = payload.gib.max(1).min(128); // clamp to a sane range
-    let bytes = gib as usize * 1024 * 1024 * 1024;
+    let gib = payload.gib.clamp(1, 128); // clamp to a sane range
+    let bytes = gib * 1024 * 1024 * 1024;
     set_max_cache_size(bytes);
 
     (headers, StatusCode::OK)
 }
 
+async fn cache_stats_handler(State(_state): State<AppState>) -> impl IntoResponse {
+    let mut headers = HeaderMap::new();
+    apply_cors(&mut headers);
+
+    let (accounted_bytes, max_bytes) = get_cache_usage();
+    let decoders = DECODER.decoder_stats();
+    let mut totals = DecoderMetricsSnapshot::default();
+    for decoder in &decoders {
+        totals.merge(&decoder.metrics);
+    }
+
+    let response = CacheStatsResponse {
+        accounted_bytes,
+        recomputed_bytes: decoders.iter().map(|decoder| decoder.cached_bytes).sum(),
+        max_bytes,
+        cached_frames: decoders.iter().map(|decoder| decoder.cached_frames).sum(),
+        totals,
+        decoders,
+    };
+
+    (headers, Json(response))
+}
+
 async fn set_progress_handler(
     State(_state): State<AppState>,
     Json(payload): Json<ProgressRequest>,
@@ -501,6 +868,21 @@ async fn set_progress_handler(
     if let Some(total) = payload.total {
         RENDER_TOTAL.store(total, Ordering::Relaxed);
     }
+    if let Some(canceled) = payload.canceled {
+        RENDER_CANCEL_FINALIZED.store(canceled, Ordering::Relaxed);
+    }
+    // 古いレンダラーや UI は heartbeat を送らないので停止判定の対象にしない
+    if let Some(heartbeat) = payload.heartbeat_ms {
+        *RENDER_HEARTBEAT.lock().unwrap() = Some((Instant::now(), heartbeat));
+    }
+    // 段階のない更新 (UI の初期化など) は前回のレンダーの段階を消す
+    *RENDER_STAGE.lock().unwrap() = RenderStage {
+        stage: payload.stage,
+        frames_per_second: payload.frames_per_second,
+        eta_seconds: payload.eta_seconds,
+        job: payload.job,
+        stage_percent: payload.stage_percent,
+    };
     if let Some(completed) = payload.completed {
         RENDER_COMPLETED.store(
             completed.min(RENDER_TOTAL.load(Ordering::Relaxed)),
@@ -515,9 +897,14 @@ async fn get_progress_handler(State(_state): State<AppState>) -> impl IntoRespon
     let mut headers = HeaderMap::new();
     apply_cors(&mut headers);
 
+    let heartbeat = *RENDER_HEARTBEAT.lock().unwrap();
     let response = ProgressResponse {
         completed: RENDER_COMPLETED.load(Ordering::Relaxed),
         total: RENDER_TOTAL.load(Ordering::Relaxed),
+        canceled: RENDER_CANCEL_FINALIZED.load(Ordering::Relaxed),
+        stage: RENDER_STAGE.lock().unwrap().clone(),
+        heartbeat_ms: heartbeat.map(|(_, value)| value),
+        stalled: heartbeat.is_some_and(|(at, _)| at.elapsed() > *RENDER_STALL_AFTER),
     };
 
     (headers, Json(response))
@@ -534,16 +921,68 @@ async fn is_canceled_handler(State(_state): State<AppState>) -> impl IntoRespons
     let mut headers = HeaderMap::new();
     apply_cors(&mut headers);
     let canceled = RENDER_CANCEL.load(Ordering::Relaxed);
-    (headers, Json(serde_json::json!({ "canceled": canceled })))
+    let finalized = RENDER_CANCEL_FINALIZED.load(Ordering::Relaxed);
+    (
+        headers,
+        Json(serde_json::json!({ "canceled": canceled, "finalized": finalized })),
+    )
+}
+
+#[derive(Deserialize, Serialize)]
+struct DecodeBackendOverride {
+    /// Decode everything in software, e.g. when a GPU driver corrupts frames.
+    force_software: bool,
+}
+
+async fn get_decode_backend_handler(State(_state): State<AppState>) -> impl IntoResponse {
+    let mut headers = HeaderMap::new();
+    apply_cors(&mut headers);
+    (
+        headers,
+        Json(DecodeBackendOverride {
+            force_software: software_forced(),
+        }),
+    )
+}
+
+async fn set_decode_backend_handler(
+    State(_state): State<AppState>,
+    Json(payload): Json<DecodeBackendOverride>,
+) -> impl IntoResponse {
+    let mut headers = HeaderMap::new();
+    apply_cors(&mut headers);
+    if software_forced() != payload.force_software {
+        force_software(payload.force_software);
+        // 壊れたフレームが残らないよう、切り替え時はデコード済みのものを捨てる
+        DECODER.clear().await;
+    }
+    info!(
+        force_software = payload.force_software,
+        "decode backend override changed"
+    );
+    (headers, Json(payload))
 }
 
 async fn reset_handler(State(_state): State<AppState>) -> impl IntoResponse {
     let mut headers = HeaderMap::new();
     apply_cors(&mut headers);
-    DECODER.clear().await;
+    let outcome = DECODER.clear().await;
+    forget_hw_failures();
     RENDER_CANCEL.store(false, Ordering::Relaxed);
     *RENDER_AUDIO_PLAN.lock().unwrap() = None;
-    (headers, StatusCode::OK)
+    // レンダラーは終了時に reset を呼ぶので、以降は停止扱いにしない
+    *RENDER_HEARTBEAT.lock().unwrap() = None;
+
+    let abandoned = match &outcome {
+        ClearOutcome::Clean => 0,
+        ClearOutcome::Forced { abandoned } => abandoned.len(),
+    };
+    let body = serde_json::json!({
+        "clean": matches!(outcome, ClearOutcome::Clean),
+        "abandoned": abandoned,
+    });
+
+    (headers, Json(body))
 }
 
 async fn set_audio_plan_handler(
@@ -593,22 +1032,49 @@ async fn set_audio_plan_handler(
         };
         let source_total_frames =
             ((source_duration_ms as f64 / 1000.0) * fps).round().max(0.0) as i64;
+        let playback_rate = seg
+            .playback_rate
+            .filter(|rate| rate.is_finite() && *rate > 0.0 && *rate != 1.0);
+        // 速度を変えたセグメントは 1 フレームにつき rate フレーム分のソースを使う
         let available = (source_total_frames - source_start_frame).max(0);
+        let available = (available as f64 / playback_rate.unwrap_or(1.0)).floor() as i64;
         let duration_frames = duration_frames.min(available);
         if duration_frames == 0 {
             continue;
         }
 
+        // フェードは切り詰めた後の長さに収める
+        let fade = |frames: Option<i64>| {
+            frames
+                .map(|frames| frames.clamp(0, duration_frames))
+                .filter(|&frames| frames > 0)
+        };
         segments.push(AudioSegmentResolved {
             id: seg.id,
             source,
             project_start_frame,
             source_start_frame,
             duration_frames,
+            gain: seg
+                .gain
+                .filter(|gain| gain.is_finite() && *gain >= 0.0 && *gain != 1.0),
+            fade_in_frames: fade(seg.fade_in_frames),
+            fade_out_frames: fade(seg.fade_out_frames),
+            playback_rate,
         });
     }
 
-    *RENDER_AUDIO_PLAN.lock().unwrap() = Some(AudioPlanResolved { fps, segments });
+    let loudness_target = payload
+        .loudness_target
+        .filter(|target| target.is_finite())
+        .map(|target| target.clamp(-70.0, -5.0));
+    *RENDER_AUDIO_PLAN.lock().unwrap() = Some(AudioPlanResolved {
+        fps,
+        segments,
+        mastering: payload.mastering,
+        loudness_target,
+        channel_layout: payload.channel_layout,
+    });
 
     (headers, StatusCode::OK)
 }
@@ -620,6 +1086,9 @@ async fn get_audio_plan_handler(State(_state): State<AppState>) -> impl IntoResp
     let plan = RENDER_AUDIO_PLAN.lock().unwrap().clone().unwrap_or(AudioPlanResolved {
         fps: 60.0,
         segments: Vec::new(),
+        mastering: None,
+        loudness_target: None,
+        channel_layout: None,
     });
 
     (headers, Json(plan))
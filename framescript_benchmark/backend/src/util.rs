@@ -1,6 +1,58 @@
-use std::{env, error::Error, path::PathBuf};
+use std::{env, error::Error, path::PathBuf, sync::LazyLock};
+
+/// Hosts whose `http(s)://` sources are handed to ffmpeg directly
+/// (`FRAMESCRIPT_REMOTE_HOSTS`, comma separated, `*` for any). Unset allows none.
+static REMOTE_HOSTS: LazyLock<Vec<String>> = LazyLock::new(|| {
+    env::var("FRAMESCRIPT_REMOTE_HOSTS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|host| host.trim().to_ascii_lowercase())
+        .filter(|host| !host.is_empty())
+        .collect()
+});
+
+pub fn is_remote_url(input: &str) -> bool {
+    let lower = input.trim_start().to_ascii_lowercase();
+    lower.starts_with("http://") || lower.starts_with("https://")
+}
+
+/// Host of an `http(s)://` URL without userinfo or port.
+fn url_host(url: &str) -> Option<String> {
+    let (_, rest) = url.split_once("://")?;
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let host_port = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    let host = match host_port.strip_prefix('[') {
+        Some(ipv6) => ipv6.split(']').next()?,
+        None => host_port.split(':').next()?,
+    };
+    (!host.is_empty()).then(|| host.to_ascii_lowercase())
+}
+
+fn remote_host_allowed(url: &str) -> bool {
+    let Some(host) = url_host(url) else {
+        return false;
+    };
+    REMOTE_HOSTS.iter().any(|allowed| {
+        allowed == "*"
+            || *allowed == host
+            || allowed
+                .strip_prefix("*.")
+                .is_some_and(|domain| host.ends_with(&format!(".{domain}")))
+    })
+}
 
 pub fn resolve_path_to_string(input: &str) -> Result<String, Box<dyn Error>> {
+    // URL はそのまま ffmpeg に渡す。展開や正規化をすると署名付き URL が壊れる
+    if is_remote_url(input) {
+        let url = input.trim();
+        if !remote_host_allowed(url) {
+            return Err(format!("remote host not allowed: {url}").into());
+        }
+        return Ok(url.to_string());
+    }
+
     let env_expanded = shellexpand::env(input)?; // -> Cow<str>
 
     let tilde_expanded = shellexpand::tilde(&env_expanded);
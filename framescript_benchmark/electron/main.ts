@@ -284,6 +284,7 @@ function startRenderProcess(payload: RenderStartPayload) {
           ...getBundledBinaryEnv(),
           RENDER_PAGE_URL: getRenderPageUrl(),
           RENDER_OUTPUT_PATH: getRenderOutputPath(),
+          RENDER_OVERWRITE: "1",
         },
         stdio: "inherit",
       });
@@ -318,6 +319,7 @@ function startRenderProcess(payload: RenderStartPayload) {
           ...getBundledBinaryEnv(),
           RENDER_PAGE_URL: getRenderPageUrl(),
           RENDER_OUTPUT_PATH: getRenderOutputPath(),
+          RENDER_OVERWRITE: "1",
         },
         stdio: "inherit",
       });
@@ -0,0 +1,657 @@
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use chromiumoxide::{
+    Browser, Handler, Page,
+    cdp::{
+        browser_protocol::page::CaptureScreenshotParams,
+        js_protocol::runtime::EventConsoleApiCalled,
+    },
+    error::CdpError,
+    handler::viewport::Viewport,
+    page::ScreenshotParams,
+};
+use futures::StreamExt;
+
+use chromiumoxide::browser::BrowserConfig;
+use tempfile::{Builder, TempDir};
+
+static CHROMIUM_EXECUTABLE: OnceLock<Option<PathBuf>> = OnceLock::new();
+
+fn resolve_chromium_executable() -> Option<PathBuf> {
+    CHROMIUM_EXECUTABLE
+        .get_or_init(|| {
+            let path = std::env::var("FRAMESCRIPT_CHROMIUM_PATH")
+                .or_else(|_| std::env::var("PUPPETEER_EXECUTABLE_PATH"))
+                .ok()
+                // Windows で "C:\Program Files\..." と引用符ごと設定されることがある
+                .map(|value| value.trim().trim_matches('"').to_string())
+                .filter(|value| !value.is_empty())
+                .map(PathBuf::from);
+
+            if let Some(path) = path
+                && path.is_file()
+            {
+                return Some(path);
+            }
+            None
+        })
+        .clone()
+}
+
+/// A running Chromium and the profile directory it was started with. The directory
+/// is removed when this is dropped, so it has to live as long as the browser.
+pub struct BrowserInstance {
+    pub browser: Browser,
+    profile: TempDir,
+}
+
+impl BrowserInstance {
+    /// Close the browser, wait for the process to exit, then delete its profile.
+    pub async fn close(mut self) {
+        self.browser.close().await.ok();
+        self.browser.wait().await.ok();
+        let path = self.profile.keep();
+        if let Err(error) = remove_profile(&path).await {
+            tracing::warn!("cannot remove browser profile {}: {error}", path.display());
+        }
+    }
+}
+
+/// Delete a profile directory, retrying for a while: on Windows, Chromium's child
+/// processes keep files in it locked for a moment after the browser has exited.
+async fn remove_profile(path: &Path) -> std::io::Result<()> {
+    let mut delay = Duration::from_millis(100);
+    for _ in 0..5 {
+        match tokio::fs::remove_dir_all(path).await {
+            Err(error) if error.kind() != std::io::ErrorKind::NotFound => {
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            _ => return Ok(()),
+        }
+    }
+    match tokio::fs::remove_dir_all(path).await {
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// `path` in the `\\?\` form Windows needs for paths longer than MAX_PATH, which a
+/// profile under a deep %TEMP% reaches quickly.
+fn extended_length_path(path: &Path) -> PathBuf {
+    let text = path.to_string_lossy();
+    if text.starts_with(r"\\?\") {
+        path.to_path_buf()
+    } else if let Some(share) = text.strip_prefix(r"\\") {
+        PathBuf::from(format!(r"\\?\UNC\{share}"))
+    } else {
+        PathBuf::from(format!(r"\\?\{text}"))
+    }
+}
+
+/// How Chromium is started, beyond the viewport size.
+#[derive(Debug, Clone, Default)]
+pub struct BrowserOptions {
+    /// Chromium to run. When `None`, `FRAMESCRIPT_CHROMIUM_PATH` or
+    /// `PUPPETEER_EXECUTABLE_PATH`, then whatever chromiumoxide finds.
+    pub executable: Option<PathBuf>,
+    /// Parent of the profile directories; the system temp dir when `None`.
+    pub profile_dir: Option<PathBuf>,
+    /// Switches appended after chromiumoxide's defaults.
+    pub extra_args: Vec<String>,
+    /// Show the browser window, for debugging compositions.
+    pub headed: bool,
+    /// Device pixels per CSS pixel; screenshots are this many times larger.
+    pub device_scale_factor: Option<f64>,
+    /// Run without Chromium's sandbox, which some Windows setups and containers need.
+    pub no_sandbox: bool,
+    /// Disable only the GPU process sandbox.
+    pub disable_gpu_sandbox: bool,
+}
+
+impl BrowserOptions {
+    /// One line describing the launch, for logs and bug reports.
+    pub fn describe(&self) -> String {
+        let executable = self
+            .executable
+            .clone()
+            .or_else(resolve_chromium_executable)
+            .map(|path| path.display().to_string())
+            .unwrap_or_else(|| "auto-detected chromium".to_string());
+        let mode = if self.headed { "headed" } else { "headless" };
+        let mut line = format!("{executable} ({mode})");
+        if let Some(scale) = self.device_scale_factor {
+            line.push_str(&format!(" at {scale}x"));
+        }
+        if self.no_sandbox {
+            line.push_str(" --no-sandbox");
+        }
+        if self.disable_gpu_sandbox {
+            line.push_str(" --disable-gpu-sandbox");
+        }
+        for arg in &self.extra_args {
+            line.push(' ');
+            line.push_str(arg);
+        }
+        line
+    }
+}
+
+/// Launch Chromium with a fresh profile directory, created under
+/// `options.profile_dir` or the system temp directory.
+pub async fn spawn_browser_instance(
+    profile_id: usize,
+    width: u32,
+    height: u32,
+    options: &BrowserOptions,
+) -> Result<(BrowserInstance, Handler), Box<dyn std::error::Error>> {
+    // 一時ディレクトリをブラウザプロファイルとして使う
+    let prefix = format!("framescript-profile-{profile_id}-");
+    let mut builder = Builder::new();
+    builder.prefix(&prefix);
+    let profile = match &options.profile_dir {
+        Some(base) => {
+            std::fs::create_dir_all(base)?;
+            builder.tempdir_in(base)?
+        }
+        None => builder.tempdir()?,
+    };
+    let mut user_data_dir: PathBuf = profile.path().to_path_buf();
+    if cfg!(windows) {
+        user_data_dir = extended_length_path(&std::path::absolute(&user_data_dir)?);
+    }
+
+    let mut builder = BrowserConfig::builder();
+    builder = if options.headed {
+        builder.with_head()
+    } else {
+        builder.new_headless_mode()
+    };
+    let mut builder = builder
+        .args(&options.extra_args)
+        .viewport(Viewport {
+            width,
+            height,
+            device_scale_factor: options.device_scale_factor,
+            emulating_mobile: false,
+            is_landscape: false,
+            has_touch: false,
+        })
+        .request_timeout(Duration::from_secs(24 * 60 * 60))
+        .user_data_dir(user_data_dir); // ★ インスタンスごとに別のディレクトリ
+
+    if options.no_sandbox {
+        builder = builder.no_sandbox();
+    }
+    if options.disable_gpu_sandbox {
+        builder = builder.arg("--disable-gpu-sandbox");
+    }
+    if let Some(path) = options
+        .executable
+        .clone()
+        .or_else(resolve_chromium_executable)
+    {
+        builder = builder.chrome_executable(path);
+    }
+
+    let config = builder.build()?;
+
+    let (browser, handler) = Browser::launch(config).await?;
+    Ok((BrowserInstance { browser, profile }, handler))
+}
+
+/// Why a render page could not be opened or driven.
+#[derive(Debug)]
+pub enum PageError {
+    /// `window.__frameScript.setFrame` did not show up in time, so the page is most
+    /// likely not the render entry point.
+    FrameApiMissing {
+        url: String,
+        title: String,
+        console: Vec<String>,
+    },
+    /// Opening the page took longer than `timeout` in all; `step` is the one that was
+    /// still running.
+    Timeout {
+        step: &'static str,
+        timeout: Duration,
+    },
+    Cdp(CdpError),
+}
+
+impl std::fmt::Display for PageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PageError::FrameApiMissing {
+                url,
+                title,
+                console,
+            } => {
+                write!(
+                    f,
+                    "frameScript API not found at {url} (title {title:?}) — is this the render entry point?"
+                )?;
+                for line in console {
+                    write!(f, "\n  console: {line}")?;
+                }
+                Ok(())
+            }
+            PageError::Timeout { step, timeout } => {
+                write!(f, "not ready after {timeout:?}, timed out {step}")
+            }
+            PageError::Cdp(error) => error.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for PageError {}
+
+impl From<CdpError> for PageError {
+    fn from(error: CdpError) -> Self {
+        PageError::Cdp(error)
+    }
+}
+
+/// The time left to open a render page, shared by all of its steps. Each CDP call
+/// would otherwise only be bounded by the browser's day-long request timeout.
+#[derive(Debug, Clone, Copy)]
+struct ReadyBudget {
+    deadline: tokio::time::Instant,
+    timeout: Duration,
+}
+
+impl ReadyBudget {
+    fn new(timeout: Duration) -> Self {
+        Self {
+            deadline: tokio::time::Instant::now() + timeout,
+            timeout,
+        }
+    }
+
+    fn remaining(&self) -> Duration {
+        self.deadline
+            .saturating_duration_since(tokio::time::Instant::now())
+    }
+
+    /// Run `step` until the deadline; past it, a `PageError::Timeout` naming `name`.
+    async fn run<T, E: Into<PageError>>(
+        &self,
+        name: &'static str,
+        step: impl Future<Output = Result<T, E>>,
+    ) -> Result<T, PageError> {
+        match tokio::time::timeout_at(self.deadline, step).await {
+            Ok(result) => result.map_err(Into::into),
+            Err(_) => Err(PageError::Timeout {
+                step: name,
+                timeout: self.timeout,
+            }),
+        }
+    }
+}
+
+/// How long the title, URL and close of a page that failed to open may take.
+const CLEANUP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Console lines quoted in `PageError::FrameApiMissing`.
+const MISSING_API_CONSOLE_LINES: usize = 10;
+
+/// Open the render page and wait until the composition is ready to be driven, giving
+/// up when that takes longer than `ready_timeout` in all. A page whose frame API never
+/// appeared fails with `PageError::FrameApiMissing`. The console is collected from
+/// before the page loads, keeping the last `console_lines` messages.
+pub async fn open_render_page(
+    browser: &Browser,
+    url: &str,
+    ready_timeout: Duration,
+    console_lines: usize,
+) -> Result<(Page, Arc<Mutex<VecDeque<String>>>), PageError> {
+    let budget = ReadyBudget::new(ready_timeout);
+    let page = budget
+        .run("opening a tab", browser.new_page("about:blank"))
+        .await?;
+    match load_render_page(&page, url, budget, console_lines).await {
+        Ok(console) => Ok((page, console)),
+        Err(error) => {
+            tokio::time::timeout(CLEANUP_TIMEOUT, page.close())
+                .await
+                .ok();
+            Err(error)
+        }
+    }
+}
+
+async fn load_render_page(
+    page: &Page,
+    url: &str,
+    budget: ReadyBudget,
+    console_lines: usize,
+) -> Result<Arc<Mutex<VecDeque<String>>>, PageError> {
+    let console = budget
+        .run(
+            "listening to the console",
+            collect_console(page, console_lines),
+        )
+        .await?;
+    budget.run("navigating to the page", page.goto(url)).await?;
+    budget
+        .run("waiting for the page to load", page.wait_for_navigation())
+        .await?;
+
+    // 期限切れも API が見つからなかったものとして診断情報を付ける
+    let found = match budget
+        .run(
+            "waiting for window.__frameScript",
+            wait_for_frame_api(page, budget.remaining()),
+        )
+        .await
+    {
+        Ok(found) => found,
+        Err(PageError::Timeout { .. }) => false,
+        Err(error) => return Err(error),
+    };
+    if !found {
+        let console = console.lock().unwrap().iter().cloned().collect::<Vec<_>>();
+        let console = console[console.len().saturating_sub(MISSING_API_CONSOLE_LINES)..].to_vec();
+        let url = tokio::time::timeout(CLEANUP_TIMEOUT, page.url())
+            .await
+            .ok()
+            .and_then(Result::ok)
+            .flatten()
+            .unwrap_or_else(|| url.to_string());
+        let title = tokio::time::timeout(CLEANUP_TIMEOUT, page.get_title())
+            .await
+            .ok()
+            .and_then(Result::ok)
+            .flatten()
+            .unwrap_or_default();
+        return Err(PageError::FrameApiMissing {
+            url,
+            title,
+            console,
+        });
+    }
+    budget
+        .run(
+            "waiting for animations to be ready",
+            wait_for_animation_ready(page),
+        )
+        .await?;
+    Ok(console)
+}
+
+/// The last `capacity` console messages of `page`, kept up to date in the background.
+pub async fn collect_console(
+    page: &Page,
+    capacity: usize,
+) -> Result<Arc<Mutex<VecDeque<String>>>, CdpError> {
+    let mut events = page.event_listener::<EventConsoleApiCalled>().await?;
+    let log = Arc::new(Mutex::new(VecDeque::with_capacity(capacity)));
+    let sink = log.clone();
+    tokio::spawn(async move {
+        while let Some(event) = events.next().await {
+            let text = event
+                .args
+                .iter()
+                .map(|arg| match (&arg.value, &arg.description) {
+                    (Some(serde_json::Value::String(text)), _) => text.clone(),
+                    (Some(value), _) => value.to_string(),
+                    (None, Some(description)) => description.clone(),
+                    (None, None) => String::new(),
+                })
+                .collect::<Vec<_>>()
+                .join(" ");
+            let mut log = sink.lock().unwrap();
+            if log.len() == capacity {
+                log.pop_front();
+            }
+            log.push_back(format!("[{:?}] {text}", event.r#type));
+        }
+    });
+    Ok(log)
+}
+
+/// Decode a screenshot into packed RGBA, checking it has the expected size.
+pub fn decode_png_rgba(png: &[u8], width: u32, height: u32) -> Result<Vec<u8>, String> {
+    let mut decoder = png::Decoder::new(png);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info().map_err(|e| e.to_string())?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf).map_err(|e| e.to_string())?;
+    buf.truncate(info.buffer_size());
+
+    if info.width != width || info.height != height {
+        return Err(format!(
+            "screenshot is {}x{}, expected {width}x{height}",
+            info.width, info.height
+        ));
+    }
+
+    Ok(match info.color_type {
+        png::ColorType::Rgba => buf,
+        png::ColorType::Rgb => buf
+            .chunks_exact(3)
+            .flat_map(|px| [px[0], px[1], px[2], 255])
+            .collect(),
+        png::ColorType::GrayscaleAlpha => buf
+            .chunks_exact(2)
+            .flat_map(|px| [px[0], px[0], px[0], px[1]])
+            .collect(),
+        png::ColorType::Grayscale => buf.iter().flat_map(|&v| [v, v, v, 255]).collect(),
+        png::ColorType::Indexed => return Err("palette PNG was not expanded".to_string()),
+    })
+}
+
+pub async fn wait_for_next_frame(page: &Page) -> Result<(), PageError> {
+    let script = r#"
+        (async () => {
+          await new Promise(resolve => {
+            requestAnimationFrame(() => {
+              requestAnimationFrame(resolve);
+            });
+          });
+        })()
+    "#;
+    page.evaluate(script).await?;
+    Ok(())
+}
+
+/// Whether `window.__frameScript.setFrame` showed up within `timeout`.
+async fn wait_for_frame_api(page: &Page, timeout: Duration) -> Result<bool, PageError> {
+    let script = format!(
+        r#"
+        (async () => {{
+          const start = Date.now();
+          while (true) {{
+            const api = window.__frameScript;
+            if (api && typeof api.setFrame === "function") return true;
+            if (Date.now() - start > {}) return false;
+            await new Promise(resolve => {{
+              requestAnimationFrame(() => {{
+                requestAnimationFrame(resolve);
+              }});
+            }});
+          }}
+        }})()
+    "#,
+        timeout.as_millis()
+    );
+    Ok(page
+        .evaluate(script)
+        .await?
+        .into_value::<bool>()
+        .unwrap_or(false))
+}
+
+async fn wait_for_animation_ready(page: &Page) -> Result<(), PageError> {
+    let script = r#"
+        (async () => {
+          const api = window.__frameScript;
+          if (api && typeof api.waitAnimationsReady === "function") {
+            await api.waitAnimationsReady();
+          }
+        })()
+    "#;
+    page.evaluate(script).await?;
+    Ok(())
+}
+
+/// Where the time of one `capture_frame` went.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CaptureTimings {
+    /// The `setFrame` call.
+    pub set_frame: Duration,
+    /// Animation frames and `waitCanvasFrame` around it.
+    pub wait: Duration,
+    pub screenshot: Duration,
+}
+
+/// Seek the page to `frame` and screenshot it with `cdp_params` once it has been
+/// drawn. The background is left transparent for PNG.
+pub async fn capture_frame(
+    page: &Page,
+    frame: usize,
+    cdp_params: CaptureScreenshotParams,
+) -> Result<(Vec<u8>, CaptureTimings), PageError> {
+    let mut timings = CaptureTimings::default();
+    let started = Instant::now();
+    wait_for_next_frame(page).await?;
+    timings.wait += started.elapsed();
+
+    let js = format!(
+        r#"
+        (() => {{
+          const api = window.__frameScript;
+          if (api && typeof api.setFrame === "function") {{
+            api.setFrame({});
+          }}
+        }})()
+        "#,
+        frame
+    );
+    let started = Instant::now();
+    page.evaluate(js).await?;
+    timings.set_frame = started.elapsed();
+
+    let started = Instant::now();
+    wait_for_next_frame(page).await?;
+
+    let script = format!(
+        r#"
+        (async () => {{
+          const api = window.__frameScript;
+          if (api && typeof api.waitCanvasFrame === "function") {{
+            try {{
+              await api.waitCanvasFrame({});
+            }} catch (_e) {{
+              // ignore
+            }}
+          }}
+        }})()
+    "#,
+        frame
+    );
+    page.evaluate(script).await?;
+    timings.wait += started.elapsed();
+
+    let started = Instant::now();
+    let image = page
+        .screenshot(ScreenshotParams {
+            cdp_params,
+            full_page: None,
+            omit_background: Some(true),
+        })
+        .await?;
+    timings.screenshot = started.elapsed();
+    Ok((image, timings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drive_paths_get_the_extended_length_prefix() {
+        assert_eq!(
+            extended_length_path(Path::new(r"C:\Users\me\AppData\Local\Temp\profile")),
+            PathBuf::from(r"\\?\C:\Users\me\AppData\Local\Temp\profile")
+        );
+    }
+
+    #[test]
+    fn unc_paths_get_the_unc_form_of_the_prefix() {
+        assert_eq!(
+            extended_length_path(Path::new(r"\\server\share\temp\profile")),
+            PathBuf::from(r"\\?\UNC\server\share\temp\profile")
+        );
+    }
+
+    #[test]
+    fn prefixed_paths_are_left_alone() {
+        for path in [r"\\?\C:\Temp\profile", r"\\?\UNC\server\share\profile"] {
+            assert_eq!(extended_length_path(Path::new(path)), PathBuf::from(path));
+        }
+    }
+
+    #[cfg(windows)]
+    #[tokio::test]
+    async fn closing_the_browser_removes_its_profile() {
+        let installed = [
+            r"C:\Program Files\Google\Chrome\Application\chrome.exe",
+            r"C:\Program Files (x86)\Microsoft\Edge\Application\msedge.exe",
+        ]
+        .map(PathBuf::from)
+        .into_iter()
+        .find(|path| path.is_file());
+        let Some(executable) = resolve_chromium_executable().or(installed) else {
+            eprintln!("skipping: no chromium installed");
+            return;
+        };
+        let parent = tempfile::tempdir().unwrap();
+        let options = BrowserOptions {
+            executable: Some(executable),
+            profile_dir: Some(parent.path().to_path_buf()),
+            ..BrowserOptions::default()
+        };
+
+        let (instance, mut handler) = spawn_browser_instance(0, 64, 36, &options).await.unwrap();
+        let events = tokio::spawn(async move { while handler.next().await.is_some() {} });
+        assert_eq!(std::fs::read_dir(parent.path()).unwrap().count(), 1);
+
+        instance.close().await;
+        events.abort();
+        assert_eq!(std::fs::read_dir(parent.path()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn a_step_past_the_deadline_is_named() {
+        let budget = ReadyBudget::new(Duration::from_millis(20));
+        let done = budget
+            .run("opening a tab", async { Ok::<_, CdpError>(1) })
+            .await
+            .unwrap();
+        assert_eq!(done, 1);
+
+        let error = budget
+            .run(
+                "waiting for the page to load",
+                std::future::pending::<Result<(), CdpError>>(),
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            PageError::Timeout {
+                step: "waiting for the page to load",
+                ..
+            }
+        ));
+        assert_eq!(
+            error.to_string(),
+            "not ready after 20ms, timed out waiting for the page to load"
+        );
+        assert_eq!(budget.remaining(), Duration::ZERO);
+    }
+}
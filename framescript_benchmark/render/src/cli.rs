@@ -0,0 +1,1283 @@
+use std::ffi::OsString;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::{CommandFactory, Parser, ValueEnum, error::ErrorKind};
+
+use crate::ffmpeg::{
+    AudioCodec, AudioEncode, AudioMastering, ChannelLayout, ColorRange, EncoderKind, EncoderTuning,
+    Quality, VideoCodec,
+};
+use crate::progress::ProgressTarget;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Codec {
+    #[value(name = "h264")]
+    H264,
+    #[value(name = "h265", alias = "hevc")]
+    H265,
+    #[value(name = "h264_nvenc")]
+    H264Nvenc,
+    #[value(name = "hevc_nvenc")]
+    HevcNvenc,
+    #[value(name = "h264_vaapi")]
+    H264Vaapi,
+    #[value(name = "h264_qsv")]
+    H264Qsv,
+    #[value(name = "h264_videotoolbox")]
+    H264Videotoolbox,
+    /// ProRes 4444 with alpha, in .mov.
+    #[value(name = "prores4444")]
+    Prores4444,
+    /// QuickTime Animation (lossless RGBA), in .mov.
+    #[value(name = "qtrle")]
+    Qtrle,
+    /// VP9 with yuva420p alpha, in .webm.
+    #[value(name = "vp9")]
+    Vp9,
+}
+
+/// Pixel format of the encoded video.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum PixelFormat {
+    /// 8-bit 4:2:0. Plays everywhere, but thin colored lines bleed.
+    Yuv420p,
+    /// 8-bit with full-resolution chroma (x264 high444, x265 main444-8). Browsers
+    /// and phones often cannot play it.
+    Yuv444p,
+    /// 10-bit 4:2:0 for masters (x265 main10).
+    Yuv420p10le,
+}
+
+impl PixelFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PixelFormat::Yuv420p => "yuv420p",
+            PixelFormat::Yuv444p => "yuv444p",
+            PixelFormat::Yuv420p10le => "yuv420p10le",
+        }
+    }
+}
+
+/// How worker screenshots travel from Chromium to ffmpeg.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CaptureMode {
+    /// PNG screenshots decoded by ffmpeg.
+    Png,
+    /// Speed-optimized PNG decoded in-process and piped to ffmpeg as rawvideo RGBA.
+    /// CDP has no uncompressed screenshot format, so this is as raw as it gets.
+    Raw,
+    /// Lossy JPEG screenshots for fast draft renders. Drops transparency.
+    Jpeg,
+}
+
+impl std::fmt::Display for CaptureMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            CaptureMode::Png => "png",
+            CaptureMode::Raw => "raw",
+            CaptureMode::Jpeg => "jpeg",
+        })
+    }
+}
+
+/// How frames are shared out between workers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Distribution {
+    /// Runs of `--chunk-frames` consecutive frames from a shared queue.
+    Contiguous,
+    /// Worker i renders frames i, i+W, i+2W, ... as image files, which are encoded
+    /// in order afterwards. The whole timeline fills in evenly while rendering.
+    Interleaved,
+}
+
+/// What to do with a frame that timed out twice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum FrameTimeoutPolicy {
+    /// Repeat the previous frame and carry on.
+    Skip,
+    /// Give up on the rest of the worker's chunk.
+    Fail,
+}
+
+/// How segments are joined into the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ConcatMode {
+    /// Stream copy; fails on segments the concat demuxer cannot copy.
+    Copy,
+    /// Decode and encode everything again with the segment settings. Joins
+    /// segments whose encoder parameters differ.
+    Reencode,
+    /// Stream copy, re-encoding only if that fails.
+    Auto,
+}
+
+impl std::fmt::Display for ConcatMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ConcatMode::Copy => "copy",
+            ConcatMode::Reencode => "reencode",
+            ConcatMode::Auto => "auto",
+        })
+    }
+}
+
+/// How workers get their page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum BrowserMode {
+    /// One Chromium process per worker.
+    Processes,
+    /// One Chromium shared by all workers, each in its own tab. Saves memory and
+    /// startup time; the GPU process is shared too.
+    Tabs,
+}
+
+impl std::fmt::Display for BrowserMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            BrowserMode::Processes => "processes",
+            BrowserMode::Tabs => "tabs",
+        })
+    }
+}
+
+impl std::fmt::Display for Distribution {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Distribution::Contiguous => "contiguous",
+            Distribution::Interleaved => "interleaved",
+        })
+    }
+}
+
+impl Codec {
+    /// The encoder `SegmentWriter` runs for this codec.
+    pub fn encoder(self) -> EncoderKind {
+        match self {
+            Codec::H264 => EncoderKind::X264,
+            Codec::H265 => EncoderKind::X265,
+            Codec::H264Nvenc => EncoderKind::Nvenc(VideoCodec::H264),
+            Codec::HevcNvenc => EncoderKind::Nvenc(VideoCodec::H265),
+            Codec::H264Vaapi => EncoderKind::Vaapi,
+            Codec::H264Qsv => EncoderKind::Qsv,
+            Codec::H264Videotoolbox => EncoderKind::VideoToolbox,
+            Codec::Prores4444 => EncoderKind::ProRes4444,
+            Codec::Qtrle => EncoderKind::Qtrle,
+            Codec::Vp9 => EncoderKind::Vp9,
+        }
+    }
+
+    /// Whether the codec keeps the screenshots' alpha channel.
+    pub fn keeps_alpha(self) -> bool {
+        matches!(self, Codec::Prores4444 | Codec::Qtrle | Codec::Vp9)
+    }
+
+    /// Valid `--crf` values, or `None` when the codec has no rate control.
+    pub fn crf_range(self) -> Option<std::ops::RangeInclusive<u32>> {
+        match self {
+            Codec::Prores4444 | Codec::Qtrle => None,
+            Codec::Vp9 => Some(0..=63),
+            Codec::H264Qsv => Some(1..=51),
+            _ => Some(0..=51),
+        }
+    }
+
+    /// Container used when `--container` is not given.
+    pub fn default_container(self) -> Container {
+        match self {
+            Codec::Prores4444 | Codec::Qtrle => Container::Mov,
+            Codec::Vp9 => Container::Webm,
+            _ => Container::Mp4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Container {
+    Mp4,
+    Mov,
+    Mkv,
+    /// VP9 only.
+    Webm,
+}
+
+impl Container {
+    /// Extension of the segment, concat and final output files.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Container::Mp4 => "mp4",
+            Container::Mov => "mov",
+            Container::Mkv => "mkv",
+            Container::Webm => "webm",
+        }
+    }
+
+    pub fn holds(self, codec: Codec) -> bool {
+        match self {
+            Container::Mkv => true,
+            Container::Webm => codec == Codec::Vp9,
+            Container::Mp4 => !matches!(codec, Codec::Prores4444 | Codec::Qtrle),
+            Container::Mov => codec != Codec::Vp9,
+        }
+    }
+}
+
+/// What the render produces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputMode {
+    /// An encoded video with the audio plan muxed in.
+    Video,
+    /// Numbered image files in `--frames-dir`, without encoding.
+    Frames,
+    /// Looping GIF converted from the rendered video, without audio.
+    Gif,
+    /// Looping animated WebP converted from the rendered video, without audio.
+    Webp,
+    /// Only the mixed audio plan; no browser is launched.
+    Audio,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum AudioFormat {
+    M4a,
+    Wav,
+    Flac,
+}
+
+impl AudioFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            AudioFormat::M4a => "m4a",
+            AudioFormat::Wav => "wav",
+            AudioFormat::Flac => "flac",
+        }
+    }
+}
+
+/// `paletteuse` dither modes for `--output-mode gif`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Dither {
+    None,
+    Bayer,
+    #[value(name = "floyd_steinberg")]
+    FloydSteinberg,
+    Sierra2,
+    #[value(name = "sierra2_4a")]
+    Sierra24a,
+}
+
+impl Dither {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Dither::None => "none",
+            Dither::Bayer => "bayer",
+            Dither::FloydSteinberg => "floyd_steinberg",
+            Dither::Sierra2 => "sierra2",
+            Dither::Sierra24a => "sierra2_4a",
+        }
+    }
+}
+
+/// How log lines on stderr are written. RUST_LOG picks what is logged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per line, for the benchmark harness.
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum FrameFormat {
+    Png,
+    Jpeg,
+}
+
+impl FrameFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            FrameFormat::Png => "png",
+            FrameFormat::Jpeg => "jpg",
+        }
+    }
+}
+
+/// A rectangle of the page in CSS pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Clip {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Render a FrameScript composition to video through headless Chromium and ffmpeg.
+#[derive(Debug, Clone, Parser)]
+#[command(name = "render", version)]
+pub struct RenderArgs {
+    // 以下の 4 つは --jobs では各ジョブが上書きするので、既定値は仮のもの
+    /// Output width in pixels.
+    #[arg(
+        long,
+        env = "RENDER_WIDTH",
+        required_unless_present = "jobs",
+        default_value_t = 1,
+        hide_default_value = true,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub width: u32,
+
+    /// Output height in pixels.
+    #[arg(
+        long,
+        env = "RENDER_HEIGHT",
+        required_unless_present = "jobs",
+        default_value_t = 1,
+        hide_default_value = true,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub height: u32,
+
+    /// Device scale factor. The page is laid out at --width x --height and captured
+    /// at N times that, so each screenshot takes N² times the memory.
+    #[arg(long, env = "RENDER_SCALE", default_value_t = 1.0, value_parser = parse_scale)]
+    pub scale: f64,
+
+    /// Scale --scale captures back down to the page or --clip size (lanczos)
+    /// instead of encoding at the larger size. Image sequences keep the captured size.
+    #[arg(long, env = "RENDER_DOWNSCALE")]
+    pub downscale: bool,
+
+    /// Capture only the rectangle `x,y,w,h` of the page, e.g. `420,0,1080,1080` for a
+    /// square out of a 1920x1080 composition. The output takes the clip's size.
+    #[arg(long, env = "RENDER_CLIP", value_parser = parse_clip)]
+    pub clip: Option<Clip>,
+
+    /// Frames per second.
+    #[arg(
+        long,
+        env = "RENDER_FPS",
+        required_unless_present = "jobs",
+        default_value_t = 1.0,
+        hide_default_value = true,
+        value_parser = parse_fps
+    )]
+    pub fps: f64,
+
+    /// Total number of frames in the composition.
+    #[arg(
+        long,
+        env = "RENDER_FRAMES",
+        required_unless_present = "jobs",
+        default_value_t = 1,
+        hide_default_value = true
+    )]
+    pub frames: usize,
+
+    /// First frame to render (inclusive).
+    #[arg(long, env = "RENDER_START_FRAME", default_value_t = 0)]
+    pub start_frame: usize,
+
+    /// Frame to stop at (exclusive). Defaults to `--frames`.
+    #[arg(long, env = "RENDER_END_FRAME")]
+    pub end_frame: Option<usize>,
+
+    /// Number of browser instances rendering in parallel.
+    #[arg(long, env = "RENDER_WORKERS", default_value_t = 1, value_parser = parse_workers)]
+    pub workers: usize,
+
+    /// Frames per unit of work. Workers take the next chunk as soon as they finish
+    /// one, so a slow section of the timeline does not hold up the others.
+    #[arg(
+        long,
+        env = "RENDER_CHUNK_FRAMES",
+        default_value_t = 30,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    pub chunk_frames: usize,
+
+    /// Captured frames each worker may hold while ffmpeg catches up. The page renders
+    /// the next frame while the previous ones are encoded; memory grows by one frame
+    /// per step (about 33 MB for raw 4K).
+    #[arg(
+        long,
+        env = "RENDER_PIPELINE_DEPTH",
+        default_value_t = 2,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..=64)
+    )]
+    pub pipeline_depth: usize,
+
+    /// Directory to create the browser profiles in, e.g. on a RAM disk. Defaults to
+    /// the system temp directory.
+    #[arg(long, env = "RENDER_PROFILE_DIR")]
+    pub profile_dir: Option<PathBuf>,
+
+    /// Extra Chromium switch, e.g. `--chromium-arg=--use-gl=angle`. Repeatable;
+    /// RENDER_CHROMIUM_ARGS takes them space-separated.
+    #[arg(
+        long = "chromium-arg",
+        env = "RENDER_CHROMIUM_ARGS",
+        allow_hyphen_values = true,
+        value_delimiter = ' '
+    )]
+    pub chromium_args: Vec<String>,
+
+    /// Launch Chromium without its sandbox (`--no-sandbox`). Often needed on Windows
+    /// and in containers when Chromium exits right after starting.
+    #[arg(long, env = "RENDER_NO_SANDBOX")]
+    pub no_sandbox: bool,
+
+    /// Pass `--disable-gpu-sandbox` to Chromium, for GPUs whose drivers fail inside it.
+    #[arg(long, env = "RENDER_DISABLE_GPU_SANDBOX")]
+    pub disable_gpu_sandbox: bool,
+
+    /// Launch a visible browser window instead of headless Chromium.
+    #[arg(long, env = "RENDER_NO_HEADLESS")]
+    pub no_headless: bool,
+
+    /// Give each worker its own browser or a tab in a shared one.
+    #[arg(
+        long,
+        env = "RENDER_BROWSER_MODE",
+        value_enum,
+        ignore_case = true,
+        default_value = "processes"
+    )]
+    pub browser_mode: BrowserMode,
+
+    /// Order in which workers take frames.
+    #[arg(
+        long,
+        env = "RENDER_DISTRIBUTION",
+        value_enum,
+        ignore_case = true,
+        default_value = "contiguous"
+    )]
+    pub distribution: Distribution,
+
+    /// Video codec [default: h264, or prores4444 with --transparent]. Hardware
+    /// encoders fall back to software when unavailable.
+    #[arg(long, env = "RENDER_CODEC", value_enum, ignore_case = true)]
+    pub codec: Option<Codec>,
+
+    /// Constant quality (lower is better) [default: 18].
+    #[arg(long, env = "RENDER_CRF")]
+    pub crf: Option<u32>,
+
+    /// Target bitrate such as `8M` or `2500k`, instead of constant quality.
+    #[arg(long, env = "RENDER_BITRATE", conflicts_with = "crf", value_parser = parse_bitrate)]
+    pub bitrate: Option<u64>,
+
+    /// Frames between keyframes [default: one second of frames]. Longer intervals
+    /// shrink mostly static videos. Every segment is encoded on its own and so still
+    /// starts on a keyframe, which keeps concat clean however large this is.
+    #[arg(
+        long,
+        env = "RENDER_GOP",
+        value_parser = clap::builder::RangedU64ValueParser::<u32>::new().range(1..)
+    )]
+    pub gop: Option<u32>,
+
+    /// Leave keyframe placement to the encoder instead of a fixed interval.
+    #[arg(long, env = "RENDER_NO_FIXED_GOP", conflicts_with = "gop")]
+    pub no_fixed_gop: bool,
+
+    /// Pixel format for h264/h265 [default: yuv420p]. yuv420p is what web players
+    /// expect; yuv444p keeps thin colored lines sharp and yuv420p10le (h265 only) is
+    /// for masters, at the cost of compatibility.
+    #[arg(long, env = "RENDER_PIX_FMT", value_enum, ignore_case = true)]
+    pub pix_fmt: Option<PixelFormat>,
+
+    /// Range of the encoded YUV values. Frames are converted from sRGB with the
+    /// BT.709 matrix and tagged BT.709 either way.
+    #[arg(
+        long,
+        env = "RENDER_COLOR_RANGE",
+        value_enum,
+        ignore_case = true,
+        default_value = "limited"
+    )]
+    pub color_range: ColorRange,
+
+    /// Output container [default: mp4, mov for prores4444/qtrle, webm for vp9].
+    #[arg(long, env = "RENDER_CONTAINER", value_enum, ignore_case = true)]
+    pub container: Option<Container>,
+
+    /// Keep the page's transparent background, using an alpha-capable codec.
+    #[arg(long, env = "RENDER_TRANSPARENT")]
+    pub transparent: bool,
+
+    /// Screenshot format handed from Chromium to ffmpeg.
+    #[arg(
+        long,
+        env = "RENDER_CAPTURE",
+        value_enum,
+        ignore_case = true,
+        default_value = "png"
+    )]
+    pub capture: CaptureMode,
+
+    /// JPEG quality for `--capture jpeg`.
+    #[arg(
+        long,
+        env = "RENDER_JPEG_QUALITY",
+        default_value_t = 85,
+        value_parser = clap::value_parser!(u8).range(1..=100)
+    )]
+    pub jpeg_quality: u8,
+
+    /// Keep segments left by an earlier run and only render the missing or short ones.
+    #[arg(long)]
+    pub resume: bool,
+
+    /// Write MP4/MOV segments fragmented, so one cut short by a crash is still
+    /// readable up to its last keyframe and `--resume` renders only the rest of it.
+    /// Segments come out slightly larger; the final output is not fragmented.
+    #[arg(long, env = "RENDER_RECOVERABLE_SEGMENTS")]
+    pub recoverable_segments: bool,
+
+    /// Leave the partial segments in the work directory when the render is canceled.
+    #[arg(long, env = "RENDER_KEEP_PARTIALS")]
+    pub keep_partials: bool,
+
+    /// Keep everything in the work directory for debugging: the segments, the concat
+    /// list and the video before the audio was muxed in (`output.no-audio.*`), with a
+    /// `manifest.json` saying which worker rendered which frames. The directory is
+    /// printed at the end. Without it the segments and the list are deleted once
+    /// joined.
+    #[arg(long, env = "RENDER_KEEP_INTERMEDIATES")]
+    pub keep_intermediates: bool,
+
+    /// Assemble the output even when some frames failed. The exit code still
+    /// reports the failure.
+    #[arg(long, env = "RENDER_KEEP_GOING")]
+    pub keep_going: bool,
+
+    /// Leave out segments that are missing, unreadable, the wrong size or short of
+    /// frames instead of failing before concat. The video jumps over their frames.
+    #[arg(long, env = "RENDER_ALLOW_GAPS")]
+    pub allow_gaps: bool,
+
+    /// How segments are joined. auto stream-copies and re-encodes only when the copy
+    /// fails, e.g. after an encoder fallback changed parameters mid-render.
+    #[arg(
+        long,
+        env = "RENDER_CONCAT_MODE",
+        value_enum,
+        ignore_case = true,
+        default_value = "auto"
+    )]
+    pub concat_mode: ConcatMode,
+
+    /// Extra attempts at a failed frame before the worker's browser is relaunched.
+    #[arg(long, env = "RENDER_FRAME_RETRIES", default_value_t = 2)]
+    pub frame_retries: usize,
+
+    /// Browser relaunches a worker may do over the whole render before it gives up
+    /// on the rest of its range.
+    #[arg(long, env = "RENDER_MAX_RELAUNCHES", default_value_t = 3)]
+    pub max_relaunches: usize,
+
+    /// Seconds a single frame (seek, waits and screenshot) may take before it counts
+    /// as hung. A hung frame is retried once, with a screenshot and the page console
+    /// saved under `diagnostics` in the work directory.
+    #[arg(long, env = "RENDER_FRAME_TIMEOUT", default_value = "30", value_parser = parse_seconds)]
+    pub frame_timeout: Duration,
+
+    /// What to do when a frame hangs again after its retry.
+    #[arg(
+        long,
+        env = "RENDER_ON_FRAME_TIMEOUT",
+        value_enum,
+        ignore_case = true,
+        default_value = "fail"
+    )]
+    pub on_frame_timeout: FrameTimeoutPolicy,
+
+    /// Seconds a render page may take to load, expose `window.__frameScript` and get
+    /// its animations ready. A page that never exposes the API fails the whole render
+    /// with its title and console; one stuck elsewhere is retried.
+    #[arg(long, env = "RENDER_PAGE_READY_TIMEOUT", default_value = "15", value_parser = parse_seconds)]
+    pub page_ready_timeout: Duration,
+
+    /// Encoder preset passed to ffmpeg.
+    #[arg(long, env = "RENDER_PRESET", default_value = "medium")]
+    pub preset: String,
+
+    /// Encoder `-tune`, e.g. `animation` for x264.
+    #[arg(long, env = "RENDER_TUNE")]
+    pub tune: Option<String>,
+
+    /// Encoder profile, e.g. `high`. Replaces the one `--pix-fmt` picks.
+    #[arg(long, env = "RENDER_PROFILE")]
+    pub profile: Option<String>,
+
+    /// Encoder level for device compatibility, e.g. `4.1`.
+    #[arg(long, env = "RENDER_LEVEL")]
+    pub level: Option<String>,
+
+    /// Extra `key=value` encoder option, sent as `-x264-params`/`-x265-params` for
+    /// h264/h265 and as `-key value` otherwise. Repeatable; RENDER_ENCODER_PARAMS takes
+    /// them comma-separated. Checked with a test encode before rendering.
+    #[arg(
+        long = "encoder-param",
+        env = "RENDER_ENCODER_PARAMS",
+        value_delimiter = ',',
+        value_parser = parse_key_value
+    )]
+    pub encoder_params: Vec<(String, String)>,
+
+    /// Produce a video, an image sequence or a GIF/WebP animation.
+    #[arg(
+        long,
+        env = "RENDER_OUTPUT_MODE",
+        value_enum,
+        ignore_case = true,
+        default_value = "video"
+    )]
+    pub output_mode: OutputMode,
+
+    /// Directory receiving `frame_000123.png` files in `--output-mode frames`.
+    #[arg(
+        long,
+        env = "RENDER_FRAMES_DIR",
+        required_if_eq("output_mode", "frames"),
+        conflicts_with = "jobs"
+    )]
+    pub frames_dir: Option<PathBuf>,
+
+    /// Image format of `--output-mode frames`. JPEG uses `--jpeg-quality`.
+    #[arg(
+        long,
+        env = "RENDER_FRAME_FORMAT",
+        value_enum,
+        ignore_case = true,
+        default_value = "png"
+    )]
+    pub frame_format: FrameFormat,
+
+    /// Also mix the audio plan into `audio.m4a` next to the frames.
+    #[arg(long, env = "RENDER_FRAMES_AUDIO")]
+    pub frames_audio: bool,
+
+    /// File format of `--output-mode audio`.
+    #[arg(
+        long,
+        env = "RENDER_AUDIO_FORMAT",
+        value_enum,
+        ignore_case = true,
+        default_value = "m4a"
+    )]
+    pub audio_format: AudioFormat,
+
+    /// Read the audio plan from this JSON file instead of `--audio-plan-url`, for
+    /// renders without the backend. The format is documented on
+    /// `ffmpeg::AudioPlanResolved`.
+    #[arg(long, env = "RENDER_AUDIO_PLAN_FILE")]
+    pub audio_plan: Option<PathBuf>,
+
+    /// Stage on the final audio mix [default: the audio plan's, or limiter].
+    #[arg(long, env = "RENDER_AUDIO_MASTERING", value_enum, ignore_case = true)]
+    pub audio_mastering: Option<AudioMastering>,
+
+    /// Integrated loudness in LUFS for `--audio-mastering loudnorm`, -70 to -5
+    /// [default: the audio plan's, or -14].
+    #[arg(
+        long,
+        env = "RENDER_LOUDNESS_TARGET",
+        allow_negative_numbers = true,
+        value_parser = parse_lufs
+    )]
+    pub loudness_target: Option<f64>,
+
+    /// Channels of the mixed audio [default: the audio plan's, or stereo].
+    #[arg(long, env = "RENDER_AUDIO_CHANNELS", value_enum, ignore_case = true)]
+    pub audio_channels: Option<ChannelLayout>,
+
+    /// Encoder of the audio track [default: opus for .webm, pcm for .wav, flac for
+    /// .flac, aac otherwise].
+    #[arg(long, env = "RENDER_AUDIO_CODEC", value_enum, ignore_case = true)]
+    pub audio_codec: Option<AudioCodec>,
+
+    /// Audio bitrate of aac and opus, such as 96k [default: 192k].
+    #[arg(long, env = "RENDER_AUDIO_BITRATE", value_parser = parse_bitrate)]
+    pub audio_bitrate: Option<u64>,
+
+    /// Sample rate of the audio track in Hz [default: 48000].
+    #[arg(
+        long,
+        env = "RENDER_AUDIO_SAMPLE_RATE",
+        value_parser = clap::value_parser!(u32).range(8000..=192000)
+    )]
+    pub audio_sample_rate: Option<u32>,
+
+    /// Frame rate of `--output-mode gif|webp` [default: the render fps].
+    #[arg(long, env = "RENDER_ANIM_FPS", value_parser = parse_fps)]
+    pub anim_fps: Option<f64>,
+
+    /// Downscale GIF/WebP output to at most this width.
+    #[arg(long, env = "RENDER_ANIM_MAX_WIDTH", value_parser = clap::value_parser!(u32).range(2..))]
+    pub anim_max_width: Option<u32>,
+
+    /// Stop GIF/WebP output after this many frames.
+    #[arg(long, env = "RENDER_ANIM_MAX_FRAMES")]
+    pub anim_max_frames: Option<usize>,
+
+    /// Dithering used when reducing GIF colors to the palette.
+    #[arg(
+        long,
+        env = "RENDER_DITHER",
+        value_enum,
+        ignore_case = true,
+        default_value = "sierra2_4a"
+    )]
+    pub dither: Dither,
+
+    /// Keep the intermediate video next to the GIF/WebP output.
+    #[arg(long, env = "RENDER_KEEP_VIDEO")]
+    pub keep_video: bool,
+
+    /// Check ffmpeg, the encoder, Chromium, the render page, the backend and the
+    /// output directory, print the results and exit without rendering.
+    #[arg(long, env = "RENDER_DRY_RUN")]
+    pub dry_run: bool,
+
+    /// Log format on stderr. The summary lines such as `TOTAL` stay on stdout.
+    #[arg(
+        long,
+        env = "RENDER_LOG_FORMAT",
+        value_enum,
+        ignore_case = true,
+        default_value = "text"
+    )]
+    pub log_format: LogFormat,
+
+    /// Write stage, per-frame and per-worker timings, settings and segment checks to
+    /// this JSON file when the render finishes.
+    #[arg(long, env = "RENDER_REPORT", conflicts_with = "jobs")]
+    pub report: Option<PathBuf>,
+
+    /// Render every entry of this JSON job list instead of a single composition.
+    /// Each entry gives `output`, `width`, `height`, `fps` and `frames`, and may give
+    /// `id`, `pageUrl`, `composition` (added to the page URL as `composition=`) and
+    /// `report`. Every other option applies to all jobs.
+    #[arg(long, env = "RENDER_JOBS")]
+    pub jobs: Option<PathBuf>,
+
+    /// Jobs rendered at the same time with `--jobs`. With `--browser-mode tabs`, jobs
+    /// of the same size share one browser. Above 1 only with `--standalone`.
+    #[arg(
+        long,
+        env = "RENDER_PARALLEL_JOBS",
+        default_value_t = 1,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    pub parallel_jobs: usize,
+
+    /// With `--jobs`, start no further jobs once one has failed.
+    #[arg(long, env = "RENDER_FAIL_FAST")]
+    pub fail_fast: bool,
+
+    /// Id of the `--jobs` entry these arguments were made for.
+    #[arg(skip)]
+    pub job_id: Option<String>,
+
+    /// Where the finished video is written.
+    #[arg(long, env = "RENDER_OUTPUT_PATH", default_value = "output.mp4")]
+    pub output: PathBuf,
+
+    /// Replace `--output` if it already exists.
+    #[arg(long, env = "RENDER_OVERWRITE")]
+    pub overwrite: bool,
+
+    /// Directory for segments and other intermediate files. Defaults to a directory
+    /// under the system temp dir named after `--output`, so `--resume` finds it again.
+    /// Renders only ever delete the files they recorded creating there.
+    #[arg(long, env = "RENDER_WORK_DIR", conflicts_with = "jobs")]
+    pub work_dir: Option<PathBuf>,
+
+    /// Render page URL. Dev defaults to the Vite dev server; Electron passes a
+    /// `file://.../dist-render/render.html` URL outside dev.
+    #[arg(long, env = "RENDER_PAGE_URL")]
+    pub page_url: Option<String>,
+
+    /// Do not append workerId, startFrame, endFrame, fps, width and height to the
+    /// render page URL.
+    #[arg(long, env = "RENDER_NO_URL_PARAMS")]
+    pub no_url_params: bool,
+
+    /// Run without the backend: no progress, cancel, reset or audio plan requests.
+    /// Progress goes to `--progress-file` or stdout and SIGINT/SIGTERM cancel the
+    /// render. Also used when the backend does not answer `--healthz-url` at start.
+    #[arg(long, env = "RENDER_STANDALONE")]
+    pub standalone: bool,
+
+    /// With `--standalone`, keep the latest progress update in this JSON file
+    /// instead of printing it.
+    #[arg(long, env = "RENDER_PROGRESS_FILE")]
+    pub progress_file: Option<PathBuf>,
+
+    /// How often progress is checked, in milliseconds. An update is only sent when
+    /// the count or stage changed, or at least once a second as a heartbeat.
+    #[arg(
+        long,
+        env = "RENDER_PROGRESS_INTERVAL_MS",
+        default_value_t = 50,
+        value_parser = clap::value_parser!(u64).range(10..)
+    )]
+    pub progress_interval_ms: u64,
+
+    /// How often the backend is asked whether the render was canceled, in
+    /// milliseconds. Backs off up to 30 s while the backend does not answer.
+    #[arg(
+        long,
+        env = "RENDER_CANCEL_INTERVAL_MS",
+        default_value_t = 1000,
+        value_parser = clap::value_parser!(u64).range(50..)
+    )]
+    pub cancel_interval_ms: u64,
+
+    #[arg(
+        long,
+        env = "RENDER_HEALTHZ_URL",
+        default_value = "http://127.0.0.1:3000/healthz"
+    )]
+    pub healthz_url: String,
+
+    #[arg(
+        long,
+        env = "RENDER_PROGRESS_URL",
+        default_value = "http://127.0.0.1:3000/render_progress"
+    )]
+    pub progress_url: String,
+
+    #[arg(
+        long,
+        env = "RENDER_CANCEL_URL",
+        default_value = "http://127.0.0.1:3000/is_canceled"
+    )]
+    pub cancel_url: String,
+
+    #[arg(
+        long,
+        env = "RENDER_RESET_URL",
+        default_value = "http://127.0.0.1:3000/reset"
+    )]
+    pub reset_url: String,
+
+    #[arg(
+        long,
+        env = "RENDER_AUDIO_PLAN_URL",
+        default_value = "http://127.0.0.1:3000/render_audio_plan"
+    )]
+    pub audio_plan_url: String,
+}
+
+impl RenderArgs {
+    /// Parse the process arguments. A first argument without leading dashes is the
+    /// legacy `width:height:fps:frames:workers:encode:preset` string Electron passes.
+    pub fn parse_with_legacy() -> Self {
+        let args = Self::parse_from(expand_legacy(std::env::args_os().collect()));
+        if let Err(message) = args.validate() {
+            Self::command()
+                .error(ErrorKind::ValueValidation, message)
+                .exit();
+        }
+        args
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let codec = self.codec();
+        if self.transparent && !codec.keeps_alpha() {
+            return Err(format!(
+                "--transparent needs an alpha-capable codec (prores4444, qtrle or vp9), not {}",
+                codec.encoder()
+            ));
+        }
+        if !self.container().holds(codec) {
+            return Err(format!(
+                "{} cannot be written to .{}",
+                codec.encoder(),
+                self.container().extension()
+            ));
+        }
+        match (codec.crf_range(), self.crf) {
+            (None, _) if self.crf.is_some() || self.bitrate.is_some() => {
+                return Err(format!(
+                    "{} has no rate control; drop --crf/--bitrate",
+                    codec.encoder()
+                ));
+            }
+            (Some(range), Some(crf)) if !range.contains(&crf) => {
+                return Err(format!(
+                    "--crf {crf} is out of range for {} ({}..={})",
+                    codec.encoder(),
+                    range.start(),
+                    range.end()
+                ));
+            }
+            _ => {}
+        }
+        if let Some(format) = self.pix_fmt
+            && !codec.encoder().takes_pixel_format(format.as_str())
+        {
+            return Err(format!(
+                "--pix-fmt {} is not supported with {}",
+                format.as_str(),
+                codec.encoder()
+            ));
+        }
+        if codec.keeps_alpha() && self.capture == CaptureMode::Jpeg {
+            return Err(format!(
+                "--capture jpeg has no alpha channel; use png or raw with {}",
+                codec.encoder()
+            ));
+        }
+
+        // バックエンドの進捗とオーディオプランは 1 つの描画分しかない
+        if self.parallel_jobs > 1 && !self.standalone {
+            return Err(
+                "--parallel-jobs above 1 needs --standalone; the backend follows one render at a time"
+                    .to_string(),
+            );
+        }
+
+        let audio_extension = match self.output_mode {
+            OutputMode::Video => Some(self.container().extension()),
+            OutputMode::Audio => Some(self.audio_format.extension()),
+            OutputMode::Frames if self.frames_audio => Some("m4a"),
+            OutputMode::Frames | OutputMode::Gif | OutputMode::Webp => None,
+        };
+        if let Some(extension) = audio_extension {
+            self.audio_encode().check(extension)?;
+        }
+
+        let end = self.end_frame.unwrap_or(self.frames);
+        if end > self.frames {
+            return Err(format!(
+                "--end-frame {end} is past the end of the composition ({} frames)",
+                self.frames
+            ));
+        }
+        if self.start_frame >= end {
+            return Err(format!(
+                "--start-frame {} must be before --end-frame {end}",
+                self.start_frame
+            ));
+        }
+        if let Some(clip) = self.clip
+            && (clip.x as u64 + clip.width as u64 > self.width as u64
+                || clip.y as u64 + clip.height as u64 > self.height as u64)
+        {
+            return Err(format!(
+                "--clip {},{},{},{} does not fit in the {}x{} page",
+                clip.x, clip.y, clip.width, clip.height, self.width, self.height
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec.unwrap_or(if self.transparent {
+            Codec::Prores4444
+        } else {
+            Codec::H264
+        })
+    }
+
+    pub fn container(&self) -> Container {
+        self.container
+            .unwrap_or_else(|| self.codec().default_container())
+    }
+
+    pub fn quality(&self) -> Quality {
+        match self.bitrate {
+            Some(bits) => Quality::Bitrate(bits),
+            None => Quality::Crf(self.crf.unwrap_or(18)),
+        }
+    }
+
+    pub fn tuning(&self) -> EncoderTuning {
+        EncoderTuning {
+            tune: self.tune.clone(),
+            profile: self.profile.clone(),
+            level: self.level.clone(),
+            params: self.encoder_params.clone(),
+        }
+    }
+
+    pub fn audio_encode(&self) -> AudioEncode {
+        AudioEncode {
+            codec: self.audio_codec,
+            bitrate: self.audio_bitrate,
+            sample_rate: self.audio_sample_rate,
+        }
+    }
+
+    pub fn progress_interval(&self) -> Duration {
+        Duration::from_millis(self.progress_interval_ms)
+    }
+
+    pub fn cancel_interval(&self) -> Duration {
+        Duration::from_millis(self.cancel_interval_ms)
+    }
+
+    pub fn progress_target(&self) -> ProgressTarget {
+        match (&self.progress_file, self.standalone) {
+            (_, false) => ProgressTarget::Backend(self.progress_url.clone()),
+            (Some(path), true) => ProgressTarget::File(path.clone()),
+            (None, true) => ProgressTarget::Stdout,
+        }
+    }
+
+    /// Fixed keyframe interval in frames, `None` with `--no-fixed-gop`.
+    pub fn gop(&self) -> Option<u32> {
+        if self.no_fixed_gop {
+            return None;
+        }
+        Some(self.gop.unwrap_or((self.fps.round() as u32).max(1)))
+    }
+
+    /// Frames to render, as absolute composition frame numbers.
+    pub fn frame_range(&self) -> Range<usize> {
+        self.start_frame..self.end_frame.unwrap_or(self.frames)
+    }
+
+    pub fn work_dir(&self) -> PathBuf {
+        if let Some(dir) = &self.work_dir {
+            return dir.clone();
+        }
+        let output = std::path::absolute(&self.output).unwrap_or_else(|_| self.output.clone());
+        let mut hasher = DefaultHasher::new();
+        output.hash(&mut hasher);
+        std::env::temp_dir().join(format!("framescript-render-{:016x}", hasher.finish()))
+    }
+
+    pub fn page_url(&self) -> String {
+        self.page_url
+            .clone()
+            .or_else(|| std::env::var("RENDER_DEV_SERVER_URL").ok())
+            .unwrap_or_else(|| "http://localhost:5174/render".to_string())
+    }
+}
+
+const LEGACY_FIELDS: [&str; 7] = [
+    "--width",
+    "--height",
+    "--fps",
+    "--frames",
+    "--workers",
+    "--codec",
+    "--preset",
+];
+
+fn expand_legacy(args: Vec<OsString>) -> Vec<OsString> {
+    let Some(first) = args.get(1).and_then(|arg| arg.to_str()) else {
+        return args;
+    };
+    if first.starts_with('-') {
+        return args;
+    }
+
+    let fields = first.split(':').collect::<Vec<_>>();
+    if fields.len() != LEGACY_FIELDS.len() {
+        clap::Error::raw(
+            clap::error::ErrorKind::ValueValidation,
+            format!(
+                "legacy argument '{first}' must be width:height:fps:frames:workers:encode:preset \
+                 ({} fields, got {})\n",
+                LEGACY_FIELDS.len(),
+                fields.len()
+            ),
+        )
+        .exit();
+    }
+
+    let mut expanded = vec![args[0].clone()];
+    for (flag, value) in LEGACY_FIELDS.iter().zip(fields) {
+        expanded.push(flag.into());
+        expanded.push(value.into());
+    }
+    expanded.extend(args.into_iter().skip(2));
+    expanded
+}
+
+/// `8M`, `2500k` or plain bits per second.
+fn parse_bitrate(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (number, scale) = match value.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&value[..i], 1_000.0),
+        Some((i, 'm' | 'M')) => (&value[..i], 1_000_000.0),
+        _ => (value, 1.0),
+    };
+    match number.parse::<f64>() {
+        Ok(n) if n.is_finite() && n > 0.0 => Ok((n * scale).round() as u64),
+        Ok(_) => Err("must be a positive bitrate".to_string()),
+        Err(_) => Err(format!("'{value}' is not a bitrate like 8M or 2500k")),
+    }
+}
+
+/// `key=value` with a non-empty key.
+fn parse_key_value(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => {
+            Ok((key.trim().to_string(), value.trim().to_string()))
+        }
+        _ => Err(format!("'{value}' is not key=value")),
+    }
+}
+
+fn parse_fps(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(fps) if fps.is_finite() && fps > 0.0 => Ok(fps),
+        Ok(_) => Err("must be a positive number".to_string()),
+        Err(error) => Err(error.to_string()),
+    }
+}
+
+/// `x,y,w,h` with a non-empty size.
+fn parse_clip(value: &str) -> Result<Clip, String> {
+    let parts = value
+        .split(',')
+        .map(|part| part.trim().parse::<u32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| format!("'{value}' is not x,y,w,h"))?;
+    let [x, y, width, height] = parts[..] else {
+        return Err(format!("'{value}' is not x,y,w,h"));
+    };
+    if width == 0 || height == 0 {
+        return Err("clip width and height must be at least 1".to_string());
+    }
+    Ok(Clip {
+        x,
+        y,
+        width,
+        height,
+    })
+}
+
+fn parse_lufs(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(lufs) if (-70.0..=-5.0).contains(&lufs) => Ok(lufs),
+        Ok(_) => Err("must be between -70 and -5 LUFS".to_string()),
+        Err(error) => Err(error.to_string()),
+    }
+}
+
+/// Up to 4×: a 4K frame at 4× is already over 500 MB of RGBA per worker.
+fn parse_scale(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(scale) if scale.is_finite() && scale > 0.0 && scale <= 4.0 => Ok(scale),
+        Ok(_) => Err("must be greater than 0 and at most 4".to_string()),
+        Err(error) => Err(error.to_string()),
+    }
+}
+
+fn parse_seconds(value: &str) -> Result<Duration, String> {
+    match value.parse::<f64>() {
+        Ok(secs) if secs.is_finite() && secs > 0.0 => Ok(Duration::from_secs_f64(secs)),
+        Ok(_) => Err("must be a positive number of seconds".to_string()),
+        Err(error) => Err(error.to_string()),
+    }
+}
+
+fn parse_workers(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(0) => Err("at least one worker is required".to_string()),
+        Ok(workers) => Ok(workers),
+        Err(error) => Err(error.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `render` with `extra`, parsed and validated like `parse_with_legacy` does.
+    fn parse(extra: &[&str]) -> Result<RenderArgs, String> {
+        let argv = ["render"].iter().chain(extra).map(OsString::from).collect();
+        let args =
+            RenderArgs::try_parse_from(expand_legacy(argv)).map_err(|error| error.to_string())?;
+        args.validate()?;
+        Ok(args)
+    }
+
+    /// `parse` with a 64x36, 30 fps, 4 frame composition before `extra`.
+    fn parse_page(extra: &[&str]) -> Result<RenderArgs, String> {
+        let mut argv = vec!["--width=64", "--height=36", "--fps=30", "--frames=4"];
+        argv.extend(extra);
+        parse(&argv)
+    }
+
+    #[test]
+    fn legacy_strings_expand_to_flags() {
+        let args = parse(&["1920:1080:30:90:4:h265:fast", "--standalone"]).unwrap();
+        assert_eq!((args.width, args.height, args.fps), (1920, 1080, 30.0));
+        assert_eq!((args.frames, args.workers), (90, 4));
+        assert_eq!(args.codec, Some(Codec::H265));
+        assert_eq!(args.preset, "fast");
+        assert!(args.standalone);
+    }
+
+    #[test]
+    fn flags_are_not_taken_for_a_legacy_string() {
+        let argv = ["render", "--width", "64"].map(OsString::from).to_vec();
+        assert_eq!(expand_legacy(argv.clone()), argv);
+    }
+
+    #[test]
+    fn transparency_needs_an_alpha_codec() {
+        assert_eq!(
+            parse_page(&["--transparent", "--codec", "h264"]).unwrap_err(),
+            "--transparent needs an alpha-capable codec (prores4444, qtrle or vp9), not H264"
+        );
+        let args = parse_page(&["--transparent"]).unwrap();
+        assert_eq!(args.codec(), Codec::Prores4444);
+        assert_eq!(args.container(), Container::Mov);
+        parse_page(&["--transparent", "--codec", "vp9"]).unwrap();
+    }
+
+    #[test]
+    fn crf_is_checked_against_the_codec() {
+        parse_page(&["--crf", "51"]).unwrap();
+        assert_eq!(
+            parse_page(&["--crf", "52"]).unwrap_err(),
+            "--crf 52 is out of range for H264 (0..=51)"
+        );
+        parse_page(&["--codec", "vp9", "--crf", "63"]).unwrap();
+        assert_eq!(
+            parse_page(&["--codec", "h264_qsv", "--crf", "0"]).unwrap_err(),
+            "--crf 0 is out of range for h264_qsv (1..=51)"
+        );
+        assert_eq!(
+            parse_page(&["--codec", "prores4444", "--crf", "10"]).unwrap_err(),
+            "prores4444 has no rate control; drop --crf/--bitrate"
+        );
+    }
+
+    #[test]
+    fn the_clip_has_to_fit_in_the_page() {
+        let args = parse_page(&["--clip", "32,0,32,36"]).unwrap();
+        assert_eq!(
+            args.clip,
+            Some(Clip {
+                x: 32,
+                y: 0,
+                width: 32,
+                height: 36
+            })
+        );
+        assert_eq!(
+            parse_page(&["--clip", "33,0,32,36"]).unwrap_err(),
+            "--clip 33,0,32,36 does not fit in the 64x36 page"
+        );
+        let error = parse_page(&["--clip", "1,2,3"]).unwrap_err();
+        assert!(error.contains("'1,2,3' is not x,y,w,h"), "{error}");
+        let error = parse_page(&["--clip", "0,0,0,10"]).unwrap_err();
+        assert!(
+            error.contains("clip width and height must be at least 1"),
+            "{error}"
+        );
+    }
+}
@@ -0,0 +1,197 @@
+use std::path::Path;
+use std::process::Stdio;
+
+use reqwest::{Client, Method};
+use tokio::process::Command as TokioCommand;
+
+use crate::cli::{OutputMode, RenderArgs};
+use crate::ffmpeg::{check_encoder_options, ffmpeg_version, resolve_checked_ffmpeg, usable_encoder};
+use crate::worker::{WorkerConfig, check_render_page};
+
+/// Outcome of one `--dry-run` check.
+enum Check {
+    Ok(String),
+    /// The render would still run, but not as asked.
+    Warn(String),
+    Fail(String),
+}
+
+#[derive(Default)]
+struct Report {
+    failed: usize,
+}
+
+impl Report {
+    fn record(&mut self, name: &str, check: Check) {
+        let (status, detail) = match check {
+            Check::Ok(detail) => ("ok", detail),
+            Check::Warn(detail) => ("warn", detail),
+            Check::Fail(detail) => {
+                self.failed += 1;
+                ("FAIL", detail)
+            }
+        };
+        println!("[dry-run] {status:<4} {name}: {detail}");
+    }
+}
+
+async fn check_ffmpeg() -> Check {
+    match resolve_checked_ffmpeg() {
+        Ok(ffmpeg) => match ffmpeg_version() {
+            Some(version) => Check::Ok(format!("{ffmpeg} (ffmpeg {version})")),
+            // no release number (e.g. a git build), or it does not run at all
+            None => match TokioCommand::new(&ffmpeg)
+                .arg("-version")
+                .stdin(Stdio::null())
+                .output()
+                .await
+            {
+                Ok(output) if output.status.success() => {
+                    Check::Ok(format!("{ffmpeg} (version unknown)"))
+                }
+                Ok(output) => {
+                    Check::Fail(format!("{ffmpeg} -version exited with {}", output.status))
+                }
+                Err(error) => Check::Fail(format!("cannot run {ffmpeg}: {error}")),
+            },
+        },
+        Err(error) => Check::Fail(error.to_string()),
+    }
+}
+
+async fn check_encoder(args: &RenderArgs) -> Check {
+    let codec = args.codec().encoder();
+    let encode = match usable_encoder(codec, args.quality(), &args.preset).await {
+        Ok(encode) => encode,
+        Err(error) => return Check::Fail(error.to_string()),
+    };
+    let pixel_format = args.pix_fmt.map(|format| format.as_str());
+    let tuning = args.tuning();
+    if (pixel_format.is_some() || !tuning.is_empty())
+        && let Err(error) =
+            check_encoder_options(encode, args.quality(), &args.preset, pixel_format, &tuning).await
+    {
+        return Check::Fail(error.to_string());
+    }
+    match encode {
+        encode if encode == codec => Check::Ok(encode.to_string()),
+        encode => Check::Warn(format!(
+            "{codec} is not usable here, would fall back to {encode}"
+        )),
+    }
+}
+
+/// `dir` takes a new file. A directory the render would create is checked at its
+/// nearest existing ancestor.
+fn check_writable(dir: &Path) -> Check {
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    let Some(existing) = dir.ancestors().find(|path| path.is_dir()) else {
+        return Check::Fail(format!(
+            "no existing parent directory for {}",
+            dir.display()
+        ));
+    };
+    match tempfile::Builder::new()
+        .prefix(".framescript-dry-run-")
+        .tempfile_in(existing)
+    {
+        Ok(_) => Check::Ok(format!("{} is writable", dir.display())),
+        Err(error) => Check::Fail(format!("cannot write to {}: {error}", existing.display())),
+    }
+}
+
+/// `url` answers `method` with a success status.
+async fn check_endpoint(client: &Client, method: Method, url: &str) -> Check {
+    match client.request(method.clone(), url).send().await {
+        Ok(response) if response.status().is_success() => Check::Ok(format!("{method} {url}")),
+        Ok(response) => Check::Fail(format!("{method} {url} returned {}", response.status())),
+        Err(error) => Check::Fail(format!("{method} {url}: {error}")),
+    }
+}
+
+/// Run every check a render depends on through the code the render itself uses,
+/// print one line per check and fail if any of them failed.
+pub async fn dry_run(
+    args: &RenderArgs,
+    config: &WorkerConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut report = Report::default();
+    let renders_frames = args.output_mode != OutputMode::Audio;
+
+    report.record("ffmpeg", check_ffmpeg().await);
+    if renders_frames && args.output_mode != OutputMode::Frames {
+        report.record("encoder", check_encoder(args).await);
+    }
+
+    match (&args.frames_dir, args.output_mode) {
+        (Some(dir), OutputMode::Frames) => report.record("frames dir", check_writable(dir)),
+        _ => {
+            let output = match crate::check_overwrite(&args.output, args.overwrite) {
+                Ok(()) => check_writable(args.output.parent().unwrap_or(Path::new("."))),
+                Err(error) => Check::Fail(error),
+            };
+            report.record("output", output);
+            report.record("work dir", check_writable(&args.work_dir()));
+        }
+    }
+
+    let client = crate::progress::http_client();
+    if args.standalone {
+        let progress = match &args.progress_file {
+            Some(path) => check_writable(path.parent().unwrap_or(Path::new("."))),
+            None => Check::Ok("printed to stdout".to_string()),
+        };
+        report.record("progress", progress);
+    } else {
+        report.record(
+            "progress",
+            check_endpoint(client, Method::GET, &args.progress_url).await,
+        );
+        report.record(
+            "cancel",
+            check_endpoint(client, Method::GET, &args.cancel_url).await,
+        );
+        // reset は呼ぶとデコーダーのキャッシュが消えるので OPTIONS で疎通だけ見る
+        report.record(
+            "reset",
+            check_endpoint(client, Method::OPTIONS, &args.reset_url).await,
+        );
+    }
+    let audio_plan = match &args.audio_plan {
+        Some(path) => match crate::read_audio_plan(path).await {
+            Ok(plan) => Check::Ok(format!(
+                "{} ({} segments)",
+                path.display(),
+                plan.segments.len()
+            )),
+            Err(error) => Check::Fail(error.to_string()),
+        },
+        None if args.standalone => Check::Ok("none (standalone)".to_string()),
+        None => check_endpoint(client, Method::GET, &args.audio_plan_url).await,
+    };
+    report.record("audio plan", audio_plan);
+
+    if renders_frames {
+        let page = match check_render_page(config, args.frame_range()).await {
+            Ok(()) => Check::Ok(format!(
+                "{} loaded with {}",
+                config.page_url,
+                config.browser.describe()
+            )),
+            Err(error) => Check::Fail(error),
+        };
+        report.record("render page", page);
+    }
+
+    match report.failed {
+        0 => {
+            println!("[dry-run] all checks passed");
+            Ok(())
+        }
+        failed => Err(format!("{failed} dry-run check(s) failed").into()),
+    }
+}
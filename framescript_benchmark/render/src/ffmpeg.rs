@@ -1,18 +1,33 @@
 use std::{
     error::Error,
-    collections::BTreeMap,
+    collections::{BTreeMap, VecDeque},
     io,
+    ops::Range,
     path::{Path, PathBuf},
-    process::Stdio,
-    sync::{Mutex, OnceLock},
+    process::{ExitStatus, Stdio},
+    sync::{
+        Arc, Mutex, OnceLock,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
 };
 
 use serde::Deserialize;
 use tokio::{
     fs,
-    io::AsyncWriteExt,
-    process::{Child, ChildStdin, Command as TokioCommand},
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    process::{Child, ChildStderr, ChildStdin, ChildStdout, Command as TokioCommand},
+    task::JoinHandle,
 };
+use tracing::{debug, warn};
+
+#[path = "../../backend/src/ffmpeg/version.rs"]
+mod version;
+
+#[cfg(test)]
+pub(crate) mod fixtures;
+
+pub use version::FfmpegVersion;
 
 static FFMPEG_PATH: OnceLock<Mutex<Option<String>>> = OnceLock::new();
 
@@ -37,7 +52,10 @@ fn resolve_ffmpeg_path() -> Result<String, Box<dyn Error>> {
         .arg("-version")
         .output()
     {
-        Ok(_) => {
+        Ok(output) => {
+            // keep the version so the minimum check does not run ffmpeg again
+            FFMPEG_VERSION
+                .get_or_init(|| FfmpegVersion::parse(&String::from_utf8_lossy(&output.stdout)));
             let path = "ffmpeg".to_string();
             *cached = Some(path.clone());
             Ok(path)
@@ -54,343 +72,4141 @@ fn resolve_ffmpeg_path() -> Result<String, Box<dyn Error>> {
     }
 }
 
-pub struct SegmentWriter {
-    child: Child,
-    stdin: ChildStdin,
-}
-
-impl SegmentWriter {
-    pub async fn new(
-        output_path: &str,
-        width: u32,
-        height: u32,
-        fps: f64,
-        crf: u32,
-        encode: &str,
-        preset: Option<&str>,
-        gop: Option<u32>,
-    ) -> Result<Self, Box<dyn std::error::Error>> {
-        let vcodec = match encode {
-            "H264" => "libx264",
-            "H265" => "libx265",
-            _ => return Err(format!("Unsupported encode: {}", encode).into()),
-        };
-
-        let preset = preset.unwrap_or("medium");
-
-        let ffmpeg = resolve_ffmpeg_path()?;
-        let mut cmd = TokioCommand::new(ffmpeg);
-        cmd.arg("-y")
-            .arg("-hide_banner")
-            .arg("-loglevel")
-            .arg("error")
-            .arg("-f")
-            .arg("image2pipe")
-            .arg("-vcodec")
-            .arg("png")
-            .arg("-framerate")
-            .arg(format!("{}", fps))
-            .arg("-s")
-            .arg(format!("{}x{}", width, height))
-            .arg("-i")
-            .arg("pipe:0")
-            .arg("-r")
-            .arg(format!("{}", fps))
-            .arg("-c:v")
-            .arg(vcodec)
-            .arg("-preset")
-            .arg(preset)
-            .arg("-crf")
-            .arg(crf.to_string())
-            .arg("-pix_fmt")
-            .arg("yuv420p")
-            .arg("-movflags")
-            .arg("+faststart");
-
-        if let Some(g) = gop {
-            cmd.arg("-g")
-                .arg(g.to_string())
-                .arg("-keyint_min")
-                .arg(g.to_string())
-                .arg("-sc_threshold")
-                .arg("0");
-        }
-
-        cmd.arg(output_path)
-            .stdin(Stdio::piped())
-            .stdout(Stdio::null())
-            .stderr(Stdio::inherit());
-
-        let mut child = cmd.spawn().map_err(|e| {
-            format!(
-                "Failed to spawn ffmpeg. Is ffmpeg installed and on PATH? error={}",
-                e
-            )
-        })?;
-
-        let stdin = child
-            .stdin
-            .take()
-            .ok_or_else(|| "Failed to open ffmpeg stdin".to_string())?;
-
-        Ok(Self { child, stdin })
-    }
-
-    pub async fn write_png_frame(&mut self, png: &[u8]) -> Result<(), Box<dyn Error>> {
-        self.stdin.write_all(png).await?;
-        Ok(())
+/// `FRAMESCRIPT_FFPROBE_PATH`, otherwise the ffprobe next to the resolved ffmpeg.
+fn resolve_ffprobe_path() -> Result<String, Box<dyn Error>> {
+    if let Some(path) = read_env_path("FRAMESCRIPT_FFPROBE_PATH") {
+        return Ok(path);
     }
 
-    pub async fn finish(mut self) -> Result<(), Box<dyn Error>> {
-        self.stdin.shutdown().await?;
-        drop(self.stdin);
-
-        let status = self.child.wait().await?;
-        if !status.success() {
-            return Err(format!("ffmpeg exited with status: {}", status).into());
-        }
-        Ok(())
+    let ffmpeg = resolve_ffmpeg_path()?;
+    let ffmpeg = Path::new(&ffmpeg);
+    match ffmpeg.file_name().and_then(|name| name.to_str()) {
+        Some(name) if name.contains("ffmpeg") => Ok(ffmpeg
+            .with_file_name(name.replacen("ffmpeg", "ffprobe", 1))
+            .to_string_lossy()
+            .into_owned()),
+        _ => Ok("ffprobe".to_string()),
     }
 }
 
-fn escape_concat_path(p: &str) -> String {
-    p.replace('\'', r"'\''")
+/// Number of video frames in `path`, or `None` when ffprobe cannot read it
+/// (missing, truncated, no moov atom, ...).
+pub async fn probe_video_frame_count(path: &Path) -> Result<Option<usize>, Box<dyn Error>> {
+    count_video_stream(path, "-count_packets", "stream=nb_read_packets").await
 }
 
-fn normalize_concat_path(path: &str) -> String {
-    if cfg!(windows) {
-        let mut normalized = path.to_string();
-        if let Some(rest) = normalized.strip_prefix(r"\\?\UNC\") {
-            normalized = format!(r"\\{}", rest);
-        } else if let Some(rest) = normalized.strip_prefix(r"\\?\") {
-            normalized = rest.to_string();
-        }
-        normalized.replace('\\', "/")
-    } else {
-        path.to_string()
-    }
+/// Frames of `path` that actually decode, which for a file cut off mid-write can be
+/// fewer than its packets. Slower than `probe_video_frame_count`.
+pub async fn probe_decodable_frames(path: &Path) -> Result<Option<usize>, Box<dyn Error>> {
+    count_video_stream(path, "-count_frames", "stream=nb_read_frames").await
 }
 
-pub async fn concat_segments_mp4(
-    segments: Vec<PathBuf>,
-    output_path: &Path,
-) -> Result<(), Box<dyn Error>> {
-    if segments.is_empty() {
-        return Err("No segment files.".into());
+async fn count_video_stream(
+    path: &Path,
+    count: &str,
+    entry: &str,
+) -> Result<Option<usize>, Box<dyn Error>> {
+    let ffprobe = resolve_ffprobe_path()?;
+    let output = TokioCommand::new(ffprobe)
+        .arg("-v")
+        .arg("error")
+        .arg("-select_streams")
+        .arg("v:0")
+        .arg(count)
+        .arg("-show_entries")
+        .arg(entry)
+        .arg("-of")
+        .arg("csv=p=0")
+        .arg(path)
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        return Ok(None);
     }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse::<usize>()
+        .ok())
+}
 
-    let list_path = output_path.with_extension("segments.txt");
-    let list_dir = list_path.parent().unwrap_or_else(|| Path::new("."));
-    let list_dir_abs = tokio::task::spawn_blocking({
-        let list_dir = list_dir.to_path_buf();
-        move || std::fs::canonicalize(&list_dir).unwrap_or(list_dir)
-    })
-    .await?;
+/// First video stream of a file, as ffprobe reads it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VideoProbe {
+    pub width: u32,
+    pub height: u32,
+    pub frames: usize,
+}
 
-    let mut lines = String::new();
-    for seg in segments {
-        let abs_path = tokio::task::spawn_blocking(move || std::fs::canonicalize(seg))
-            .await??;
-        let rel_path = match abs_path.strip_prefix(&list_dir_abs) {
-            Ok(rel) => rel.to_path_buf(),
-            Err(_) => abs_path,
-        };
-        let abs = normalize_concat_path(rel_path.to_string_lossy().as_ref());
+/// Size and frame count of `path`, or `None` when ffprobe cannot read it or it has
+/// no video stream.
+pub async fn probe_video(path: &Path) -> Result<Option<VideoProbe>, Box<dyn Error>> {
+    let ffprobe = resolve_ffprobe_path()?;
+    let output = TokioCommand::new(ffprobe)
+        .arg("-v")
+        .arg("error")
+        .arg("-select_streams")
+        .arg("v:0")
+        .arg("-count_packets")
+        .arg("-show_entries")
+        .arg("stream=width,height,nb_read_packets")
+        .arg("-of")
+        .arg("default=noprint_wrappers=1")
+        .arg(path)
+        .stdin(Stdio::null())
+        .output()
+        .await?;
 
-        lines.push_str("file '");
-        lines.push_str(&escape_concat_path(&abs));
-        lines.push_str("'\n");
+    if !output.status.success() {
+        return Ok(None);
     }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let field = |key: &str| {
+        stdout
+            .lines()
+            .find_map(|line| line.strip_prefix(key)?.strip_prefix('='))
+            .and_then(|value| value.trim().parse::<usize>().ok())
+    };
+    Ok(match (field("width"), field("height"), field("nb_read_packets")) {
+        (Some(width), Some(height), Some(frames)) => Some(VideoProbe {
+            width: width as u32,
+            height: height as u32,
+            frames,
+        }),
+        _ => None,
+    })
+}
 
-    fs::write(&list_path, lines).await?;
+/// First audio stream of a file, as ffprobe reads it.
+struct AudioProbe {
+    codec: String,
+    sample_rate: u32,
+    channels: u32,
+    /// Seconds of the stream, or of the file when the container does not say.
+    duration: Option<f64>,
+}
 
-    let ffmpeg = resolve_ffmpeg_path()?;
-    let status = TokioCommand::new(ffmpeg)
-        .arg("-y")
-        .arg("-hide_banner")
-        .arg("-loglevel")
+/// Codec, sample rate, channels and duration of the first audio stream of `path`,
+/// or `None` when ffprobe cannot read it or it has no audio.
+async fn probe_audio(path: &Path) -> Result<Option<AudioProbe>, Box<dyn Error>> {
+    let ffprobe = resolve_ffprobe_path()?;
+    let output = TokioCommand::new(ffprobe)
+        .arg("-v")
         .arg("error")
-        .arg("-f")
-        .arg("concat")
-        .arg("-safe")
-        .arg("0")
-        .arg("-i")
-        .arg(&list_path)
-        .arg("-c")
-        .arg("copy")
-        .arg("-movflags")
-        .arg("+faststart")
-        .arg(output_path)
+        .arg("-select_streams")
+        .arg("a:0")
+        .arg("-show_entries")
+        .arg("stream=codec_name,sample_rate,channels,duration:format=duration")
+        .arg("-of")
+        .arg("default=noprint_wrappers=1")
+        .arg(path)
         .stdin(Stdio::null())
-        .stdout(Stdio::null())
-        .stderr(Stdio::inherit())
-        .status()
+        .output()
         .await?;
 
-    if !status.success() {
-        return Err(format!("ffmpeg concat failed: {}", status).into());
+    if !output.status.success() {
+        return Ok(None);
     }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let field = |key: &str| {
+        stdout
+            .lines()
+            .find_map(|line| line.strip_prefix(key)?.strip_prefix('='))
+            .map(|value| value.trim().to_string())
+    };
+    let number = |key: &str| field(key).and_then(|value| value.parse::<u32>().ok());
+    // ストリームの duration が N/A なら次に出るフォーマットの方を使う
+    let duration = stdout
+        .lines()
+        .filter_map(|line| line.strip_prefix("duration="))
+        .find_map(|value| value.trim().parse::<f64>().ok());
+    Ok(match (field("codec_name"), number("sample_rate"), number("channels")) {
+        (Some(codec), Some(sample_rate), Some(channels)) => Some(AudioProbe {
+            codec,
+            sample_rate,
+            channels,
+            duration,
+        }),
+        _ => None,
+    })
+}
 
-    Ok(())
+static FFMPEG_VERSION: OnceLock<Option<FfmpegVersion>> = OnceLock::new();
+
+/// Version of the resolved ffmpeg, `None` if it could not be run or parsed. Only an
+/// ffmpeg from `FRAMESCRIPT_FFMPEG_PATH` has not been run by the resolver yet.
+pub fn ffmpeg_version() -> Option<FfmpegVersion> {
+    *FFMPEG_VERSION.get_or_init(|| {
+        let ffmpeg = resolve_ffmpeg_path().ok()?;
+        let output = std::process::Command::new(ffmpeg)
+            .arg("-version")
+            .output()
+            .ok()?;
+        FfmpegVersion::parse(&String::from_utf8_lossy(&output.stdout))
+    })
 }
 
-#[derive(Debug, Clone, Deserialize)]
-#[serde(tag = "kind", rename_all = "lowercase")]
-pub enum AudioSourceResolved {
-    Video { path: String },
-    Sound { path: String },
+/// Resolve ffmpeg and fail fast when it is older than the minimum, instead of
+/// hitting an unknown filter option halfway through a render.
+pub fn resolve_checked_ffmpeg() -> Result<String, Box<dyn Error>> {
+    let ffmpeg = resolve_ffmpeg_path()?;
+    let found = ffmpeg_version();
+    let required = FfmpegVersion::minimum();
+    match found {
+        Some(found) if found < required => Err(format!(
+            "ffmpeg {found} found at {ffmpeg}, but {required} or newer is required \
+             (set FRAMESCRIPT_FFMPEG_PATH to a newer build)"
+        )
+        .into()),
+        _ => Ok(ffmpeg),
+    }
 }
 
-#[derive(Debug, Clone, Deserialize)]
-pub struct AudioSegmentResolved {
-    pub id: String,
-    pub source: AudioSourceResolved,
-    #[serde(rename = "projectStartFrame")]
-    pub project_start_frame: i64,
-    #[serde(rename = "sourceStartFrame")]
-    pub source_start_frame: i64,
-    #[serde(rename = "durationFrames")]
-    pub duration_frames: i64,
+/// H.264 or H.265, for the hardware encoders that do both.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoCodec {
+    H264,
+    H265,
 }
 
-#[derive(Debug, Clone, Deserialize)]
-pub struct AudioPlanResolved {
-    pub fps: f64,
-    pub segments: Vec<AudioSegmentResolved>,
+/// A video encoder `SegmentWriter` can drive. The CLI picks one; everything about
+/// how ffmpeg is told to use it lives here.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncoderKind {
+    /// libx264.
+    X264,
+    /// libx265.
+    X265,
+    Nvenc(VideoCodec),
+    /// H.264 through VA-API on `FRAMESCRIPT_VAAPI_DEVICE`.
+    Vaapi,
+    /// H.264 through Intel Quick Sync.
+    Qsv,
+    /// H.264 through macOS VideoToolbox.
+    VideoToolbox,
+    /// prores_ks 4444 with alpha.
+    ProRes4444,
+    /// QuickTime Animation, lossless RGB with alpha.
+    Qtrle,
+    /// libvpx-vp9 with yuva420p alpha.
+    Vp9,
 }
 
-pub async fn mux_audio_plan_into_mp4(
-    input_video: &Path,
-    output_video: &Path,
-    plan: &AudioPlanResolved,
-    total_frames: usize,
-    fps: f64,
-) -> Result<(), Box<dyn Error>> {
-    if plan.segments.is_empty() {
-        // nothing to mux
-        return Ok(());
+impl EncoderKind {
+    /// Name used in logs and reports.
+    pub fn name(self) -> &'static str {
+        match self {
+            EncoderKind::X264 => "H264",
+            EncoderKind::X265 => "H265",
+            EncoderKind::Nvenc(VideoCodec::H264) => "h264_nvenc",
+            EncoderKind::Nvenc(VideoCodec::H265) => "hevc_nvenc",
+            EncoderKind::Vaapi => "h264_vaapi",
+            EncoderKind::Qsv => "h264_qsv",
+            EncoderKind::VideoToolbox => "h264_videotoolbox",
+            EncoderKind::ProRes4444 => "prores4444",
+            EncoderKind::Qtrle => "qtrle",
+            EncoderKind::Vp9 => "vp9",
+        }
     }
 
-    let fps = if fps.is_finite() && fps > 0.0 { fps } else { plan.fps };
-    let fps = if fps.is_finite() && fps > 0.0 { fps } else { 60.0 };
-    let duration_sec = (total_frames as f64) / fps;
-
-    let mut sources: BTreeMap<String, usize> = BTreeMap::new();
-    let mut next_input_index: usize = 1; // input #0 is video
-    for seg in &plan.segments {
-        let path = match &seg.source {
-            AudioSourceResolved::Video { path } => path,
-            AudioSourceResolved::Sound { path } => path,
-        };
-        if !sources.contains_key(path) {
-            sources.insert(path.clone(), next_input_index);
-            next_input_index += 1;
+    /// `-c:v` value.
+    pub fn codec_name(self) -> &'static str {
+        match self {
+            EncoderKind::X264 => "libx264",
+            EncoderKind::X265 => "libx265",
+            EncoderKind::ProRes4444 => "prores_ks",
+            EncoderKind::Vp9 => "libvpx-vp9",
+            other => other.name(),
         }
     }
 
-    let ffmpeg = resolve_ffmpeg_path()?;
-    let mut cmd = TokioCommand::new(ffmpeg);
-    cmd.arg("-y")
-        .arg("-hide_banner")
-        .arg("-loglevel")
-        .arg("error")
-        .arg("-i")
-        .arg(input_video);
+    /// Runs on the CPU, so it works wherever ffmpeg was built with it.
+    pub fn is_software(self) -> bool {
+        matches!(
+            self,
+            EncoderKind::X264
+                | EncoderKind::X265
+                | EncoderKind::ProRes4444
+                | EncoderKind::Qtrle
+                | EncoderKind::Vp9
+        )
+    }
 
-    let mut ordered_sources: Vec<(String, usize)> = sources.into_iter().collect();
-    ordered_sources.sort_by_key(|(_, idx)| *idx);
-    for (path, _) in &ordered_sources {
-        cmd.arg("-i").arg(path);
+    fn is_hevc(self) -> bool {
+        matches!(self, EncoderKind::X265 | EncoderKind::Nvenc(VideoCodec::H265))
     }
 
-    let mut filter_parts: Vec<String> = Vec::new();
+    /// Software encoder used when this hardware one is unavailable.
+    pub fn software_equivalent(self) -> EncoderKind {
+        if self.is_hevc() {
+            EncoderKind::X265
+        } else {
+            EncoderKind::X264
+        }
+    }
 
-    let fmt_f = |value: f64| format!("{:.6}", value.max(0.0));
+    /// Whether the output is YUV, so sRGB frames need converting and tagging. qtrle
+    /// keeps RGB.
+    pub fn is_yuv(self) -> bool {
+        self != EncoderKind::Qtrle
+    }
 
-    // Base silent bed so output audio always starts at 0 and has deterministic duration.
-    filter_parts.push(format!(
-        "anullsrc=r=48000:cl=stereo:d={}[base]",
-        fmt_f(duration_sec)
-    ));
+    /// `--pix-fmt` choices and the profile each one needs. Encoders not listed
+    /// here always use their own format.
+    fn pixel_formats(self) -> &'static [(&'static str, Option<&'static str>)] {
+        match self {
+            EncoderKind::X264 => &[("yuv420p", None), ("yuv444p", Some("high444"))],
+            EncoderKind::X265 => &[
+                ("yuv420p", None),
+                ("yuv444p", Some("main444-8")),
+                ("yuv420p10le", Some("main10")),
+            ],
+            _ => &[],
+        }
+    }
 
-    let mut segment_labels: Vec<String> = Vec::new();
+    pub fn takes_pixel_format(self, format: &str) -> bool {
+        self.pixel_formats().iter().any(|(name, _)| *name == format)
+    }
 
-    for seg in plan.segments.iter() {
-        let n = segment_labels.len();
-        let src_path = match &seg.source {
-            AudioSourceResolved::Video { path } => path,
-            AudioSourceResolved::Sound { path } => path,
+    /// Option that takes `key=value` encoder params as one `:`-joined string.
+    fn params_option(self) -> Option<&'static str> {
+        match self {
+            EncoderKind::X264 => Some("-x264-params"),
+            EncoderKind::X265 => Some("-x265-params"),
+            _ => None,
+        }
+    }
+
+    /// Rate control: a crf becomes each encoder's constant-quality knob, a bitrate
+    /// `-b:v` with a matching `-maxrate`/`-bufsize`. ProRes and qtrle have none.
+    fn rate_args(self, quality: Quality) -> Vec<String> {
+        let crf = match quality {
+            Quality::Crf(crf) => crf,
+            Quality::Bitrate(bits) => {
+                if matches!(self, EncoderKind::ProRes4444 | EncoderKind::Qtrle) {
+                    return Vec::new();
+                }
+                let bitrate = bits.to_string();
+                let bufsize = (bits * 2).to_string();
+                return ["-b:v", &bitrate, "-maxrate", &bitrate, "-bufsize", &bufsize]
+                    .iter()
+                    .map(|arg| arg.to_string())
+                    .collect();
+            }
         };
-        let Some(&input_idx) = ordered_sources
-            .iter()
-            .find(|(p, _)| p == src_path)
-            .map(|(_, idx)| idx)
-        else {
-            continue;
+        let (option, value, zero_bitrate) = match self {
+            EncoderKind::X264 | EncoderKind::X265 => ("-crf", crf, false),
+            EncoderKind::Vp9 => ("-crf", crf, true),
+            EncoderKind::Nvenc(_) => ("-cq", crf, true),
+            EncoderKind::Qsv => ("-global_quality", crf, false),
+            EncoderKind::Vaapi => ("-qp", crf, false),
+            // -q:v は 1..100 で大きいほど高画質。crf 0..51 を逆向きに割り当てる
+            EncoderKind::VideoToolbox => ("-q:v", (100 - (crf.min(51) * 100 / 51)).max(1), false),
+            EncoderKind::ProRes4444 | EncoderKind::Qtrle => return Vec::new(),
         };
-
-        let project_start_frame = seg.project_start_frame.max(0) as f64;
-        let source_start_frame = seg.source_start_frame.max(0) as f64;
-        let duration_frames = seg.duration_frames.max(0) as f64;
-        if duration_frames <= 0.0 {
-            continue;
+        let mut args = vec![option.to_string(), value.to_string()];
+        if zero_bitrate {
+            args.extend(["-b:v".to_string(), "0".to_string()]);
         }
+        args
+    }
+}
 
-        let start_sec = source_start_frame / fps;
-        let dur_sec = duration_frames / fps;
-        let delay_ms = ((project_start_frame / fps) * 1000.0).round().max(0.0) as i64;
+impl std::fmt::Display for EncoderKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
 
-        filter_parts.push(format!(
-            "[{input_idx}:a]atrim=start={}:duration={},asetpts=PTS-STARTPTS,aresample=48000,adelay={delay_ms}:all=1[a{n}]",
-            fmt_f(start_sec),
-            fmt_f(dur_sec),
-        ));
+/// ffmpeg arguments selecting and tuning a video encoder.
+struct VideoEncoder {
+    /// Global options that must precede the inputs (hardware devices).
+    input_args: Vec<String>,
+    output_args: Vec<String>,
+    /// Filter the encoder needs last in the chain (hardware upload).
+    filter: Option<&'static str>,
+}
 
-        segment_labels.push(format!("[a{n}]"));
+impl VideoEncoder {
+    /// `-vf` with `filters` followed by the encoder's own, then the encoder options.
+    fn output_args(&self, mut filters: Vec<String>) -> Vec<String> {
+        filters.extend(self.filter.map(str::to_string));
+        let mut args = Vec::new();
+        if !filters.is_empty() {
+            args.push("-vf".to_string());
+            args.push(filters.join(","));
+        }
+        args.extend(self.output_args.iter().cloned());
+        args
     }
+}
 
-    if segment_labels.is_empty() {
-        return Ok(());
+/// x264-style preset names mapped onto NVENC's p1 (fastest) .. p7 (slowest).
+fn nvenc_preset(preset: &str) -> &'static str {
+    match preset {
+        "ultrafast" | "superfast" => "p1",
+        "veryfast" => "p2",
+        "faster" => "p3",
+        "fast" | "medium" => "p4",
+        "slow" => "p5",
+        "slower" => "p6",
+        "veryslow" | "placebo" => "p7",
+        _ => "p4",
     }
+}
 
-    let seg_count = segment_labels.len();
-    let mix_inputs = std::iter::once("[base]".to_string())
-        .chain(segment_labels.iter().cloned())
-        .collect::<String>();
+/// Rate control for a video encoder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quality {
+    /// Constant quality on the x264 0..51 scale (0..63 for VP9).
+    Crf(u32),
+    /// Target bitrate in bits per second.
+    Bitrate(u64),
+}
 
-    let total_inputs = 1 + seg_count;
-    filter_parts.push(format!(
-        "{mix_inputs}amix=inputs={total_inputs}:duration=first:normalize=0,aformat=sample_fmts=fltp:sample_rates=48000:channel_layouts=stereo[aout]"
-    ));
+impl std::fmt::Display for Quality {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Quality::Crf(crf) => write!(f, "crf {crf}"),
+            Quality::Bitrate(bits) => write!(f, "bitrate {}k", bits / 1000),
+        }
+    }
+}
+
+/// Encoder options on top of the preset and rate control, passed through as given.
+/// ffmpeg rejects values the encoder does not know.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EncoderTuning {
+    pub tune: Option<String>,
+    pub profile: Option<String>,
+    pub level: Option<String>,
+    /// `key=value` encoder options: `-x264-params`/`-x265-params` for the software
+    /// encoders, one `-key value` option each for the others.
+    pub params: Vec<(String, String)>,
+}
 
-    let filter_complex = filter_parts.join(";");
+impl EncoderTuning {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
 
-    cmd.arg("-filter_complex")
-        .arg(filter_complex)
-        .arg("-map")
-        .arg("0:v:0")
-        .arg("-map")
-        .arg("[aout]")
-        .arg("-c:v")
-        .arg("copy")
-        .arg("-c:a")
-        .arg("aac")
-        .arg("-b:a")
-        .arg("192k")
-        .arg("-shortest")
-        .arg("-avoid_negative_ts")
-        .arg("make_zero")
-        .arg("-movflags")
-        .arg("+faststart")
-        .arg(output_video)
-        .stdin(Stdio::null())
-        .stdout(Stdio::null())
-        .stderr(Stdio::inherit());
+/// How segments are encoded. The re-encode concat takes the same settings, so its
+/// output matches what `SegmentWriter` would have produced.
+#[derive(Debug, Clone)]
+pub struct EncodeSettings {
+    pub encode: EncoderKind,
+    pub quality: Quality,
+    pub preset: String,
+    pub gop: Option<u32>,
+    /// Replaces yuv420p for the encoders that take a pixel format.
+    pub pixel_format: Option<&'static str>,
+    pub color_range: ColorRange,
+    pub tuning: EncoderTuning,
+}
 
-    let status = cmd.status().await?;
-    if !status.success() {
-        return Err(format!("ffmpeg audio mux failed: {}", status).into());
+impl EncodeSettings {
+    fn video_encoder(&self) -> Result<VideoEncoder, Box<dyn Error>> {
+        video_encoder(
+            self.encode,
+            self.quality,
+            &self.preset,
+            self.pixel_format,
+            &self.tuning,
+        )
     }
+}
 
-    Ok(())
+/// `tuning` as options for `encode`.
+fn tuning_args(encode: EncoderKind, tuning: &EncoderTuning) -> Result<Vec<String>, Box<dyn Error>> {
+    if tuning.is_empty() {
+        return Ok(Vec::new());
+    }
+    match encode {
+        EncoderKind::ProRes4444 | EncoderKind::Qtrle => {
+            return Err(format!("{encode} takes no tune, profile, level or encoder params").into());
+        }
+        EncoderKind::Vp9
+            if tuning.tune.is_some() || tuning.profile.is_some() || tuning.level.is_some() =>
+        {
+            return Err("vp9 only takes encoder params".into());
+        }
+        _ => {}
+    }
+
+    let mut args = Vec::new();
+    if let Some(tune) = &tuning.tune {
+        args.extend(["-tune".to_string(), tune.clone()]);
+    }
+    if let Some(profile) = &tuning.profile {
+        args.extend(["-profile:v".to_string(), profile.clone()]);
+    }
+    let mut params = tuning.params.clone();
+    // libx265 に -level はないので x265-params で渡す
+    match (&tuning.level, encode) {
+        (Some(level), EncoderKind::X265) => params.push(("level-idc".to_string(), level.clone())),
+        (Some(level), _) => args.extend(["-level".to_string(), level.clone()]),
+        (None, _) => {}
+    }
+    match encode.params_option() {
+        _ if params.is_empty() => {}
+        Some(option) => {
+            let joined = params
+                .iter()
+                .map(|(key, value)| format!("{key}={value}"))
+                .collect::<Vec<_>>()
+                .join(":");
+            args.extend([option.to_string(), joined]);
+        }
+        None => {
+            for (key, value) in &params {
+                args.extend([format!("-{key}"), value.clone()]);
+            }
+        }
+    }
+    Ok(args)
+}
+
+/// Options selecting `encode` with `quality` and `preset`. `pixel_format` replaces
+/// yuv420p for the encoders that take one, with the profile that format needs; the
+/// others keep their own. `tuning` comes last, and its profile wins over the pixel
+/// format's.
+fn video_encoder(
+    encode: EncoderKind,
+    quality: Quality,
+    preset: &str,
+    pixel_format: Option<&str>,
+    tuning: &EncoderTuning,
+) -> Result<VideoEncoder, Box<dyn Error>> {
+    let profile = match pixel_format {
+        None => None,
+        Some(format) => match encode.pixel_formats().iter().find(|(name, _)| *name == format) {
+            Some((_, profile)) => *profile,
+            None if encode.pixel_formats().is_empty() => {
+                return Err(format!(
+                    "{encode} cannot encode {format}; only H264/H265 take a pixel format"
+                )
+                .into());
+            }
+            None => return Err(format!("{encode} cannot encode {format}").into()),
+        },
+    };
+    let args = |values: &[&str]| values.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+
+    let mut filter = None;
+    let mut input_args = Vec::new();
+    let mut output = args(&["-c:v", encode.codec_name()]);
+    match encode {
+        EncoderKind::X264 | EncoderKind::X265 => {
+            output.extend(args(&[
+                "-preset", preset,
+                "-pix_fmt", pixel_format.unwrap_or("yuv420p"),
+            ]));
+            // 420p 以外はプロファイルも合わせないとエンコーダーに拒否される
+            if let Some(profile) = profile
+                && tuning.profile.is_none()
+            {
+                output.extend(args(&["-profile:v", profile]));
+            }
+        }
+        EncoderKind::Nvenc(_) => {
+            output.extend(args(&[
+                "-preset", nvenc_preset(preset),
+                "-rc", "vbr",
+                "-pix_fmt", "yuv420p",
+            ]));
+        }
+        // QSV は x264 と同じプリセット名を受け付ける
+        EncoderKind::Qsv => output.extend(args(&["-preset", preset, "-pix_fmt", "nv12"])),
+        EncoderKind::Vaapi => {
+            let device = read_env_path("FRAMESCRIPT_VAAPI_DEVICE")
+                .unwrap_or_else(|| "/dev/dri/renderD128".to_string());
+            filter = Some("format=nv12,hwupload");
+            input_args = args(&["-vaapi_device", &device]);
+        }
+        EncoderKind::ProRes4444 => {
+            output.extend(args(&[
+                "-profile:v", "4444",
+                "-pix_fmt", "yuva444p10le",
+                "-vendor", "apl0",
+            ]));
+        }
+        EncoderKind::Qtrle => output.extend(args(&["-pix_fmt", "argb"])),
+        EncoderKind::Vp9 => output.extend(args(&["-pix_fmt", "yuva420p", "-row-mt", "1"])),
+        EncoderKind::VideoToolbox => output.extend(args(&["-pix_fmt", "yuv420p"])),
+    }
+    output.extend(encode.rate_args(quality));
+    output.extend(tuning_args(encode, tuning)?);
+
+    Ok(VideoEncoder {
+        input_args,
+        output_args: output,
+        filter,
+    })
+}
+
+fn is_mov_family(output_path: &Path) -> bool {
+    let extension = output_path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase());
+    matches!(extension.as_deref(), Some("mp4" | "mov" | "m4v" | "m4a"))
+}
+
+/// `+faststart` for the MP4/MOV family; other muxers reject `-movflags`.
+fn faststart_args(output_path: &Path) -> Vec<&'static str> {
+    if is_mov_family(output_path) {
+        vec!["-movflags", "+faststart"]
+    } else {
+        Vec::new()
+    }
+}
+
+/// Options after the encoder's: faststart (or fragments when `fragmented`), the HEVC
+/// tag and the keyframe interval.
+fn container_args(
+    encode: EncoderKind,
+    output_path: &Path,
+    gop: Option<u32>,
+    fragmented: bool,
+) -> Vec<String> {
+    let mut args = if fragmented && is_mov_family(output_path) {
+        // moov を先に書いてキーフレームごとに断片を足すので、途中で落ちても書けた所までは読める
+        vec![
+            "-movflags".to_string(),
+            "+frag_keyframe+empty_moov+default_base_moof".to_string(),
+        ]
+    } else {
+        faststart_args(output_path)
+            .into_iter()
+            .map(str::to_string)
+            .collect::<Vec<_>>()
+    };
+
+    // QuickTime/Apple は hev1 タグの HEVC を再生できない
+    if encode.is_hevc() && is_mov_family(output_path) {
+        args.extend(["-tag:v".to_string(), "hvc1".to_string()]);
+    }
+
+    if let Some(g) = gop {
+        args.extend([
+            "-g".to_string(),
+            g.to_string(),
+            "-keyint_min".to_string(),
+            g.to_string(),
+        ]);
+        // シーンカット無効化は x264/x265 のオプション
+        if encode.is_software() {
+            args.extend(["-sc_threshold".to_string(), "0".to_string()]);
+        }
+    }
+    args
+}
+
+/// Range of the encoded YUV values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ColorRange {
+    /// 16..235, what players assume for video.
+    Limited,
+    /// 0..255. Keeps every sRGB level, but players that ignore the tag show it
+    /// washed out.
+    Full,
+}
+
+impl ColorRange {
+    fn ffmpeg_name(self) -> &'static str {
+        match self {
+            ColorRange::Limited => "tv",
+            ColorRange::Full => "pc",
+        }
+    }
+}
+
+impl std::fmt::Display for ColorRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ColorRange::Limited => "limited",
+            ColorRange::Full => "full",
+        })
+    }
+}
+
+/// `scale` options converting sRGB input with the BT.709 matrix; swscale would use
+/// BT.601 otherwise, which shifts and desaturates colors.
+fn color_conversion(range: ColorRange) -> String {
+    format!("out_color_matrix=bt709:out_range={}", range.ffmpeg_name())
+}
+
+/// Tag the stream as BT.709 with the sRGB transfer it was captured in, so players
+/// do not guess.
+fn color_tags(encode: EncoderKind, range: ColorRange) -> Vec<String> {
+    if !encode.is_yuv() {
+        return Vec::new();
+    }
+    [
+        "-colorspace", "bt709",
+        "-color_primaries", "bt709",
+        "-color_trc", "iec61966-2-1",
+        "-color_range", range.ffmpeg_name(),
+    ]
+    .iter()
+    .map(|arg| arg.to_string())
+    .collect()
+}
+
+/// Encoder of the audio track.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum AudioCodec {
+    Aac,
+    Opus,
+    Flac,
+    /// 16-bit PCM.
+    Pcm,
+}
+
+impl AudioCodec {
+    fn encoder(self) -> &'static str {
+        match self {
+            AudioCodec::Aac => "aac",
+            AudioCodec::Opus => "libopus",
+            AudioCodec::Flac => "flac",
+            AudioCodec::Pcm => "pcm_s16le",
+        }
+    }
+
+    fn is_lossless(self) -> bool {
+        matches!(self, AudioCodec::Flac | AudioCodec::Pcm)
+    }
+
+    /// The codec of a stream ffprobe calls `name`.
+    fn from_ffprobe(name: &str) -> Option<Self> {
+        match name {
+            "aac" => Some(AudioCodec::Aac),
+            "opus" => Some(AudioCodec::Opus),
+            "flac" => Some(AudioCodec::Flac),
+            "pcm_s16le" => Some(AudioCodec::Pcm),
+            _ => None,
+        }
+    }
+
+    /// The codec for an output with `extension` when none is asked for: Opus for
+    /// WebM, PCM for WAV, FLAC for FLAC, AAC otherwise.
+    fn for_extension(extension: &str) -> Self {
+        match extension {
+            "webm" => AudioCodec::Opus,
+            "wav" => AudioCodec::Pcm,
+            "flac" => AudioCodec::Flac,
+            _ => AudioCodec::Aac,
+        }
+    }
+
+    /// Whether an output with `extension` can hold this codec.
+    fn fits(self, extension: &str) -> bool {
+        match extension {
+            "mp4" | "m4a" => self == AudioCodec::Aac,
+            "mov" => matches!(self, AudioCodec::Aac | AudioCodec::Pcm),
+            "webm" => self == AudioCodec::Opus,
+            "wav" => self == AudioCodec::Pcm,
+            "flac" => self == AudioCodec::Flac,
+            _ => true,
+        }
+    }
+
+    /// Sample rates the encoder takes.
+    fn takes_sample_rate(self, rate: u32) -> bool {
+        match self {
+            AudioCodec::Opus => matches!(rate, 8000 | 12000 | 16000 | 24000 | 48000),
+            AudioCodec::Aac => rate <= 96000,
+            AudioCodec::Flac | AudioCodec::Pcm => true,
+        }
+    }
+}
+
+impl std::fmt::Display for AudioCodec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            AudioCodec::Aac => "aac",
+            AudioCodec::Opus => "opus",
+            AudioCodec::Flac => "flac",
+            AudioCodec::Pcm => "pcm",
+        })
+    }
+}
+
+/// How the audio track is encoded. Settings left at `None` also let a lone
+/// source be copied as it is (see `copyable_source`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AudioEncode {
+    /// Picked from the output's extension when `None`.
+    pub codec: Option<AudioCodec>,
+    /// Bits per second of AAC and Opus; 192k when `None`.
+    pub bitrate: Option<u64>,
+    /// 48 kHz when `None`.
+    pub sample_rate: Option<u32>,
+}
+
+/// Extension of `path` in lower case, empty when it has none.
+fn lowercase_extension(path: &Path) -> String {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase())
+        .unwrap_or_default()
+}
+
+impl AudioEncode {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate.unwrap_or(48000)
+    }
+
+    fn codec(&self, extension: &str) -> AudioCodec {
+        self.codec.unwrap_or_else(|| AudioCodec::for_extension(extension))
+    }
+
+    /// Check the settings against an output with `extension`, before anything is
+    /// rendered.
+    pub fn check(&self, extension: &str) -> Result<(), String> {
+        let codec = self.codec(extension);
+        if !codec.fits(extension) {
+            return Err(format!("{codec} audio cannot be written to .{extension}"));
+        }
+        if codec.is_lossless() && self.bitrate.is_some() {
+            return Err(format!("{codec} audio is lossless; drop --audio-bitrate"));
+        }
+        if !codec.takes_sample_rate(self.sample_rate()) {
+            return Err(format!("{codec} audio does not support {} Hz", self.sample_rate()));
+        }
+        Ok(())
+    }
+
+    /// `-c:a` and `-b:a` for `output_path`.
+    fn args(&self, output_path: &Path) -> Vec<String> {
+        let codec = self.codec(&lowercase_extension(output_path));
+        let mut args = vec!["-c:a".to_string(), codec.encoder().to_string()];
+        if !codec.is_lossless() {
+            args.push("-b:a".to_string());
+            args.push(match self.bitrate {
+                Some(bits) => bits.to_string(),
+                None => "192k".to_string(),
+            });
+        }
+        args
+    }
+}
+
+/// `encode` if it works on this machine, otherwise its software equivalent. Hardware
+/// encoders are checked with a one-frame test encode, since `ffmpeg -encoders` lists
+/// them whether or not a device is present.
+pub async fn usable_encoder(
+    encode: EncoderKind,
+    quality: Quality,
+    preset: &str,
+) -> Result<EncoderKind, Box<dyn Error>> {
+    if encode.is_software() {
+        return Ok(encode);
+    }
+
+    let encoder = video_encoder(encode, quality, preset, None, &EncoderTuning::default())?;
+    let output = test_encode(&encoder).await?;
+    if output.status.success() {
+        return Ok(encode);
+    }
+
+    let fallback = encode.software_equivalent();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    warn!(
+        "{encode} is not usable here ({}), falling back to {fallback}",
+        stderr.lines().last().unwrap_or("test encode failed").trim()
+    );
+    Ok(fallback)
+}
+
+/// Check that `encode` accepts `pixel_format` and `tuning` with a one-frame test
+/// encode, so a bad option fails before any browser starts, with ffmpeg's reason.
+pub async fn check_encoder_options(
+    encode: EncoderKind,
+    quality: Quality,
+    preset: &str,
+    pixel_format: Option<&str>,
+    tuning: &EncoderTuning,
+) -> Result<(), Box<dyn Error>> {
+    let encoder = video_encoder(encode, quality, preset, pixel_format, tuning)?;
+    let output = test_encode(&encoder).await?;
+    if output.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    Err(format!(
+        "{encode} rejected the encoder options ({}): {}",
+        encoder.output_args.join(" "),
+        stderr.trim()
+    )
+    .into())
+}
+
+/// Encode one black frame with `encoder` and throw it away.
+async fn test_encode(encoder: &VideoEncoder) -> Result<std::process::Output, Box<dyn Error>> {
+    let ffmpeg = resolve_checked_ffmpeg()?;
+    Ok(TokioCommand::new(ffmpeg)
+        .arg("-hide_banner")
+        .arg("-loglevel")
+        .arg("error")
+        .args(&encoder.input_args)
+        .arg("-f")
+        .arg("lavfi")
+        .arg("-i")
+        .arg("color=c=black:s=256x256:r=30")
+        .arg("-frames:v")
+        .arg("1")
+        .args(encoder.output_args(Vec::new()))
+        .arg("-f")
+        .arg("null")
+        .arg("-")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .output()
+        .await?)
+}
+
+/// Frame format written to a `SegmentWriter`'s stdin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameInput {
+    /// Concatenated PNG files.
+    Png,
+    /// Concatenated JPEG files.
+    Jpeg,
+    /// Packed 8-bit RGBA, `width * height * 4` bytes per frame.
+    Rgba,
+    /// Packed 8-bit BGRA, as most native frame grabbers deliver it.
+    Bgra,
+}
+
+impl FrameInput {
+    /// Uncompressed frames of a fixed size, as opposed to image files.
+    pub fn is_raw(self) -> bool {
+        matches!(self, FrameInput::Rgba | FrameInput::Bgra)
+    }
+}
+
+/// Lines of ffmpeg's stderr kept for error messages.
+const STDERR_TAIL_LINES: usize = 20;
+
+/// Longest stderr line kept, in bytes; the rest of the line is dropped.
+const STDERR_LINE_BYTES: usize = 1000;
+
+/// How long a failed encoder gets to exit before its stderr is reported as is.
+const EXIT_GRACE: Duration = Duration::from_secs(5);
+
+/// The last lines ffmpeg wrote to stderr. The pipe is drained in the background so
+/// a chatty encoder never blocks on it.
+struct StderrTail {
+    lines: Arc<Mutex<VecDeque<String>>>,
+    reader: Option<JoinHandle<()>>,
+}
+
+impl StderrTail {
+    fn collect(stderr: ChildStderr) -> Self {
+        let lines = Arc::new(Mutex::new(VecDeque::new()));
+        let reader = tokio::spawn({
+            let lines = lines.clone();
+            async move {
+                let mut stderr = BufReader::new(stderr);
+                let mut line = Vec::new();
+                // 非 UTF-8 の行で止まるとパイプが詰まるので bytes で読む
+                while stderr.read_until(b'\n', &mut line).await.is_ok_and(|read| read > 0) {
+                    line.truncate(STDERR_LINE_BYTES);
+                    let text = String::from_utf8_lossy(&line).trim_end().to_string();
+                    line.clear();
+                    if text.is_empty() {
+                        continue;
+                    }
+                    let mut lines = lines.lock().unwrap();
+                    if lines.len() == STDERR_TAIL_LINES {
+                        lines.pop_front();
+                    }
+                    lines.push_back(text);
+                }
+            }
+        });
+        Self { lines, reader: Some(reader) }
+    }
+
+    /// Wait until ffmpeg closed stderr, so the tail is complete.
+    async fn drain(&mut self) {
+        if let Some(reader) = self.reader.take() {
+            tokio::time::timeout(EXIT_GRACE, reader).await.ok();
+        }
+    }
+
+    fn text(&self) -> String {
+        let lines = self.lines.lock().unwrap();
+        lines.iter().cloned().collect::<Vec<_>>().join("\n")
+    }
+}
+
+pub struct SegmentWriter {
+    child: Child,
+    stdin: ChildStdin,
+    input: FrameInput,
+    frame_bytes: usize,
+    /// Frames written so far; the index of the next one.
+    frames_written: usize,
+    /// The ffmpeg command line, for error messages.
+    command: String,
+    stderr: StderrTail,
+}
+
+impl SegmentWriter {
+    /// Start ffmpeg reading `width`x`height` frames from stdin and encoding them as
+    /// `settings` say. `scale_to` resizes them (lanczos) before encoding. YUV output is
+    /// converted from sRGB as BT.709 in the settings' color range and tagged so.
+    /// `fragmented` writes MP4/MOV in fragments, so a segment whose render died is
+    /// still readable up to its last keyframe.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn new(
+        output_path: &str,
+        width: u32,
+        height: u32,
+        fps: f64,
+        settings: &EncodeSettings,
+        input: FrameInput,
+        scale_to: Option<(u32, u32)>,
+        fragmented: bool,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let encode = settings.encode;
+        let encoder = settings.video_encoder()?;
+
+        let ffmpeg = resolve_checked_ffmpeg()?;
+        let mut cmd = TokioCommand::new(ffmpeg);
+        cmd.arg("-y")
+            .arg("-hide_banner")
+            .arg("-loglevel")
+            .arg("error")
+            .args(&encoder.input_args);
+
+        match input {
+            FrameInput::Png => {
+                cmd.arg("-f").arg("image2pipe").arg("-vcodec").arg("png");
+            }
+            FrameInput::Jpeg => {
+                cmd.arg("-f").arg("image2pipe").arg("-vcodec").arg("mjpeg");
+            }
+            FrameInput::Rgba => {
+                cmd.arg("-f").arg("rawvideo").arg("-pix_fmt").arg("rgba");
+            }
+            FrameInput::Bgra => {
+                cmd.arg("-f").arg("rawvideo").arg("-pix_fmt").arg("bgra");
+            }
+        }
+
+        let mut scale = scale_to
+            .filter(|&size| size != (width, height))
+            .map(|(w, h)| format!("scale={w}:{h}:flags=lanczos"));
+        if encode.is_yuv() {
+            // リサイズと同じ scale で変換すれば 1 回で済む
+            let conversion = color_conversion(settings.color_range);
+            scale = Some(match scale {
+                Some(scale) => format!("{scale}:{conversion}"),
+                None => format!("scale={conversion}"),
+            });
+        }
+
+        cmd.arg("-framerate")
+            .arg(format!("{}", fps))
+            .arg("-s")
+            .arg(format!("{}x{}", width, height))
+            .arg("-i")
+            .arg("pipe:0")
+            .arg("-r")
+            .arg(format!("{}", fps))
+            .args(encoder.output_args(scale.into_iter().collect()))
+            .args(color_tags(encode, settings.color_range))
+            .args(container_args(encode, Path::new(output_path), settings.gop, fragmented));
+
+        cmd.arg(output_path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped());
+
+        let command = command_line(&cmd);
+        debug!(command, "starting segment encoder");
+        let mut child = cmd.spawn().map_err(|e| {
+            format!(
+                "Failed to spawn ffmpeg. Is ffmpeg installed and on PATH? error={}",
+                e
+            )
+        })?;
+
+        let stdin = child
+            .stdin
+            .take()
+            .ok_or_else(|| "Failed to open ffmpeg stdin".to_string())?;
+        let stderr = child
+            .stderr
+            .take()
+            .ok_or_else(|| "Failed to open ffmpeg stderr".to_string())?;
+
+        Ok(Self {
+            child,
+            stdin,
+            input,
+            frame_bytes: width as usize * height as usize * 4,
+            frames_written: 0,
+            command,
+            stderr: StderrTail::collect(stderr),
+        })
+    }
+
+    /// `message` with the exit status, the end of ffmpeg's stderr and the command
+    /// line. Waits a little for ffmpeg to exit first, so its last words are in.
+    async fn failure(&mut self, message: String) -> Box<dyn Error> {
+        let status = tokio::time::timeout(EXIT_GRACE, self.child.wait())
+            .await
+            .ok()
+            .and_then(Result::ok);
+        self.stderr.drain().await;
+        describe_failure(message, status, &self.stderr.text(), &self.command).into()
+    }
+
+    /// Write one frame in the writer's `FrameInput` format.
+    pub async fn write_frame(&mut self, frame: &[u8]) -> Result<(), Box<dyn Error>> {
+        // rawvideo はサイズがずれると以降のフレームが全部崩れるので先に弾く
+        if self.input.is_raw() && frame.len() != self.frame_bytes {
+            return Err(format!(
+                "raw frame is {} bytes, expected {}",
+                frame.len(),
+                self.frame_bytes
+            )
+            .into());
+        }
+        // 落ちた後に書き続けると、ずっと後のフレームで BrokenPipe になるだけなので先に見る
+        if let Ok(Some(status)) = self.child.try_wait() {
+            self.stderr.drain().await;
+            return Err(describe_failure(
+                format!("ffmpeg exited before frame {} of the segment", self.frames_written),
+                Some(status),
+                &self.stderr.text(),
+                &self.command,
+            )
+            .into());
+        }
+        if let Err(error) = self.stdin.write_all(frame).await {
+            let message = format!(
+                "cannot write frame {} of the segment to ffmpeg: {error}",
+                self.frames_written
+            );
+            return Err(self.failure(message).await);
+        }
+        self.frames_written += 1;
+        Ok(())
+    }
+
+    pub async fn finish(mut self) -> Result<(), Box<dyn Error>> {
+        if let Err(error) = self.stdin.shutdown().await {
+            return Err(self.failure(format!("cannot close ffmpeg's input: {error}")).await);
+        }
+
+        let status = self.child.wait().await?;
+        self.stderr.drain().await;
+        if !status.success() {
+            return Err(describe_failure(
+                format!("encode failed after {} frames", self.frames_written),
+                Some(status),
+                &self.stderr.text(),
+                &self.command,
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// The command line of `cmd`, for error messages.
+fn command_line(cmd: &TokioCommand) -> String {
+    std::iter::once(cmd.as_std().get_program())
+        .chain(cmd.as_std().get_args())
+        .map(|arg| arg.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The last lines of a finished ffmpeg's `stderr`, cut like `StderrTail` cuts them.
+fn stderr_tail(stderr: &[u8]) -> String {
+    let lines = stderr
+        .split(|&byte| byte == b'\n')
+        .map(|line| String::from_utf8_lossy(&line[..line.len().min(STDERR_LINE_BYTES)]))
+        .map(|line| line.trim_end().to_string())
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>();
+    lines[lines.len().saturating_sub(STDERR_TAIL_LINES)..].join("\n")
+}
+
+fn describe_failure(
+    message: String,
+    status: Option<ExitStatus>,
+    stderr: &str,
+    command: &str,
+) -> String {
+    let mut description = message;
+    if let Some(status) = status {
+        description.push_str(&format!(" (ffmpeg {status})"));
+    }
+    for line in stderr.lines() {
+        description.push_str("\n  ffmpeg: ");
+        description.push_str(line);
+    }
+    description.push_str("\n  command: ");
+    description.push_str(command);
+    description
+}
+
+fn escape_concat_path(p: &str) -> String {
+    p.replace('\'', r"'\''")
+}
+
+fn normalize_concat_path(path: &str) -> String {
+    if cfg!(windows) {
+        let mut normalized = path.to_string();
+        if let Some(rest) = normalized.strip_prefix(r"\\?\UNC\") {
+            normalized = format!(r"\\{}", rest);
+        } else if let Some(rest) = normalized.strip_prefix(r"\\?\") {
+            normalized = rest.to_string();
+        }
+        normalized.replace('\\', "/")
+    } else {
+        path.to_string()
+    }
+}
+
+/// Where the concat demuxer list for `output_path` is written.
+pub fn concat_list_path(output_path: &Path) -> PathBuf {
+    output_path.with_extension("segments.txt")
+}
+
+/// A segment to join, and what rendered it for error messages.
+#[derive(Debug, Clone)]
+pub struct ConcatSegment {
+    pub path: PathBuf,
+    /// e.g. `frames 0..60, worker 2`.
+    pub origin: String,
+}
+
+/// Why the concat demuxer could not read `path`, found without decoding it: the file
+/// is missing, empty, cannot be opened or has no stream ffprobe recognizes.
+pub async fn unreadable_segment(path: &Path) -> Option<String> {
+    match fs::metadata(path).await {
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Some("missing".to_string()),
+        Err(error) => return Some(format!("cannot stat: {error}")),
+        Ok(metadata) if metadata.len() == 0 => return Some("empty file".to_string()),
+        Ok(_) => {}
+    }
+    if let Err(error) = fs::File::open(path).await {
+        return Some(format!("cannot open: {error}"));
+    }
+    let ffprobe = match resolve_ffprobe_path() {
+        Ok(ffprobe) => ffprobe,
+        Err(error) => return Some(format!("cannot probe: {error}")),
+    };
+    let output = TokioCommand::new(ffprobe)
+        .arg("-v")
+        .arg("error")
+        .arg("-show_entries")
+        .arg("stream=codec_type")
+        .arg("-of")
+        .arg("csv=p=0")
+        .arg(path)
+        .stdin(Stdio::null())
+        .output()
+        .await;
+    match output {
+        Ok(output) if output.status.success() && !output.stdout.trim_ascii().is_empty() => None,
+        Ok(output) => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            Some(match stderr.lines().last() {
+                Some(line) => format!("unreadable: {}", line.trim()),
+                None => "unreadable".to_string(),
+            })
+        }
+        Err(error) => Some(format!("cannot probe: {error}")),
+    }
+}
+
+/// The paths of `segments` the concat demuxer can read, out of the `expected` the
+/// frame range is split into. An unreadable one fails the concat with its path and
+/// origin, and so does ending up with fewer than `expected`; with `allow_missing`
+/// they are left out with a warning instead. Having none left fails either way.
+async fn readable_segments(
+    segments: Vec<ConcatSegment>,
+    expected: usize,
+    allow_missing: bool,
+) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let total = segments.len();
+    let mut readable = Vec::new();
+    for segment in segments {
+        let Some(problem) = unreadable_segment(&segment.path).await else {
+            readable.push(segment.path);
+            continue;
+        };
+        let problem = format!("segment {} ({}): {problem}", segment.path.display(), segment.origin);
+        if !allow_missing {
+            return Err(format!("cannot concat {problem}").into());
+        }
+        warn!("leaving out {problem}");
+    }
+    if readable.is_empty() {
+        return Err(format!("none of the {total} segment(s) can be read; nothing to concat").into());
+    }
+    if readable.len() < expected {
+        let missing = format!(
+            "{} of the frame range's {expected} segments",
+            expected - readable.len()
+        );
+        if !allow_missing {
+            return Err(format!("cannot concat without {missing}").into());
+        }
+        warn!("concatenating without {missing}");
+    }
+    Ok(readable)
+}
+
+/// Write the concat demuxer list for `segments` next to `output_path`.
+async fn write_concat_list(
+    segments: &[PathBuf],
+    output_path: &Path,
+) -> Result<PathBuf, Box<dyn Error>> {
+    if segments.is_empty() {
+        return Err("No segment files.".into());
+    }
+
+    let list_path = concat_list_path(output_path);
+    let list_dir = list_path.parent().unwrap_or_else(|| Path::new("."));
+    let list_dir_abs = tokio::task::spawn_blocking({
+        let list_dir = list_dir.to_path_buf();
+        move || std::fs::canonicalize(&list_dir).unwrap_or(list_dir)
+    })
+    .await?;
+
+    let mut lines = String::new();
+    for seg in segments.iter().cloned() {
+        let abs_path = tokio::task::spawn_blocking(move || std::fs::canonicalize(seg))
+            .await??;
+        let rel_path = match abs_path.strip_prefix(&list_dir_abs) {
+            Ok(rel) => rel.to_path_buf(),
+            Err(_) => abs_path,
+        };
+        let abs = normalize_concat_path(rel_path.to_string_lossy().as_ref());
+
+        lines.push_str("file '");
+        lines.push_str(&escape_concat_path(&abs));
+        lines.push_str("'\n");
+    }
+
+    fs::write(&list_path, lines).await?;
+    Ok(list_path)
+}
+
+/// Frames of output done according to one line of ffmpeg's `-progress` output:
+/// `frame=` as is, `out_time_us=`/`out_time_ms=` (both microseconds) at `fps`.
+/// Other keys and values such as `N/A` give `None`.
+pub fn progress_frames(line: &str, fps: f64) -> Option<usize> {
+    let (key, value) = line.trim().split_once('=')?;
+    let value = value.trim().parse::<u64>().ok()?;
+    match key {
+        "frame" => Some(value as usize),
+        "out_time_us" | "out_time_ms" => Some((value as f64 / 1_000_000.0 * fps) as usize),
+        _ => None,
+    }
+}
+
+/// Where an ffmpeg run stores how many of `total` frames it has written, as read
+/// from its `-progress` output.
+#[derive(Clone, Copy)]
+pub struct FrameProgress<'a> {
+    pub done: &'a AtomicUsize,
+    pub fps: f64,
+    pub total: usize,
+}
+
+impl FrameProgress<'_> {
+    /// Follow `-progress pipe:1` on `stdout` until ffmpeg closes it. Output that
+    /// cannot be read or parsed is skipped; it never fails or stalls the encode.
+    async fn follow(self, stdout: ChildStdout) {
+        let mut reader = BufReader::new(stdout);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            match reader.read_until(b'\n', &mut line).await {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+            if let Some(frames) = progress_frames(&String::from_utf8_lossy(&line), self.fps) {
+                self.done.store(frames.min(self.total), Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// `-progress` options for `cmd`, before its output path, when `progress` is set.
+fn add_progress_args(cmd: &mut TokioCommand, progress: Option<FrameProgress<'_>>) {
+    if progress.is_some() {
+        cmd.arg("-progress").arg("pipe:1").arg("-nostats");
+    }
+}
+
+/// Run `cmd`, following its `-progress` output into `progress` and collecting its
+/// stderr if that is piped.
+async fn output_with_progress(
+    cmd: &mut TokioCommand,
+    progress: Option<FrameProgress<'_>>,
+) -> io::Result<std::process::Output> {
+    cmd.stdout(if progress.is_some() {
+        Stdio::piped()
+    } else {
+        Stdio::null()
+    });
+    let mut child = cmd.spawn()?;
+    let stdout = child.stdout.take();
+    let mut stderr = child.stderr.take();
+    let read_stderr = async {
+        let mut buffer = Vec::new();
+        if let Some(stderr) = &mut stderr {
+            stderr.read_to_end(&mut buffer).await.ok();
+        }
+        buffer
+    };
+    let follow = async {
+        if let (Some(progress), Some(stdout)) = (progress, stdout) {
+            progress.follow(stdout).await;
+        }
+    };
+    let (stderr, ()) = tokio::join!(read_stderr, follow);
+    Ok(std::process::Output {
+        status: child.wait().await?,
+        stdout: Vec::new(),
+        stderr,
+    })
+}
+
+/// Run `cmd` to the end, following its `-progress` output into `progress`. A failure
+/// is `message` with ffmpeg's exit status, the end of its stderr and the command line.
+async fn run_ffmpeg(
+    cmd: &mut TokioCommand,
+    progress: Option<FrameProgress<'_>>,
+    message: &str,
+) -> Result<(), Box<dyn Error>> {
+    cmd.stdin(Stdio::null()).stderr(Stdio::piped());
+    let output = output_with_progress(cmd, progress).await?;
+    if !output.status.success() {
+        return Err(describe_failure(
+            message.to_string(),
+            Some(output.status),
+            &stderr_tail(&output.stderr),
+            &command_line(cmd),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Join `segments` into `output_path` with the concat demuxer and `output_args`.
+/// Segments are checked first against the `expected` count, see `readable_segments`. The error carries the end
+/// of ffmpeg's stderr. On success the list is deleted, and the segments in it too unless
+/// `keep_segments`; on failure everything stays. `progress` follows the join.
+#[allow(clippy::too_many_arguments)]
+async fn run_concat(
+    segments: Vec<ConcatSegment>,
+    expected: usize,
+    output_path: &Path,
+    input_args: &[String],
+    output_args: Vec<String>,
+    keep_segments: bool,
+    allow_missing: bool,
+    progress: Option<FrameProgress<'_>>,
+) -> Result<(), Box<dyn Error>> {
+    let segments = readable_segments(segments, expected, allow_missing).await?;
+    debug!(
+        "concatenating {} segments into {} ({})",
+        segments.len(),
+        output_path.display(),
+        output_args.join(" ")
+    );
+    let list_path = write_concat_list(&segments, output_path).await?;
+
+    let ffmpeg = resolve_checked_ffmpeg()?;
+    let mut cmd = TokioCommand::new(ffmpeg);
+    cmd.arg("-y")
+        .arg("-hide_banner")
+        .arg("-loglevel")
+        .arg("error")
+        .args(input_args)
+        .arg("-f")
+        .arg("concat")
+        .arg("-safe")
+        .arg("0")
+        .arg("-i")
+        .arg(&list_path)
+        .args(output_args);
+    add_progress_args(&mut cmd, progress);
+    cmd.arg(output_path);
+    run_ffmpeg(&mut cmd, progress, "ffmpeg concat failed").await?;
+
+    // --keep-intermediates はリストも残す約束なので一緒に残す
+    if !keep_segments {
+        for path in segments.iter().chain([&list_path]) {
+            if let Err(error) = fs::remove_file(path).await {
+                warn!("cannot delete {}: {error}", path.display());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Join `segments`, `expected` of them, without re-encoding. They must share codec
+/// parameters and have continuous timestamps.
+pub async fn concat_segments_mp4(
+    segments: Vec<ConcatSegment>,
+    expected: usize,
+    output_path: &Path,
+    keep_segments: bool,
+    allow_missing: bool,
+    progress: Option<FrameProgress<'_>>,
+) -> Result<(), Box<dyn Error>> {
+    let mut output_args = vec!["-c".to_string(), "copy".to_string()];
+    output_args.extend(faststart_args(output_path).into_iter().map(str::to_string));
+    run_concat(
+        segments,
+        expected,
+        output_path,
+        &[],
+        output_args,
+        keep_segments,
+        allow_missing,
+        progress,
+    )
+    .await
+}
+
+/// Join `segments`, `expected` of them, by decoding them and encoding once more
+/// with `settings`, the ones `SegmentWriter` used. Much slower than stream copy, but
+/// takes segments the concat demuxer refuses to copy, such as ones encoded with
+/// different presets or parameter sets.
+pub async fn concat_segments_reencode(
+    segments: Vec<ConcatSegment>,
+    expected: usize,
+    output_path: &Path,
+    settings: &EncodeSettings,
+    keep_segments: bool,
+    allow_missing: bool,
+    progress: Option<FrameProgress<'_>>,
+) -> Result<(), Box<dyn Error>> {
+    let encoder = settings.video_encoder()?;
+    let mut output_args = encoder.output_args(Vec::new());
+    // セグメントは変換済みなのでタグだけ付け直す
+    output_args.extend(color_tags(settings.encode, settings.color_range));
+    output_args.extend(container_args(settings.encode, output_path, settings.gop, false));
+    run_concat(
+        segments,
+        expected,
+        output_path,
+        &encoder.input_args,
+        output_args,
+        keep_segments,
+        allow_missing,
+        progress,
+    )
+    .await
+}
+
+/// Copy the first `frames` frames of a segment that was cut short into `output_path`,
+/// as a complete file the concat demuxer takes.
+pub async fn salvage_segment(
+    partial: &Path,
+    frames: usize,
+    output_path: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let ffmpeg = resolve_checked_ffmpeg()?;
+    let mut cmd = TokioCommand::new(ffmpeg);
+    cmd.arg("-y")
+        .arg("-hide_banner")
+        .arg("-loglevel")
+        .arg("error")
+        .arg("-i")
+        .arg(partial)
+        .arg("-map")
+        .arg("0:v:0")
+        .arg("-c")
+        .arg("copy")
+        .arg("-frames:v")
+        .arg(frames.to_string())
+        .args(faststart_args(output_path))
+        .arg(output_path);
+    run_ffmpeg(
+        &mut cmd,
+        None,
+        &format!("cannot salvage {}", partial.display()),
+    )
+    .await
+}
+
+/// Settings for GIF/WebP conversion.
+#[derive(Debug, Clone)]
+pub struct AnimationOptions {
+    /// Output frame rate; `None` keeps the source rate.
+    pub fps: Option<f64>,
+    /// Downscale to at most this width, keeping the aspect ratio.
+    pub max_width: Option<u32>,
+    /// Stop after this many output frames.
+    pub max_frames: Option<usize>,
+    /// `paletteuse` dither mode (GIF only).
+    pub dither: String,
+}
+
+impl AnimationOptions {
+    fn filter(&self) -> String {
+        let mut filters = Vec::new();
+        if let Some(fps) = self.fps {
+            filters.push(format!("fps={fps}"));
+        }
+        if let Some(width) = self.max_width {
+            filters.push(format!("scale='min({width},iw)':-2:flags=lanczos"));
+        }
+        // -frames:v は出力を止めるだけなので、palettegen にも効くようフィルタで切る
+        if let Some(frames) = self.max_frames {
+            filters.push(format!("trim=end_frame={frames}"));
+        }
+        if filters.is_empty() {
+            "null".to_string()
+        } else {
+            filters.join(",")
+        }
+    }
+}
+
+/// Convert `input_video` into a looping animation, GIF or WebP by the extension of
+/// `output`. GIF goes through palettegen/paletteuse so the 256 colors are picked from
+/// the actual frames instead of a fixed palette.
+pub async fn convert_to_animation(
+    input_video: &Path,
+    output: &Path,
+    options: &AnimationOptions,
+) -> Result<(), Box<dyn Error>> {
+    let ffmpeg = resolve_checked_ffmpeg()?;
+    let filter = options.filter();
+    let webp = output
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("webp"));
+
+    debug!("converting {} to {}", input_video.display(), output.display());
+    if webp {
+        let mut cmd = TokioCommand::new(&ffmpeg);
+        cmd.arg("-y")
+            .arg("-hide_banner")
+            .arg("-loglevel")
+            .arg("error")
+            .arg("-i")
+            .arg(input_video)
+            .arg("-vf")
+            .arg(&filter)
+            .arg("-an")
+            .arg("-c:v")
+            .arg("libwebp_anim")
+            .arg("-lossless")
+            .arg("0")
+            .arg("-q:v")
+            .arg("80")
+            .arg("-loop")
+            .arg("0")
+            .arg(output);
+        return run_ffmpeg(&mut cmd, None, "ffmpeg webp conversion failed").await;
+    }
+
+    let palette = output.with_extension("palette.png");
+    let mut cmd = TokioCommand::new(&ffmpeg);
+    cmd.arg("-y")
+        .arg("-hide_banner")
+        .arg("-loglevel")
+        .arg("error")
+        .arg("-i")
+        .arg(input_video)
+        .arg("-vf")
+        .arg(format!("{filter},palettegen=stats_mode=diff"))
+        .arg(&palette);
+    run_ffmpeg(&mut cmd, None, "ffmpeg palettegen failed").await?;
+
+    let mut cmd = TokioCommand::new(&ffmpeg);
+    cmd.arg("-y")
+        .arg("-hide_banner")
+        .arg("-loglevel")
+        .arg("error")
+        .arg("-i")
+        .arg(input_video)
+        .arg("-i")
+        .arg(&palette)
+        .arg("-lavfi")
+        .arg(format!(
+            "[0:v]{filter}[x];[x][1:v]paletteuse=dither={}:diff_mode=rectangle",
+            options.dither
+        ))
+        .arg("-loop")
+        .arg("0")
+        .arg(output);
+    let result = run_ffmpeg(&mut cmd, None, "ffmpeg paletteuse failed").await;
+    fs::remove_file(&palette).await.ok();
+    result
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum AudioSourceResolved {
+    Video { path: String },
+    Sound { path: String },
+}
+
+impl AudioSourceResolved {
+    pub fn path(&self) -> &str {
+        match self {
+            AudioSourceResolved::Video { path } | AudioSourceResolved::Sound { path } => path,
+        }
+    }
+}
+
+/// An `http(s)://` source, which ffmpeg fetches itself; the same test the backend
+/// uses when it resolves plan paths.
+pub fn is_remote_url(input: &str) -> bool {
+    let lower = input.trim_start().to_ascii_lowercase();
+    lower.starts_with("http://") || lower.starts_with("https://")
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AudioSegmentResolved {
+    pub id: String,
+    pub source: AudioSourceResolved,
+    #[serde(rename = "projectStartFrame")]
+    pub project_start_frame: i64,
+    #[serde(rename = "sourceStartFrame")]
+    pub source_start_frame: i64,
+    #[serde(rename = "durationFrames")]
+    pub duration_frames: i64,
+    /// Linear volume; 1 leaves the source as it is.
+    #[serde(default = "one")]
+    pub gain: f64,
+    #[serde(rename = "fadeInFrames", default)]
+    pub fade_in_frames: i64,
+    #[serde(rename = "fadeOutFrames", default)]
+    pub fade_out_frames: i64,
+    /// Source seconds played per second of output, as for a sped-up clip.
+    #[serde(rename = "playbackRate", default = "one")]
+    pub playback_rate: f64,
+}
+
+fn one() -> f64 {
+    1.0
+}
+
+impl AudioSegmentResolved {
+    /// `playbackRate`, or 1 when it is not a positive number.
+    fn rate(&self) -> f64 {
+        if self.playback_rate.is_finite() && self.playback_rate > 0.0 {
+            self.playback_rate
+        } else {
+            1.0
+        }
+    }
+}
+
+/// The audio of a composition, as served by the backend's `/render_audio_plan` or
+/// read from an `--audio-plan` file:
+///
+/// ```json
+/// {
+///   "fps": 60,
+///   "segments": [
+///     {
+///       "id": "bgm",
+///       "source": { "kind": "sound", "path": "/abs/path/bgm.mp3" },
+///       "projectStartFrame": 0,
+///       "sourceStartFrame": 120,
+///       "durationFrames": 600,
+///       "gain": 0.5,
+///       "fadeInFrames": 30,
+///       "fadeOutFrames": 60,
+///       "playbackRate": 1.5
+///     }
+///   ]
+/// }
+/// ```
+///
+/// `kind` is `sound` or `video` (the audio track of a video file). A segment plays
+/// `durationFrames` of its source from `sourceStartFrame`, starting at project frame
+/// `projectStartFrame`, scaled by `gain` (default 1) and faded in over its first
+/// `fadeInFrames` and out over its last `fadeOutFrames` (default 0, at most
+/// `durationFrames`). With `playbackRate` r (default 1) it takes
+/// `durationFrames * r` frames of source and time-stretches them to
+/// `durationFrames`. Frames are counted at the render's fps; `fps` is only the
+/// fallback when that is not set. Paths are opened by ffmpeg as is,
+/// so relative ones resolve against the render's working directory.
+///
+/// The plan may also set `"mastering"` (see `AudioMastering`),
+/// `"loudnessTarget"` in LUFS for it and `"channelLayout"` (`mono`, `stereo` or
+/// `5.1`); `--audio-mastering`, `--loudness-target` and `--audio-channels` take
+/// precedence.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AudioPlanResolved {
+    pub fps: f64,
+    pub segments: Vec<AudioSegmentResolved>,
+    #[serde(default)]
+    pub mastering: Option<AudioMastering>,
+    #[serde(rename = "loudnessTarget", default)]
+    pub loudness_target: Option<f64>,
+    #[serde(rename = "channelLayout", default)]
+    pub channel_layout: Option<ChannelLayout>,
+}
+
+/// Channels of the mixed audio. Every segment is converted to it on the way in,
+/// with ffmpeg's standard matrix: a mono source plays on both sides of stereo and
+/// from the center of 5.1, a stereo or 5.1 one is folded down to fewer channels.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, Deserialize)]
+pub enum ChannelLayout {
+    #[serde(rename = "mono")]
+    Mono,
+    #[default]
+    #[serde(rename = "stereo")]
+    Stereo,
+    #[value(name = "5.1")]
+    #[serde(rename = "5.1")]
+    Surround51,
+}
+
+impl ChannelLayout {
+    fn channels(self) -> u32 {
+        match self {
+            ChannelLayout::Mono => 1,
+            ChannelLayout::Stereo => 2,
+            ChannelLayout::Surround51 => 6,
+        }
+    }
+
+    fn ffmpeg_name(self) -> &'static str {
+        match self {
+            ChannelLayout::Mono => "mono",
+            ChannelLayout::Stereo => "stereo",
+            ChannelLayout::Surround51 => "5.1",
+        }
+    }
+}
+
+/// Stage on the final audio mix, which sums the segments as they are.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioMastering {
+    /// The plain sum, which clips where loud segments overlap.
+    Off,
+    /// Hold peaks under -1 dBFS and leave the level otherwise as it is.
+    #[default]
+    Limiter,
+    /// Two-pass `loudnorm` to the loudness target: the mix is measured first.
+    Loudnorm,
+}
+
+/// Integrated loudness `loudnorm` aims for without a `loudnessTarget`, in LUFS.
+const DEFAULT_LOUDNESS_TARGET: f64 = -14.0;
+
+/// -1 dBFS, as a limiter without auto-level so quiet mixes stay quiet.
+const LIMITER: &str = "alimiter=limit=0.891251:level=0";
+
+/// First ffmpeg whose `alimiter` has the `latency` option.
+const LIMITER_LATENCY_SINCE: FfmpegVersion = FfmpegVersion {
+    major: 5,
+    minor: 1,
+    patch: 0,
+};
+
+/// `LIMITER` for ffmpeg `version`, compensating its lookahead delay where the
+/// filter can so the audio stays in sync with the video.
+fn limiter(version: Option<FfmpegVersion>) -> String {
+    match version {
+        Some(version) if version >= LIMITER_LATENCY_SINCE => format!("{LIMITER}:latency=1"),
+        _ => LIMITER.to_string(),
+    }
+}
+
+/// What `loudnorm` prints with `print_format=json` after the measurement pass.
+#[derive(Deserialize)]
+struct LoudnessMeasurement {
+    input_i: String,
+    input_tp: String,
+    input_lra: String,
+    input_thresh: String,
+    target_offset: String,
+}
+
+/// `atempo` stages playing the source at `rate`, each within the 0.5 to 2 that every
+/// ffmpeg version accepts, then padded or cut to exactly `seconds` of output so
+/// rounding in atempo cannot shift the segments after it. Empty at rate 1.
+fn atempo_stages(rate: f64, seconds: f64) -> String {
+    if rate == 1.0 {
+        return String::new();
+    }
+    let mut stages = String::new();
+    let mut rest = rate;
+    while rest > 2.0 {
+        stages.push_str(",atempo=2.0");
+        rest /= 2.0;
+    }
+    while rest < 0.5 {
+        stages.push_str(",atempo=0.5");
+        rest /= 0.5;
+    }
+    stages.push_str(&format!(",atempo={rest:.6}"));
+    stages.push_str(&format!(",apad=whole_dur={seconds:.6},atrim=duration={seconds:.6}"));
+    stages
+}
+
+/// `volume` and `afade` stages for `seg`, of which `lead` frames are cut off the
+/// start and `length` frames are played, each stage starting with a comma.
+/// Fades are timed over the whole segment; when `lead` is set the audio has to be
+/// trimmed from the segment start and the `lead` dropped after fading, which the
+/// returned flag asks for. Empty for a segment at gain 1 without fades.
+fn gain_and_fades(
+    seg: &AudioSegmentResolved,
+    lead: i64,
+    length: i64,
+    fps: f64,
+) -> (String, bool) {
+    let fmt_f = |value: f64| format!("{:.6}", value.max(0.0));
+    let duration = seg.duration_frames.max(0);
+    let fade_in = seg.fade_in_frames.clamp(0, duration);
+    let fade_out = seg.fade_out_frames.clamp(0, duration);
+
+    let mut stages = String::new();
+    if seg.gain.is_finite() && seg.gain >= 0.0 && seg.gain != 1.0 {
+        stages.push_str(&format!(",volume={}", fmt_f(seg.gain)));
+    }
+    // 描画範囲より前で終わるフェードは付けない
+    let fade_in = (fade_in > lead).then_some(fade_in);
+    let fade_out = (duration - fade_out < lead + length && fade_out > 0).then_some(fade_out);
+    if let Some(fade_in) = fade_in {
+        stages.push_str(&format!(",afade=t=in:st=0:d={}", fmt_f(fade_in as f64 / fps)));
+    }
+    if let Some(fade_out) = fade_out {
+        stages.push_str(&format!(
+            ",afade=t=out:st={}:d={}",
+            fmt_f((duration - fade_out) as f64 / fps),
+            fmt_f(fade_out as f64 / fps)
+        ));
+    }
+    let from_segment_start = lead > 0 && (fade_in.is_some() || fade_out.is_some());
+    if from_segment_start {
+        stages.push_str(&format!(
+            ",atrim=start={},asetpts=PTS-STARTPTS",
+            fmt_f(lead as f64 / fps)
+        ));
+    }
+    (stages, from_segment_start)
+}
+
+/// Most inputs of one `amix`; more segments are mixed in groups of this size first.
+/// With `normalize=0` the sum is the same either way.
+const MIX_GROUP: usize = 32;
+
+/// Graphs longer than this go to ffmpeg in a file instead, well clear of the
+/// command-line limit (32 KiB in total on Windows).
+const MAX_INLINE_FILTER: usize = 16 * 1024;
+
+/// Set `filter` as the `-filter_complex` of `cmd`. A long one is written to a temp
+/// file for `-filter_complex_script`, which has to be kept until ffmpeg exits.
+fn set_filter_complex(
+    cmd: &mut TokioCommand,
+    filter: &str,
+) -> io::Result<Option<tempfile::NamedTempFile>> {
+    if filter.len() <= MAX_INLINE_FILTER {
+        cmd.arg("-filter_complex").arg(filter);
+        return Ok(None);
+    }
+    let mut script = tempfile::Builder::new()
+        .prefix("framescript-filter-")
+        .suffix(".txt")
+        .tempfile()?;
+    io::Write::write_all(&mut script, filter.as_bytes())?;
+    cmd.arg("-filter_complex_script").arg(script.path());
+    Ok(Some(script))
+}
+
+/// Audio inputs and a filter graph mixing an audio plan; `filter` gives it the
+/// mastering stage and the `[aout]` output.
+struct AudioPlanGraph {
+    inputs: Vec<String>,
+    mix: String,
+    layout: ChannelLayout,
+    sample_rate: u32,
+}
+
+impl AudioPlanGraph {
+    fn filter(&self, master: Option<&str>) -> String {
+        let master = master.map(|master| format!(",{master}")).unwrap_or_default();
+        format!(
+            "{}{master},aformat=sample_fmts=fltp:sample_rates={}:channel_layouts={}[aout]",
+            self.mix,
+            self.sample_rate,
+            self.layout.ffmpeg_name()
+        )
+    }
+}
+
+/// The mastering stage of `plan` over `frames`. `loudnorm` needs the mix measured
+/// first, which takes a pass of its own; the limiter and `off` do not.
+async fn mastering_filter(
+    plan: &AudioPlanResolved,
+    frames: &Range<usize>,
+    fps: f64,
+    sample_rate: u32,
+) -> Result<Option<String>, Box<dyn Error>> {
+    match plan.mastering.unwrap_or_default() {
+        AudioMastering::Off => Ok(None),
+        AudioMastering::Limiter => Ok(Some(limiter(ffmpeg_version()))),
+        AudioMastering::Loudnorm => {
+            let target = plan.loudness_target.unwrap_or(DEFAULT_LOUDNESS_TARGET);
+            let loudnorm = format!("loudnorm=I={target}:TP=-1:LRA=11");
+            match measure_loudness(plan, frames, fps, sample_rate, &loudnorm).await? {
+                Some(measured) => Ok(Some(format!(
+                    "{loudnorm}:measured_I={}:measured_TP={}:measured_LRA={}:measured_thresh={}:offset={}:linear=true,aresample={sample_rate}",
+                    measured.input_i,
+                    measured.input_tp,
+                    measured.input_lra,
+                    measured.input_thresh,
+                    measured.target_offset,
+                ))),
+                None => {
+                    // 無音だと -inf が返り、2 パス目に渡せない
+                    warn!("the audio mix has no measurable loudness, limiting it instead");
+                    Ok(Some(limiter(ffmpeg_version())))
+                }
+            }
+        }
+    }
+}
+
+/// Run the mix through `loudnorm` without writing it and read what it measured.
+/// `None` when a value is not a finite number, as for a silent mix.
+async fn measure_loudness(
+    plan: &AudioPlanResolved,
+    frames: &Range<usize>,
+    fps: f64,
+    sample_rate: u32,
+    loudnorm: &str,
+) -> Result<Option<LoudnessMeasurement>, Box<dyn Error>> {
+    let Some(graph) = audio_plan_graph(plan, frames, fps, 0, sample_rate) else {
+        return Ok(None);
+    };
+
+    let ffmpeg = resolve_checked_ffmpeg()?;
+    let mut cmd = TokioCommand::new(ffmpeg);
+    cmd.arg("-hide_banner")
+        .arg("-nostats")
+        .arg("-loglevel")
+        .arg("info");
+    for path in &graph.inputs {
+        cmd.arg("-i").arg(path);
+    }
+    let _script = set_filter_complex(
+        &mut cmd,
+        &graph.filter(Some(&format!("{loudnorm}:print_format=json"))),
+    )?;
+    cmd.arg("-map")
+        .arg("[aout]")
+        .arg("-f")
+        .arg("null")
+        .arg("-")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null());
+
+    debug!(command = ?cmd.as_std(), "measuring loudness");
+    let output = cmd.output().await?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        return Err(describe_failure(
+            "ffmpeg loudness measurement failed".to_string(),
+            Some(output.status),
+            &stderr_tail(&output.stderr),
+            &command_line(&cmd),
+        )
+        .into());
+    }
+    // JSON は最後の {...} ブロック
+    let json = stderr
+        .rfind('{')
+        .map(|start| &stderr[start..])
+        .and_then(|tail| tail.find('}').map(|end| &tail[..=end]))
+        .ok_or("ffmpeg printed no loudness measurement")?;
+    let measured: LoudnessMeasurement = serde_json::from_str(json)
+        .map_err(|e| format!("cannot read the loudness measurement: {e}"))?;
+    let finite = [
+        &measured.input_i,
+        &measured.input_tp,
+        &measured.input_lra,
+        &measured.input_thresh,
+        &measured.target_offset,
+    ]
+    .iter()
+    .all(|value| value.trim().parse::<f64>().is_ok_and(f64::is_finite));
+    Ok(finite.then_some(measured))
+}
+
+/// Graph for the plan over `frames`, where project frame `frames.start` becomes time 0.
+/// Audio inputs are numbered from `first_input`. `None` when no segment overlaps.
+fn audio_plan_graph(
+    plan: &AudioPlanResolved,
+    frames: &Range<usize>,
+    fps: f64,
+    first_input: usize,
+    sample_rate: u32,
+) -> Option<AudioPlanGraph> {
+    let fps = if fps.is_finite() && fps > 0.0 { fps } else { plan.fps };
+    let fps = if fps.is_finite() && fps > 0.0 { fps } else { 60.0 };
+    let duration_sec = (frames.len() as f64) / fps;
+
+    let mut sources: BTreeMap<String, usize> = BTreeMap::new();
+    let mut next_input_index = first_input;
+    for seg in &plan.segments {
+        let path = match &seg.source {
+            AudioSourceResolved::Video { path } => path,
+            AudioSourceResolved::Sound { path } => path,
+        };
+        if !sources.contains_key(path) {
+            sources.insert(path.clone(), next_input_index);
+            next_input_index += 1;
+        }
+    }
+
+    let mut ordered_sources: Vec<(String, usize)> = sources.into_iter().collect();
+    ordered_sources.sort_by_key(|(_, idx)| *idx);
+
+    let mut filter_parts: Vec<String> = Vec::new();
+
+    let fmt_f = |value: f64| format!("{:.6}", value.max(0.0));
+    let layout = plan.channel_layout.unwrap_or_default();
+    let cl = layout.ffmpeg_name();
+
+    // Base silent bed so output audio always starts at 0 and has deterministic duration.
+    filter_parts.push(format!(
+        "anullsrc=r={sample_rate}:cl={cl}:d={}[base]",
+        fmt_f(duration_sec)
+    ));
+
+    let mut segment_labels: Vec<String> = Vec::new();
+
+    for seg in plan.segments.iter() {
+        let n = segment_labels.len();
+        let src_path = match &seg.source {
+            AudioSourceResolved::Video { path } => path,
+            AudioSourceResolved::Sound { path } => path,
+        };
+        let Some(&input_idx) = ordered_sources
+            .iter()
+            .find(|(p, _)| p == src_path)
+            .map(|(_, idx)| idx)
+        else {
+            continue;
+        };
+
+        // 描画範囲に切り詰める
+        let seg_start = seg.project_start_frame.max(0);
+        let seg_end = seg_start + seg.duration_frames.max(0);
+        let clipped_start = seg_start.max(frames.start as i64);
+        let clipped_end = seg_end.min(frames.end as i64);
+        if clipped_end <= clipped_start {
+            continue;
+        }
+
+        let lead = clipped_start - seg_start;
+        let (stages, from_segment_start) =
+            gain_and_fades(seg, lead, clipped_end - clipped_start, fps);
+        let trim_from = if from_segment_start { seg_start } else { clipped_start };
+
+        let project_start_frame = (clipped_start - frames.start as i64) as f64;
+        // 再生速度 r のセグメントは出力 1 フレームにつき r フレーム分のソースを使う
+        let rate = seg.rate();
+        let source_start_frame =
+            seg.source_start_frame.max(0) as f64 + (trim_from - seg_start) as f64 * rate;
+        let duration_frames = (clipped_end - trim_from) as f64;
+        let tempo = atempo_stages(rate, duration_frames / fps);
+
+        let start_sec = source_start_frame / fps;
+        let dur_sec = duration_frames * rate / fps;
+        let delay_ms = ((project_start_frame / fps) * 1000.0).round().max(0.0) as i64;
+
+        filter_parts.push(format!(
+            "[{input_idx}:a]atrim=start={}:duration={},asetpts=PTS-STARTPTS,aresample={sample_rate},aformat=channel_layouts={cl}{tempo}{stages},adelay={delay_ms}:all=1[a{n}]",
+            fmt_f(start_sec),
+            fmt_f(dur_sec),
+        ));
+
+        segment_labels.push(format!("[a{n}]"));
+    }
+
+    if segment_labels.is_empty() {
+        return None;
+    }
+
+    // 入力が多い amix は遅いので、まとまりごとに先に足し合わせる
+    let mut level = 0;
+    while segment_labels.len() >= MIX_GROUP {
+        segment_labels = segment_labels
+            .chunks(MIX_GROUP)
+            .enumerate()
+            .map(|(group, labels)| {
+                if labels.len() == 1 {
+                    return labels[0].clone();
+                }
+                filter_parts.push(format!(
+                    "{}amix=inputs={}:duration=longest:normalize=0[m{level}_{group}]",
+                    labels.concat(),
+                    labels.len()
+                ));
+                format!("[m{level}_{group}]")
+            })
+            .collect();
+        level += 1;
+    }
+
+    let seg_count = segment_labels.len();
+    let mix_inputs = std::iter::once("[base]".to_string())
+        .chain(segment_labels.iter().cloned())
+        .collect::<String>();
+
+    let total_inputs = 1 + seg_count;
+    filter_parts.push(format!(
+        "{mix_inputs}amix=inputs={total_inputs}:duration=first:normalize=0"
+    ));
+
+    Some(AudioPlanGraph {
+        inputs: ordered_sources.into_iter().map(|(path, _)| path).collect(),
+        mix: filter_parts.join(";"),
+        layout,
+        sample_rate,
+    })
+}
+
+/// The source of `plan` when its audio can go under the video without decoding it:
+/// one segment playing its source from the start over all of `frames`, at gain 1
+/// without fades, tempo or loudnorm, in a codec the output holds and at the rate,
+/// layout and codec asked for, if any. The source has to last as long as the frames
+/// at `fps`, since `-shortest` would otherwise cut the video to it. A lone segment
+/// has nothing to clip against, so the limiter is not missed.
+async fn copyable_source<'a>(
+    plan: &'a AudioPlanResolved,
+    frames: &Range<usize>,
+    fps: f64,
+    audio: &AudioEncode,
+    output: &Path,
+) -> Result<Option<&'a str>, Box<dyn Error>> {
+    let mut overlapping = plan.segments.iter().filter(|seg| {
+        let start = seg.project_start_frame.max(0);
+        start < frames.end as i64 && start + seg.duration_frames.max(0) > frames.start as i64
+    });
+    let (Some(seg), None) = (overlapping.next(), overlapping.next()) else {
+        return Ok(None);
+    };
+    let untouched = seg.project_start_frame == frames.start as i64
+        && seg.source_start_frame == 0
+        && seg.project_start_frame + seg.duration_frames >= frames.end as i64
+        && seg.gain == 1.0
+        && seg.fade_in_frames <= 0
+        && seg.fade_out_frames <= 0
+        && seg.rate() == 1.0
+        && plan.mastering != Some(AudioMastering::Loudnorm)
+        && audio.bitrate.is_none();
+    if !untouched {
+        return Ok(None);
+    }
+
+    let Some(probe) = probe_audio(Path::new(seg.source.path())).await? else {
+        return Ok(None);
+    };
+    let extension = lowercase_extension(output);
+    let Some(codec) = AudioCodec::from_ffprobe(&probe.codec) else {
+        return Ok(None);
+    };
+    let seconds = frames.len() as f64 / fps;
+    let copyable = codec.fits(&extension)
+        && probe.duration.is_some_and(|duration| duration >= seconds)
+        && audio.codec.is_none_or(|wanted| wanted == codec)
+        && audio.sample_rate.is_none_or(|rate| rate == probe.sample_rate)
+        && plan
+            .channel_layout
+            .is_none_or(|layout| layout.channels() == probe.channels);
+    Ok(copyable.then(|| seg.source.path()))
+}
+
+/// Mux the audio plan under `input_video`, which holds the composition frames in
+/// `frames`: project frame `frames.start` becomes output time 0. Returns `false`
+/// without writing anything when no audio falls inside `frames`. A plan that is
+/// one untouched source (see `copyable_source`) is stream-copied instead of mixed.
+pub async fn mux_audio_plan_into_mp4(
+    input_video: &Path,
+    output_video: &Path,
+    plan: &AudioPlanResolved,
+    frames: Range<usize>,
+    fps: f64,
+    audio: &AudioEncode,
+    progress: Option<FrameProgress<'_>>,
+) -> Result<bool, Box<dyn Error>> {
+    let ffmpeg = resolve_checked_ffmpeg()?;
+    let mut cmd = TokioCommand::new(ffmpeg);
+    cmd.arg("-y")
+        .arg("-hide_banner")
+        .arg("-loglevel")
+        .arg("error")
+        .arg("-i")
+        .arg(input_video);
+
+    // input #0 is video
+    let mut _script = None;
+    if let Some(source) = copyable_source(plan, &frames, fps, audio, output_video).await? {
+        debug!("copying the audio of {source} as it is");
+        cmd.arg("-i")
+            .arg(source)
+            .arg("-map")
+            .arg("0:v:0")
+            .arg("-map")
+            .arg("1:a:0")
+            .arg("-c:v")
+            .arg("copy")
+            .arg("-c:a")
+            .arg("copy");
+    } else {
+        let Some(graph) = audio_plan_graph(plan, &frames, fps, 1, audio.sample_rate()) else {
+            // nothing to mux
+            return Ok(false);
+        };
+        let master = mastering_filter(plan, &frames, fps, audio.sample_rate()).await?;
+        for path in &graph.inputs {
+            cmd.arg("-i").arg(path);
+        }
+        _script = set_filter_complex(&mut cmd, &graph.filter(master.as_deref()))?;
+        cmd.arg("-map")
+            .arg("0:v:0")
+            .arg("-map")
+            .arg("[aout]")
+            .arg("-c:v")
+            .arg("copy")
+            .args(audio.args(output_video));
+    }
+    cmd.arg("-shortest")
+        .arg("-avoid_negative_ts")
+        .arg("make_zero")
+        .args(faststart_args(output_video));
+    add_progress_args(&mut cmd, progress);
+    cmd.arg(output_video);
+
+    debug!(command = ?cmd.as_std(), "muxing audio");
+    run_ffmpeg(&mut cmd, progress, "ffmpeg audio mux failed").await?;
+
+    Ok(true)
+}
+
+/// Mix the audio plan over `frames` into a standalone audio file. Returns `false`
+/// without writing anything when no audio falls inside `frames`. With `mixed_frames`,
+/// ffmpeg's `-progress` output is followed and the number of frames' worth of audio
+/// written so far is stored there.
+pub async fn export_audio_plan(
+    output_audio: &Path,
+    plan: &AudioPlanResolved,
+    frames: Range<usize>,
+    fps: f64,
+    audio: &AudioEncode,
+    mixed_frames: Option<&AtomicUsize>,
+) -> Result<bool, Box<dyn Error>> {
+    let Some(graph) = audio_plan_graph(plan, &frames, fps, 0, audio.sample_rate()) else {
+        return Ok(false);
+    };
+    let master = mastering_filter(plan, &frames, fps, audio.sample_rate()).await?;
+
+    let ffmpeg = resolve_checked_ffmpeg()?;
+    let mut cmd = TokioCommand::new(ffmpeg);
+    cmd.arg("-y")
+        .arg("-hide_banner")
+        .arg("-loglevel")
+        .arg("error");
+
+    for path in &graph.inputs {
+        cmd.arg("-i").arg(path);
+    }
+
+    let _script = set_filter_complex(&mut cmd, &graph.filter(master.as_deref()))?;
+    cmd.arg("-map")
+        .arg("[aout]")
+        .args(audio.args(output_audio))
+        .args(faststart_args(output_audio));
+    let progress = mixed_frames.map(|done| FrameProgress {
+        done,
+        fps,
+        total: frames.len(),
+    });
+    add_progress_args(&mut cmd, progress);
+    cmd.arg(output_audio);
+
+    debug!(command = ?cmd.as_std(), "mixing audio");
+    run_ffmpeg(&mut cmd, progress, "ffmpeg audio export failed").await?;
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plan(segments: serde_json::Value) -> AudioPlanResolved {
+        serde_json::from_value(serde_json::json!({ "fps": 30, "segments": segments })).unwrap()
+    }
+
+    /// The filter of the segment labelled `[a{n}]` in `graph`.
+    fn segment_filter(graph: &AudioPlanGraph, n: usize) -> &str {
+        let label = format!("[a{n}]");
+        graph
+            .mix
+            .split(';')
+            .find(|part| part.ends_with(&label))
+            .unwrap_or_else(|| panic!("no segment {label} in {}", graph.mix))
+    }
+
+    #[test]
+    fn a_sub_range_starts_the_audio_at_its_first_frame() {
+        let plan = plan(serde_json::json!([
+            {
+                "id": "inside",
+                "source": { "kind": "sound", "path": "inside.wav" },
+                "projectStartFrame": 45,
+                "sourceStartFrame": 0,
+                "durationFrames": 30
+            },
+            {
+                "id": "across",
+                "source": { "kind": "sound", "path": "across.wav" },
+                "projectStartFrame": 0,
+                "sourceStartFrame": 15,
+                "durationFrames": 120
+            },
+            {
+                "id": "before",
+                "source": { "kind": "sound", "path": "before.wav" },
+                "projectStartFrame": 0,
+                "sourceStartFrame": 0,
+                "durationFrames": 30
+            }
+        ]));
+        let graph = audio_plan_graph(&plan, &(30..60), 30.0, 1, 48000).unwrap();
+
+        // one second of output, whatever the length of the composition
+        assert!(
+            graph
+                .mix
+                .starts_with("anullsrc=r=48000:cl=stereo:d=1.000000[base]")
+        );
+        assert_eq!(graph.inputs, ["inside.wav", "across.wav", "before.wav"]);
+
+        // frame 45 is half a second into a range starting at frame 30
+        let inside = segment_filter(&graph, 0);
+        assert!(
+            inside.starts_with("[1:a]atrim=start=0.000000:duration=0.500000,"),
+            "{inside}"
+        );
+        assert!(inside.ends_with(",adelay=500:all=1[a0]"), "{inside}");
+
+        // a segment already playing picks up its source at frame 30, with no delay
+        let across = segment_filter(&graph, 1);
+        assert!(
+            across.starts_with("[2:a]atrim=start=1.500000:duration=1.000000,"),
+            "{across}"
+        );
+        assert!(across.ends_with(",adelay=0:all=1[a1]"), "{across}");
+
+        // one ending at frame 30 is left out
+        assert!(!graph.mix.contains("[3:a]"), "{}", graph.mix);
+        assert!(
+            graph
+                .mix
+                .ends_with("[base][a0][a1]amix=inputs=3:duration=first:normalize=0")
+        );
+    }
+
+    #[test]
+    fn a_range_without_audio_has_no_graph() {
+        let plan = plan(serde_json::json!([{
+            "id": "later",
+            "source": { "kind": "sound", "path": "later.wav" },
+            "projectStartFrame": 90,
+            "sourceStartFrame": 0,
+            "durationFrames": 30
+        }]));
+        assert!(audio_plan_graph(&plan, &(30..90), 30.0, 1, 48000).is_none());
+    }
+
+    #[tokio::test]
+    async fn a_muxed_sub_range_lasts_as_long_as_its_frames_and_stays_in_sync() {
+        // frames 30..60 of the composition, already encoded
+        let Some(video) = fixtures::test_video("range.mp4", 30) else {
+            return;
+        };
+        let Some(tone) = fixtures::sine("tone.wav", 2.0) else {
+            return;
+        };
+        let plan = plan(serde_json::json!([{
+            "id": "tone",
+            "source": { "kind": "sound", "path": tone.path },
+            "projectStartFrame": 45,
+            "sourceStartFrame": 0,
+            "durationFrames": 60
+        }]));
+        let output = video.dir.path().join("muxed.mp4");
+
+        let muxed = mux_audio_plan_into_mp4(
+            &video.path,
+            &output,
+            &plan,
+            30..60,
+            30.0,
+            &AudioEncode::default(),
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(muxed);
+
+        let format = fixtures::probe(&output, "a:0", "format=duration");
+        let duration = fixtures::seconds(&format, "duration");
+        assert!((duration - 1.0).abs() < 0.05, "{duration}s");
+        let video_stream = fixtures::probe(&output, "v:0", "stream=nb_frames");
+        assert_eq!(fixtures::entry(&video_stream, "nb_frames"), "30");
+
+        // silent until frame 45, half a second in, then the tone
+        let samples = fixtures::mono_pcm(&output);
+        let peak_before = fixtures::peak(&samples[..19_200]);
+        let peak_after = fixtures::peak(&samples[28_800..45_600]);
+        assert!(
+            peak_before < 0.01,
+            "audio before frame 45 peaks at {peak_before}"
+        );
+        assert!(
+            peak_after > 0.1,
+            "audio after frame 45 peaks at {peak_after}"
+        );
+    }
+
+    const SIZE: u32 = 32;
+
+    /// A frame whose left half is fully transparent and right half opaque red.
+    fn half_transparent_frame() -> Vec<u8> {
+        (0..SIZE * SIZE)
+            .flat_map(|pixel| {
+                if pixel % SIZE < SIZE / 2 {
+                    [0, 0, 0, 0]
+                } else {
+                    [255, 0, 0, 255]
+                }
+            })
+            .collect()
+    }
+
+    /// `encode` at crf 18 with everything else at its default.
+    fn settings(encode: EncoderKind) -> EncodeSettings {
+        EncodeSettings {
+            encode,
+            quality: Quality::Crf(18),
+            preset: "medium".to_string(),
+            gop: None,
+            pixel_format: None,
+            color_range: ColorRange::Limited,
+            tuning: EncoderTuning::default(),
+        }
+    }
+
+    fn alpha_at(rgba: &[u8], x: u32, y: u32) -> u8 {
+        rgba[((y * SIZE + x) * 4 + 3) as usize]
+    }
+
+    /// Encode two segments of half-transparent frames with `encode`, join them and
+    /// mux a tone under them, as a render does. The path of the final file.
+    async fn render_with_audio(
+        encode: EncoderKind,
+        extension: &str,
+        dir: &Path,
+        tone: &Path,
+    ) -> PathBuf {
+        let settings = settings(encode);
+        let frame = half_transparent_frame();
+        let mut segments = Vec::new();
+        for index in 0..2 {
+            let path = dir.join(format!("segment-{index}.{extension}"));
+            let mut writer = SegmentWriter::new(
+                path.to_str().unwrap(),
+                SIZE,
+                SIZE,
+                30.0,
+                &settings,
+                FrameInput::Rgba,
+                None,
+                false,
+            )
+            .await
+            .unwrap();
+            for _ in 0..5 {
+                writer.write_frame(&frame).await.unwrap();
+            }
+            writer.finish().await.unwrap();
+            segments.push(ConcatSegment {
+                path,
+                origin: format!("segment {index}"),
+            });
+        }
+
+        let joined = dir.join(format!("joined.{extension}"));
+        concat_segments_mp4(segments, 2, &joined, true, false, None)
+            .await
+            .unwrap();
+        let plan = plan(serde_json::json!([{
+            "id": "tone",
+            "source": { "kind": "sound", "path": tone },
+            "projectStartFrame": 0,
+            "sourceStartFrame": 0,
+            "durationFrames": 10
+        }]));
+        let output = dir.join(format!("output.{extension}"));
+        let muxed = mux_audio_plan_into_mp4(
+            &joined,
+            &output,
+            &plan,
+            0..10,
+            30.0,
+            &AudioEncode::default(),
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(muxed);
+        output
+    }
+
+    #[tokio::test]
+    async fn transparent_pixels_survive_to_the_final_file() {
+        let Some(tone) = fixtures::sine("tone.wav", 1.0) else {
+            return;
+        };
+        let cases = [
+            (EncoderKind::ProRes4444, "mov", "yuva444p10le", None),
+            (EncoderKind::Qtrle, "mov", "argb", None),
+            // ffprobe reports the base layer; the alpha is a side stream libvpx reads
+            (EncoderKind::Vp9, "webm", "yuv420p", Some("libvpx-vp9")),
+        ];
+        for (encode, extension, pix_fmt, decoder) in cases {
+            let output = render_with_audio(encode, extension, tone.dir.path(), &tone.path).await;
+
+            let video = fixtures::probe(&output, "v:0", "stream=pix_fmt,nb_frames");
+            assert_eq!(fixtures::entry(&video, "pix_fmt"), pix_fmt, "{encode}");
+            if encode == EncoderKind::Vp9 {
+                let tags = fixtures::probe(&output, "v:0", "stream_tags=alpha_mode");
+                assert_eq!(fixtures::entry(&tags, "TAG:alpha_mode"), "1", "{encode}");
+            }
+            let audio = fixtures::probe(&output, "a", "stream=codec_type");
+            assert_eq!(fixtures::entry(&audio, "codec_type"), "audio", "{encode}");
+
+            let rgba = fixtures::rgba_frame(&output, decoder);
+            assert_eq!(rgba.len(), (SIZE * SIZE * 4) as usize, "{encode}");
+            let transparent = alpha_at(&rgba, 4, SIZE / 2);
+            let opaque = alpha_at(&rgba, SIZE - 4, SIZE / 2);
+            assert!(
+                transparent < 16,
+                "{encode}: alpha {transparent} on the left"
+            );
+            assert!(opaque > 240, "{encode}: alpha {opaque} on the right");
+        }
+    }
+
+    #[tokio::test]
+    async fn every_container_holds_the_video_and_its_audio() {
+        let Some(tone) = fixtures::sine("tone.wav", 1.0) else {
+            return;
+        };
+        let cases = [
+            (
+                EncoderKind::X264,
+                "mp4",
+                "mov,mp4,m4a,3gp,3g2,mj2",
+                "h264",
+                "aac",
+            ),
+            (
+                EncoderKind::X264,
+                "mov",
+                "mov,mp4,m4a,3gp,3g2,mj2",
+                "h264",
+                "aac",
+            ),
+            (EncoderKind::X264, "mkv", "matroska,webm", "h264", "aac"),
+            (EncoderKind::Vp9, "webm", "matroska,webm", "vp9", "opus"),
+        ];
+        for (encode, extension, format_name, video_codec, audio_codec) in cases {
+            let output = render_with_audio(encode, extension, tone.dir.path(), &tone.path).await;
+
+            let format = fixtures::probe(&output, "v:0", "format=format_name");
+            assert_eq!(
+                fixtures::entry(&format, "format_name"),
+                format_name,
+                "{extension}"
+            );
+            let video = fixtures::probe(&output, "v", "stream=codec_name");
+            assert_eq!(
+                fixtures::entry(&video, "codec_name"),
+                video_codec,
+                "{extension}"
+            );
+            let audio = fixtures::probe(&output, "a", "stream=codec_name");
+            assert_eq!(
+                fixtures::entry(&audio, "codec_name"),
+                audio_codec,
+                "{extension}"
+            );
+            let streams = fixtures::probe(&output, "", "stream=codec_type");
+            assert_eq!(streams.len(), 2, "{extension}: {streams:?}");
+        }
+    }
+
+    #[test]
+    fn the_stderr_tail_keeps_the_last_lines_cut_short() {
+        let mut stderr = (0..30)
+            .map(|line| format!("line {line}\n"))
+            .collect::<String>()
+            .into_bytes();
+        stderr.extend(b"\n  \n");
+        stderr.extend(vec![b'x'; 2 * STDERR_LINE_BYTES]);
+        let tail = stderr_tail(&stderr);
+        let lines = tail.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), STDERR_TAIL_LINES);
+        assert_eq!(lines[0], "line 11");
+        assert_eq!(lines[STDERR_TAIL_LINES - 2], "line 29");
+        assert_eq!(lines[STDERR_TAIL_LINES - 1].len(), STDERR_LINE_BYTES);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn a_failed_run_reports_the_stderr_and_the_command() {
+        let mut cmd = TokioCommand::new("sh");
+        cmd.arg("-c")
+            .arg("echo 'Unknown encoder libfoo' >&2; exit 3");
+        let error = run_ffmpeg(&mut cmd, None, "ffmpeg webp conversion failed")
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.starts_with("ffmpeg webp conversion failed (ffmpeg exit status: 3)"),
+            "{error}"
+        );
+        assert!(
+            error.contains("\n  ffmpeg: Unknown encoder libfoo"),
+            "{error}"
+        );
+        assert!(
+            error.ends_with("\n  command: sh -c echo 'Unknown encoder libfoo' >&2; exit 3"),
+            "{error}"
+        );
+    }
+
+    /// A writer of `SIZE`x`SIZE` `input` frames into `path`.
+    async fn writer(path: &Path, settings: &EncodeSettings, input: FrameInput) -> SegmentWriter {
+        SegmentWriter::new(
+            path.to_str().unwrap(),
+            SIZE,
+            SIZE,
+            30.0,
+            settings,
+            input,
+            None,
+            false,
+        )
+        .await
+        .unwrap()
+    }
+
+    /// A `SIZE`x`SIZE` frame of one 4-byte pixel.
+    fn solid_frame(pixel: [u8; 4]) -> Vec<u8> {
+        pixel.repeat((SIZE * SIZE) as usize)
+    }
+
+    #[tokio::test]
+    async fn raw_frames_round_trip_through_a_segment() {
+        if !fixtures::tools_available() {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        // the same orange in both byte orders
+        let cases = [
+            (FrameInput::Rgba, [255, 128, 0, 255]),
+            (FrameInput::Bgra, [0, 128, 255, 255]),
+        ];
+        for (input, pixel) in cases {
+            let path = dir.path().join(format!("{input:?}.mp4"));
+            let mut writer = writer(&path, &settings(EncoderKind::X264), input).await;
+            for _ in 0..6 {
+                writer.write_frame(&solid_frame(pixel)).await.unwrap();
+            }
+            writer.finish().await.unwrap();
+
+            let video = fixtures::probe(&path, "v:0", "stream=width,height,nb_frames");
+            assert_eq!(
+                fixtures::entry(&video, "width"),
+                SIZE.to_string(),
+                "{input:?}"
+            );
+            assert_eq!(
+                fixtures::entry(&video, "height"),
+                SIZE.to_string(),
+                "{input:?}"
+            );
+            assert_eq!(fixtures::entry(&video, "nb_frames"), "6", "{input:?}");
+            let rgba = fixtures::rgba_frame(&path, None);
+            let center = ((SIZE / 2 * SIZE + SIZE / 2) * 4) as usize;
+            assert!(
+                rgba[center] > 230 && rgba[center + 2] < 25,
+                "{input:?} decoded to {:?}",
+                &rgba[center..center + 4]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn a_raw_frame_of_the_wrong_size_is_rejected() {
+        if !fixtures::tools_available() {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("segment.mp4");
+        let mut writer = writer(&path, &settings(EncoderKind::X264), FrameInput::Bgra).await;
+
+        let mut frame = solid_frame([0, 0, 0, 255]);
+        frame.pop();
+        let error = writer.write_frame(&frame).await.unwrap_err().to_string();
+        assert_eq!(
+            error,
+            format!(
+                "raw frame is {} bytes, expected {}",
+                frame.len(),
+                frame.len() + 1
+            )
+        );
+        // nothing reached ffmpeg, so the segment is still whole
+        frame.push(255);
+        writer.write_frame(&frame).await.unwrap();
+        writer.finish().await.unwrap();
+        let video = fixtures::probe(&path, "v:0", "stream=nb_frames");
+        assert_eq!(fixtures::entry(&video, "nb_frames"), "1");
+    }
+
+    #[test]
+    fn pixel_formats_select_the_profile_they_need() {
+        let cases = [
+            (EncoderKind::X264, "yuv420p", None),
+            (EncoderKind::X264, "yuv444p", Some("high444")),
+            (EncoderKind::X265, "yuv420p", None),
+            (EncoderKind::X265, "yuv444p", Some("main444-8")),
+            (EncoderKind::X265, "yuv420p10le", Some("main10")),
+        ];
+        for (encode, format, profile) in cases {
+            let encoder = video_encoder(
+                encode,
+                Quality::Crf(18),
+                "medium",
+                Some(format),
+                &EncoderTuning::default(),
+            )
+            .unwrap();
+            let args = encoder.output_args.join(" ");
+            assert!(args.contains(&format!("-pix_fmt {format}")), "{args}");
+            match profile {
+                Some(profile) => assert!(args.contains(&format!("-profile:v {profile}")), "{args}"),
+                None => assert!(!args.contains("-profile:v"), "{args}"),
+            }
+        }
+
+        // a profile given as tuning wins
+        let tuning = EncoderTuning {
+            profile: Some("high444".to_string()),
+            ..EncoderTuning::default()
+        };
+        let encoder = video_encoder(
+            EncoderKind::X264,
+            Quality::Crf(18),
+            "medium",
+            Some("yuv444p"),
+            &tuning,
+        )
+        .unwrap();
+        assert_eq!(
+            encoder
+                .output_args
+                .iter()
+                .filter(|arg| *arg == "-profile:v")
+                .count(),
+            1
+        );
+
+        let unsupported = |encode, format| {
+            video_encoder(
+                encode,
+                Quality::Crf(18),
+                "medium",
+                Some(format),
+                &EncoderTuning::default(),
+            )
+            .err()
+            .map(|error| error.to_string())
+        };
+        assert_eq!(
+            unsupported(EncoderKind::X264, "yuv420p10le").as_deref(),
+            Some("H264 cannot encode yuv420p10le")
+        );
+        assert!(
+            unsupported(EncoderKind::Vp9, "yuv444p")
+                .is_some_and(|error| error.contains("only H264/H265"))
+        );
+    }
+
+    #[tokio::test]
+    async fn every_pixel_format_encodes_and_survives_the_mux() {
+        let Some(tone) = fixtures::sine("tone.wav", 1.0) else {
+            return;
+        };
+        let cases = [
+            (EncoderKind::X264, "yuv420p", "High"),
+            (EncoderKind::X264, "yuv444p", "High 4:4:4 Predictive"),
+            (EncoderKind::X265, "yuv420p", "Main"),
+            (EncoderKind::X265, "yuv444p", "Main 4:4:4"),
+            (EncoderKind::X265, "yuv420p10le", "Main 10"),
+        ];
+        for (encode, format, profile) in cases {
+            let settings = EncodeSettings {
+                pixel_format: Some(format),
+                ..settings(encode)
+            };
+            let available = test_encode(&settings.video_encoder().unwrap()).await;
+            if !available.is_ok_and(|output| output.status.success()) {
+                eprintln!("skipping {encode} {format}: not in this ffmpeg build");
+                continue;
+            }
+
+            let segment = tone.dir.path().join(format!("{encode}-{format}.mp4"));
+            let mut writer = writer(&segment, &settings, FrameInput::Rgba).await;
+            for _ in 0..3 {
+                writer
+                    .write_frame(&solid_frame([40, 160, 220, 255]))
+                    .await
+                    .unwrap();
+            }
+            writer.finish().await.unwrap();
+            let video = fixtures::probe(&segment, "v:0", "stream=pix_fmt,profile");
+            assert_eq!(fixtures::entry(&video, "pix_fmt"), format, "{encode}");
+            assert_eq!(
+                fixtures::entry(&video, "profile"),
+                profile,
+                "{encode} {format}"
+            );
+
+            // the mux copies the video as it is
+            let output = tone.dir.path().join(format!("{encode}-{format}-muxed.mp4"));
+            let plan = plan(serde_json::json!([{
+                "id": "tone",
+                "source": { "kind": "sound", "path": tone.path },
+                "projectStartFrame": 0,
+                "sourceStartFrame": 0,
+                "durationFrames": 3
+            }]));
+            let muxed = mux_audio_plan_into_mp4(
+                &segment,
+                &output,
+                &plan,
+                0..3,
+                30.0,
+                &AudioEncode::default(),
+                None,
+            )
+            .await
+            .unwrap();
+            assert!(muxed);
+            assert_eq!(
+                fixtures::probe(&output, "v:0", "stream=pix_fmt,profile"),
+                video
+            );
+        }
+    }
+
+    #[test]
+    fn yuv_output_is_converted_and_tagged_as_bt709() {
+        assert_eq!(
+            color_conversion(ColorRange::Full),
+            "out_color_matrix=bt709:out_range=pc"
+        );
+        assert_eq!(
+            color_tags(EncoderKind::X264, ColorRange::Limited).join(" "),
+            "-colorspace bt709 -color_primaries bt709 -color_trc iec61966-2-1 -color_range tv"
+        );
+        assert!(color_tags(EncoderKind::Qtrle, ColorRange::Limited).is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_solid_color_keeps_its_value_in_either_range() {
+        if !fixtures::tools_available() {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let source = [200, 60, 30];
+        for range in [ColorRange::Limited, ColorRange::Full] {
+            let settings = EncodeSettings {
+                // 444 so chroma subsampling plays no part
+                pixel_format: Some("yuv444p"),
+                color_range: range,
+                ..settings(EncoderKind::X264)
+            };
+            let path = dir.path().join(format!("{range}.mp4"));
+            let mut writer = writer(&path, &settings, FrameInput::Rgba).await;
+            for _ in 0..3 {
+                let [r, g, b] = source;
+                writer
+                    .write_frame(&solid_frame([r, g, b, 255]))
+                    .await
+                    .unwrap();
+            }
+            writer.finish().await.unwrap();
+
+            let tags = fixtures::probe(
+                &path,
+                "v:0",
+                "stream=color_space,color_primaries,color_transfer,color_range",
+            );
+            assert_eq!(fixtures::entry(&tags, "color_space"), "bt709", "{range}");
+            assert_eq!(
+                fixtures::entry(&tags, "color_primaries"),
+                "bt709",
+                "{range}"
+            );
+            assert_eq!(
+                fixtures::entry(&tags, "color_transfer"),
+                "iec61966-2-1",
+                "{range}"
+            );
+            assert_eq!(
+                fixtures::entry(&tags, "color_range"),
+                range.ffmpeg_name(),
+                "{range}"
+            );
+
+            // decode as a player reading the tags would
+            let filter = format!(
+                "scale=in_color_matrix=bt709:in_range={},format=rgba",
+                range.ffmpeg_name()
+            );
+            let output = std::process::Command::new(resolve_ffmpeg_path().unwrap())
+                .args(["-hide_banner", "-loglevel", "error", "-i"])
+                .arg(&path)
+                .args(["-frames:v", "1", "-vf", &filter, "-f", "rawvideo", "-"])
+                .output()
+                .unwrap();
+            assert!(output.status.success());
+            let center = ((SIZE / 2 * SIZE + SIZE / 2) * 4) as usize;
+            let decoded = &output.stdout[center..center + 3];
+            for (channel, (&decoded, source)) in decoded.iter().zip(source).enumerate() {
+                assert!(
+                    decoded.abs_diff(source) <= 6,
+                    "{range}: channel {channel} is {decoded}, not {source}"
+                );
+            }
+        }
+    }
+
+    /// The encoder options `encode` gets for crf 23 at the medium preset.
+    fn argv(encode: EncoderKind) -> String {
+        video_encoder(
+            encode,
+            Quality::Crf(23),
+            "medium",
+            None,
+            &EncoderTuning::default(),
+        )
+        .unwrap()
+        .output_args(Vec::new())
+        .join(" ")
+    }
+
+    #[test]
+    fn x264_argv() {
+        assert_eq!(
+            argv(EncoderKind::X264),
+            "-c:v libx264 -preset medium -pix_fmt yuv420p -crf 23"
+        );
+        assert_eq!(
+            container_args(EncoderKind::X264, Path::new("out.mp4"), Some(30), false).join(" "),
+            "-movflags +faststart -g 30 -keyint_min 30 -sc_threshold 0"
+        );
+    }
+
+    #[test]
+    fn x265_argv() {
+        assert_eq!(
+            argv(EncoderKind::X265),
+            "-c:v libx265 -preset medium -pix_fmt yuv420p -crf 23"
+        );
+        assert_eq!(
+            container_args(EncoderKind::X265, Path::new("out.mov"), None, false).join(" "),
+            "-movflags +faststart -tag:v hvc1"
+        );
+        assert_eq!(
+            container_args(EncoderKind::X265, Path::new("out.mkv"), None, false),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn nvenc_h264_argv() {
+        assert_eq!(
+            argv(EncoderKind::Nvenc(VideoCodec::H264)),
+            "-c:v h264_nvenc -preset p4 -rc vbr -pix_fmt yuv420p -cq 23 -b:v 0"
+        );
+        assert_eq!(
+            container_args(
+                EncoderKind::Nvenc(VideoCodec::H264),
+                Path::new("out.mp4"),
+                Some(30),
+                false
+            )
+            .join(" "),
+            "-movflags +faststart -g 30 -keyint_min 30"
+        );
+    }
+
+    #[test]
+    fn nvenc_h265_argv() {
+        assert_eq!(
+            argv(EncoderKind::Nvenc(VideoCodec::H265)),
+            "-c:v hevc_nvenc -preset p4 -rc vbr -pix_fmt yuv420p -cq 23 -b:v 0"
+        );
+        assert_eq!(
+            container_args(
+                EncoderKind::Nvenc(VideoCodec::H265),
+                Path::new("out.mp4"),
+                None,
+                false
+            )
+            .join(" "),
+            "-movflags +faststart -tag:v hvc1"
+        );
+    }
+
+    #[test]
+    fn vaapi_argv() {
+        let encoder = video_encoder(
+            EncoderKind::Vaapi,
+            Quality::Crf(23),
+            "medium",
+            None,
+            &EncoderTuning::default(),
+        )
+        .unwrap();
+        assert_eq!(encoder.input_args[0], "-vaapi_device");
+        assert_eq!(
+            encoder
+                .output_args(vec!["scale=640:360".to_string()])
+                .join(" "),
+            "-vf scale=640:360,format=nv12,hwupload -c:v h264_vaapi -qp 23"
+        );
+    }
+
+    #[test]
+    fn qsv_argv() {
+        assert_eq!(
+            argv(EncoderKind::Qsv),
+            "-c:v h264_qsv -preset medium -pix_fmt nv12 -global_quality 23"
+        );
+    }
+
+    #[test]
+    fn videotoolbox_argv() {
+        // crf 23 of 51 is q 55 of 100, higher being better
+        assert_eq!(
+            argv(EncoderKind::VideoToolbox),
+            "-c:v h264_videotoolbox -pix_fmt yuv420p -q:v 55"
+        );
+    }
+
+    #[test]
+    fn prores4444_argv() {
+        assert_eq!(
+            argv(EncoderKind::ProRes4444),
+            "-c:v prores_ks -profile:v 4444 -pix_fmt yuva444p10le -vendor apl0"
+        );
+        assert!(
+            color_tags(EncoderKind::ProRes4444, ColorRange::Limited).contains(&"bt709".to_string())
+        );
+    }
+
+    #[test]
+    fn qtrle_argv() {
+        assert_eq!(argv(EncoderKind::Qtrle), "-c:v qtrle -pix_fmt argb");
+        assert!(!EncoderKind::Qtrle.is_yuv());
+    }
+
+    #[test]
+    fn vp9_argv() {
+        assert_eq!(
+            argv(EncoderKind::Vp9),
+            "-c:v libvpx-vp9 -pix_fmt yuva420p -row-mt 1 -crf 23 -b:v 0"
+        );
+        let bitrate = video_encoder(
+            EncoderKind::Vp9,
+            Quality::Bitrate(2_000_000),
+            "medium",
+            None,
+            &EncoderTuning::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            bitrate.output_args.join(" "),
+            "-c:v libvpx-vp9 -pix_fmt yuva420p -row-mt 1 -b:v 2000000 -maxrate 2000000 -bufsize 4000000"
+        );
+    }
+
+    fn concat_segment(path: PathBuf, index: usize) -> ConcatSegment {
+        ConcatSegment {
+            path,
+            origin: format!("frames {}..{}, worker 1", index * 10, index * 10 + 10),
+        }
+    }
+
+    #[tokio::test]
+    async fn unreadable_segments_fail_the_concat_unless_allowed_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        let empty = dir.path().join("segment-0.mp4");
+        std::fs::write(&empty, b"").unwrap();
+        let missing = dir.path().join("segment-1.mp4");
+        let segments = vec![concat_segment(empty.clone(), 0), concat_segment(missing, 1)];
+
+        let error = readable_segments(segments.clone(), 2, false)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            error,
+            format!(
+                "cannot concat segment {} (frames 0..10, worker 1): empty file",
+                empty.display()
+            )
+        );
+        let error = readable_segments(segments, 2, true)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            error,
+            "none of the 2 segment(s) can be read; nothing to concat"
+        );
+    }
+
+    #[tokio::test]
+    async fn fewer_segments_than_the_range_needs_fail_unless_allowed_missing() {
+        let Some(first) = fixtures::test_video("segment-0.mp4", 10) else {
+            return;
+        };
+        let second = first.dir.path().join("segment-1.mp4");
+        std::fs::copy(&first.path, &second).unwrap();
+        let segments = vec![
+            concat_segment(first.path.clone(), 0),
+            concat_segment(second.clone(), 1),
+        ];
+
+        let error = readable_segments(segments.clone(), 3, false)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            error,
+            "cannot concat without 1 of the frame range's 3 segments"
+        );
+        assert_eq!(
+            readable_segments(segments.clone(), 3, true).await.unwrap(),
+            vec![first.path.clone(), second.clone()]
+        );
+        assert_eq!(
+            readable_segments(segments, 2, false).await.unwrap(),
+            vec![first.path.clone(), second]
+        );
+    }
+
+    #[test]
+    fn progress_lines_count_frames() {
+        assert_eq!(progress_frames("frame=42", 30.0), Some(42));
+        assert_eq!(progress_frames("  frame= 7 \n", 30.0), Some(7));
+        // out_time_ms is in microseconds too
+        assert_eq!(progress_frames("out_time_us=2000000", 30.0), Some(60));
+        assert_eq!(progress_frames("out_time_ms=2000000", 30.0), Some(60));
+        assert_eq!(progress_frames("out_time_us=1016666", 30.0), Some(30));
+        assert_eq!(progress_frames("out_time_us=500000", 29.97), Some(14));
+
+        assert_eq!(progress_frames("out_time_us=N/A", 30.0), None);
+        assert_eq!(
+            progress_frames("out_time_ms=-9223372036854775807", 30.0),
+            None
+        );
+        assert_eq!(progress_frames("out_time=00:00:02.000000", 30.0), None);
+        assert_eq!(progress_frames("progress=continue", 30.0), None);
+        assert_eq!(progress_frames("progress=end", 30.0), None);
+        assert_eq!(progress_frames("", 30.0), None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn following_progress_keeps_the_last_count() {
+        let mut child = TokioCommand::new("sh")
+            .arg("-c")
+            .arg("printf 'frame=3\\nout_time_us=N/A\\nprogress=continue\\nout_time_us=20000000\\nprogress=end\\n'")
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let done = AtomicUsize::new(0);
+        let progress = FrameProgress {
+            done: &done,
+            fps: 30.0,
+            total: 1000,
+        };
+        progress.follow(child.stdout.take().unwrap()).await;
+        child.wait().await.unwrap();
+        // N/A and progress=end leave the count where the last time put it
+        assert_eq!(done.load(Ordering::Relaxed), 600);
+    }
+
+    /// A 90-frame segment at `gain` with the given fades.
+    fn faded_segment(gain: f64, fade_in: i64, fade_out: i64) -> AudioSegmentResolved {
+        serde_json::from_value(serde_json::json!({
+            "id": "clip",
+            "source": { "kind": "sound", "path": "clip.wav" },
+            "projectStartFrame": 0,
+            "sourceStartFrame": 0,
+            "durationFrames": 90,
+            "gain": gain,
+            "fadeInFrames": fade_in,
+            "fadeOutFrames": fade_out
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn gain_and_fades_over_the_whole_segment() {
+        let stages = |segment| gain_and_fades(&segment, 0, 90, 30.0);
+        assert_eq!(stages(faded_segment(1.0, 0, 0)), (String::new(), false));
+        assert_eq!(
+            stages(faded_segment(0.5, 15, 30)),
+            (
+                ",volume=0.500000,afade=t=in:st=0:d=0.500000,afade=t=out:st=2.000000:d=1.000000"
+                    .to_string(),
+                false
+            )
+        );
+        assert_eq!(
+            stages(faded_segment(0.0, 0, 0)),
+            (",volume=0.000000".to_string(), false)
+        );
+        // a gain that is not a volume is left out
+        assert_eq!(stages(faded_segment(-1.0, 0, 0)), (String::new(), false));
+        // fades are no longer than the segment
+        assert_eq!(
+            stages(faded_segment(1.0, 200, 0)),
+            (",afade=t=in:st=0:d=3.000000".to_string(), false)
+        );
+    }
+
+    #[test]
+    fn gain_and_fades_of_a_segment_cut_by_the_range() {
+        // frames 30..90: the fade-in is over before them, the fade-out within
+        assert_eq!(
+            gain_and_fades(&faded_segment(1.0, 15, 30), 30, 60, 30.0),
+            (
+                ",afade=t=out:st=2.000000:d=1.000000,atrim=start=1.000000,asetpts=PTS-STARTPTS"
+                    .to_string(),
+                true
+            )
+        );
+        // frames 10..30: within the fade-in, before the fade-out
+        assert_eq!(
+            gain_and_fades(&faded_segment(1.0, 15, 30), 10, 20, 30.0),
+            (
+                ",afade=t=in:st=0:d=0.500000,atrim=start=0.333333,asetpts=PTS-STARTPTS".to_string(),
+                true
+            )
+        );
+        // frames 20..50: no fade reaches them, so there is nothing to trim
+        assert_eq!(
+            gain_and_fades(&faded_segment(2.0, 15, 30), 20, 30, 30.0),
+            (",volume=2.000000".to_string(), false)
+        );
+    }
+
+    #[test]
+    fn atempo_splits_rates_outside_half_to_double() {
+        let pad = ",apad=whole_dur=2.000000,atrim=duration=2.000000";
+        assert_eq!(atempo_stages(1.0, 2.0), "");
+        assert_eq!(atempo_stages(1.5, 2.0), format!(",atempo=1.500000{pad}"));
+        assert_eq!(atempo_stages(2.0, 2.0), format!(",atempo=2.000000{pad}"));
+        assert_eq!(atempo_stages(0.5, 2.0), format!(",atempo=0.500000{pad}"));
+        assert_eq!(
+            atempo_stages(3.0, 2.0),
+            format!(",atempo=2.0,atempo=1.500000{pad}")
+        );
+        assert_eq!(
+            atempo_stages(10.0, 2.0),
+            format!(",atempo=2.0,atempo=2.0,atempo=2.0,atempo=1.250000{pad}")
+        );
+        assert_eq!(
+            atempo_stages(0.3, 2.0),
+            format!(",atempo=0.5,atempo=0.600000{pad}")
+        );
+        assert_eq!(
+            atempo_stages(0.1, 2.0),
+            format!(",atempo=0.5,atempo=0.5,atempo=0.5,atempo=0.800000{pad}")
+        );
+        assert_eq!(
+            atempo_stages(4.0, 1.0 / 3.0),
+            ",atempo=2.0,atempo=2.000000,apad=whole_dur=0.333333,atrim=duration=0.333333"
+        );
+    }
+
+    #[tokio::test]
+    async fn atempo_output_is_padded_or_cut_to_its_duration() {
+        let Some(sine) = fixtures::sine("sine.wav", 1.0) else {
+            return;
+        };
+        // 1 s at 4x is 0.25 s, padded to 0.5 s; at 0.25x it is 4 s, cut to 0.5 s
+        for rate in [4.0, 0.25] {
+            let filter = format!("anull{}", atempo_stages(rate, 0.5));
+            let output = sine.dir.path().join(format!("atempo-{rate}.wav"));
+            let status = std::process::Command::new(resolve_ffmpeg_path().unwrap())
+                .args(["-hide_banner", "-loglevel", "error", "-i"])
+                .arg(&sine.path)
+                .args(["-af", &filter])
+                .arg(&output)
+                .status()
+                .unwrap();
+            assert!(status.success(), "{filter}");
+
+            let samples = fixtures::mono_pcm(&output);
+            assert_eq!(samples.len(), 24_000, "rate {rate}");
+            if rate > 1.0 {
+                assert!(fixtures::peak(&samples[..11_000]) > 0.1);
+                assert_eq!(fixtures::peak(&samples[13_000..]), 0.0);
+            } else {
+                assert!(fixtures::peak(&samples[23_000..]) > 0.1);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn mastering_keeps_overlapping_segments_under_the_ceiling() {
+        let Some(tone) = fixtures::sine("tone.wav", 3.0) else {
+            return;
+        };
+        // four tones of 0.375 at once sum to 1.5
+        let segments = (0..4)
+            .map(|index| {
+                serde_json::json!({
+                    "id": format!("tone-{index}"),
+                    "source": { "kind": "sound", "path": tone.path },
+                    "projectStartFrame": 0,
+                    "sourceStartFrame": 0,
+                    "durationFrames": 90,
+                    "gain": 3.0
+                })
+            })
+            .collect::<Vec<_>>();
+        for mastering in [
+            AudioMastering::Off,
+            AudioMastering::Limiter,
+            AudioMastering::Loudnorm,
+        ] {
+            let plan = AudioPlanResolved {
+                mastering: Some(mastering),
+                ..plan(serde_json::Value::Array(segments.clone()))
+            };
+            let output = tone.dir.path().join(format!("{mastering:?}.wav"));
+            let mixed =
+                export_audio_plan(&output, &plan, 0..90, 30.0, &AudioEncode::default(), None)
+                    .await
+                    .unwrap();
+            assert!(mixed);
+
+            let peak = fixtures::peak(&fixtures::mono_pcm(&output));
+            match mastering {
+                AudioMastering::Off => assert!(peak > 0.99, "{mastering:?}: peak {peak}"),
+                // -1 dBFS is 0.891; loudnorm's true-peak limit lands a little either side
+                _ => assert!((0.3..0.95).contains(&peak), "{mastering:?}: peak {peak}"),
+            }
+        }
+    }
+
+    /// `count` three-frame segments of `path`, one every `spacing` frames.
+    fn short_segments(path: &Path, count: usize, spacing: usize) -> AudioPlanResolved {
+        plan(
+            (0..count)
+                .map(|index| {
+                    serde_json::json!({
+                        "id": format!("blip-{index}"),
+                        "source": { "kind": "sound", "path": path },
+                        "projectStartFrame": index * spacing,
+                        "sourceStartFrame": 0,
+                        "durationFrames": 3
+                    })
+                })
+                .collect(),
+        )
+    }
+
+    /// `graph` with every segment going straight into one `amix`.
+    fn flat_mix(graph: &AudioPlanGraph) -> AudioPlanGraph {
+        let mut parts = graph
+            .mix
+            .split(';')
+            .filter(|part| !part.contains("amix="))
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        let segments = parts.len() - 1;
+        parts.push(format!(
+            "[base]{}amix=inputs={}:duration=first:normalize=0",
+            (0..segments).map(|n| format!("[a{n}]")).collect::<String>(),
+            segments + 1
+        ));
+        AudioPlanGraph {
+            inputs: graph.inputs.clone(),
+            mix: parts.join(";"),
+            layout: graph.layout,
+            sample_rate: graph.sample_rate,
+        }
+    }
+
+    /// What ffmpeg makes of `graph`, as interleaved f32 samples.
+    fn mixed_samples(graph: &AudioPlanGraph) -> Vec<f32> {
+        let mut command = TokioCommand::new(resolve_ffmpeg_path().unwrap());
+        command.args(["-hide_banner", "-loglevel", "error"]);
+        for input in &graph.inputs {
+            command.arg("-i").arg(input);
+        }
+        let _script = set_filter_complex(&mut command, &graph.filter(None)).unwrap();
+        let output = command
+            .as_std_mut()
+            .args(["-map", "[aout]", "-f", "f32le", "-"])
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        output
+            .stdout
+            .chunks_exact(4)
+            .map(|sample| f32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]]))
+            .collect()
+    }
+
+    #[test]
+    fn five_hundred_segments_are_mixed_in_groups() {
+        let plan = short_segments(Path::new("blip.wav"), 500, 3);
+        let graph = audio_plan_graph(&plan, &(0..1500), 30.0, 0, 48000).unwrap();
+        assert_eq!(graph.inputs, ["blip.wav"]);
+
+        let mixes = graph
+            .mix
+            .split(';')
+            .filter(|part| part.contains("amix="))
+            .collect::<Vec<_>>();
+        // 15 groups of 32 and one of 20, then those 16 over the bed
+        assert_eq!(mixes.len(), 17);
+        assert!(
+            mixes[..16]
+                .iter()
+                .all(|mix| mix.contains("amix=inputs=32:") || mix.contains("amix=inputs=20:"))
+        );
+        assert!(mixes[16].starts_with("[base][m0_0][m0_1]"), "{}", mixes[16]);
+        assert!(
+            mixes[16].contains("amix=inputs=17:duration=first"),
+            "{}",
+            mixes[16]
+        );
+        // every segment is made once and mixed once
+        for n in 0..500 {
+            assert_eq!(graph.mix.matches(&format!("[a{n}]")).count(), 2, "[a{n}]");
+        }
+
+        // far too long for a command line
+        let filter = graph.filter(None);
+        assert!(filter.len() > MAX_INLINE_FILTER);
+        let mut cmd = TokioCommand::new("ffmpeg");
+        let script = set_filter_complex(&mut cmd, &filter).unwrap().unwrap();
+        let args = cmd.as_std().get_args().collect::<Vec<_>>();
+        assert_eq!(
+            args,
+            [
+                std::ffi::OsStr::new("-filter_complex_script"),
+                script.path().as_os_str()
+            ]
+        );
+        assert_eq!(std::fs::read_to_string(script.path()).unwrap(), filter);
+    }
+
+    #[test]
+    fn a_few_segments_are_mixed_at_once() {
+        let plan = short_segments(Path::new("blip.wav"), MIX_GROUP - 1, 3);
+        let graph = audio_plan_graph(&plan, &(0..300), 30.0, 0, 48000).unwrap();
+        assert_eq!(graph.mix.matches("amix=").count(), 1);
+        assert_eq!(flat_mix(&graph).mix, graph.mix);
+    }
+
+    #[tokio::test]
+    async fn the_grouped_mix_sounds_like_the_flat_one() {
+        let Some(blip) = fixtures::sine("blip.wav", 0.1) else {
+            return;
+        };
+        // overlapping, so the groups have something to sum
+        let plan = short_segments(&blip.path, 40, 2);
+        let graph = audio_plan_graph(&plan, &(0..90), 30.0, 0, 48000).unwrap();
+        assert!(graph.mix.contains("[m0_0]"));
+
+        let grouped = mixed_samples(&graph);
+        let flat = mixed_samples(&flat_mix(&graph));
+        assert_eq!(grouped.len(), flat.len());
+        assert!(fixtures::peak(&flat) > 0.1);
+        let worst = grouped
+            .iter()
+            .zip(&flat)
+            .fold(0.0f32, |worst, (a, b)| worst.max((a - b).abs()));
+        assert!(worst < 1e-5, "grouped and flat differ by {worst}");
+    }
+
+    #[tokio::test]
+    async fn five_hundred_segments_export() {
+        let Some(blip) = fixtures::sine("blip.wav", 0.1) else {
+            return;
+        };
+        let plan = AudioPlanResolved {
+            mastering: Some(AudioMastering::Off),
+            ..short_segments(&blip.path, 500, 3)
+        };
+        let output = blip.dir.path().join("mix.wav");
+        let mixed = export_audio_plan(&output, &plan, 0..1500, 30.0, &AudioEncode::default(), None)
+            .await
+            .unwrap();
+        assert!(mixed);
+
+        let samples = fixtures::mono_pcm(&output);
+        assert_eq!(samples.len(), 50 * 48_000);
+        // the last blip is in its place
+        assert!(fixtures::peak(&samples[49 * 48_000 + 48_000 * 9 / 10..]) > 0.1);
+    }
+
+    #[test]
+    fn the_channel_layout_runs_through_the_whole_graph() {
+        for (layout, name) in [
+            (ChannelLayout::Mono, "mono"),
+            (ChannelLayout::Stereo, "stereo"),
+            (ChannelLayout::Surround51, "5.1"),
+        ] {
+            let plan = AudioPlanResolved {
+                channel_layout: Some(layout),
+                ..short_segments(Path::new("blip.wav"), 2, 3)
+            };
+            let graph = audio_plan_graph(&plan, &(0..30), 30.0, 0, 44100).unwrap();
+            assert!(
+                graph
+                    .mix
+                    .starts_with(&format!("anullsrc=r=44100:cl={name}:d=1.000000[base]")),
+                "{}",
+                graph.mix
+            );
+            for n in 0..2 {
+                assert!(
+                    segment_filter(&graph, n)
+                        .contains(&format!("aresample=44100,aformat=channel_layouts={name},")),
+                    "{}",
+                    segment_filter(&graph, n)
+                );
+            }
+            assert!(graph.filter(None).ends_with(&format!(
+                "aformat=sample_fmts=fltp:sample_rates=44100:channel_layouts={name}[aout]"
+            )));
+        }
+    }
+
+    #[tokio::test]
+    async fn every_channel_layout_reaches_the_muxed_file() {
+        let Some(video) = fixtures::test_video("video.mp4", 30) else {
+            return;
+        };
+        let Some(stereo) = fixtures::generate(
+            "stereo.wav",
+            &[
+                "-f",
+                "lavfi",
+                "-i",
+                "sine=frequency=440:sample_rate=44100:duration=1",
+                "-ac",
+                "2",
+            ],
+        ) else {
+            return;
+        };
+        let Some(mono) = fixtures::sine("mono.wav", 1.0) else {
+            return;
+        };
+        // a mono and a stereo source, up- or downmixed to each layout
+        let segments = serde_json::json!([
+            {
+                "id": "mono",
+                "source": { "kind": "sound", "path": mono.path },
+                "projectStartFrame": 0,
+                "sourceStartFrame": 0,
+                "durationFrames": 15
+            },
+            {
+                "id": "stereo",
+                "source": { "kind": "sound", "path": stereo.path },
+                "projectStartFrame": 15,
+                "sourceStartFrame": 0,
+                "durationFrames": 15
+            }
+        ]);
+        for (layout, channels, name) in [
+            (ChannelLayout::Mono, "1", "mono"),
+            (ChannelLayout::Stereo, "2", "stereo"),
+            (ChannelLayout::Surround51, "6", "5.1"),
+        ] {
+            let plan = AudioPlanResolved {
+                channel_layout: Some(layout),
+                ..plan(segments.clone())
+            };
+            let output = video.dir.path().join(format!("{name}.mp4"));
+            let muxed = mux_audio_plan_into_mp4(
+                &video.path,
+                &output,
+                &plan,
+                0..30,
+                30.0,
+                &AudioEncode::default(),
+                None,
+            )
+            .await
+            .unwrap();
+            assert!(muxed);
+
+            let audio = fixtures::probe(&output, "a:0", "stream=channels,channel_layout");
+            assert_eq!(fixtures::entry(&audio, "channels"), channels, "{name}");
+            assert_eq!(fixtures::entry(&audio, "channel_layout"), name, "{name}");
+            // both halves are heard whatever the layout
+            let samples = fixtures::mono_pcm(&output);
+            assert!(fixtures::peak(&samples[..20_000]) > 0.05, "{name}");
+            assert!(fixtures::peak(&samples[28_000..44_000]) > 0.05, "{name}");
+        }
+    }
+
+    #[test]
+    fn audio_settings_are_checked_against_the_container() {
+        let encode = |codec, bitrate, sample_rate| AudioEncode {
+            codec,
+            bitrate,
+            sample_rate,
+        };
+        assert!(AudioEncode::default().check("mp4").is_ok());
+        assert!(AudioEncode::default().check("webm").is_ok());
+        assert_eq!(
+            encode(Some(AudioCodec::Aac), None, None).check("webm"),
+            Err("aac audio cannot be written to .webm".to_string())
+        );
+        assert_eq!(
+            encode(Some(AudioCodec::Flac), None, None).check("mp4"),
+            Err("flac audio cannot be written to .mp4".to_string())
+        );
+        assert_eq!(
+            encode(Some(AudioCodec::Pcm), Some(96_000), None).check("mkv"),
+            Err("pcm audio is lossless; drop --audio-bitrate".to_string())
+        );
+        assert_eq!(
+            encode(Some(AudioCodec::Opus), None, Some(44100)).check("mkv"),
+            Err("opus audio does not support 44100 Hz".to_string())
+        );
+
+        assert_eq!(
+            encode(None, Some(96_000), None)
+                .args(Path::new("out.webm"))
+                .join(" "),
+            "-c:a libopus -b:a 96000"
+        );
+        assert_eq!(
+            AudioEncode::default().args(Path::new("out.mp4")).join(" "),
+            "-c:a aac -b:a 192k"
+        );
+        assert_eq!(
+            encode(Some(AudioCodec::Flac), None, None)
+                .args(Path::new("out.mkv"))
+                .join(" "),
+            "-c:a flac"
+        );
+    }
+
+    #[tokio::test]
+    async fn every_audio_codec_is_muxed_as_asked() {
+        let Some(h264) = fixtures::test_video("video.mp4", 30) else {
+            return;
+        };
+        let Some(vp9) = fixtures::generate(
+            "video.webm",
+            &[
+                "-f",
+                "lavfi",
+                "-i",
+                "testsrc2=size=64x36:rate=30:duration=1",
+                "-c:v",
+                "libvpx-vp9",
+            ],
+        ) else {
+            return;
+        };
+        let Some(tone) = fixtures::sine("tone.wav", 1.0) else {
+            return;
+        };
+        // two segments, so the audio is always encoded rather than copied
+        let plan = short_segments(&tone.path, 2, 15);
+        let cases = [
+            ("mp4", Some(AudioCodec::Aac), Some(96_000), 44100, "aac"),
+            ("mov", Some(AudioCodec::Aac), None, 48000, "aac"),
+            ("mov", Some(AudioCodec::Pcm), None, 48000, "pcm_s16le"),
+            ("mkv", Some(AudioCodec::Flac), None, 96000, "flac"),
+            ("mkv", Some(AudioCodec::Pcm), None, 44100, "pcm_s16le"),
+            ("mkv", Some(AudioCodec::Opus), Some(64_000), 48000, "opus"),
+            ("webm", None, Some(96_000), 24000, "opus"),
+        ];
+        for (extension, codec, bitrate, sample_rate, codec_name) in cases {
+            let audio = AudioEncode {
+                codec,
+                bitrate,
+                sample_rate: Some(sample_rate),
+            };
+            audio.check(extension).unwrap();
+            let video = if extension == "webm" { &vp9 } else { &h264 };
+            let output = tone
+                .dir
+                .path()
+                .join(format!("{codec_name}-{sample_rate}.{extension}"));
+            let muxed =
+                mux_audio_plan_into_mp4(&video.path, &output, &plan, 0..30, 30.0, &audio, None)
+                    .await
+                    .unwrap();
+            assert!(muxed);
+
+            let case = format!("{codec_name} in .{extension}");
+            let stream = fixtures::probe(&output, "a:0", "stream=codec_name,sample_rate,bit_rate");
+            assert_eq!(fixtures::entry(&stream, "codec_name"), codec_name, "{case}");
+            // Opus always decodes at 48 kHz, whatever it was encoded at
+            let decoded_rate = if codec_name == "opus" {
+                48000
+            } else {
+                sample_rate
+            };
+            assert_eq!(
+                fixtures::entry(&stream, "sample_rate"),
+                decoded_rate.to_string(),
+                "{case}"
+            );
+            if let (Some(bitrate), Ok(measured)) =
+                (bitrate, fixtures::entry(&stream, "bit_rate").parse::<f64>())
+            {
+                let ratio = measured / bitrate as f64;
+                assert!((0.7..1.3).contains(&ratio), "{case}: {measured} b/s");
+            }
+        }
+    }
+
+    /// One segment of `source` over frames 0..30.
+    fn lone_segment(source: &Path) -> AudioPlanResolved {
+        plan(serde_json::json!([{
+            "id": "voice-over",
+            "source": { "kind": "sound", "path": source },
+            "projectStartFrame": 0,
+            "sourceStartFrame": 0,
+            "durationFrames": 30
+        }]))
+    }
+
+    #[tokio::test]
+    async fn audio_that_covers_the_video_is_copied() {
+        let Some(video) = fixtures::test_video("video.mp4", 30) else {
+            return;
+        };
+        let Some(voice) = fixtures::sine("voice.m4a", 1.5) else {
+            return;
+        };
+        let plan = lone_segment(&voice.path);
+        let output = video.dir.path().join("output.mp4");
+        let audio = AudioEncode::default();
+        assert_eq!(
+            copyable_source(&plan, &(0..30), 30.0, &audio, &output)
+                .await
+                .unwrap(),
+            voice.path.to_str()
+        );
+
+        let muxed = mux_audio_plan_into_mp4(&video.path, &output, &plan, 0..30, 30.0, &audio, None)
+            .await
+            .unwrap();
+        assert!(muxed);
+        let frames = fixtures::probe(&output, "v:0", "stream=nb_frames");
+        assert_eq!(fixtures::entry(&frames, "nb_frames"), "30");
+        // copied, so still at the source's bitrate rather than the 192k of an encode
+        let source = fixtures::probe(&voice.path, "a:0", "stream=codec_name,bit_rate");
+        let copied = fixtures::probe(&output, "a:0", "stream=codec_name,bit_rate");
+        assert_eq!(fixtures::entry(&copied, "codec_name"), "aac");
+        assert_eq!(
+            fixtures::entry(&copied, "bit_rate"),
+            fixtures::entry(&source, "bit_rate")
+        );
+    }
+
+    #[tokio::test]
+    async fn audio_just_shorter_than_the_video_is_mixed_without_cutting_it() {
+        let Some(video) = fixtures::test_video("video.mp4", 30) else {
+            return;
+        };
+        let Some(voice) = fixtures::sine("voice.m4a", 0.95) else {
+            return;
+        };
+        let plan = lone_segment(&voice.path);
+        let output = video.dir.path().join("output.mp4");
+        let audio = AudioEncode::default();
+        assert_eq!(
+            copyable_source(&plan, &(0..30), 30.0, &audio, &output)
+                .await
+                .unwrap(),
+            None
+        );
+
+        let muxed = mux_audio_plan_into_mp4(&video.path, &output, &plan, 0..30, 30.0, &audio, None)
+            .await
+            .unwrap();
+        assert!(muxed);
+        let frames = fixtures::probe(&output, "v:0", "stream=nb_frames");
+        assert_eq!(fixtures::entry(&frames, "nb_frames"), "30");
+        // the silent bed fills the rest
+        let audio = fixtures::probe(&output, "a:0", "stream=duration");
+        assert!(fixtures::seconds(&audio, "duration") >= 0.99);
+    }
+
+    /// 15 frames of `testsrc2` encoded with libx264 and `args`.
+    fn h264_segment(name: &str, args: &[&str]) -> Option<fixtures::Fixture> {
+        let mut all = vec![
+            "-f",
+            "lavfi",
+            "-i",
+            "testsrc2=size=64x36:rate=30:duration=0.5",
+            "-c:v",
+            "libx264",
+            "-pix_fmt",
+            "yuv420p",
+        ];
+        all.extend(args);
+        fixtures::generate(name, &all)
+    }
+
+    #[tokio::test]
+    async fn reencoding_joins_segments_with_different_encoder_settings() {
+        let Some(fast) = h264_segment(
+            "fast.mp4",
+            &["-preset", "ultrafast", "-profile:v", "baseline"],
+        ) else {
+            return;
+        };
+        let Some(slow) = h264_segment("slow.mp4", &["-preset", "slow", "-profile:v", "high"])
+        else {
+            return;
+        };
+        let output = fast.dir.path().join("joined.mp4");
+        let segments = vec![
+            concat_segment(fast.path.clone(), 0),
+            concat_segment(slow.path.clone(), 1),
+        ];
+
+        concat_segments_reencode(
+            segments,
+            2,
+            &output,
+            &settings(EncoderKind::X264),
+            true,
+            false,
+            None,
+        )
+        .await
+        .unwrap();
+
+        let streams = fixtures::probe(&output, "", "stream=index,nb_frames");
+        assert_eq!(streams.iter().filter(|(key, _)| key == "index").count(), 1);
+        assert_eq!(fixtures::entry(&streams, "nb_frames"), "30");
+    }
+
+    #[tokio::test]
+    async fn long_gop_segments_join_to_every_frame() {
+        if !fixtures::tools_available() {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        // one keyframe per segment: the copy concat must not drop the frames after it
+        let settings = EncodeSettings {
+            gop: Some(600),
+            ..settings(EncoderKind::X264)
+        };
+        let mut segments = Vec::new();
+        for (index, frames) in [40, 25].into_iter().enumerate() {
+            let path = dir.path().join(format!("segment-{index}.mp4"));
+            let mut writer = SegmentWriter::new(
+                path.to_str().unwrap(),
+                SIZE,
+                SIZE,
+                30.0,
+                &settings,
+                FrameInput::Rgba,
+                None,
+                false,
+            )
+            .await
+            .unwrap();
+            for frame in 0..frames {
+                writer
+                    .write_frame(&solid_frame([frame as u8 * 4, 0, 0, 255]))
+                    .await
+                    .unwrap();
+            }
+            writer.finish().await.unwrap();
+            segments.push(concat_segment(path, index));
+        }
+
+        let output = dir.path().join("joined.mp4");
+        concat_segments_mp4(segments, 2, &output, true, false, None)
+            .await
+            .unwrap();
+
+        let stream = fixtures::probe(&output, "v:0", "stream=nb_frames");
+        assert_eq!(fixtures::entry(&stream, "nb_frames"), "65");
+    }
+
+    fn animation_options(max_frames: Option<usize>) -> AnimationOptions {
+        AnimationOptions {
+            fps: Some(15.0),
+            max_width: Some(32),
+            max_frames,
+            dither: "bayer".to_string(),
+        }
+    }
+
+    /// Loop count in the NETSCAPE2.0 extension of a GIF; 0 loops forever.
+    fn gif_loops(gif: &[u8]) -> Option<u16> {
+        let at = gif
+            .windows(11)
+            .position(|window| window == b"NETSCAPE2.0")?
+            + 11;
+        match gif.get(at..at + 4)? {
+            [3, 1, low, high] => Some(u16::from_le_bytes([*low, *high])),
+            _ => None,
+        }
+    }
+
+    /// Loop count in the ANIM chunk of a WebP and the number of ANMF frames.
+    fn webp_animation(webp: &[u8]) -> (Option<u16>, usize) {
+        let (mut loops, mut frames) = (None, 0);
+        let mut at = 12;
+        while let Some(header) = webp.get(at..at + 8) {
+            let size = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+            match &header[..4] {
+                b"ANIM" => loops = Some(u16::from_le_bytes([webp[at + 12], webp[at + 13]])),
+                b"ANMF" => frames += 1,
+                _ => {}
+            }
+            at += 8 + size + size % 2;
+        }
+        (loops, frames)
+    }
+
+    #[tokio::test]
+    async fn gifs_loop_and_stop_at_the_frame_limit() {
+        let Some(video) = fixtures::test_video("clip.mp4", 60) else {
+            return;
+        };
+        let gif = video.dir.path().join("clip.gif");
+
+        convert_to_animation(&video.path, &gif, &animation_options(Some(10)))
+            .await
+            .unwrap();
+        assert_eq!(fixtures::frame_count(&gif), 10);
+        assert_eq!(gif_loops(&std::fs::read(&gif).unwrap()), Some(0));
+        assert!(!gif.with_extension("palette.png").exists());
+
+        convert_to_animation(&video.path, &gif, &animation_options(None))
+            .await
+            .unwrap();
+        assert_eq!(fixtures::frame_count(&gif), 30);
+    }
+
+    #[tokio::test]
+    async fn webps_loop_and_stop_at_the_frame_limit() {
+        let Some(video) = fixtures::test_video("clip.mp4", 60) else {
+            return;
+        };
+        let webp = video.dir.path().join("clip.webp");
+
+        if let Err(error) =
+            convert_to_animation(&video.path, &webp, &animation_options(Some(10))).await
+        {
+            // not every build has libwebp
+            eprintln!("skipping: {error}");
+            return;
+        }
+        // ffmpeg only decodes animated WebP from 7.1 on, so read the chunks instead
+        assert_eq!(
+            webp_animation(&std::fs::read(&webp).unwrap()),
+            (Some(0), 10)
+        );
+    }
+
+    #[tokio::test]
+    async fn a_failed_salvage_reports_the_stderr_tail_and_the_command() {
+        if resolve_checked_ffmpeg().is_err() {
+            eprintln!("skipping: ffmpeg not available");
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let partial = dir.path().join("missing.mp4");
+        let error = salvage_segment(&partial, 10, &dir.path().join("salvaged.mp4"))
+            .await
+            .unwrap_err()
+            .to_string();
+        let message = format!("cannot salvage {} (ffmpeg exit status", partial.display());
+        assert!(error.starts_with(&message), "{error}");
+        assert!(error.contains("\n  ffmpeg: "), "{error}");
+        assert!(error.contains("\n  command: "), "{error}");
+    }
+
+    #[test]
+    fn the_limiter_compensates_its_delay_from_ffmpeg_5_1() {
+        let version = |major, minor| {
+            Some(FfmpegVersion {
+                major,
+                minor,
+                patch: 0,
+            })
+        };
+        assert_eq!(limiter(version(4, 3)), LIMITER);
+        assert_eq!(limiter(version(5, 0)), LIMITER);
+        assert_eq!(limiter(None), LIMITER);
+        assert_eq!(limiter(version(5, 1)), format!("{LIMITER}:latency=1"));
+        assert_eq!(limiter(version(7, 0)), format!("{LIMITER}:latency=1"));
+    }
 }
@@ -0,0 +1,175 @@
+//! Media generated with ffmpeg's lavfi sources for tests. Helpers return `None`
+//! when ffmpeg or ffprobe cannot be run, and the calling test skips.
+
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use tempfile::TempDir;
+
+use super::{resolve_ffmpeg_path, resolve_ffprobe_path};
+
+/// A generated file inside a temp dir that is removed on drop.
+pub(crate) struct Fixture {
+    pub dir: TempDir,
+    pub path: PathBuf,
+}
+
+/// Whether ffmpeg and ffprobe run; says the test is skipped when not.
+pub(crate) fn tools_available() -> bool {
+    let runs = |program: Result<String, _>| {
+        program.is_ok_and(|program| Command::new(program).arg("-version").output().is_ok())
+    };
+    let available = runs(resolve_ffmpeg_path()) && runs(resolve_ffprobe_path());
+    if !available {
+        eprintln!("skipping: ffmpeg/ffprobe not available");
+    }
+    available
+}
+
+/// Run ffmpeg with `args` followed by the output file `name` in a fresh temp dir.
+pub(crate) fn generate(name: &str, args: &[&str]) -> Option<Fixture> {
+    if !tools_available() {
+        return None;
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join(name);
+    let output = Command::new(resolve_ffmpeg_path().unwrap())
+        .args(["-hide_banner", "-loglevel", "error", "-y"])
+        .args(args)
+        .arg(&path)
+        .output()
+        .unwrap();
+    if !output.status.success() {
+        // e.g. an encoder missing from this build
+        eprintln!(
+            "skipping: could not generate {name}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        return None;
+    }
+
+    Some(Fixture { dir, path })
+}
+
+/// `testsrc2` video of `frames` frames at 30 fps.
+pub(crate) fn test_video(name: &str, frames: usize) -> Option<Fixture> {
+    let source = format!(
+        "testsrc2=size=64x36:rate=30:duration={}",
+        frames as f64 / 30.0
+    );
+    generate(name, &["-f", "lavfi", "-i", &source, "-pix_fmt", "yuv420p"])
+}
+
+/// A 1 kHz sine of `seconds` at 48 kHz, encoded for the extension of `name`.
+pub(crate) fn sine(name: &str, seconds: f64) -> Option<Fixture> {
+    let source = format!("sine=frequency=1000:sample_rate=48000:duration={seconds}");
+    generate(name, &["-f", "lavfi", "-i", &source])
+}
+
+/// `key=value` lines ffprobe prints for `entries` of the streams `select`ed in
+/// `path`, e.g. `("a:0", "stream=codec_name,duration")`. An empty `select` takes
+/// every stream.
+pub(crate) fn probe(path: &Path, select: &str, entries: &str) -> Vec<(String, String)> {
+    let mut command = Command::new(resolve_ffprobe_path().unwrap());
+    command.args(["-v", "error"]);
+    if !select.is_empty() {
+        command.args(["-select_streams", select]);
+    }
+    let output = command
+        .arg("-show_entries")
+        .arg(entries)
+        .args(["-of", "default=noprint_wrappers=1"])
+        .arg(path)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "ffprobe cannot read {}: {}",
+        path.display(),
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.trim().to_string()))
+        .collect()
+}
+
+/// Frames ffprobe decodes from the first video stream of `path`.
+pub(crate) fn frame_count(path: &Path) -> usize {
+    let output = Command::new(resolve_ffprobe_path().unwrap())
+        .args(["-v", "error", "-count_frames", "-select_streams", "v:0"])
+        .args(["-show_entries", "stream=nb_read_frames", "-of", "csv=p=0"])
+        .arg(path)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "ffprobe cannot read {}",
+        path.display()
+    );
+    String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse()
+        .unwrap()
+}
+
+/// The first `key` in `entries`.
+pub(crate) fn entry<'a>(entries: &'a [(String, String)], key: &str) -> &'a str {
+    entries
+        .iter()
+        .find(|(name, _)| name == key)
+        .map(|(_, value)| value.as_str())
+        .unwrap_or_else(|| panic!("ffprobe printed no {key}"))
+}
+
+/// Seconds in the first `key` of `entries`.
+pub(crate) fn seconds(entries: &[(String, String)], key: &str) -> f64 {
+    entry(entries, key).parse().unwrap()
+}
+
+/// The audio of `path` decoded to mono f32 samples at 48 kHz.
+pub(crate) fn mono_pcm(path: &Path) -> Vec<f32> {
+    let output = Command::new(resolve_ffmpeg_path().unwrap())
+        .args(["-hide_banner", "-loglevel", "error", "-i"])
+        .arg(path)
+        .args([
+            "-map", "0:a:0", "-ac", "1", "-ar", "48000", "-f", "f32le", "-",
+        ])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "cannot decode {}", path.display());
+    output
+        .stdout
+        .chunks_exact(4)
+        .map(|sample| f32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]]))
+        .collect()
+}
+
+/// Largest absolute sample of `samples`.
+pub(crate) fn peak(samples: &[f32]) -> f32 {
+    samples
+        .iter()
+        .fold(0.0, |peak, sample| peak.max(sample.abs()))
+}
+
+/// The first frame of `path` decoded to RGBA, with `decoder` forced when set (the
+/// native VP9 decoder drops the alpha that libvpx-vp9 keeps).
+pub(crate) fn rgba_frame(path: &Path, decoder: Option<&str>) -> Vec<u8> {
+    let mut command = Command::new(resolve_ffmpeg_path().unwrap());
+    command.args(["-hide_banner", "-loglevel", "error"]);
+    if let Some(decoder) = decoder {
+        command.args(["-c:v", decoder]);
+    }
+    let output = command
+        .arg("-i")
+        .arg(path)
+        .args(["-frames:v", "1", "-pix_fmt", "rgba", "-f", "rawvideo", "-"])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "cannot decode {}", path.display());
+    output.stdout
+}
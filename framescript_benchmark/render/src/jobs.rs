@@ -0,0 +1,295 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use futures::stream::{FuturesUnordered, StreamExt};
+use serde::Deserialize;
+use tracing::{Instrument, error, info};
+
+use crate::cli::{BrowserMode, RenderArgs};
+use crate::worker::{SharedBrowser, with_query};
+use crate::{Canceled, RenderFailed};
+
+/// One entry of the `--jobs` file.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct JobSpec {
+    /// Defaults to the entry's position, counting from 1.
+    id: Option<String>,
+    page_url: Option<String>,
+    composition: Option<String>,
+    width: u32,
+    height: u32,
+    fps: f64,
+    frames: usize,
+    output: PathBuf,
+    report: Option<PathBuf>,
+}
+
+/// How a job ended.
+enum Outcome {
+    Done,
+    Failed(String),
+    Canceled,
+    /// Not started because an earlier job was canceled or failed with `--fail-fast`.
+    Skipped,
+}
+
+/// `base` with the settings of each entry of the job list at `path`.
+async fn read_jobs(path: &Path, base: &RenderArgs) -> Result<Vec<RenderArgs>, String> {
+    let text = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| format!("cannot read job list {}: {e}", path.display()))?;
+    let specs: Vec<JobSpec> = serde_json::from_str(&text)
+        .map_err(|e| format!("invalid job list {}: {e}", path.display()))?;
+    if specs.is_empty() {
+        return Err(format!("job list {} is empty", path.display()));
+    }
+
+    let mut ids = HashSet::new();
+    let mut outputs = HashSet::new();
+    let mut jobs = Vec::new();
+    for (index, spec) in specs.into_iter().enumerate() {
+        let id = spec.id.unwrap_or_else(|| (index + 1).to_string());
+        if !ids.insert(id.clone()) {
+            return Err(format!("job id {id} is used twice"));
+        }
+        // 作業ディレクトリは出力パスから決まるので、出力が同じだと混ざる
+        let output = std::path::absolute(&spec.output).unwrap_or_else(|_| spec.output.clone());
+        if !outputs.insert(output) {
+            return Err(format!(
+                "job {id}: {} is the output of an earlier job",
+                spec.output.display()
+            ));
+        }
+        if spec.width == 0 || spec.height == 0 {
+            return Err(format!("job {id}: width and height must be at least 1"));
+        }
+        if !spec.fps.is_finite() || spec.fps <= 0.0 {
+            return Err(format!("job {id}: fps must be a positive number"));
+        }
+
+        let mut args = base.clone();
+        args.width = spec.width;
+        args.height = spec.height;
+        args.fps = spec.fps;
+        args.frames = spec.frames;
+        args.output = spec.output;
+        args.report = spec.report;
+        let page_url = spec.page_url.unwrap_or_else(|| base.page_url());
+        args.page_url = Some(match &spec.composition {
+            Some(composition) => {
+                if composition.is_empty()
+                    || !composition
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+                {
+                    return Err(format!(
+                        "job {id}: composition '{composition}' may only use letters, digits, '-', '_' and '.'"
+                    ));
+                }
+                with_query(&page_url, &[("composition", composition.clone())])
+            }
+            None => page_url,
+        });
+        args.validate().map_err(|e| format!("job {id}: {e}"))?;
+        args.job_id = Some(id);
+        jobs.push(args);
+    }
+    Ok(jobs)
+}
+
+/// Render every entry of the job list at `path`, `--parallel-jobs` at a time, and
+/// print one `JOB` line per entry. A failed job does not stop the others unless
+/// `--fail-fast` is set; a canceled one stops the whole list. The backend is reset
+/// once, after the last job.
+pub async fn run_jobs(args: RenderArgs, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let jobs = read_jobs(path, &args).await?;
+    let ids = jobs
+        .iter()
+        .map(|job| job.job_id.clone().unwrap_or_default())
+        .collect::<Vec<_>>();
+    let outputs = jobs
+        .iter()
+        .map(|job| job.output.clone())
+        .collect::<Vec<_>>();
+    info!("{} jobs from {}", jobs.len(), path.display());
+
+    // 同じサイズのジョブはブラウザを使い回す (tabs のときだけ)
+    let mut browsers: HashMap<(u32, u32), Arc<SharedBrowser>> = HashMap::new();
+    let mut outcomes = jobs.iter().map(|_| Outcome::Skipped).collect::<Vec<_>>();
+    let mut pending = jobs.into_iter().enumerate();
+    let mut running = FuturesUnordered::new();
+    let mut stop = false;
+    loop {
+        while !stop
+            && running.len() < args.parallel_jobs
+            && let Some((index, job)) = pending.next()
+        {
+            let browser = (job.browser_mode == BrowserMode::Tabs)
+                .then(|| browsers.entry((job.width, job.height)).or_default().clone());
+            let span = tracing::info_span!("job", id = ids[index]);
+            running.push(async move { (index, crate::run(job, browser).await) }.instrument(span));
+        }
+        let Some((index, result)) = running.next().await else {
+            break;
+        };
+        outcomes[index] = match result {
+            Ok(()) => Outcome::Done,
+            Err(error) if error.is::<Canceled>() => {
+                stop = true;
+                Outcome::Canceled
+            }
+            Err(error) => {
+                error!("job {} failed: {error}", ids[index]);
+                stop |= args.fail_fast;
+                Outcome::Failed(error.to_string())
+            }
+        };
+    }
+    for browser in browsers.values() {
+        browser.close().await;
+    }
+    crate::reset_backend(&args).await;
+
+    let mut failed = 0;
+    let mut canceled = false;
+    for ((id, output), outcome) in ids.iter().zip(&outputs).zip(&outcomes) {
+        match outcome {
+            Outcome::Done => println!("JOB {id} : ok ({})", output.display()),
+            Outcome::Failed(error) => {
+                failed += 1;
+                println!("JOB {id} : failed: {error}");
+            }
+            Outcome::Canceled => {
+                canceled = true;
+                println!("JOB {id} : canceled");
+            }
+            Outcome::Skipped => println!("JOB {id} : skipped"),
+        }
+    }
+    if canceled {
+        return Err(Canceled.into());
+    }
+    match failed {
+        0 => Ok(()),
+        failed => Err(RenderFailed(format!("{failed} of {} jobs failed", outcomes.len())).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    /// The jobs read from a list holding `json`, over a base with `extra` options.
+    async fn read(json: &str, extra: &[&str]) -> Result<Vec<RenderArgs>, String> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("jobs.json");
+        std::fs::write(&path, json).unwrap();
+        let mut argv = vec!["render".to_string(), format!("--jobs={}", path.display())];
+        argv.extend(extra.iter().map(|arg| arg.to_string()));
+        let base = RenderArgs::try_parse_from(argv).unwrap();
+        read_jobs(&path, &base).await
+    }
+
+    /// A job entry rendering 4 frames of 64x36 at 30 fps to `output`.
+    fn job(id: &str, output: &str) -> String {
+        format!(
+            r#"{{"id": "{id}", "width": 64, "height": 36, "fps": 30, "frames": 4, "output": "{output}"}}"#
+        )
+    }
+
+    #[tokio::test]
+    async fn entries_override_the_base_and_default_their_ids() {
+        let json = r#"[
+            {"width": 64, "height": 36, "fps": 30, "frames": 4, "output": "a.mp4"},
+            {"width": 32, "height": 18, "fps": 24, "frames": 8, "output": "b.mp4",
+             "pageUrl": "http://localhost:9000/render", "composition": "intro_v2"}
+        ]"#;
+        let jobs = read(json, &["--workers=3"]).await.unwrap();
+        assert_eq!(jobs.len(), 2);
+        assert_eq!(jobs[0].job_id.as_deref(), Some("1"));
+        assert_eq!(jobs[1].job_id.as_deref(), Some("2"));
+        assert_eq!((jobs[1].width, jobs[1].height, jobs[1].fps), (32, 18, 24.0));
+        assert_eq!(jobs[1].frames, 8);
+        assert!(jobs.iter().all(|job| job.workers == 3));
+        assert_eq!(
+            jobs[1].page_url.as_deref(),
+            Some("http://localhost:9000/render?composition=intro_v2")
+        );
+    }
+
+    #[tokio::test]
+    async fn an_empty_list_is_refused() {
+        let error = read("[]", &[]).await.unwrap_err();
+        assert!(error.ends_with("is empty"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn ids_are_unique_including_the_defaults() {
+        let json = format!("[{}, {}]", job("a", "a.mp4"), job("a", "b.mp4"));
+        let error = read(&json, &[]).await.unwrap_err();
+        assert_eq!(error, "job id a is used twice");
+
+        // the second entry's default id is "2"
+        let json = format!(
+            r#"[{}, {{"width": 64, "height": 36, "fps": 30, "frames": 4, "output": "b.mp4"}}]"#,
+            job("2", "a.mp4")
+        );
+        let error = read(&json, &[]).await.unwrap_err();
+        assert_eq!(error, "job id 2 is used twice");
+    }
+
+    #[tokio::test]
+    async fn two_jobs_may_not_write_the_same_output() {
+        let json = format!("[{}, {}]", job("a", "out.mp4"), job("b", "./out.mp4"));
+        let error = read(&json, &[]).await.unwrap_err();
+        assert_eq!(error, "job b: ./out.mp4 is the output of an earlier job");
+    }
+
+    #[tokio::test]
+    async fn compositions_are_plain_names() {
+        for composition in ["", "intro&scene=2", "../intro", "intro scene"] {
+            let json = format!(
+                r#"[{{"width": 64, "height": 36, "fps": 30, "frames": 4, "output": "a.mp4",
+                     "composition": "{composition}"}}]"#
+            );
+            let error = read(&json, &[]).await.unwrap_err();
+            assert!(
+                error.starts_with(&format!("job 1: composition '{composition}' may only use")),
+                "{error}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn each_job_is_validated_with_the_base_options() {
+        // the clip fits the first job's page but not the second's
+        let json = format!(
+            r#"[{}, {{"id": "small", "width": 16, "height": 9, "fps": 30, "frames": 4, "output": "b.mp4"}}]"#,
+            job("large", "a.mp4")
+        );
+        let error = read(&json, &["--clip=0,0,32,18"]).await.unwrap_err();
+        assert_eq!(
+            error,
+            "job small: --clip 0,0,32,18 does not fit in the 16x9 page"
+        );
+
+        let json = format!("[{}]", job("short", "a.mp4"));
+        let error = read(&json, &["--start-frame=4"]).await.unwrap_err();
+        assert_eq!(
+            error,
+            "job short: --start-frame 4 must be before --end-frame 4"
+        );
+
+        let json = r#"[{"width": 0, "height": 36, "fps": 30, "frames": 4, "output": "a.mp4"}]"#;
+        let error = read(json, &[]).await.unwrap_err();
+        assert_eq!(error, "job 1: width and height must be at least 1");
+
+        let json = r#"[{"width": 64, "height": 36, "fps": 0, "frames": 4, "output": "a.mp4"}]"#;
+        let error = read(json, &[]).await.unwrap_err();
+        assert_eq!(error, "job 1: fps must be a positive number");
+    }
+}
@@ -1,410 +1,2149 @@
+pub mod browser;
+pub mod cli;
+pub mod dry_run;
 pub mod ffmpeg;
+pub mod jobs;
+pub mod progress;
+pub mod report;
+pub mod worker;
 
+use std::fmt;
+use std::process::ExitCode;
 use std::time::{Duration, Instant};
 
-use chromiumoxide::{
-    Browser, Handler, Page, cdp::browser_protocol::page::CaptureScreenshotFormat,
-    handler::viewport::Viewport, page::ScreenshotParams,
-};
 use futures::{StreamExt, stream::FuturesUnordered};
+use tokio::io::AsyncWriteExt;
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, error, info, warn};
 
-use chromiumoxide::browser::BrowserConfig;
-use reqwest::Client;
-use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
-use std::sync::{Arc, OnceLock};
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use tempfile::TempDir;
-
-use crate::ffmpeg::{AudioPlanResolved, SegmentWriter, mux_audio_plan_into_mp4};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-#[derive(Serialize)]
-struct ProgressPayload {
-    completed: usize,
-    total: usize,
-}
+use crate::browser::BrowserOptions;
+use crate::cli::{
+    BrowserMode, CaptureMode, ConcatMode, Distribution, FrameFormat, LogFormat, OutputMode,
+    PixelFormat, RenderArgs,
+};
+use crate::ffmpeg::{
+    AnimationOptions, AudioPlanResolved, ConcatSegment, EncoderKind, FrameInput, FrameProgress,
+    SegmentWriter, convert_to_animation, export_audio_plan, is_remote_url, mux_audio_plan_into_mp4,
+};
+use crate::progress::{Progress, Stage, backoff, http_client};
+use crate::report::{
+    FrameSummary, FrameTimes, Machine, Manifest, ManifestSegment, RenderReport, SegmentCheck,
+    Settings, stage_millis, write_manifest, write_report,
+};
+use crate::worker::{
+    Chunk, FrameOutput, RangeFailure, SharedBrowser, StageTimings, WorkQueue, WorkerConfig,
+    frame_file_path, run_worker,
+};
 
 #[derive(Deserialize)]
 struct CancelResponse {
     canceled: bool,
 }
 
-static CHROMIUM_EXECUTABLE: OnceLock<Option<PathBuf>> = OnceLock::new();
+/// Segments are named after their frame range so that `--resume` never picks up a
+/// file rendered for a different split.
+fn segment_path(directory: &Path, start: usize, end: usize, extension: &str) -> PathBuf {
+    directory.join(format!("segment-{start:08}-{end:08}.{extension}"))
+}
 
-fn resolve_chromium_executable() -> Option<PathBuf> {
-    CHROMIUM_EXECUTABLE
-        .get_or_init(|| {
-            let path = std::env::var("FRAMESCRIPT_CHROMIUM_PATH")
-                .or_else(|_| std::env::var("PUPPETEER_EXECUTABLE_PATH"))
-                .ok()
-                .map(|value| value.trim().to_string())
-                .filter(|value| !value.is_empty())
-                .map(PathBuf::from);
+/// File in the work dir listing, one name per line, the entries renders created
+/// there. Only those are ever cleared, so a `--work-dir` shared with other files is
+/// safe to point at.
+const WORK_DIR_LEDGER: &str = ".framescript-render";
 
-            if let Some(path) = path {
-                if path.is_file() {
-                    return Some(path);
-                }
+/// Add `paths`, entries of `directory`, to its ledger before they are created.
+async fn record_work_files(
+    directory: &Path,
+    paths: impl IntoIterator<Item = PathBuf>,
+) -> std::io::Result<()> {
+    let mut names = String::new();
+    for path in paths {
+        if let Some(name) = path.file_name() {
+            names.push_str(&name.to_string_lossy());
+            names.push('\n');
+        }
+    }
+    let mut ledger = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(directory.join(WORK_DIR_LEDGER))
+        .await?;
+    ledger.write_all(names.as_bytes()).await
+}
+
+/// Remove what earlier renders recorded in the ledger of `directory`, then the
+/// ledger. Anything else in there is not ours and stays.
+async fn clear_work_dir(directory: &Path) -> std::io::Result<()> {
+    let ledger = directory.join(WORK_DIR_LEDGER);
+    let names = match tokio::fs::read_to_string(&ledger).await {
+        Ok(names) => names,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(error) => return Err(error),
+    };
+    let names = names.lines().collect::<std::collections::BTreeSet<_>>();
+    for name in names {
+        // 書き換えられた台帳でも作業ディレクトリの外には触れない
+        if !matches!(
+            Path::new(name).components().collect::<Vec<_>>()[..],
+            [std::path::Component::Normal(_)]
+        ) {
+            continue;
+        }
+        let path = directory.join(name);
+        let removed = match tokio::fs::symlink_metadata(&path).await {
+            Ok(metadata) if metadata.is_dir() => tokio::fs::remove_dir_all(&path).await,
+            Ok(_) => tokio::fs::remove_file(&path).await,
+            Err(error) => Err(error),
+        };
+        match removed {
+            Err(error) if error.kind() != std::io::ErrorKind::NotFound => return Err(error),
+            _ => {}
+        }
+    }
+    tokio::fs::remove_file(&ledger).await
+}
+
+/// Remove `paths`, the segments and frame files a canceled `--resume` run set out to
+/// write, except the `kept` ones an earlier run finished. The ledger still lists them
+/// all, so a later run without `--resume` clears the rest.
+async fn remove_run_files(paths: &[PathBuf], kept: &std::collections::HashSet<PathBuf>) {
+    for path in paths.iter().filter(|path| !kept.contains(*path)) {
+        match tokio::fs::remove_file(path).await {
+            Err(error) if error.kind() != std::io::ErrorKind::NotFound => {
+                warn!("cannot delete {}: {error}", path.display());
             }
-            None
-        })
-        .clone()
+            _ => {}
+        }
+    }
+}
+
+async fn segment_is_complete(path: &Path, expected_frames: usize) -> bool {
+    match crate::ffmpeg::probe_video_frame_count(path).await {
+        Ok(Some(frames)) => frames >= expected_frames,
+        Ok(None) => false,
+        Err(err) => {
+            warn!("resume: cannot validate {} ({err})", path.display());
+            false
+        }
+    }
+}
+
+/// Keep what an earlier run left of the segment for `frames`: the whole segment, or
+/// with `recoverable` the readable start of one it did not finish, saved as its own
+/// segment. Appends what is kept to `segments` and returns the first frame that
+/// still has to be rendered.
+async fn resume_segments(
+    directory: &Path,
+    frames: std::ops::Range<usize>,
+    extension: &str,
+    recoverable: bool,
+    segments: &mut Vec<(std::ops::Range<usize>, PathBuf)>,
+) -> usize {
+    let mut from = frames.start;
+    while from < frames.end {
+        let path = segment_path(directory, from, frames.end, extension);
+        if segment_is_complete(&path, frames.end - from).await {
+            info!("resume: keeping {}", path.display());
+            segments.push((from..frames.end, path));
+            return frames.end;
+        }
+        if !recoverable {
+            break;
+        }
+        let Some(end) = salvaged_segment_end(directory, from..frames.end, extension).await else {
+            break;
+        };
+        segments.push((from..end, segment_path(directory, from, end, extension)));
+        from = end;
+    }
+    from
+}
+
+/// End of the salvaged segment starting at `frames.start`: one an earlier `--resume`
+/// already cut, or one cut now from the unfinished segment for `frames`.
+async fn salvaged_segment_end(
+    directory: &Path,
+    frames: std::ops::Range<usize>,
+    extension: &str,
+) -> Option<usize> {
+    if let Some(end) = earlier_salvage(directory, &frames, extension).await {
+        let path = segment_path(directory, frames.start, end, extension);
+        if segment_is_complete(&path, end - frames.start).await {
+            info!("resume: keeping salvaged {}", path.display());
+            return Some(end);
+        }
+    }
+
+    let partial = segment_path(directory, frames.start, frames.end, extension);
+    if !partial.is_file() {
+        return None;
+    }
+    let readable = match crate::ffmpeg::probe_decodable_frames(&partial).await {
+        Ok(Some(readable)) if readable > 0 => readable.min(frames.len() - 1),
+        _ => return None,
+    };
+    let end = frames.start + readable;
+    let salvaged = segment_path(directory, frames.start, end, extension);
+    if let Err(err) = crate::ffmpeg::salvage_segment(&partial, readable, &salvaged).await {
+        warn!("resume: {err}");
+        return None;
+    }
+    if !segment_is_complete(&salvaged, readable).await {
+        warn!("resume: {} came out short", salvaged.display());
+        tokio::fs::remove_file(&salvaged).await.ok();
+        return None;
+    }
+    info!(
+        "resume: salvaged frames {}..{end} from {}",
+        frames.start,
+        partial.display()
+    );
+    tokio::fs::remove_file(&partial).await.ok();
+    Some(end)
 }
 
-async fn spawn_browser_instance(
-    profile_id: usize,
-    width: u32,
-    height: u32,
-) -> Result<(Browser, Handler), Box<dyn std::error::Error>> {
-    // 一時ディレクトリをブラウザプロファイルとして使う
-    let tmp = TempDir::new()?; // ライフタイム管理は適宜
-    let user_data_dir: PathBuf = tmp.path().join(format!("profile-{}", profile_id));
-
-    let mut builder = BrowserConfig::builder()
-        .new_headless_mode()
-        .viewport(Viewport {
-            width,
-            height,
-            device_scale_factor: None,
-            emulating_mobile: false,
-            is_landscape: false,
-            has_touch: false,
+/// The longest segment in `directory` that starts at `frames.start` and ends inside
+/// `frames`, found by name: what an earlier `--resume` salvaged.
+async fn earlier_salvage(
+    directory: &Path,
+    frames: &std::ops::Range<usize>,
+    extension: &str,
+) -> Option<usize> {
+    let prefix = format!("segment-{:08}-", frames.start);
+    let suffix = format!(".{extension}");
+    let mut entries = tokio::fs::read_dir(directory).await.ok()?;
+    let mut longest = None;
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let name = entry.file_name();
+        let end = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(&prefix))
+            .and_then(|rest| rest.strip_suffix(&suffix))
+            .and_then(|end| end.parse::<usize>().ok())
+            .filter(|end| *end > frames.start && *end < frames.end);
+        longest = longest.max(end);
+    }
+    longest
+}
+
+async fn fetch_audio_plan(url: &str) -> Option<AudioPlanResolved> {
+    let resp = http_client().get(url).send().await.ok()?;
+    if !resp.status().is_success() {
+        return None;
+    }
+    resp.json::<AudioPlanResolved>()
+        .await
+        .ok()
+        .filter(|plan| !plan.segments.is_empty())
+}
+
+/// Read an `--audio-plan` file and check that every source it names exists, so a
+/// typo fails before the render instead of at the mux.
+async fn read_audio_plan(path: &Path) -> Result<AudioPlanResolved, Box<dyn std::error::Error>> {
+    let bytes = tokio::fs::read(path)
+        .await
+        .map_err(|e| format!("cannot read audio plan {}: {e}", path.display()))?;
+    let plan: AudioPlanResolved = serde_json::from_slice(&bytes)
+        .map_err(|e| format!("invalid audio plan {}: {e}", path.display()))?;
+
+    let missing = plan
+        .segments
+        .iter()
+        .filter(|segment| {
+            let source = segment.source.path();
+            !is_remote_url(source) && !Path::new(source).is_file()
         })
-        .request_timeout(Duration::from_secs(24 * 60 * 60))
-        .user_data_dir(user_data_dir); // ★ インスタンスごとに別のディレクトリ
+        .map(|segment| format!("{} ({})", segment.source.path(), segment.id))
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        return Err(format!(
+            "audio plan {} names missing files: {}",
+            path.display(),
+            missing.join(", ")
+        )
+        .into());
+    }
+    Ok(plan)
+}
 
-    if let Some(path) = resolve_chromium_executable() {
-        builder = builder.chrome_executable(path);
+/// The `--audio-plan` file if one was given, otherwise the backend's plan, with
+/// `--audio-mastering`, `--loudness-target` and `--audio-channels` over what the
+/// plan says.
+async fn resolve_audio_plan(
+    file_plan: Option<&AudioPlanResolved>,
+    args: &RenderArgs,
+) -> Option<AudioPlanResolved> {
+    let mut plan = match file_plan {
+        Some(plan) => plan.clone(),
+        None if args.standalone => return None,
+        None => fetch_audio_plan(&args.audio_plan_url).await?,
+    };
+    if args.audio_mastering.is_some() {
+        plan.mastering = args.audio_mastering;
+    }
+    if args.loudness_target.is_some() {
+        plan.loudness_target = args.loudness_target;
     }
+    if args.audio_channels.is_some() {
+        plan.channel_layout = args.audio_channels;
+    }
+    Some(plan)
+}
 
-    let config = builder.build()?;
+/// Tell the backend the render is over, so it can drop what it cached for it. A
+/// `--jobs` entry leaves that to `run_jobs`, since the reset also drops the audio
+/// plan the later jobs render with.
+async fn reset_backend(args: &RenderArgs) {
+    if !args.standalone && args.job_id.is_none() {
+        let _ = http_client().post(&args.reset_url).send().await;
+    }
+}
 
-    let (browser, handler) = Browser::launch(config).await?;
-    Ok((browser, handler))
+async fn backend_reachable(url: &str) -> bool {
+    http_client()
+        .get(url)
+        .timeout(Duration::from_secs(2))
+        .send()
+        .await
+        .is_ok_and(|response| response.status().is_success())
 }
 
-async fn wait_for_next_frame(page: &Page) {
-    let script = r#"
-        (async () => {
-          await new Promise(resolve => {
-            requestAnimationFrame(() => {
-              requestAnimationFrame(resolve);
-            });
-          });
-        })()
-    "#;
-    page.evaluate(script).await.unwrap();
-}
-
-async fn wait_for_frame_api(page: &Page) {
-    let script = r#"
-        (async () => {
-          const start = Date.now();
-          while (true) {
-            const api = window.__frameScript;
-            if (api && typeof api.setFrame === "function") return true;
-            if (Date.now() - start > 15000) {
-              throw new Error("frameScript setFrame not available");
+/// SIGINT, or SIGTERM on Unix: how a standalone render is canceled.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
             }
-            await new Promise(resolve => {
-              requestAnimationFrame(() => {
-                requestAnimationFrame(resolve);
-              });
-            });
-          }
-        })()
-    "#;
-    page.evaluate(script).await.unwrap();
+            return;
+        }
+    }
+    if tokio::signal::ctrl_c().await.is_err() {
+        std::future::pending::<()>().await;
+    }
 }
 
-async fn wait_for_animation_ready(page: &Page) {
-    let script = r#"
-        (async () => {
-          const api = window.__frameScript;
-          if (api && typeof api.waitAnimationsReady === "function") {
-            await api.waitAnimationsReady();
-          }
-        })()
-    "#;
-    page.evaluate(script).await.unwrap();
+/// `--output-mode audio`: mix the audio plan without rendering any frame. Progress
+/// counts the frames' worth of audio ffmpeg has written.
+async fn export_audio_only(args: &RenderArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let start = Instant::now();
+    let frame_range = args.frame_range();
+    let total = frame_range.len();
+
+    let file_plan = match &args.audio_plan {
+        Some(path) => Some(read_audio_plan(path).await?),
+        None => None,
+    };
+    let Some(plan) = resolve_audio_plan(file_plan.as_ref(), args).await else {
+        return Err("no audio plan to export".into());
+    };
+
+    let extension = args.audio_format.extension();
+    let mut output_path = args.output.clone();
+    if output_path.extension().and_then(|ext| ext.to_str()) != Some(extension) {
+        output_path.set_extension(extension);
+        warn!(
+            "{} does not match the .{extension} output, writing {} instead",
+            args.output.display(),
+            output_path.display()
+        );
+    }
+    check_overwrite(&output_path, args.overwrite)?;
+    let work_dir = args.work_dir();
+    tokio::fs::create_dir_all(&work_dir).await?;
+    let working_output = work_dir.join(format!("output.{extension}"));
+    record_work_files(&work_dir, [working_output.clone()]).await?;
+
+    let mixed = Arc::new(AtomicUsize::new(0));
+    let progress = Arc::new(Progress::new(
+        args.progress_target(),
+        total,
+        mixed.clone(),
+        Stage::Mux,
+    ));
+    let interval = args.progress_interval();
+    let reporter = tokio::spawn({
+        let progress = progress.clone();
+        async move { progress.report_every(interval).await }
+    });
+
+    let result = export_audio_plan(
+        &working_output,
+        &plan,
+        frame_range.clone(),
+        args.fps,
+        &args.audio_encode(),
+        Some(&mixed),
+    )
+    .await;
+    if matches!(result, Ok(true)) {
+        mixed.store(total, Ordering::Relaxed);
+        progress.enter(Stage::Finalizing).await;
+    }
+    progress.finish(false).await;
+    reporter.await.ok();
+    reset_backend(args).await;
+
+    if !result? {
+        return Err(format!(
+            "audio plan has no audio in frames {}..{}",
+            frame_range.start, frame_range.end
+        )
+        .into());
+    }
+    place_output(&working_output, &output_path).await?;
+
+    println!("TOTAL : {}[ms]", start.elapsed().as_millis());
+    Ok(())
+}
+
+/// Encode the image files of `frames` into `segment`, in frame order. Used to put
+/// `--distribution interleaved` renders back into sequence. With `skip_missing`,
+/// frames that were never rendered are left out instead of failing.
+async fn encode_frame_files(
+    dir: &Path,
+    extension: &str,
+    frames: std::ops::Range<usize>,
+    segment: &Path,
+    config: &WorkerConfig,
+    skip_missing: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (width, height) = config.capture_size();
+    let mut writer = SegmentWriter::new(
+        &segment.to_string_lossy(),
+        width,
+        height,
+        config.fps,
+        &config.encode_settings(),
+        if extension == "jpg" {
+            FrameInput::Jpeg
+        } else {
+            FrameInput::Png
+        },
+        Some(config.output_size()),
+        config.recoverable_segments,
+    )
+    .await?;
+    for frame in frames {
+        let path = frame_file_path(dir, frame, extension);
+        if skip_missing && !path.is_file() {
+            continue;
+        }
+        let bytes = tokio::fs::read(&path)
+            .await
+            .map_err(|e| format!("cannot read {}: {e}", path.display()))?;
+        writer.write_frame(&bytes).await?;
+    }
+    writer.finish().await
+}
+
+/// Fail early instead of rendering for an hour into a path we may not replace.
+fn check_overwrite(path: &Path, overwrite: bool) -> Result<(), String> {
+    if !overwrite && path.exists() {
+        return Err(format!(
+            "{} already exists; pass --overwrite to replace it",
+            path.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Move `source` to `destination` so that a partial file never shows up there. Across
+/// filesystems the file is copied to `<destination>.tmp-<pid>`, synced and renamed
+/// over the destination; the temp file is removed if any step fails.
+async fn place_output(source: &Path, destination: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(parent) = destination.parent()
+        && !parent.as_os_str().is_empty()
+    {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    if tokio::fs::rename(source, destination).await.is_ok() {
+        return Ok(());
+    }
+
+    let mut temp = destination.as_os_str().to_owned();
+    temp.push(format!(".tmp-{}", std::process::id()));
+    let temp = PathBuf::from(temp);
+    let copied = async {
+        tokio::fs::copy(source, &temp).await?;
+        tokio::fs::File::open(&temp).await?.sync_all().await?;
+        tokio::fs::rename(&temp, destination).await
+    }
+    .await;
+    if let Err(error) = copied {
+        tokio::fs::remove_file(&temp).await.ok();
+        return Err(format!("cannot write {}: {error}", destination.display()).into());
+    }
+    tokio::fs::remove_file(source).await.ok();
+    Ok(())
+}
+
+/// Difference in frames a segment may have from its range, for encoders that drop or
+/// repeat a frame at the edges.
+const SEGMENT_FRAME_TOLERANCE: usize = 1;
+
+/// What is wrong with the segment at `path` for `frames`, if anything. A `partial`
+/// segment belongs to a range that already failed and only has to be readable.
+async fn segment_problem(
+    path: &Path,
+    frames: &std::ops::Range<usize>,
+    size: (u32, u32),
+    partial: bool,
+) -> Option<String> {
+    match tokio::fs::metadata(path).await {
+        Err(_) => return Some("missing".to_string()),
+        Ok(metadata) if metadata.len() == 0 => return Some("empty file".to_string()),
+        Ok(_) => {}
+    }
+    let probe = match crate::ffmpeg::probe_video(path).await {
+        Ok(Some(probe)) => probe,
+        Ok(None) => return Some("not decodable".to_string()),
+        Err(error) => return Some(format!("cannot probe: {error}")),
+    };
+    if (probe.width, probe.height) != size {
+        return Some(format!(
+            "{}x{}, expected {}x{}",
+            probe.width, probe.height, size.0, size.1
+        ));
+    }
+    if !partial && probe.frames.abs_diff(frames.len()) > SEGMENT_FRAME_TOLERANCE {
+        return Some(format!(
+            "{} frames, expected {}",
+            probe.frames,
+            frames.len()
+        ));
+    }
+    None
+}
+
+/// Probe every segment before concat so that a short or broken one is reported by
+/// frame range instead of as an ffmpeg concat error or a silent gap. Bad segments
+/// fail the render, or are left out with `allow_gaps`. Segments of ranges that
+/// failed under `--keep-going` are kept as far as they got. Problems name the workers
+/// that rendered the segment.
+async fn check_segments(
+    segments: Vec<(std::ops::Range<usize>, PathBuf)>,
+    size: (u32, u32),
+    failed: &[RangeFailure],
+    allow_gaps: bool,
+    timings: &StageTimings,
+    checks: &mut Vec<SegmentCheck>,
+) -> Result<Vec<ConcatSegment>, RenderFailed> {
+    let covered = match (segments.first(), segments.last()) {
+        (Some((first, _)), Some((last, _))) => first.start..last.end,
+        _ => 0..0,
+    };
+    let mut usable = Vec::new();
+    let mut problems = Vec::new();
+    for (frames, path) in segments {
+        let origin = segment_origin(&frames, &timings.workers_for(&frames));
+        let partial = failed
+            .iter()
+            .any(|failure| failure.frames.start < frames.end && frames.start < failure.frames.end);
+        let problem = segment_problem(&path, &frames, size, partial).await;
+        checks.push(SegmentCheck {
+            frames: frames.clone(),
+            path: path.clone(),
+            problem: problem.clone(),
+        });
+        match problem {
+            None => usable.push(ConcatSegment { path, origin }),
+            Some(problem) => {
+                let problem = format!("{} ({origin}): {problem}", path.display());
+                if partial {
+                    warn!("leaving out the failed segment for {problem}");
+                } else {
+                    problems.push(problem);
+                }
+            }
+        }
+    }
+
+    if problems.is_empty() && !usable.is_empty() {
+        return Ok(usable);
+    }
+    if usable.is_empty() {
+        return Err(RenderFailed(format!(
+            "no usable segment is left for frames {}..{}{}",
+            covered.start,
+            covered.end,
+            problems
+                .iter()
+                .map(|problem| format!("\n  {problem}"))
+                .collect::<String>()
+        )));
+    }
+    if !allow_gaps {
+        return Err(RenderFailed(format!(
+            "{} segment(s) failed validation; rerun with --resume to render them again, \
+             or pass --allow-gaps to leave them out:\n  {}",
+            problems.len(),
+            problems.join("\n  ")
+        )));
+    }
+    for problem in &problems {
+        warn!("--allow-gaps: leaving out {problem}");
+    }
+    Ok(usable)
+}
+
+/// `frames 0..60, worker 2` for a segment rendered by `workers` this run.
+fn segment_origin(frames: &std::ops::Range<usize>, workers: &[usize]) -> String {
+    let by = match workers {
+        [] => "kept from an earlier run".to_string(),
+        [worker] => format!("worker {worker}"),
+        workers => format!(
+            "workers {}",
+            workers
+                .iter()
+                .map(usize::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    format!("frames {}..{}, {by}", frames.start, frames.end)
+}
+
+/// Join `segments`, `expected` of them, into `output_path` as `mode` says. The
+/// segments are deleted after a successful join unless `keep_segments`.
+/// Re-encoding uses the segment encoder's settings from `config`.
+#[allow(clippy::too_many_arguments)]
+async fn concat_segments(
+    segments: Vec<ConcatSegment>,
+    expected: usize,
+    output_path: &Path,
+    mode: ConcatMode,
+    config: &WorkerConfig,
+    keep_segments: bool,
+    allow_missing: bool,
+    progress: Option<FrameProgress<'_>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let settings = config.encode_settings();
+    let reencode = |segments| {
+        crate::ffmpeg::concat_segments_reencode(
+            segments,
+            expected,
+            output_path,
+            &settings,
+            keep_segments,
+            allow_missing,
+            progress,
+        )
+    };
+    match mode {
+        ConcatMode::Copy => {
+            crate::ffmpeg::concat_segments_mp4(
+                segments,
+                expected,
+                output_path,
+                keep_segments,
+                allow_missing,
+                progress,
+            )
+            .await
+        }
+        ConcatMode::Reencode => reencode(segments).await,
+        ConcatMode::Auto => {
+            match crate::ffmpeg::concat_segments_mp4(
+                segments.clone(),
+                expected,
+                output_path,
+                keep_segments,
+                allow_missing,
+                progress,
+            )
+            .await
+            {
+                Ok(()) => Ok(()),
+                Err(error) => {
+                    warn!(
+                        "stream-copy concat failed, falling back to the slower re-encode \
+                         concat. {error}"
+                    );
+                    reencode(segments).await
+                }
+            }
+        }
+    }
+}
+
+/// Concatenate the segments, `expected` of them, mux `audio_plan` if there is one and
+/// move the result to `output_path`. The segments are deleted once joined unless
+/// `keep_segments`, and unreadable or missing ones are left out with `allow_missing`.
+/// With
+/// `keep_intermediates` the video without audio stays in `directory` and its path is
+/// returned.
+#[allow(clippy::too_many_arguments)]
+async fn assemble_video(
+    directory: &Path,
+    segments: Vec<ConcatSegment>,
+    expected: usize,
+    extension: &str,
+    output_path: &Path,
+    audio_plan: Option<AudioPlanResolved>,
+    frame_range: std::ops::Range<usize>,
+    concat_mode: ConcatMode,
+    config: &WorkerConfig,
+    progress: &Progress,
+    keep_segments: bool,
+    allow_missing: bool,
+    keep_intermediates: bool,
+) -> Result<Option<PathBuf>, Box<dyn std::error::Error>> {
+    progress.enter(Stage::Concat).await;
+    let working_output = directory.join(format!("output.{extension}"));
+    let ffmpeg_progress = FrameProgress {
+        done: progress.stage_frames(),
+        fps: config.fps,
+        total: frame_range.len(),
+    };
+    concat_segments(
+        segments,
+        expected,
+        &working_output,
+        concat_mode,
+        config,
+        keep_segments,
+        allow_missing,
+        Some(ffmpeg_progress),
+    )
+    .await?;
+
+    let mut video_only = None;
+    if let Some(plan) = audio_plan {
+        progress.enter(Stage::Mux).await;
+        let input_video = working_output.clone();
+        let temp_video = directory.join(format!("output.audio.{extension}"));
+        if mux_audio_plan_into_mp4(
+            &input_video,
+            &temp_video,
+            &plan,
+            frame_range,
+            config.fps,
+            &config.audio,
+            Some(ffmpeg_progress),
+        )
+        .await?
+        {
+            if keep_intermediates {
+                let kept = directory.join(format!("output.no-audio.{extension}"));
+                tokio::fs::rename(&input_video, &kept).await?;
+                video_only = Some(kept);
+            } else {
+                tokio::fs::remove_file(&input_video).await.ok();
+            }
+            tokio::fs::rename(&temp_video, &input_video).await?;
+        }
+    }
+
+    if output_path != working_output {
+        progress.enter(Stage::Finalizing).await;
+        place_output(&working_output, output_path).await?;
+    }
+
+    Ok(video_only)
+}
+
+fn worker_config(
+    args: &RenderArgs,
+    encode: EncoderKind,
+    capture: CaptureMode,
+    work_dir: &Path,
+) -> WorkerConfig {
+    WorkerConfig {
+        width: args.width,
+        height: args.height,
+        scale: args.scale,
+        downscale: args.downscale,
+        clip: args.clip,
+        fps: args.fps,
+        quality: args.quality(),
+        encode,
+        preset: args.preset.clone(),
+        gop: args.gop(),
+        pixel_format: args.pix_fmt,
+        color_range: args.color_range,
+        tuning: args.tuning(),
+        audio: args.audio_encode(),
+        page_url: args.page_url(),
+        url_params: !args.no_url_params,
+        capture,
+        jpeg_quality: args.jpeg_quality,
+        frame_retries: args.frame_retries,
+        max_relaunches: args.max_relaunches,
+        pipeline_depth: args.pipeline_depth,
+        resume: args.resume,
+        recoverable_segments: args.recoverable_segments,
+        frame_timeout: args.frame_timeout,
+        on_frame_timeout: args.on_frame_timeout,
+        page_ready_timeout: args.page_ready_timeout,
+        diagnostics_dir: work_dir.join("diagnostics"),
+        browser: BrowserOptions {
+            executable: None,
+            profile_dir: args.profile_dir.clone(),
+            extra_args: args
+                .chromium_args
+                .iter()
+                .filter(|arg| !arg.is_empty())
+                .cloned()
+                .collect(),
+            headed: args.no_headless,
+            device_scale_factor: (args.scale != 1.0).then_some(args.scale),
+            no_sandbox: args.no_sandbox,
+            disable_gpu_sandbox: args.disable_gpu_sandbox,
+        },
+    }
+}
+
+/// Exit code when frames could not be rendered. 1 is any other error and 2 is an
+/// invalid command line (from clap).
+const EXIT_RENDER_FAILED: u8 = 3;
+
+/// Exit code of a canceled render, as for a process stopped with Ctrl-C.
+const EXIT_CANCELED: u8 = 130;
+
+/// The render was canceled; nothing was written to the output path.
+#[derive(Debug)]
+struct Canceled;
+
+impl fmt::Display for Canceled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("render canceled")
+    }
+}
+
+impl std::error::Error for Canceled {}
+
+/// Some frames never made it into the output.
+#[derive(Debug)]
+struct RenderFailed(String);
+
+impl fmt::Display for RenderFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for RenderFailed {}
+
+/// Log to stderr at the level RUST_LOG asks for, `info` by default. Panics are
+/// logged too, so they show up in the JSON log instead of as bare stderr text.
+fn init_logging(format: LogFormat) {
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        // Electron は stderr をそのまま表示するので色は端末のときだけ
+        .with_ansi(std::io::IsTerminal::is_terminal(&std::io::stderr()));
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().init(),
+    }
+
+    std::panic::set_hook(Box::new(|panic| {
+        let location = panic
+            .location()
+            .map(|location| location.to_string())
+            .unwrap_or_default();
+        error!(
+            location,
+            "panic: {}",
+            panic.payload_as_str().unwrap_or("(no message)")
+        );
+    }));
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = std::env::args().collect::<Vec<String>>();
+async fn main() -> ExitCode {
+    let args = RenderArgs::parse_with_legacy();
+    init_logging(args.log_format);
+    let result = match args.jobs.clone() {
+        Some(path) => jobs::run_jobs(args, &path).await,
+        None => run(args, None).await,
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            if error.is::<Canceled>() {
+                warn!("{error}");
+            } else {
+                error!("{error}");
+            }
+            ExitCode::from(exit_code(error.as_ref()))
+        }
+    }
+}
 
-    if args.len() < 2 {
-        return Err("Invalid command.".into());
+/// Exit code of a render that stopped with `error`.
+fn exit_code(error: &(dyn std::error::Error + 'static)) -> u8 {
+    if error.is::<Canceled>() {
+        EXIT_CANCELED
+    } else if error.is::<RenderFailed>() {
+        EXIT_RENDER_FAILED
+    } else {
+        1
     }
+}
 
-    let splited = args[1].split(":").collect::<Vec<_>>();
+/// Render as `args` asks. `browser` is the tab-mode browser to use instead of
+/// launching one, kept open afterwards for the next job.
+async fn run(
+    args: RenderArgs,
+    browser: Option<Arc<SharedBrowser>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    run_with(args, browser, |_| {}).await
+}
 
-    if splited.len() != 7 {
-        return Err("Invalid command(split).".into());
+/// [`run`], with `configure` adjusting the worker config built from `args`.
+async fn run_with(
+    mut args: RenderArgs,
+    browser: Option<Arc<SharedBrowser>>,
+    configure: impl FnOnce(&mut WorkerConfig),
+) -> Result<(), Box<dyn std::error::Error>> {
+    if args.dry_run {
+        let encode = args.codec().encoder();
+        let mut config = worker_config(&args, encode, args.capture, &args.work_dir());
+        configure(&mut config);
+        return dry_run::dry_run(&args, &config).await;
+    }
+    if !args.standalone && !backend_reachable(&args.healthz_url).await {
+        warn!(
+            "backend does not answer {}, running standalone",
+            args.healthz_url
+        );
+        args.standalone = true;
+    }
+    if args.output_mode == OutputMode::Audio {
+        return export_audio_only(&args).await;
     }
 
-    let width = splited[0].parse::<u32>()?;
-    let height = splited[1].parse::<u32>()?;
-    let fps = splited[2].parse::<f64>()?;
-    let total_frames = splited[3].parse::<usize>()?;
-    let workers = splited[4].parse::<usize>()?;
-    let encode = splited[5].to_string();
-    let preset = splited[6].to_string();
+    let fps = args.fps;
+    let frame_range = args.frame_range();
+    let total_frames = frame_range.len();
+    let preset = args.preset.clone();
+    let quality = args.quality();
+    let frames_dir = match args.output_mode {
+        OutputMode::Frames => args.frames_dir.clone(),
+        _ => None,
+    };
+    let encode = if frames_dir.is_some() {
+        args.codec().encoder()
+    } else {
+        let encode =
+            crate::ffmpeg::usable_encoder(args.codec().encoder(), quality, &preset).await?;
+        let pixel_format = args.pix_fmt.map(PixelFormat::as_str);
+        let tuning = args.tuning();
+        if pixel_format.is_some() || !tuning.is_empty() {
+            crate::ffmpeg::check_encoder_options(encode, quality, &preset, pixel_format, &tuning)
+                .await?;
+        }
+        encode
+    };
+    let file_plan = match &args.audio_plan {
+        Some(path) => Some(read_audio_plan(path).await?),
+        None => None,
+    };
 
-    let worker_count = workers.max(1);
-    let base_chunk = total_frames / worker_count;
-    let remainder = total_frames % worker_count;
-    let progress_url = std::env::var("RENDER_PROGRESS_URL")
-        .unwrap_or_else(|_| "http://127.0.0.1:3000/render_progress".to_string());
-    let progress_client = Client::new();
+    let worker_count = args.workers.max(1);
     let completed = Arc::new(AtomicUsize::new(0));
     let total_frames_usize = total_frames;
 
-    let cancel_url = std::env::var("RENDER_CANCEL_URL")
-        .unwrap_or_else(|_| "http://127.0.0.1:3000/is_canceled".to_string());
-    let is_canceled = Arc::new(AtomicBool::new(false));
-    let is_canceled_clone = is_canceled.clone();
+    // cancel はレンダーの中断、stop は中断か完了でポーリングを止める
+    let cancel = CancellationToken::new();
+    let stop = cancel.child_token();
+    let _stop_on_return = stop.clone().drop_guard();
+
+    let standalone = args.standalone;
+    let cancel_url = args.cancel_url.clone();
+    let cancel_interval = args.cancel_interval();
+    let cancel_clone = cancel.clone();
+    let stop_clone = stop.clone();
     tokio::spawn(async move {
+        if standalone {
+            tokio::select! {
+                _ = stop_clone.cancelled() => {}
+                _ = shutdown_signal() => {
+                    warn!("signal received, canceling the render");
+                    cancel_clone.cancel();
+                }
+            }
+            return;
+        }
+
+        let mut failures = 0;
         loop {
-            let client = Client::new();
-            let is_canceled = match client.get(&cancel_url).send().await {
-                Ok(resp) => match resp.json::<CancelResponse>().await {
-                    Ok(body) => body.canceled,
-                    Err(_) => false,
-                },
-                Err(_) => false,
+            let is_canceled = match http_client().get(&cancel_url).send().await {
+                Ok(resp) => {
+                    failures = 0;
+                    match resp.json::<CancelResponse>().await {
+                        Ok(body) => body.canceled,
+                        Err(_) => false,
+                    }
+                }
+                Err(_) => {
+                    failures += 1;
+                    false
+                }
             };
 
             if is_canceled {
-                is_canceled_clone.store(true, Ordering::Relaxed);
+                cancel_clone.cancel();
                 break;
             }
 
-            tokio::time::sleep(Duration::from_secs(1)).await;
+            // バックエンドが落ちている間は間隔を広げて叩きすぎない
+            tokio::select! {
+                _ = stop_clone.cancelled() => break,
+                _ = tokio::time::sleep(backoff(cancel_interval, failures)) => {}
+            }
         }
     });
 
-    // initialize progress
-    let _ = progress_client
-        .post(&progress_url)
-        .json(&ProgressPayload {
-            completed: 0,
-            total: total_frames_usize,
-        })
-        .send()
-        .await;
+    let work_dir = args.work_dir();
+    let extension = args.container().extension();
+    let output_extension = match args.output_mode {
+        OutputMode::Gif => "gif",
+        OutputMode::Webp => "webp",
+        _ => extension,
+    };
+    let mut output_path = args.output.clone();
+    if frames_dir.is_none()
+        && output_path.extension().and_then(|ext| ext.to_str()) != Some(output_extension)
+    {
+        output_path.set_extension(output_extension);
+        warn!(
+            "{} does not match the .{output_extension} output, writing {} instead",
+            args.output.display(),
+            output_path.display()
+        );
+    }
+    if frames_dir.is_none() {
+        check_overwrite(&output_path, args.overwrite)?;
+        if args.keep_video && matches!(args.output_mode, OutputMode::Gif | OutputMode::Webp) {
+            check_overwrite(&output_path.with_extension(extension), args.overwrite)?;
+        }
+    }
 
-    // share progress
-    let progress_url_clone = progress_url.clone();
-    let completed_clone = completed.clone();
-    let is_canceled_clone = is_canceled.clone();
-    tokio::spawn(async move {
-        loop {
-            let _ = Client::new()
-                .post(&progress_url_clone)
-                .json(&ProgressPayload {
-                    completed: completed_clone.load(Ordering::Relaxed),
-                    total: total_frames,
-                })
-                .send()
-                .await;
+    if let Some(dir) = &frames_dir {
+        tokio::fs::create_dir_all(dir).await?;
+    } else {
+        tokio::fs::create_dir_all(&work_dir).await?;
+        if !args.resume {
+            clear_work_dir(&work_dir).await?;
+        }
+        info!("work directory: {}", work_dir.display());
+    }
 
-            if is_canceled_clone.load(Ordering::Relaxed) {
-                break;
-            }
+    let interleaved = args.distribution == Distribution::Interleaved;
+    let capture = match (&frames_dir, args.frame_format) {
+        (Some(_), FrameFormat::Png) => CaptureMode::Png,
+        (Some(_), FrameFormat::Jpeg) => CaptureMode::Jpeg,
+        // 連番ファイルに raw RGBA は置けないので PNG にする
+        (None, _) if interleaved && args.capture == CaptureMode::Raw => CaptureMode::Png,
+        (None, _) => args.capture,
+    };
+    let files_extension = if capture == CaptureMode::Jpeg {
+        "jpg"
+    } else {
+        "png"
+    };
+    let files_dir = frames_dir
+        .clone()
+        .or_else(|| interleaved.then(|| work_dir.clone()));
+
+    // setFrame は絶対フレーム番号なので frame_range.start からずらす
+    let (ranges, stride) = if interleaved {
+        let ranges = (0..worker_count.min(total_frames))
+            .map(|worker_id| (frame_range.start + worker_id, frame_range.end))
+            .collect::<Vec<_>>();
+        (ranges, worker_count)
+    } else {
+        let ranges = frame_range
+            .clone()
+            .step_by(args.chunk_frames)
+            .map(|start| (start, (start + args.chunk_frames).min(frame_range.end)))
+            .collect::<Vec<_>>();
+        (ranges, 1)
+    };
 
-            tokio::time::sleep(Duration::from_millis(50)).await;
+    // チャンク順 = フレーム順なので、この並びのまま結合すればよい
+    let mut segments = Vec::new();
+    if interleaved {
+        segments.push((
+            frame_range.clone(),
+            segment_path(&work_dir, frame_range.start, frame_range.end, extension),
+        ));
+    }
+    let mut pending = Vec::new();
+    // --resume で引き継いだもの。キャンセルしても消さない
+    let mut kept = std::collections::HashSet::new();
+    if let Some(dir) = &files_dir {
+        // 番号はコンポジションの絶対フレーム番号なのでワーカーをまたいで一意
+        for (start, end) in ranges {
+            if args.resume {
+                let existing = (start..end)
+                    .step_by(stride)
+                    .map(|frame| frame_file_path(dir, frame, files_extension))
+                    .filter(|path| path.is_file())
+                    .collect::<Vec<_>>();
+                completed.fetch_add(existing.len(), Ordering::Relaxed);
+                kept.extend(existing);
+            }
+            let out = FrameOutput::Files {
+                dir: dir.clone(),
+                extension: files_extension,
+            };
+            pending.push(Chunk {
+                frames: start..end,
+                stride,
+                out,
+            });
         }
-    });
+    } else {
+        for (start, end) in ranges {
+            let mut from = start;
+            if args.resume {
+                let resumed = segments.len();
+                from = resume_segments(
+                    &work_dir,
+                    start..end,
+                    extension,
+                    args.recoverable_segments,
+                    &mut segments,
+                )
+                .await;
+                kept.extend(segments[resumed..].iter().map(|(_, path)| path.clone()));
+                completed.fetch_add(from - start, Ordering::Relaxed);
+            }
+            if from < end {
+                let path = segment_path(&work_dir, from, end, extension);
+                segments.push((from..end, path.clone()));
+                pending.push(Chunk {
+                    frames: from..end,
+                    stride,
+                    out: FrameOutput::Segment(path),
+                });
+            }
+        }
+    }
 
-    // Render page URL:
-    // - Dev: defaults to Vite dev server.
-    // - Non-dev: Electron can pass a `file://.../dist-render/render.html` URL.
-    let url = std::env::var("RENDER_PAGE_URL")
-        .or_else(|_| std::env::var("RENDER_DEV_SERVER_URL"))
-        .unwrap_or_else(|_| "http://localhost:5174/render".to_string());
+    let mut run_files = Vec::new();
+    if frames_dir.is_none() {
+        let outputs = [
+            format!("output.{extension}"),
+            format!("output.audio.{extension}"),
+            format!("output.no-audio.{extension}"),
+            format!("output.{output_extension}"),
+            "output.segments.txt".to_string(),
+            "output.palette.png".to_string(),
+            "manifest.json".to_string(),
+            "diagnostics".to_string(),
+        ];
+        // interleaved では作業ディレクトリに連番ファイルを書く
+        let frame_files = files_dir
+            .iter()
+            .flat_map(|dir| frame_range.clone().map(move |frame| (dir, frame)))
+            .flat_map(|(dir, frame)| {
+                let path = frame_file_path(dir, frame, files_extension);
+                [path.with_extension(format!("{files_extension}.tmp")), path]
+            });
+        run_files.extend(
+            segments
+                .iter()
+                .map(|(_, path)| path.clone())
+                .chain(frame_files),
+        );
+        record_work_files(
+            &work_dir,
+            outputs
+                .into_iter()
+                .map(PathBuf::from)
+                .chain(run_files.iter().cloned()),
+        )
+        .await?;
+    }
+
+    // 結合や音声の間も段階を伝えるため、送信はレンダー完了まで続ける
+    let progress = Arc::new(
+        Progress::new(
+            args.progress_target(),
+            total_frames_usize,
+            completed.clone(),
+            Stage::Rendering,
+        )
+        .with_job(args.job_id.clone()),
+    );
+    progress.post().await;
+    let interval = args.progress_interval();
+    tokio::spawn({
+        let progress = progress.clone();
+        async move { progress.report_every(interval).await }
+    });
 
     let mut tasks = FuturesUnordered::new();
 
-    static DIRECTORY: &'static str = "frames";
-    let output_path =
-        std::env::var("RENDER_OUTPUT_PATH").unwrap_or_else(|_| "output.mp4".to_string());
-    let output_path = PathBuf::from(output_path);
+    let start = Instant::now();
 
-    tokio::fs::remove_dir_all(DIRECTORY).await.ok();
-    tokio::fs::create_dir(DIRECTORY).await?;
+    let mut config = worker_config(&args, encode, capture, &work_dir);
+    configure(&mut config);
+    let config = Arc::new(config);
 
-    let start = Instant::now();
+    info!("chromium: {}", config.browser.describe());
+
+    let timings = Arc::new(StageTimings::default());
+
+    let worker_count = worker_count.min(pending.len());
+    let queue = Arc::new(WorkQueue::new(pending));
+    let owns_browser = browser.is_none();
+    let shared = browser.or_else(|| {
+        (args.browser_mode == BrowserMode::Tabs).then(|| Arc::new(SharedBrowser::default()))
+    });
+    let rendered_before = completed.load(Ordering::Relaxed);
 
-    let mut ranges = Vec::new();
     for worker_id in 0..worker_count {
-        let start = worker_id * base_chunk;
-        let end = start + base_chunk;
-        if start < end {
-            ranges.push((start, end));
+        let queue = queue.clone();
+        let shared = shared.clone();
+        let config = config.clone();
+        let timings = timings.clone();
+        let completed_clone = completed.clone();
+        let cancel = cancel.clone();
+        let frame_range = frame_range.clone();
+        tasks.push(async move {
+            let handle = tokio::spawn(async move {
+                run_worker(
+                    worker_id,
+                    &queue,
+                    shared.as_deref(),
+                    &config,
+                    &completed_clone,
+                    &timings,
+                    &cancel,
+                )
+                .instrument(tracing::info_span!(
+                    "worker",
+                    worker_id,
+                    frame = tracing::field::Empty
+                ))
+                .await
+            });
+            // どのチャンクで落ちたか分からないので全体を失敗扱いにする
+            handle.await.unwrap_or_else(|err| {
+                Err(RangeFailure {
+                    frames: frame_range,
+                    error: format!("worker {worker_id} panicked: {err}"),
+                    fatal: false,
+                })
+            })
+        });
+    }
+
+    let mut failures = Vec::new();
+    while let Some(result) = tasks.next().await {
+        if let Err(failure) = result {
+            error!("{failure}");
+            failures.push(failure);
         }
     }
-    if remainder > 0 {
-        let start = worker_count * base_chunk;
-        let end = total_frames;
-        if start < end {
-            ranges.push((start, end));
+    let render_elapsed = start.elapsed();
+    let rendered = completed.load(Ordering::Relaxed) - rendered_before;
+    if owns_browser && let Some(shared) = &shared {
+        shared.close().await;
+    }
+
+    // 全ワーカーが落ちると誰も取らなかったチャンクが残る
+    stop.cancel();
+
+    if !cancel.is_cancelled() {
+        let reason = queue
+            .aborted()
+            .map(|reason| format!("not rendered after a fatal error: {reason}"))
+            .unwrap_or_else(|| "no worker left to render it".to_string());
+        for chunk in queue.drain() {
+            failures.push(RangeFailure {
+                frames: chunk.frames,
+                error: reason.clone(),
+                fatal: false,
+            });
         }
     }
 
-    for (worker_id, (start, end)) in ranges.into_iter().enumerate() {
-        let encode_clone = encode.clone();
-        let preset_clone = preset.clone();
+    // キャンセル時は途中までの出力を作らない
+    if cancel.is_cancelled() {
+        if frames_dir.is_none() && !args.keep_partials && !args.keep_intermediates {
+            if args.resume {
+                remove_run_files(&run_files, &kept).await;
+            } else {
+                clear_work_dir(&work_dir).await.ok();
+            }
+        }
+        progress.finish(true).await;
+        reset_backend(&args).await;
+        return Err(Canceled.into());
+    }
 
-        let page_url = url.clone();
-        let completed_clone = completed.clone();
-        let is_canceled_clone = is_canceled.clone();
-        tasks.push(tokio::spawn(async move {
-            let (mut browser, mut handler) = spawn_browser_instance(worker_id, width, height)
-                .await
-                .unwrap();
+    let mut render_failure = None;
+    if !failures.is_empty() {
+        failures.sort_by_key(|failure| failure.frames.start);
+        error!("{} failed range(s):", failures.len());
+        for failure in &failures {
+            error!("  {failure}");
+        }
+        let ranges = failures
+            .iter()
+            .map(|failure| format!("{}..{}", failure.frames.start, failure.frames.end))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let failed = RenderFailed(format!(
+            "render failed for frames {ranges}; completed work is kept, rerun with --resume"
+        ));
+        if !args.keep_going {
+            progress.finish(false).await;
+            reset_backend(&args).await;
+            return Err(failed.into());
+        }
+        warn!("--keep-going: assembling the frames that did render");
+        render_failure = Some(failed);
+    }
 
-            tokio::spawn(async move { while handler.next().await.is_some() {} });
+    if interleaved && frames_dir.is_none() {
+        progress.enter(Stage::Encoding).await;
+        encode_frame_files(
+            &work_dir,
+            files_extension,
+            frame_range.clone(),
+            &segments[0].1,
+            &config,
+            render_failure.is_some(),
+        )
+        .await?;
+    }
 
-            let out = format!("{}/segment-{worker_id:03}.mp4", DIRECTORY);
+    let mut segment_checks = Vec::new();
+    let expected_segments = segments.len();
+    let segments = if frames_dir.is_none() {
+        progress.enter(Stage::Concat).await;
+        check_segments(
+            segments,
+            config.output_size(),
+            &failures,
+            args.allow_gaps,
+            &timings,
+            &mut segment_checks,
+        )
+        .await?
+    } else {
+        Vec::new()
+    };
 
-            let mut writer = SegmentWriter::new(
-                &out,
-                width,
-                height,
-                fps,
-                18,
-                &encode_clone,
-                Some(&preset_clone),
-                Some(fps as u32),
+    // --keep-going で欠けた範囲は --resume で埋め直せるようセグメントを残す
+    let keep_segments = args.keep_intermediates || render_failure.is_some();
+    // --keep-going で失敗した範囲のセグメントは check_segments が外している
+    let allow_missing = args.allow_gaps || render_failure.is_some();
+    let video_only = match (args.output_mode, &frames_dir) {
+        (OutputMode::Frames, Some(dir)) => {
+            if args.frames_audio
+                && let Some(plan) = resolve_audio_plan(file_plan.as_ref(), &args).await
+            {
+                progress.enter(Stage::Mux).await;
+                export_audio_plan(
+                    &dir.join("audio.m4a"),
+                    &plan,
+                    frame_range.clone(),
+                    fps,
+                    &config.audio,
+                    Some(progress.stage_frames()),
+                )
+                .await?;
+            }
+            None
+        }
+        (OutputMode::Gif | OutputMode::Webp, _) => {
+            // GIF/WebP には音声を載せないので音声プランは使わない
+            let video_path = if args.keep_video {
+                output_path.with_extension(extension)
+            } else {
+                work_dir.join(format!("output.{extension}"))
+            };
+            assemble_video(
+                &work_dir,
+                segments,
+                expected_segments,
+                extension,
+                &video_path,
+                None,
+                frame_range.clone(),
+                args.concat_mode,
+                &config,
+                &progress,
+                keep_segments,
+                allow_missing,
+                args.keep_intermediates,
             )
+            .await?;
+            progress.enter(Stage::Encoding).await;
+            let options = AnimationOptions {
+                fps: args.anim_fps,
+                max_width: args.anim_max_width,
+                max_frames: args.anim_max_frames,
+                dither: args.dither.as_str().to_string(),
+            };
+            let animation = work_dir.join(format!("output.{output_extension}"));
+            convert_to_animation(&video_path, &animation, &options).await?;
+            progress.enter(Stage::Finalizing).await;
+            place_output(&animation, &output_path).await?;
+            None
+        }
+        _ => {
+            assemble_video(
+                &work_dir,
+                segments,
+                expected_segments,
+                extension,
+                &output_path,
+                resolve_audio_plan(file_plan.as_ref(), &args).await,
+                frame_range.clone(),
+                args.concat_mode,
+                &config,
+                &progress,
+                keep_segments,
+                allow_missing,
+                args.keep_intermediates,
+            )
+            .await?
+        }
+    };
+
+    progress.finish(false).await;
+
+    reset_backend(&args).await;
+
+    println!("TOTAL : {}[ms]", start.elapsed().as_millis());
+    println!(
+        "QUALITY : {} {} ({})",
+        config.encode, config.quality, config.preset
+    );
+    println!("DISTRIBUTION : {}", args.distribution);
+    match &shared {
+        Some(shared) => println!(
+            "BROWSER : {} ({} launches)",
+            args.browser_mode,
+            shared.launches()
+        ),
+        None => println!("BROWSER : {}", args.browser_mode),
+    }
+    println!("RELAUNCHES : {}", timings.relaunches());
+    if timings.skipped() > 0 {
+        println!(
+            "SKIPPED : {} frames timed out and were repeated",
+            timings.skipped()
+        );
+    }
+    println!(
+        "THROUGHPUT : {:.2}[frames/s] ({} frames in {}[ms])",
+        rendered as f64 / render_elapsed.as_secs_f64().max(f64::EPSILON),
+        rendered,
+        render_elapsed.as_millis()
+    );
+    println!(
+        "CAPTURE : {} (screenshot {}[ms], encode {}[ms], summed over workers)",
+        config.capture,
+        timings.capture().as_millis(),
+        timings.encode().as_millis()
+    );
+    let frame_times = FrameTimes::from(&*timings);
+    println!(
+        "FRAME TIME : setFrame {:.0}[ms], wait {:.0}[ms], screenshot {:.0}[ms], encode {:.0}[ms] (summed over workers)",
+        frame_times.set_frame, frame_times.wait, frame_times.screenshot, frame_times.encode
+    );
+    let stages = progress.stage_durations();
+    println!(
+        "STAGES : {}",
+        stages
+            .iter()
+            .map(|(stage, elapsed)| format!("{} {}[ms]", stage.as_str(), elapsed.as_millis()))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    let workers = timings.workers();
+    for worker in &workers {
+        println!(
+            "WORKER {} : {} frames in {}[ms]",
+            worker.worker_id,
+            worker.frames,
+            worker.wall.as_millis()
+        );
+    }
+
+    if args.keep_intermediates && frames_dir.is_none() {
+        let concat_list =
+            crate::ffmpeg::concat_list_path(&work_dir.join(format!("output.{extension}")));
+        let manifest = Manifest {
+            segments: segment_checks
+                .iter()
+                .map(|check| ManifestSegment {
+                    path: check.path.clone(),
+                    frames: check.frames.clone(),
+                    workers: timings.workers_for(&check.frames),
+                    problem: check.problem.clone(),
+                })
+                .collect(),
+            concat_list: concat_list.is_file().then_some(concat_list),
+            video_only,
+            output: output_path.clone(),
+        };
+        write_manifest(&work_dir.join("manifest.json"), &manifest).await?;
+        println!("INTERMEDIATES : {}", work_dir.display());
+    }
+
+    if let Some(path) = &args.report {
+        let report = RenderReport {
+            total_ms: start.elapsed().as_secs_f64() * 1000.0,
+            frames: FrameSummary {
+                range: frame_range.clone(),
+                rendered,
+                frames_per_second: rendered as f64 / render_elapsed.as_secs_f64().max(f64::EPSILON),
+            },
+            settings: Settings::new(&config, &args),
+            machine: Machine::current(),
+            stages_ms: stage_millis(&stages),
+            frame_ms: frame_times,
+            workers: workers.into_iter().map(Into::into).collect(),
+            relaunches: timings.relaunches(),
+            skipped_frames: timings.skipped(),
+            segments: segment_checks,
+        };
+        write_report(path, &report).await?;
+        info!("report: {}", path.display());
+    }
+
+    match render_failure {
+        Some(failed) => Err(failed.into()),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    /// Arguments for a standalone render of 4 frames into `dir`, followed by `extra`.
+    fn args(dir: &Path, extra: &[&str]) -> RenderArgs {
+        let dir = dir.to_str().unwrap();
+        let mut argv = vec![
+            "render".to_string(),
+            "--standalone".to_string(),
+            "--width=64".to_string(),
+            "--height=36".to_string(),
+            "--fps=30".to_string(),
+            "--frames=4".to_string(),
+            "--chunk-frames=2".to_string(),
+            "--max-relaunches=0".to_string(),
+            format!("--work-dir={dir}/work"),
+            format!("--output={dir}/output.mp4"),
+            format!("--progress-file={dir}/progress.json"),
+        ];
+        argv.extend(extra.iter().map(|arg| arg.to_string()));
+        let args = RenderArgs::try_parse_from(argv).unwrap();
+        args.validate().unwrap();
+        args
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn a_failing_worker_fails_the_render_without_output() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // a Chromium that exits at once, so no worker gets a browser
+        let dir = tempfile::tempdir().unwrap();
+        let chromium = dir.path().join("chromium");
+        std::fs::write(&chromium, "#!/bin/sh\nexit 1\n").unwrap();
+        std::fs::set_permissions(&chromium, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let use_chromium = |config: &mut WorkerConfig| {
+            config.browser.executable = Some(chromium.clone());
+        };
+
+        let frames_dir = dir.path().join("frames");
+        let frames_dir_arg = format!("--frames-dir={}", frames_dir.display());
+        let error = run_with(
+            args(dir.path(), &["--output-mode=frames", &frames_dir_arg]),
+            None,
+            use_chromium,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(exit_code(error.as_ref()), EXIT_RENDER_FAILED, "{error}");
+        assert_eq!(std::fs::read_dir(&frames_dir).unwrap().count(), 0);
+
+        if crate::ffmpeg::resolve_checked_ffmpeg().is_err() {
+            eprintln!("skipping the video render: ffmpeg not available");
+            return;
+        }
+        let error = run_with(args(dir.path(), &[]), None, use_chromium)
+            .await
+            .unwrap_err();
+        assert_eq!(exit_code(error.as_ref()), EXIT_RENDER_FAILED, "{error}");
+        assert!(!dir.path().join("output.mp4").exists());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn failed_launches_count_as_relaunches_and_back_off() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let chromium = dir.path().join("chromium");
+        std::fs::write(&chromium, "#!/bin/sh\nexit 1\n").unwrap();
+        std::fs::set_permissions(&chromium, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let args = args(dir.path(), &[]);
+        let mut config = worker_config(&args, EncoderKind::X264, CaptureMode::Png, dir.path());
+        config.browser.executable = Some(chromium);
+        config.max_relaunches = 2;
+
+        let queue = WorkQueue::new(vec![Chunk {
+            frames: 0..4,
+            stride: 1,
+            out: FrameOutput::Files {
+                dir: dir.path().to_path_buf(),
+                extension: "png",
+            },
+        }]);
+        let completed = AtomicUsize::new(0);
+        let timings = StageTimings::default();
+        let started = Instant::now();
+        let failure = run_worker(
+            0,
+            &queue,
+            None,
+            &config,
+            &completed,
+            &timings,
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap_err();
+        assert!(!failure.fatal, "{}", failure.error);
+        // the first launch is not a relaunch, the two after it are
+        assert_eq!(timings.relaunches(), 2);
+        // 0.5s, then 1s between them
+        assert!(started.elapsed() >= Duration::from_millis(1500));
+    }
+
+    #[test]
+    fn failures_map_to_their_exit_codes() {
+        assert_eq!(exit_code(&Canceled), EXIT_CANCELED);
+        assert_eq!(
+            exit_code(&RenderFailed("frames 0..2".to_string())),
+            EXIT_RENDER_FAILED
+        );
+        assert_eq!(exit_code(&std::io::Error::other("no ffmpeg")), 1);
+    }
+
+    #[tokio::test]
+    async fn clearing_the_work_dir_removes_only_what_renders_recorded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name);
+        for name in ["segment-00000000-00000030.mp4", "output.mp4", "frames.txt"] {
+            std::fs::write(path(name), b"").unwrap();
+        }
+        std::fs::create_dir(path("diagnostics")).unwrap();
+        std::fs::write(path("diagnostics/worker0-frame000001.png"), b"").unwrap();
+        // the user's own files, named like ours
+        std::fs::write(path("segment-notes.txt"), b"").unwrap();
+        std::fs::write(path("manifest.json"), b"").unwrap();
+        std::fs::create_dir(path("frames")).unwrap();
+
+        record_work_files(
+            dir.path(),
+            [
+                path("segment-00000000-00000030.mp4"),
+                path("output.mp4"),
+                path("output.audio.mp4"),
+                path("diagnostics"),
+            ],
+        )
+        .await
+        .unwrap();
+        record_work_files(dir.path(), [path("output.mp4")])
             .await
             .unwrap();
+        // a ledger pointing outside the work dir is not followed
+        let outside = tempfile::NamedTempFile::new().unwrap();
+        let mut ledger = std::fs::read_to_string(path(WORK_DIR_LEDGER)).unwrap();
+        ledger.push_str(&format!("{}\n../frames.txt\n", outside.path().display()));
+        std::fs::write(path(WORK_DIR_LEDGER), ledger).unwrap();
 
-            let page = browser.new_page(page_url).await.unwrap();
-            page.wait_for_navigation().await.unwrap();
-            wait_for_frame_api(&page).await;
-            wait_for_animation_ready(&page).await;
-
-            for frame in start..end {
-                wait_for_next_frame(&page).await;
-
-                let js = format!(
-                    r#"
-                    (() => {{
-                      const api = window.__frameScript;
-                      if (api && typeof api.setFrame === "function") {{
-                        api.setFrame({});
-                      }}
-                    }})()
-                    "#,
-                    frame
-                );
-                page.evaluate(js).await.unwrap();
-
-                wait_for_next_frame(&page).await;
-
-                let script = format!(
-                    r#"
-                    (async () => {{
-                      const api = window.__frameScript;
-                      if (api && typeof api.waitCanvasFrame === "function") {{
-                        try {{
-                          await api.waitCanvasFrame({});
-                        }} catch (_e) {{
-                          // ignore
-                        }}
-                      }}
-                    }})()
-                "#,
-                    frame
-                );
-                page.evaluate(script).await.unwrap();
-
-                let bytes = page
-                    .screenshot(
-                        ScreenshotParams::builder()
-                            .format(CaptureScreenshotFormat::Png)
-                            .omit_background(true)
-                            .build(),
-                    )
-                    .await
-                    .unwrap();
-
-                writer.write_png_frame(&bytes).await.unwrap();
-
-                completed_clone.fetch_add(1, Ordering::Relaxed);
-
-                if is_canceled_clone.load(Ordering::Relaxed) {
-                    break;
-                }
-            }
+        clear_work_dir(dir.path()).await.unwrap();
 
-            writer.finish().await.unwrap();
+        let mut left = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        left.sort();
+        assert_eq!(
+            left,
+            ["frames", "frames.txt", "manifest.json", "segment-notes.txt"]
+        );
+        assert!(outside.path().exists());
 
-            browser.close().await.unwrap();
-        }));
+        // nothing recorded, nothing removed
+        clear_work_dir(dir.path()).await.unwrap();
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 4);
     }
 
-    while let Some(_) = tasks.next().await {}
+    #[tokio::test]
+    async fn a_canceled_resume_keeps_the_segments_it_took_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let resumed = segment_path(dir.path(), 0, 30, "mp4");
+        let partial = segment_path(dir.path(), 30, 60, "mp4");
+        let unstarted = segment_path(dir.path(), 60, 90, "mp4");
+        std::fs::write(&resumed, b"complete").unwrap();
+        std::fs::write(&partial, b"partial").unwrap();
 
-    let mut segs = Vec::new();
+        let kept = std::collections::HashSet::from([resumed.clone()]);
+        remove_run_files(&[resumed.clone(), partial.clone(), unstarted], &kept).await;
 
-    for worker_id in 0..worker_count + if remainder > 0 { 1 } else { 0 } {
-        let path = PathBuf::from(format!("{}/segment-{worker_id:03}.mp4", DIRECTORY));
-        if tokio::fs::metadata(&path).await.is_ok() {
-            segs.push(path);
+        assert!(resumed.exists());
+        assert!(!partial.exists());
+    }
+
+    /// A Chromium on PATH, for the tests that drive a real page.
+    fn installed_chromium() -> Option<PathBuf> {
+        let found = std::env::split_paths(&std::env::var_os("PATH")?)
+            .flat_map(|dir| {
+                [
+                    "chromium",
+                    "chromium-browser",
+                    "google-chrome",
+                    "google-chrome-stable",
+                ]
+                .map(|name| dir.join(name))
+            })
+            .find(|path| path.is_file());
+        if found.is_none() {
+            eprintln!("skipping: no chromium on PATH");
         }
+        found
     }
 
-    let working_output = PathBuf::from("frames/output.mp4");
-    crate::ffmpeg::concat_segments_mp4(segs, &working_output).await?;
+    /// Serve a render page whose frames never finish drawing: `waitCanvasFrame`
+    /// requests `/stalled`, which fires the returned receiver, and never resolves.
+    async fn serve_stalled_page() -> (String, tokio::sync::mpsc::Receiver<()>) {
+        use tokio::io::AsyncReadExt;
 
-    let audio_plan_url = std::env::var("RENDER_AUDIO_PLAN_URL")
-        .unwrap_or_else(|_| "http://127.0.0.1:3000/render_audio_plan".to_string());
-    if let Ok(resp) = Client::new().get(&audio_plan_url).send().await {
-        if resp.status().is_success() {
-            if let Ok(plan) = resp.json::<AudioPlanResolved>().await {
-                if !plan.segments.is_empty() {
-                    let input_video = working_output.clone();
-                    let temp_video = PathBuf::from("frames/output.audio.mp4");
-                    mux_audio_plan_into_mp4(&input_video, &temp_video, &plan, total_frames, fps)
-                        .await?;
-                    tokio::fs::remove_file(&input_video).await.ok();
-                    tokio::fs::rename(&temp_video, &input_video).await?;
-                }
+        const PAGE: &str = r#"<!doctype html><canvas></canvas><script>
+            window.__frameScript = {
+              setFrame(frame) {},
+              waitCanvasFrame(frame) {
+                fetch("/stalled");
+                return new Promise(() => {});
+              },
+            };
+        </script>"#;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let (stalled, receiver) = tokio::sync::mpsc::channel(16);
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let stalled = stalled.clone();
+                tokio::spawn(async move {
+                    let mut request = vec![0; 4096];
+                    let read = stream.read(&mut request).await.unwrap_or(0);
+                    let request = String::from_utf8_lossy(&request[..read]);
+                    let body = if request.starts_with("GET /stalled") {
+                        stalled.send(()).await.ok();
+                        ""
+                    } else {
+                        PAGE
+                    };
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\ncontent-type: text/html\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                        body.len()
+                    );
+                    stream.write_all(response.as_bytes()).await.ok();
+                });
             }
-        }
+        });
+        (url, receiver)
     }
 
-    if output_path != working_output {
-        if let Some(parent) = output_path.parent() {
-            tokio::fs::create_dir_all(parent).await.ok();
-        }
-        tokio::fs::remove_file(&output_path).await.ok();
-        if let Err(err) = tokio::fs::rename(&working_output, &output_path).await {
-            eprintln!("[render] rename failed ({}), falling back to copy", err);
-            if tokio::fs::copy(&working_output, &output_path).await.is_ok() {
-                tokio::fs::remove_file(&working_output).await.ok();
-            }
+    #[tokio::test]
+    async fn cancel_stops_a_worker_stuck_in_a_frame() {
+        let Some(chromium) = installed_chromium() else {
+            return;
+        };
+        let (url, mut stalled) = serve_stalled_page().await;
+        let dir = tempfile::tempdir().unwrap();
+        let page_url = format!("--page-url={url}");
+        let args = args(
+            dir.path(),
+            &[
+                &page_url,
+                "--no-url-params",
+                "--frame-timeout=60",
+                "--no-sandbox",
+            ],
+        );
+        let mut config = worker_config(&args, EncoderKind::X264, CaptureMode::Png, dir.path());
+        config.browser.executable = Some(chromium);
+
+        let queue = WorkQueue::new(vec![Chunk {
+            frames: 0..4,
+            stride: 1,
+            out: FrameOutput::Files {
+                dir: dir.path().to_path_buf(),
+                extension: "png",
+            },
+        }]);
+        let completed = AtomicUsize::new(0);
+        let timings = StageTimings::default();
+        let cancel = CancellationToken::new();
+        let worker = run_worker(0, &queue, None, &config, &completed, &timings, &cancel);
+        tokio::pin!(worker);
+
+        // the first frame is stuck in waitCanvasFrame
+        tokio::select! {
+            _ = &mut worker => panic!("the worker finished a page that never draws"),
+            _ = stalled.recv() => {}
         }
+        cancel.cancel();
+        let canceled_at = Instant::now();
+        tokio::time::timeout(Duration::from_secs(3), &mut worker)
+            .await
+            .expect("the worker kept waiting for the frame after cancel")
+            .ok();
+        assert!(canceled_at.elapsed() < Duration::from_secs(3));
+        assert_eq!(completed.load(Ordering::Relaxed), 0);
     }
 
-    let final_completed = completed.load(Ordering::Relaxed);
-    let _ = progress_client
-        .post(&progress_url)
-        .json(&ProgressPayload {
-            completed: final_completed,
-            total: total_frames_usize,
-        })
-        .send()
+    #[tokio::test]
+    async fn missing_and_empty_segments_are_caught_before_probing() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("segment-missing.mp4");
+        let empty = dir.path().join("segment-empty.mp4");
+        std::fs::write(&empty, b"").unwrap();
+
+        assert_eq!(
+            segment_problem(&missing, &(0..30), (64, 36), false).await,
+            Some("missing".to_string())
+        );
+        assert_eq!(
+            segment_problem(&empty, &(0..30), (64, 36), true).await,
+            Some("empty file".to_string())
+        );
+
+        let error = check_segments(
+            vec![(0..30, missing), (30..60, empty)],
+            (64, 36),
+            &[],
+            true,
+            &StageTimings::default(),
+            &mut Vec::new(),
+        )
+        .await
+        .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .starts_with("no usable segment is left for frames 0..60"),
+            "{error}"
+        );
+    }
+
+    #[tokio::test]
+    async fn short_truncated_and_resized_segments_are_reported_by_range() {
+        use crate::ffmpeg::fixtures;
+
+        let Some(good) = fixtures::test_video("segment-good.mp4", 30) else {
+            return;
+        };
+        let Some(short) = fixtures::test_video("segment-short.mp4", 20) else {
+            return;
+        };
+        // cut before the index at the end of the file, as a worker killed mid-write leaves it
+        let truncated = good.dir.path().join("segment-truncated.mp4");
+        let bytes = std::fs::read(&good.path).unwrap();
+        std::fs::write(&truncated, &bytes[..bytes.len() / 2]).unwrap();
+
+        assert_eq!(
+            segment_problem(&good.path, &(0..30), (64, 36), false).await,
+            None
+        );
+        assert_eq!(
+            segment_problem(&good.path, &(0..30), (32, 32), false).await,
+            Some("64x36, expected 32x32".to_string())
+        );
+        assert_eq!(
+            segment_problem(&short.path, &(30..60), (64, 36), false).await,
+            Some("20 frames, expected 30".to_string())
+        );
+        // a range that already failed only has to be readable
+        assert_eq!(
+            segment_problem(&short.path, &(30..60), (64, 36), true).await,
+            None
+        );
+        assert_eq!(
+            segment_problem(&truncated, &(60..90), (64, 36), false).await,
+            Some("not decodable".to_string())
+        );
+
+        let segments = vec![
+            (0..30, good.path.clone()),
+            (30..60, short.path.clone()),
+            (60..90, truncated.clone()),
+        ];
+        let error = check_segments(
+            segments.clone(),
+            (64, 36),
+            &[],
+            false,
+            &StageTimings::default(),
+            &mut Vec::new(),
+        )
+        .await
+        .unwrap_err()
+        .to_string();
+        assert!(error.contains("frames 30..60"), "{error}");
+        assert!(error.contains("frames 60..90"), "{error}");
+
+        let mut checks = Vec::new();
+        let usable = check_segments(
+            segments,
+            (64, 36),
+            &[],
+            true,
+            &StageTimings::default(),
+            &mut checks,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            usable
+                .iter()
+                .map(|segment| &segment.path)
+                .collect::<Vec<_>>(),
+            [&good.path]
+        );
+        assert_eq!(checks.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn only_the_job_list_resets_the_backend() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let reset_url = format!("http://{}/reset", listener.local_addr().unwrap());
+        let dir = tempfile::tempdir().unwrap();
+        let mut args = args(dir.path(), &[]);
+        args.standalone = false;
+        args.reset_url = reset_url;
+        let accept = || tokio::time::timeout(Duration::from_millis(500), listener.accept());
+
+        let mut job = args.clone();
+        job.job_id = Some("intro".to_string());
+        reset_backend(&job).await;
+        assert!(accept().await.is_err(), "a job reset the backend");
+
+        // the server never replies, so the post only has to reach it
+        let posted = tokio::spawn(async move { reset_backend(&args).await });
+        assert!(
+            accept().await.is_ok(),
+            "the job list did not reset the backend"
+        );
+        posted.abort();
+    }
+
+    #[test]
+    fn parallel_jobs_need_a_standalone_render() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut args = args(dir.path(), &["--parallel-jobs=2"]);
+        args.standalone = false;
+        assert!(args.validate().unwrap_err().contains("--standalone"));
+        args.parallel_jobs = 1;
+        args.validate().unwrap();
+    }
+
+    #[tokio::test]
+    async fn auto_concat_reencodes_what_stream_copy_refuses() {
+        use crate::ffmpeg::fixtures;
+
+        // FFV1 with different keyframe intervals: the MP4 muxer cannot take it by copy
+        let segment = |name, gop| {
+            fixtures::generate(
+                name,
+                &[
+                    "-f",
+                    "lavfi",
+                    "-i",
+                    "testsrc2=size=64x36:rate=30:duration=0.5",
+                    "-c:v",
+                    "ffv1",
+                    "-level",
+                    "1",
+                    "-g",
+                    gop,
+                ],
+            )
+        };
+        let (Some(first), Some(second)) = (segment("first.mkv", "1"), segment("second.mkv", "15"))
+        else {
+            return;
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let config = worker_config(
+            &args(dir.path(), &[]),
+            EncoderKind::X264,
+            CaptureMode::Png,
+            dir.path(),
+        );
+        let segments = || {
+            [&first.path, &second.path]
+                .into_iter()
+                .enumerate()
+                .map(|(index, path)| ConcatSegment {
+                    path: path.clone(),
+                    origin: format!("frames {}..{}", index * 15, index * 15 + 15),
+                })
+                .collect::<Vec<_>>()
+        };
+        let output = dir.path().join("joined.mp4");
+
+        let copy = concat_segments(
+            segments(),
+            2,
+            &output,
+            ConcatMode::Copy,
+            &config,
+            true,
+            false,
+            None,
+        )
         .await;
+        assert!(copy.is_err());
+        concat_segments(
+            segments(),
+            2,
+            &output,
+            ConcatMode::Auto,
+            &config,
+            true,
+            false,
+            None,
+        )
+        .await
+        .unwrap();
+
+        let stream = fixtures::probe(&output, "v:0", "stream=codec_name,nb_frames");
+        assert_eq!(fixtures::entry(&stream, "codec_name"), "h264");
+        assert_eq!(fixtures::entry(&stream, "nb_frames"), "30");
+    }
 
-    let reset_url = std::env::var("RENDER_RESET_URL")
-        .unwrap_or_else(|_| "http://127.0.0.1:3000/reset".to_string());
-    let _ = progress_client.post(&reset_url).send().await;
+    #[tokio::test]
+    async fn a_truncated_segment_is_salvaged_up_to_its_last_whole_frame() {
+        use crate::ffmpeg::fixtures;
 
-    println!("TOTAL : {}[ms]", start.elapsed().as_millis());
+        // the fragmented layout --recoverable-segments writes, cut off mid-file
+        let Some(partial) = fixtures::generate(
+            "segment-00000000-00000060.mp4",
+            &[
+                "-f",
+                "lavfi",
+                "-i",
+                "testsrc2=size=64x36:rate=30:duration=2",
+                "-c:v",
+                "libx264",
+                "-g",
+                "10",
+                "-pix_fmt",
+                "yuv420p",
+                "-movflags",
+                "+frag_keyframe+empty_moov+default_base_moof",
+            ],
+        ) else {
+            return;
+        };
+        let bytes = std::fs::read(&partial.path).unwrap();
+        std::fs::write(&partial.path, &bytes[..bytes.len() * 3 / 5]).unwrap();
+        let dir = partial.dir.path();
 
-    Ok(())
+        let end = salvaged_segment_end(dir, 0..60, "mp4").await.unwrap();
+        assert!((1..60).contains(&end), "salvaged 0..{end}");
+        let salvaged = segment_path(dir, 0, end, "mp4");
+        assert_eq!(
+            crate::ffmpeg::probe_decodable_frames(&salvaged)
+                .await
+                .unwrap(),
+            Some(end)
+        );
+        assert!(!partial.path.exists());
+
+        // the next --resume finds the salvaged segment by its name
+        assert_eq!(salvaged_segment_end(dir, 0..60, "mp4").await, Some(end));
+        assert_eq!(earlier_salvage(dir, &(0..60), "mp4").await, Some(end));
+        assert_eq!(earlier_salvage(dir, &(0..end), "mp4").await, None);
+    }
+
+    #[tokio::test]
+    async fn earlier_salvages_are_found_by_name() {
+        let dir = tempfile::tempdir().unwrap();
+        for (start, end, extension) in [
+            (0, 20, "mp4"),
+            (0, 35, "mp4"),
+            (0, 40, "webm"),
+            (0, 60, "mp4"),
+            (10, 30, "mp4"),
+        ] {
+            std::fs::write(segment_path(dir.path(), start, end, extension), b"").unwrap();
+        }
+
+        assert_eq!(earlier_salvage(dir.path(), &(0..60), "mp4").await, Some(35));
+        assert_eq!(earlier_salvage(dir.path(), &(0..30), "mp4").await, Some(20));
+        assert_eq!(earlier_salvage(dir.path(), &(20..60), "mp4").await, None);
+    }
+
+    #[tokio::test]
+    async fn audio_plans_may_name_urls_but_not_missing_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let local = dir.path().join("voice.wav");
+        std::fs::write(&local, b"").unwrap();
+        let plan_path = dir.path().join("plan.json");
+        let segment = |id: &str, path: &str| {
+            serde_json::json!({
+                "id": id,
+                "source": { "kind": "sound", "path": path },
+                "projectStartFrame": 0,
+                "sourceStartFrame": 0,
+                "durationFrames": 30
+            })
+        };
+        let write_plan = |segments: Vec<serde_json::Value>| {
+            let plan = serde_json::json!({ "fps": 30.0, "segments": segments });
+            std::fs::write(&plan_path, plan.to_string()).unwrap();
+        };
+
+        write_plan(vec![
+            segment("voice", local.to_str().unwrap()),
+            segment("music", "https://cdn.example.com/music.mp3"),
+            segment("sfx", "HTTP://cdn.example.com/sfx.wav"),
+        ]);
+        assert_eq!(read_audio_plan(&plan_path).await.unwrap().segments.len(), 3);
+
+        let missing = dir.path().join("gone.wav");
+        write_plan(vec![
+            segment("music", "https://cdn.example.com/music.mp3"),
+            segment("gone", missing.to_str().unwrap()),
+        ]);
+        let error = read_audio_plan(&plan_path).await.unwrap_err().to_string();
+        assert!(
+            error.ends_with(&format!("{} (gone)", missing.display())),
+            "{error}"
+        );
+        assert!(!error.contains("music"), "{error}");
+    }
 }
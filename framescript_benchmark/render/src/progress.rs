@@ -0,0 +1,389 @@
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use reqwest::Client;
+use serde::Serialize;
+
+/// How far back the throughput behind the ETA is measured.
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(5);
+
+/// How often progress is printed with `ProgressTarget::Stdout`.
+const PRINT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Longest gap between updates while nothing changes. Well under the backend's
+/// stall timeout.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Upper bound of `backoff`.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// How long a backend request may take to connect.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long a backend request may take in all. Stage changes wait for their post,
+/// so a backend that accepts but never answers holds the render up this long.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+static HTTP_CLIENT: OnceLock<Client> = OnceLock::new();
+
+/// The client for all backend requests, so they share one connection pool.
+pub fn http_client() -> &'static Client {
+    HTTP_CLIENT.get_or_init(|| {
+        Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .unwrap_or_default()
+    })
+}
+
+/// How long to wait after `failures` requests in a row failed: `base` for up to one
+/// failure, doubled for each further one, but no more than 30 s (or `base`).
+pub fn backoff(base: Duration, failures: u32) -> Duration {
+    base.saturating_mul(1 << failures.saturating_sub(1).min(16))
+        .min(MAX_BACKOFF.max(base))
+}
+
+/// Where progress updates go.
+#[derive(Debug, Clone)]
+pub enum ProgressTarget {
+    /// POST to the backend's `/render_progress`.
+    Backend(String),
+    /// Replace this file with the latest update, as JSON.
+    File(PathBuf),
+    /// Print a `PROGRESS` line about once a second.
+    Stdout,
+}
+
+/// What the render is doing. Once the frames are done the count stays at 100 %, so
+/// this is what shows the render has not hung.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stage {
+    Rendering,
+    Encoding,
+    Concat,
+    Mux,
+    Finalizing,
+}
+
+impl Stage {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Stage::Rendering => "rendering",
+            Stage::Encoding => "encoding",
+            Stage::Concat => "concat",
+            Stage::Mux => "mux",
+            Stage::Finalizing => "finalizing",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ProgressPayload {
+    completed: usize,
+    total: usize,
+    /// Set on the last update of a render that stopped because it was canceled.
+    canceled: bool,
+    stage: Stage,
+    /// Frames completed per second over the last few seconds.
+    frames_per_second: f64,
+    /// Seconds left at that rate; only while rendering frames.
+    eta_seconds: Option<f64>,
+    /// Milliseconds since the render started. The backend flags the render as
+    /// stalled when this stops arriving.
+    heartbeat_ms: u64,
+    /// Id of the `--jobs` entry being rendered.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    job: Option<String>,
+    /// How far ffmpeg is through the concat or mux, once it has said so.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stage_percent: Option<f64>,
+}
+
+impl ProgressPayload {
+    fn line(&self) -> String {
+        let mut line = self
+            .job
+            .as_ref()
+            .map(|job| format!("[{job}] "))
+            .unwrap_or_default();
+        line.push_str(&format!(
+            "{}/{} {} {:.2}[frames/s]",
+            self.completed,
+            self.total,
+            self.stage.as_str(),
+            self.frames_per_second
+        ));
+        if let Some(percent) = self.stage_percent {
+            line.push_str(&format!(" {percent:.0}%"));
+        }
+        if let Some(eta) = self.eta_seconds {
+            line.push_str(&format!(" ETA {eta:.0}[s]"));
+        }
+        if self.canceled {
+            line.push_str(" canceled");
+        }
+        line
+    }
+}
+
+/// Progress of one render, sent to a `ProgressTarget`.
+pub struct Progress {
+    target: ProgressTarget,
+    job: Option<String>,
+    last_printed: Mutex<Option<Instant>>,
+    /// When the last update went out, and its count and stage.
+    last_sent: Mutex<Option<(Instant, usize, Stage)>>,
+    /// Failed posts in a row and when to try again.
+    backoff: Mutex<(u32, Option<Instant>)>,
+    total: usize,
+    completed: Arc<AtomicUsize>,
+    /// Frames the current stage's ffmpeg has written, out of `total`.
+    stage_completed: AtomicUsize,
+    started: Instant,
+    stage: Mutex<Stage>,
+    /// Every stage change and when it happened, then the time of `finish`.
+    transitions: Mutex<Vec<(Option<Stage>, Instant)>>,
+    samples: Mutex<VecDeque<(Instant, usize)>>,
+    /// Held while posting; `true` once the last update went out.
+    finished: tokio::sync::Mutex<bool>,
+}
+
+impl Progress {
+    pub fn new(
+        target: ProgressTarget,
+        total: usize,
+        completed: Arc<AtomicUsize>,
+        stage: Stage,
+    ) -> Self {
+        Self {
+            target,
+            job: None,
+            last_printed: Mutex::new(None),
+            last_sent: Mutex::new(None),
+            backoff: Mutex::new((0, None)),
+            total,
+            completed,
+            stage_completed: AtomicUsize::new(0),
+            started: Instant::now(),
+            stage: Mutex::new(stage),
+            transitions: Mutex::new(vec![(Some(stage), Instant::now())]),
+            samples: Mutex::new(VecDeque::new()),
+            finished: tokio::sync::Mutex::new(false),
+        }
+    }
+
+    /// Tag every update with the id of the `--jobs` entry it is for.
+    pub fn with_job(mut self, job: Option<String>) -> Self {
+        self.job = job;
+        self
+    }
+
+    fn payload(&self, canceled: bool) -> ProgressPayload {
+        let now = Instant::now();
+        let completed = self.completed.load(Ordering::Relaxed);
+        let stage = *self.stage.lock().unwrap();
+
+        let mut samples = self.samples.lock().unwrap();
+        samples.push_back((now, completed));
+        while samples.len() > 2
+            && samples
+                .front()
+                .is_some_and(|(at, _)| now.duration_since(*at) > THROUGHPUT_WINDOW)
+        {
+            samples.pop_front();
+        }
+        let (since, completed_then) = samples[0];
+        let elapsed = now.duration_since(since).as_secs_f64();
+        let frames_per_second = if elapsed > 0.0 {
+            completed.saturating_sub(completed_then) as f64 / elapsed
+        } else {
+            0.0
+        };
+        let eta_seconds = (stage == Stage::Rendering && frames_per_second > 0.0)
+            .then(|| self.total.saturating_sub(completed) as f64 / frames_per_second);
+        let stage_completed = self.stage_completed.load(Ordering::Relaxed);
+        let stage_percent =
+            (matches!(stage, Stage::Concat | Stage::Mux) && stage_completed > 0 && self.total > 0)
+                .then(|| stage_completed as f64 / self.total as f64 * 100.0);
+
+        ProgressPayload {
+            completed,
+            total: self.total,
+            canceled,
+            stage,
+            frames_per_second,
+            eta_seconds,
+            heartbeat_ms: now.duration_since(self.started).as_millis() as u64,
+            job: self.job.clone(),
+            stage_percent,
+        }
+    }
+
+    /// Send an update. Unless `forced`, it is skipped while the count and stage stay
+    /// the same and a heartbeat is not yet due. The last update is always sent.
+    async fn send(&self, canceled: bool, last: bool, forced: bool) {
+        let mut finished = self.finished.lock().await;
+        if *finished {
+            return;
+        }
+        let payload = self.payload(canceled);
+        let now = Instant::now();
+        if !last {
+            let unchanged = self
+                .last_sent
+                .lock()
+                .unwrap()
+                .is_some_and(|(at, completed, stage)| {
+                    completed == payload.completed
+                        && stage == payload.stage
+                        && now.duration_since(at) < HEARTBEAT_INTERVAL
+                });
+            let backing_off = self
+                .backoff
+                .lock()
+                .unwrap()
+                .1
+                .is_some_and(|retry_at| now < retry_at);
+            if backing_off || (unchanged && !forced) {
+                return;
+            }
+        }
+        *finished = last;
+        *self.last_sent.lock().unwrap() = Some((now, payload.completed, payload.stage));
+
+        match &self.target {
+            ProgressTarget::Backend(url) => {
+                let sent = http_client().post(url).json(&payload).send().await;
+                let mut state = self.backoff.lock().unwrap();
+                match sent {
+                    Ok(_) => *state = (0, None),
+                    Err(error) => {
+                        if state.0 == 0 {
+                            tracing::warn!("cannot post progress to {url}: {error}");
+                        }
+                        state.0 += 1;
+                        state.1 = Some(Instant::now() + backoff(HEARTBEAT_INTERVAL, state.0));
+                    }
+                }
+            }
+            ProgressTarget::File(path) => {
+                // 読む側が書きかけを見ないよう一時ファイルから置き換える
+                let temp = path.with_extension("tmp");
+                if let Ok(json) = serde_json::to_vec(&payload)
+                    && tokio::fs::write(&temp, json).await.is_ok()
+                {
+                    tokio::fs::rename(&temp, path).await.ok();
+                }
+            }
+            ProgressTarget::Stdout => {
+                let now = Instant::now();
+                let mut last_printed = self.last_printed.lock().unwrap();
+                if last || last_printed.is_none_or(|at| now.duration_since(at) >= PRINT_INTERVAL) {
+                    *last_printed = Some(now);
+                    println!("PROGRESS : {}", payload.line());
+                }
+            }
+        }
+    }
+
+    /// Send the current numbers now. The backend may not be running, so errors only
+    /// delay the next update.
+    pub async fn post(&self) {
+        self.send(false, false, true).await;
+    }
+
+    /// Counter for ffmpeg to store the frames the current stage has written; it is
+    /// reset on every stage change.
+    pub fn stage_frames(&self) -> &AtomicUsize {
+        &self.stage_completed
+    }
+
+    /// Move on to `stage` and tell the backend straight away.
+    pub async fn enter(&self, stage: Stage) {
+        let previous = std::mem::replace(&mut *self.stage.lock().unwrap(), stage);
+        if previous != stage {
+            self.stage_completed.store(0, Ordering::Relaxed);
+            tracing::info!("stage: {}", stage.as_str());
+            self.transitions
+                .lock()
+                .unwrap()
+                .push((Some(stage), Instant::now()));
+        }
+        self.post().await;
+    }
+
+    /// Post the last update; anything posted after it is dropped.
+    pub async fn finish(&self, canceled: bool) {
+        self.transitions
+            .lock()
+            .unwrap()
+            .push((None, Instant::now()));
+        self.send(canceled, true, true).await;
+    }
+
+    /// Time spent in each stage so far, in the order the stages were first entered.
+    pub fn stage_durations(&self) -> Vec<(Stage, Duration)> {
+        let mut transitions = self.transitions.lock().unwrap().clone();
+        transitions.push((None, Instant::now()));
+
+        let mut durations: Vec<(Stage, Duration)> = Vec::new();
+        for pair in transitions.windows(2) {
+            let ((Some(stage), from), (_, to)) = (pair[0], pair[1]) else {
+                break;
+            };
+            let elapsed = to.duration_since(from);
+            match durations.iter_mut().find(|(seen, _)| *seen == stage) {
+                Some((_, total)) => *total += elapsed,
+                None => durations.push((stage, elapsed)),
+            }
+        }
+        durations
+    }
+
+    /// Check every `interval` until `finish` is called, sending an update when the
+    /// count or stage changed or a heartbeat is due.
+    pub async fn report_every(&self, interval: Duration) {
+        while !*self.finished.lock().await {
+            self.send(false, false, false).await;
+            tokio::time::sleep(interval).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn a_backend_that_never_answers_does_not_hold_up_a_stage() {
+        // accepts the connection but never reads or replies
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/render_progress", listener.local_addr().unwrap());
+        let accepted = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            drop(socket);
+        });
+
+        let progress = Progress::new(
+            ProgressTarget::Backend(url),
+            10,
+            Arc::new(AtomicUsize::new(0)),
+            Stage::Rendering,
+        );
+        tokio::time::timeout(REQUEST_TIMEOUT * 2, progress.enter(Stage::Encoding))
+            .await
+            .expect("the post should time out");
+        // the failure backs off, so the next stage does not wait again
+        let started = Instant::now();
+        progress.enter(Stage::Concat).await;
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        accepted.abort();
+    }
+}
@@ -0,0 +1,194 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::progress::Stage;
+use crate::worker::{StageTimings, WorkerConfig, WorkerTiming};
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// What `--report` writes: enough to compare two runs of the benchmark.
+#[derive(Debug, Serialize)]
+pub struct RenderReport {
+    pub total_ms: f64,
+    pub frames: FrameSummary,
+    pub settings: Settings,
+    pub machine: Machine,
+    /// Wall time of each stage, from the progress stages.
+    pub stages_ms: BTreeMap<&'static str, f64>,
+    /// Per-frame work summed over all workers.
+    pub frame_ms: FrameTimes,
+    pub workers: Vec<WorkerReport>,
+    pub relaunches: usize,
+    pub skipped_frames: usize,
+    /// Segment checks before concat; empty for image sequences.
+    pub segments: Vec<SegmentCheck>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FrameSummary {
+    pub range: Range<usize>,
+    /// Frames rendered by this run, i.e. not kept from an earlier `--resume`d one.
+    pub rendered: usize,
+    pub frames_per_second: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Settings {
+    pub width: u32,
+    pub height: u32,
+    pub fps: f64,
+    pub encode: String,
+    pub quality: String,
+    pub preset: String,
+    /// `None` when the encoder placed keyframes itself.
+    pub gop: Option<u32>,
+    /// `None` when the codec's own format was used.
+    pub pix_fmt: Option<&'static str>,
+    pub color_range: String,
+    pub capture: String,
+    pub workers: usize,
+    pub distribution: String,
+    pub browser_mode: String,
+    pub chunk_frames: usize,
+    pub pipeline_depth: usize,
+    pub concat_mode: String,
+}
+
+impl Settings {
+    pub fn new(config: &WorkerConfig, args: &crate::cli::RenderArgs) -> Self {
+        Self {
+            width: config.width,
+            height: config.height,
+            fps: config.fps,
+            encode: config.encode.to_string(),
+            quality: config.quality.to_string(),
+            preset: config.preset.clone(),
+            gop: config.gop,
+            pix_fmt: config.pixel_format.map(|format| format.as_str()),
+            color_range: config.color_range.to_string(),
+            capture: config.capture.to_string(),
+            workers: args.workers,
+            distribution: args.distribution.to_string(),
+            browser_mode: args.browser_mode.to_string(),
+            chunk_frames: args.chunk_frames,
+            pipeline_depth: args.pipeline_depth,
+            concat_mode: args.concat_mode.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Machine {
+    pub os: &'static str,
+    pub arch: &'static str,
+    pub cores: usize,
+}
+
+impl Machine {
+    pub fn current() -> Self {
+        Self {
+            os: std::env::consts::OS,
+            arch: std::env::consts::ARCH,
+            cores: std::thread::available_parallelism()
+                .map(|cores| cores.get())
+                .unwrap_or(1),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct FrameTimes {
+    pub set_frame: f64,
+    pub wait: f64,
+    pub screenshot: f64,
+    pub encode: f64,
+}
+
+impl From<&StageTimings> for FrameTimes {
+    fn from(timings: &StageTimings) -> Self {
+        Self {
+            set_frame: millis(timings.set_frame()),
+            wait: millis(timings.wait()),
+            screenshot: millis(timings.screenshot()),
+            encode: millis(timings.encode()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct WorkerReport {
+    pub worker_id: usize,
+    pub frames: usize,
+    pub wall_ms: f64,
+}
+
+impl From<WorkerTiming> for WorkerReport {
+    fn from(timing: WorkerTiming) -> Self {
+        Self {
+            worker_id: timing.worker_id,
+            frames: timing.frames,
+            wall_ms: millis(timing.wall),
+        }
+    }
+}
+
+/// Outcome of checking one segment before concat.
+#[derive(Debug, Clone, Serialize)]
+pub struct SegmentCheck {
+    pub frames: Range<usize>,
+    pub path: PathBuf,
+    /// `None` when the segment passed.
+    pub problem: Option<String>,
+}
+
+/// `manifest.json` written by `--keep-intermediates`: what the files left in the
+/// work directory are.
+#[derive(Debug, Serialize)]
+pub struct Manifest {
+    pub segments: Vec<ManifestSegment>,
+    /// The concat demuxer list the segments were joined with.
+    pub concat_list: Option<PathBuf>,
+    /// The joined video before the audio was muxed in, when there was audio.
+    pub video_only: Option<PathBuf>,
+    pub output: PathBuf,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ManifestSegment {
+    pub path: PathBuf,
+    pub frames: Range<usize>,
+    /// Workers that rendered frames of this segment; empty when it was kept from an
+    /// earlier `--resume`d run.
+    pub workers: Vec<usize>,
+    /// Why the segment failed its check before concat.
+    pub problem: Option<String>,
+}
+
+/// `Progress::stage_durations` keyed by stage name.
+pub fn stage_millis(durations: &[(Stage, Duration)]) -> BTreeMap<&'static str, f64> {
+    durations
+        .iter()
+        .map(|(stage, duration)| (stage.as_str(), millis(*duration)))
+        .collect()
+}
+
+async fn write_json(path: &Path, value: &impl Serialize, what: &str) -> Result<(), String> {
+    let json = serde_json::to_vec_pretty(value).map_err(|e| e.to_string())?;
+    tokio::fs::write(path, json)
+        .await
+        .map_err(|e| format!("cannot write {what} {}: {e}", path.display()))
+}
+
+pub async fn write_report(path: &Path, report: &RenderReport) -> Result<(), String> {
+    write_json(path, report, "report").await
+}
+
+pub async fn write_manifest(path: &Path, manifest: &Manifest) -> Result<(), String> {
+    write_json(path, manifest, "manifest").await
+}
@@ -0,0 +1,1047 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chromiumoxide::{
+    Page,
+    cdp::browser_protocol::page::{CaptureScreenshotFormat, CaptureScreenshotParams, Viewport},
+    page::ScreenshotParams,
+};
+use futures::StreamExt;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+use crate::browser::{
+    BrowserInstance, BrowserOptions, CaptureTimings, PageError, capture_frame, decode_png_rgba,
+    open_render_page, spawn_browser_instance,
+};
+use crate::cli::{CaptureMode, Clip, FrameTimeoutPolicy, PixelFormat};
+use crate::ffmpeg::{
+    AudioEncode, ColorRange, EncodeSettings, EncoderKind, EncoderTuning, FrameInput, Quality,
+    SegmentWriter,
+};
+use crate::progress::backoff;
+
+/// Settings shared by every worker of a render.
+#[derive(Debug, Clone)]
+pub struct WorkerConfig {
+    /// Page size in CSS pixels.
+    pub width: u32,
+    pub height: u32,
+    /// Device pixels per CSS pixel of the screenshots.
+    pub scale: f64,
+    /// Encode at the CSS pixel size instead of the captured size.
+    pub downscale: bool,
+    /// Part of the page to capture; all of it when `None`.
+    pub clip: Option<Clip>,
+    pub fps: f64,
+    pub quality: Quality,
+    pub encode: EncoderKind,
+    pub preset: String,
+    /// Keyframe interval; the encoder decides when `None`.
+    pub gop: Option<u32>,
+    /// `--pix-fmt`; the codec's own format when `None`.
+    pub pixel_format: Option<PixelFormat>,
+    pub color_range: ColorRange,
+    pub tuning: EncoderTuning,
+    /// Audio track of the final output; workers leave it to the mux.
+    pub audio: AudioEncode,
+    pub page_url: String,
+    /// Tell the page which worker it is and which frames it was opened for.
+    pub url_params: bool,
+    pub capture: CaptureMode,
+    pub jpeg_quality: u8,
+    /// Extra attempts at a frame on the same page before the browser is relaunched.
+    pub frame_retries: usize,
+    /// Browser relaunches a worker may do over the whole render before giving up.
+    pub max_relaunches: usize,
+    /// Captured frames waiting for the encoder, per worker.
+    pub pipeline_depth: usize,
+    /// Skip frames whose image file already exists (`FrameOutput::Files` only).
+    pub resume: bool,
+    /// Write segments as fragmented MP4/MOV that stay readable when cut short.
+    pub recoverable_segments: bool,
+    pub frame_timeout: Duration,
+    /// How long a page may take in all to load and get ready to be driven.
+    pub page_ready_timeout: Duration,
+    pub on_frame_timeout: FrameTimeoutPolicy,
+    /// Where screenshots and console logs of hung frames are saved.
+    pub diagnostics_dir: PathBuf,
+    pub browser: BrowserOptions,
+}
+
+impl WorkerConfig {
+    /// `page_url` for a page opened by `worker_id` to render `frames`. Pages are reused
+    /// for the worker's later chunks, so the range is a hint for pre-warming only.
+    fn page_url(&self, worker_id: usize, frames: &Range<usize>) -> String {
+        if !self.url_params {
+            return self.page_url.clone();
+        }
+        with_query(
+            &self.page_url,
+            &[
+                ("workerId", worker_id.to_string()),
+                ("startFrame", frames.start.to_string()),
+                ("endFrame", frames.end.to_string()),
+                ("fps", self.fps.to_string()),
+                ("width", self.width.to_string()),
+                ("height", self.height.to_string()),
+            ],
+        )
+    }
+
+    /// Size of a frame in CSS pixels: the clip, or the whole page.
+    pub fn frame_size(&self) -> (u32, u32) {
+        match self.clip {
+            Some(clip) => (clip.width, clip.height),
+            None => (self.width, self.height),
+        }
+    }
+
+    /// Size of a screenshot in device pixels.
+    pub fn capture_size(&self) -> (u32, u32) {
+        let (width, height) = self.frame_size();
+        let scaled = |size: u32| (size as f64 * self.scale).round() as u32;
+        (scaled(width), scaled(height))
+    }
+
+    /// How `SegmentWriter` encodes this render's segments.
+    pub fn encode_settings(&self) -> EncodeSettings {
+        EncodeSettings {
+            encode: self.encode,
+            quality: self.quality,
+            preset: self.preset.clone(),
+            gop: self.gop,
+            pixel_format: self.pixel_format.map(PixelFormat::as_str),
+            color_range: self.color_range,
+            tuning: self.tuning.clone(),
+        }
+    }
+
+    /// Size of the encoded video.
+    pub fn output_size(&self) -> (u32, u32) {
+        if self.downscale {
+            self.frame_size()
+        } else {
+            self.capture_size()
+        }
+    }
+}
+
+/// `url` with `params` added to its query string, ahead of any `#fragment` so hash
+/// routers still see their route. Works the same for http(s) and file URLs.
+pub fn with_query(url: &str, params: &[(&str, String)]) -> String {
+    let (base, fragment) = match url.find('#') {
+        Some(hash) => url.split_at(hash),
+        None => (url, ""),
+    };
+    let separator = if base.ends_with(['?', '&']) {
+        ""
+    } else if base.contains('?') {
+        "&"
+    } else {
+        "?"
+    };
+    let query = params
+        .iter()
+        .map(|(key, value)| format!("{key}={value}"))
+        .collect::<Vec<_>>()
+        .join("&");
+    format!("{base}{separator}{query}{fragment}")
+}
+
+/// Where a worker's frames go.
+#[derive(Debug, Clone)]
+pub enum FrameOutput {
+    /// Encoded into a single segment file.
+    Segment(PathBuf),
+    /// Numbered image files in a directory, named by absolute frame number.
+    Files {
+        dir: PathBuf,
+        extension: &'static str,
+    },
+}
+
+/// A run of frames handed to whichever worker asks first: every `stride`-th frame of
+/// `frames`.
+#[derive(Debug, Clone)]
+pub struct Chunk {
+    pub frames: Range<usize>,
+    pub stride: usize,
+    pub out: FrameOutput,
+}
+
+/// Chunks no worker has taken yet, in frame order.
+#[derive(Debug, Default)]
+pub struct WorkQueue {
+    chunks: Mutex<VecDeque<Chunk>>,
+    /// Why no more chunks are handed out, once a worker hit a failure that every
+    /// worker would hit.
+    aborted: Mutex<Option<String>>,
+}
+
+impl WorkQueue {
+    pub fn new(chunks: impl IntoIterator<Item = Chunk>) -> Self {
+        Self {
+            chunks: Mutex::new(chunks.into_iter().collect()),
+            aborted: Mutex::new(None),
+        }
+    }
+
+    fn next(&self) -> Option<Chunk> {
+        if self.aborted.lock().unwrap().is_some() {
+            return None;
+        }
+        self.chunks.lock().unwrap().pop_front()
+    }
+
+    fn abort(&self, reason: &str) {
+        self.aborted
+            .lock()
+            .unwrap()
+            .get_or_insert_with(|| reason.to_string());
+    }
+
+    /// The reason passed to the first `abort`, if any.
+    pub fn aborted(&self) -> Option<String> {
+        self.aborted.lock().unwrap().clone()
+    }
+
+    /// Take the chunks that were never handed out.
+    pub fn drain(&self) -> Vec<Chunk> {
+        self.chunks.lock().unwrap().drain(..).collect()
+    }
+}
+
+impl FrameOutput {
+    /// The image file for `frame` already exists; always `false` for segments.
+    fn has(&self, frame: usize) -> bool {
+        match self {
+            FrameOutput::Segment(_) => false,
+            FrameOutput::Files { dir, extension } => {
+                frame_file_path(dir, frame, extension).is_file()
+            }
+        }
+    }
+}
+
+pub fn frame_file_path(dir: &Path, frame: usize, extension: &str) -> PathBuf {
+    dir.join(format!("frame_{frame:06}.{extension}"))
+}
+
+enum Sink<'a> {
+    Segment(Box<SegmentWriter>),
+    Files {
+        dir: &'a Path,
+        extension: &'static str,
+    },
+}
+
+impl Sink<'_> {
+    async fn write(&mut self, frame: usize, bytes: &[u8]) -> Result<(), String> {
+        match self {
+            Sink::Segment(writer) => writer
+                .write_frame(bytes)
+                .await
+                .map_err(|e| format!("ffmpeg rejected frame {frame}: {e}")),
+            Sink::Files { dir, extension } => {
+                let path = frame_file_path(dir, frame, extension);
+                // 途中で落ちても壊れたファイルが残らないよう一時ファイル経由で置く
+                let temp = path.with_extension(format!("{extension}.tmp"));
+                tokio::fs::write(&temp, bytes)
+                    .await
+                    .map_err(|e| format!("cannot write {}: {e}", temp.display()))?;
+                tokio::fs::rename(&temp, &path)
+                    .await
+                    .map_err(|e| format!("cannot write {}: {e}", path.display()))
+            }
+        }
+    }
+
+    async fn finish(self) -> Result<(), String> {
+        match self {
+            Sink::Segment(writer) => writer.finish().await.map_err(|e| e.to_string()),
+            Sink::Files { .. } => Ok(()),
+        }
+    }
+}
+
+/// Time spent per stage, summed over all workers, plus how often a browser had to be
+/// relaunched.
+#[derive(Debug, Default)]
+pub struct StageTimings {
+    capture_ns: AtomicU64,
+    set_frame_ns: AtomicU64,
+    wait_ns: AtomicU64,
+    screenshot_ns: AtomicU64,
+    encode_ns: AtomicU64,
+    relaunches: AtomicUsize,
+    skipped: AtomicUsize,
+    workers: Mutex<Vec<WorkerTiming>>,
+    /// Each chunk a worker took, by worker id.
+    chunks: Mutex<Vec<(usize, Range<usize>)>>,
+}
+
+/// Frames one worker wrote and how long it ran.
+#[derive(Debug, Clone, Copy)]
+pub struct WorkerTiming {
+    pub worker_id: usize,
+    pub frames: usize,
+    pub wall: Duration,
+}
+
+impl StageTimings {
+    fn add(counter: &AtomicU64, elapsed: Duration) {
+        counter.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn add_capture(&self, timings: CaptureTimings) {
+        Self::add(&self.set_frame_ns, timings.set_frame);
+        Self::add(&self.wait_ns, timings.wait);
+        Self::add(&self.screenshot_ns, timings.screenshot);
+    }
+
+    fn load(counter: &AtomicU64) -> Duration {
+        Duration::from_nanos(counter.load(Ordering::Relaxed))
+    }
+
+    /// Time to capture each frame: the `setFrame` call, waiting for the page to draw
+    /// and the screenshot, with raw-mode decoding.
+    pub fn capture(&self) -> Duration {
+        Self::load(&self.capture_ns)
+    }
+
+    /// The `setFrame` calls, part of `capture`.
+    pub fn set_frame(&self) -> Duration {
+        Self::load(&self.set_frame_ns)
+    }
+
+    /// Waiting for the page to draw, part of `capture`.
+    pub fn wait(&self) -> Duration {
+        Self::load(&self.wait_ns)
+    }
+
+    /// The screenshots themselves and raw-mode decoding, part of `capture`.
+    pub fn screenshot(&self) -> Duration {
+        Self::load(&self.screenshot_ns)
+    }
+
+    /// Time spent handing frames to ffmpeg, including back-pressure from the encoder.
+    pub fn encode(&self) -> Duration {
+        Self::load(&self.encode_ns)
+    }
+
+    /// One entry per finished worker, by worker id.
+    pub fn workers(&self) -> Vec<WorkerTiming> {
+        let mut workers = self.workers.lock().unwrap().clone();
+        workers.sort_by_key(|worker| worker.worker_id);
+        workers
+    }
+
+    /// Ids of the workers that took a chunk overlapping `frames`.
+    pub fn workers_for(&self, frames: &Range<usize>) -> Vec<usize> {
+        let mut workers = self
+            .chunks
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, chunk)| chunk.start < frames.end && frames.start < chunk.end)
+            .map(|(worker_id, _)| *worker_id)
+            .collect::<Vec<_>>();
+        workers.sort_unstable();
+        workers.dedup();
+        workers
+    }
+
+    /// Browser (or, in tabs mode, tab) relaunches after a crash or failed frame.
+    pub fn relaunches(&self) -> usize {
+        self.relaunches.load(Ordering::Relaxed)
+    }
+
+    /// Frames replaced by the previous frame after timing out (`--on-frame-timeout skip`).
+    pub fn skipped(&self) -> usize {
+        self.skipped.load(Ordering::Relaxed)
+    }
+}
+
+/// Frames of a worker's range that never made it into its segment.
+#[derive(Debug)]
+pub struct RangeFailure {
+    pub frames: Range<usize>,
+    pub error: String,
+    /// Every worker would fail the same way, so the render stops early.
+    pub fatal: bool,
+}
+
+/// Why a worker gave up on its chunk.
+#[derive(Debug)]
+struct Failure {
+    error: String,
+    /// Relaunching cannot help, e.g. the page has no frame API.
+    fatal: bool,
+}
+
+impl From<String> for Failure {
+    fn from(error: String) -> Self {
+        Self {
+            error,
+            fatal: false,
+        }
+    }
+}
+
+impl From<PageError> for Failure {
+    fn from(error: PageError) -> Self {
+        Self {
+            fatal: matches!(error, PageError::FrameApiMissing { .. }),
+            error: format!("render page failed to load: {error}"),
+        }
+    }
+}
+
+impl fmt::Display for RangeFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "frames {}..{} failed: {}",
+            self.frames.start, self.frames.end, self.error
+        )
+    }
+}
+
+/// Launch a browser and drive its CDP connection. The flag turns false once the
+/// connection is gone, i.e. the browser has exited or crashed.
+async fn launch_browser(
+    profile_id: usize,
+    config: &WorkerConfig,
+) -> Result<(BrowserInstance, Arc<AtomicBool>), String> {
+    let (browser, mut handler) =
+        spawn_browser_instance(profile_id, config.width, config.height, &config.browser)
+            .await
+            .map_err(|e| format!("browser launch failed: {e}"))?;
+
+    let alive = Arc::new(AtomicBool::new(true));
+    let flag = alive.clone();
+    tokio::spawn(async move {
+        while handler.next().await.is_some() {}
+        flag.store(false, Ordering::Relaxed);
+    });
+    Ok((browser, alive))
+}
+
+struct LiveBrowser {
+    browser: Arc<BrowserInstance>,
+    alive: Arc<AtomicBool>,
+}
+
+/// One browser shared by all workers for `--browser-mode tabs`. When it dies every
+/// worker's tab goes with it; the first worker to ask for a new tab relaunches it.
+#[derive(Default)]
+pub struct SharedBrowser {
+    current: tokio::sync::Mutex<Option<LiveBrowser>>,
+    launches: AtomicUsize,
+}
+
+impl SharedBrowser {
+    async fn get(
+        &self,
+        config: &WorkerConfig,
+    ) -> Result<(Arc<BrowserInstance>, Arc<AtomicBool>), String> {
+        let mut current = self.current.lock().await;
+        if let Some(live) = current.as_ref()
+            && live.alive.load(Ordering::Relaxed)
+        {
+            return Ok((live.browser.clone(), live.alive.clone()));
+        }
+
+        let (browser, alive) = launch_browser(0, config).await?;
+        let browser = Arc::new(browser);
+        *current = Some(LiveBrowser {
+            browser: browser.clone(),
+            alive: alive.clone(),
+        });
+        self.launches.fetch_add(1, Ordering::Relaxed);
+        Ok((browser, alive))
+    }
+
+    /// Number of times the browser was started.
+    pub fn launches(&self) -> usize {
+        self.launches.load(Ordering::Relaxed)
+    }
+
+    /// Close the browser once every worker is done with it.
+    pub async fn close(&self) {
+        if let Some(live) = self.current.lock().await.take()
+            && let Ok(browser) = Arc::try_unwrap(live.browser)
+        {
+            browser.close().await;
+        }
+    }
+}
+
+/// Console messages kept per page for timeout diagnostics.
+const CONSOLE_LINES: usize = 200;
+
+/// Wait before retrying the first failed launch, doubling with each one after.
+const LAUNCH_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+struct Session {
+    /// `None` when the page is a tab of the shared browser.
+    browser: Option<BrowserInstance>,
+    page: Page,
+    alive: Arc<AtomicBool>,
+    console: Arc<Mutex<VecDeque<String>>>,
+}
+
+impl Session {
+    async fn launch(
+        worker_id: usize,
+        frames: Range<usize>,
+        config: &WorkerConfig,
+        shared: Option<&SharedBrowser>,
+    ) -> Result<Self, Failure> {
+        let url = config.page_url(worker_id, &frames);
+        let timeout = config.page_ready_timeout;
+        if let Some(shared) = shared {
+            let (instance, alive) = shared.get(config).await?;
+            let (page, console) =
+                open_render_page(&instance.browser, &url, timeout, CONSOLE_LINES).await?;
+            return Ok(Self {
+                browser: None,
+                page,
+                alive,
+                console,
+            });
+        }
+
+        let (instance, alive) = launch_browser(worker_id, config).await?;
+        match open_render_page(&instance.browser, &url, timeout, CONSOLE_LINES).await {
+            Ok((page, console)) => Ok(Self {
+                browser: Some(instance),
+                page,
+                alive,
+                console,
+            }),
+            Err(error) => {
+                instance.close().await;
+                Err(error.into())
+            }
+        }
+    }
+
+    fn is_alive(&self) -> bool {
+        self.alive.load(Ordering::Relaxed)
+    }
+
+    async fn close(self) {
+        match self.browser {
+            Some(instance) => instance.close().await,
+            None => {
+                self.page.close().await.ok();
+            }
+        }
+    }
+}
+
+/// Open the render page for `frames` the way a worker would, then close it again.
+pub async fn check_render_page(config: &WorkerConfig, frames: Range<usize>) -> Result<(), String> {
+    let session = Session::launch(0, frames, config, None)
+        .await
+        .map_err(|failure| failure.error)?;
+    session.close().await;
+    Ok(())
+}
+
+/// Save a screenshot and the recent console output of a page whose frame hung.
+async fn write_timeout_diagnostics(worker_id: usize, frame: usize, session: &Session, dir: &Path) {
+    if let Err(error) = tokio::fs::create_dir_all(dir).await {
+        warn!("cannot create {}: {error}", dir.display());
+        return;
+    }
+    let stem = dir.join(format!("worker{worker_id}-frame{frame:06}"));
+
+    // ページが固まっているとスクリーンショットも返ってこないことがある
+    let screenshot = tokio::time::timeout(
+        Duration::from_secs(5),
+        session.page.screenshot(
+            ScreenshotParams::builder()
+                .format(CaptureScreenshotFormat::Png)
+                .build(),
+        ),
+    )
+    .await;
+    if let Ok(Ok(png)) = screenshot {
+        tokio::fs::write(stem.with_extension("png"), png).await.ok();
+    }
+
+    let console = session
+        .console
+        .lock()
+        .unwrap()
+        .iter()
+        .cloned()
+        .collect::<Vec<_>>()
+        .join("\n");
+    tokio::fs::write(stem.with_extension("log"), console)
+        .await
+        .ok();
+    info!(
+        "diagnostics for frame {frame} saved as {}.{{png,log}}",
+        stem.display()
+    );
+}
+
+fn screenshot_params(config: &WorkerConfig) -> CaptureScreenshotParams {
+    let builder = CaptureScreenshotParams::builder();
+    let builder = match config.capture {
+        CaptureMode::Png => builder.format(CaptureScreenshotFormat::Png),
+        CaptureMode::Raw => builder
+            .format(CaptureScreenshotFormat::Png)
+            .optimize_for_speed(true),
+        CaptureMode::Jpeg => builder
+            .format(CaptureScreenshotFormat::Jpeg)
+            .quality(config.jpeg_quality as i64),
+    };
+    match config.clip {
+        Some(clip) => builder.clip(Viewport {
+            x: clip.x as f64,
+            y: clip.y as f64,
+            width: clip.width as f64,
+            height: clip.height as f64,
+            scale: 1.0,
+        }),
+        None => builder,
+    }
+    .build()
+}
+
+async fn capture(
+    page: &Page,
+    frame: usize,
+    config: &WorkerConfig,
+) -> Result<(Vec<u8>, CaptureTimings), String> {
+    let (image, mut timings) = capture_frame(page, frame, screenshot_params(config))
+        .await
+        .map_err(|e| e.to_string())?;
+    if config.capture != CaptureMode::Raw {
+        return Ok((image, timings));
+    }
+
+    let decoded_at = Instant::now();
+    let (width, height) = config.capture_size();
+    let rgba = tokio::task::spawn_blocking(move || decode_png_rgba(&image, width, height))
+        .await
+        .map_err(|e| e.to_string())??;
+    timings.screenshot += decoded_at.elapsed();
+    Ok((rgba, timings))
+}
+
+/// Take chunks from `queue` until it runs dry, keeping the browser open between
+/// them. A chunk that cannot be finished stops this worker; the chunks still queued
+/// are left to the others.
+pub async fn run_worker(
+    worker_id: usize,
+    queue: &WorkQueue,
+    shared: Option<&SharedBrowser>,
+    config: &WorkerConfig,
+    completed: &AtomicUsize,
+    timings: &StageTimings,
+    cancel: &CancellationToken,
+) -> Result<(), RangeFailure> {
+    let started = Instant::now();
+    let mut session = None;
+    let mut rendered = 0;
+    let mut launches = 0;
+    let mut result = Ok(());
+    while !cancel.is_cancelled()
+        && let Some(chunk) = queue.next()
+    {
+        debug!("taking frames {}..{}", chunk.frames.start, chunk.frames.end);
+        timings
+            .chunks
+            .lock()
+            .unwrap()
+            .push((worker_id, chunk.frames.clone()));
+        result = render_range(
+            worker_id,
+            &mut session,
+            &chunk,
+            shared,
+            config,
+            completed,
+            &mut rendered,
+            &mut launches,
+            timings,
+            cancel,
+        )
+        .await;
+        if let Err(failure) = &result {
+            if failure.fatal {
+                queue.abort(&failure.error);
+            }
+            break;
+        }
+    }
+
+    if let Some(current) = session {
+        current.close().await;
+    }
+    timings.workers.lock().unwrap().push(WorkerTiming {
+        worker_id,
+        frames: rendered,
+        wall: started.elapsed(),
+    });
+    result
+}
+
+/// A captured frame on its way from the page to the sink.
+type Captured = (usize, Arc<Vec<u8>>);
+
+/// Write frames from `captured` to `sink` in the order they were sent, then close
+/// it. A failed write stops the capture side through `stop`.
+async fn write_frames(
+    mut sink: Sink<'_>,
+    mut captured: mpsc::Receiver<Captured>,
+    frames: &Range<usize>,
+    completed: &AtomicUsize,
+    rendered: &mut usize,
+    timings: &StageTimings,
+    stop: &CancellationToken,
+) -> Result<(), RangeFailure> {
+    let mut failure = None;
+    loop {
+        let received = tokio::select! {
+            _ = stop.cancelled() => None,
+            received = captured.recv() => received,
+        };
+        let Some((frame, bytes)) = received else {
+            break;
+        };
+        let written_at = Instant::now();
+        let written = tokio::select! {
+            _ = stop.cancelled() => break,
+            written = sink.write(frame, &bytes) => written,
+        };
+        if let Err(error) = written {
+            stop.cancel();
+            failure = Some(RangeFailure {
+                frames: frame..frames.end,
+                error,
+                fatal: false,
+            });
+            break;
+        }
+        StageTimings::add(&timings.encode_ns, written_at.elapsed());
+        completed.fetch_add(1, Ordering::Relaxed);
+        *rendered += 1;
+    }
+
+    // 失敗しても ffmpeg は閉じて、書き込み済みのフレームを読めるファイルとして残す
+    let finished = sink.finish().await;
+    if let Some(failure) = failure {
+        return Err(failure);
+    }
+    finished.map_err(|error| RangeFailure {
+        frames: frames.clone(),
+        error: format!("ffmpeg failed: {error}"),
+        fatal: false,
+    })
+}
+
+/// Render `frames` into `out`. A frame that fails is retried on the same page, then
+/// in a relaunched browser, continuing from the last frame written. Frames already
+/// written are kept when the worker gives up. `launches` counts the worker's
+/// browser launches across its chunks, the first one included.
+///
+/// Capturing and writing run side by side, with up to `pipeline_depth` captured
+/// frames between them.
+#[allow(clippy::too_many_arguments)]
+async fn render_range(
+    worker_id: usize,
+    session: &mut Option<Session>,
+    chunk: &Chunk,
+    shared: Option<&SharedBrowser>,
+    config: &WorkerConfig,
+    completed: &AtomicUsize,
+    rendered: &mut usize,
+    launches: &mut usize,
+    timings: &StageTimings,
+    cancel: &CancellationToken,
+) -> Result<(), RangeFailure> {
+    let frames = &chunk.frames;
+
+    let (capture_width, capture_height) = config.capture_size();
+    let sink = match &chunk.out {
+        FrameOutput::Segment(path) => Sink::Segment(Box::new(
+            SegmentWriter::new(
+                &path.to_string_lossy(),
+                capture_width,
+                capture_height,
+                config.fps,
+                &config.encode_settings(),
+                match config.capture {
+                    CaptureMode::Png => FrameInput::Png,
+                    CaptureMode::Raw => FrameInput::Rgba,
+                    CaptureMode::Jpeg => FrameInput::Jpeg,
+                },
+                Some(config.output_size()),
+                config.recoverable_segments,
+            )
+            .await
+            .map_err(|e| RangeFailure {
+                frames: frames.clone(),
+                error: e.to_string(),
+                fatal: false,
+            })?,
+        )),
+        FrameOutput::Files { dir, extension } => Sink::Files { dir, extension },
+    };
+
+    let (sender, receiver) = mpsc::channel(config.pipeline_depth);
+    let stop = cancel.child_token();
+    let writer = write_frames(sink, receiver, frames, completed, rendered, timings, &stop);
+    let capturing = capture_frames(
+        worker_id, session, chunk, shared, config, launches, timings, sender, &stop,
+    );
+    let ((next, failure), written) = tokio::join!(capturing, writer);
+
+    written?;
+    match failure {
+        Some(failure) => Err(RangeFailure {
+            frames: next..frames.end,
+            error: failure.error,
+            fatal: failure.fatal,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Capture the frames of `chunk` into `sender` until they are all sent or `stop` is
+/// cancelled. Returns the first frame not sent and why capturing gave up, if it did:
+/// a frame that keeps failing, or more than `max_relaunches` launches after the
+/// first in `launches`. Failed launches are retried after a growing delay.
+#[allow(clippy::too_many_arguments)]
+async fn capture_frames(
+    worker_id: usize,
+    session: &mut Option<Session>,
+    chunk: &Chunk,
+    shared: Option<&SharedBrowser>,
+    config: &WorkerConfig,
+    launches: &mut usize,
+    timings: &StageTimings,
+    sender: mpsc::Sender<Captured>,
+    stop: &CancellationToken,
+) -> (usize, Option<Failure>) {
+    let frames = &chunk.frames;
+    let mut next = frames.start;
+    let mut attempts = 0;
+    let mut timeouts = 0;
+    let mut failed_launches = 0;
+    let mut last_frame: Option<Arc<Vec<u8>>> = None;
+    let mut failure = None;
+
+    // キャンセルは各 await と競わせて、固まったフレームを待たずに抜ける
+    while next < frames.end && !stop.is_cancelled() {
+        tracing::Span::current().record("frame", next);
+        if config.resume && chunk.out.has(next) {
+            next += chunk.stride;
+            continue;
+        }
+
+        let Some(current) = session.as_ref() else {
+            // 最初の起動以外はすべて再起動として数える
+            if *launches > 0 {
+                timings.relaunches.fetch_add(1, Ordering::Relaxed);
+            }
+            *launches += 1;
+            let launched = tokio::select! {
+                _ = stop.cancelled() => break,
+                launched = Session::launch(worker_id, next..frames.end, config, shared) => launched,
+            };
+            match launched {
+                Ok(launched) => {
+                    *session = Some(launched);
+                    failed_launches = 0;
+                }
+                Err(error) => {
+                    if error.fatal || *launches > config.max_relaunches {
+                        failure = Some(error);
+                        break;
+                    }
+                    failed_launches += 1;
+                    let delay = backoff(LAUNCH_RETRY_DELAY, failed_launches);
+                    warn!("{}, retrying in {:.1}s", error.error, delay.as_secs_f64());
+                    tokio::select! {
+                        _ = stop.cancelled() => break,
+                        _ = tokio::time::sleep(delay) => {}
+                    }
+                }
+            }
+            continue;
+        };
+
+        // クラッシュしたブラウザでは何を呼んでも失敗するので、すぐ起動し直す
+        if !current.is_alive() {
+            attempts = 0;
+            if let Some(dead) = session.take() {
+                dead.close().await;
+            }
+            if *launches > config.max_relaunches {
+                failure = Some(format!("frame {next}: browser exited").into());
+                break;
+            }
+            warn!("browser exited, relaunching");
+            continue;
+        }
+
+        let captured_at = Instant::now();
+        let attempt =
+            tokio::time::timeout(config.frame_timeout, capture(&current.page, next, config));
+        let captured = tokio::select! {
+            _ = stop.cancelled() => break,
+            captured = attempt => captured,
+        };
+        let Ok(captured) = captured else {
+            warn!(
+                "frame {next} timed out after {:.1}s",
+                config.frame_timeout.as_secs_f64()
+            );
+            write_timeout_diagnostics(worker_id, next, current, &config.diagnostics_dir).await;
+            timeouts += 1;
+            if timeouts == 1 {
+                continue;
+            }
+
+            timeouts = 0;
+            match (config.on_frame_timeout, &last_frame) {
+                (FrameTimeoutPolicy::Skip, Some(previous)) => {
+                    warn!("skipping frame {next}, repeating the previous frame");
+                    let sent = tokio::select! {
+                        _ = stop.cancelled() => break,
+                        sent = sender.send((next, previous.clone())) => sent,
+                    };
+                    // 送れないのは書き込み側が止まったときで、理由はそちらが返す
+                    if sent.is_err() {
+                        break;
+                    }
+                    timings.skipped.fetch_add(1, Ordering::Relaxed);
+                    next += chunk.stride;
+                    continue;
+                }
+                _ => {
+                    failure = Some(
+                        format!(
+                            "frame {next} timed out after {:.1}s",
+                            config.frame_timeout.as_secs_f64()
+                        )
+                        .into(),
+                    );
+                    break;
+                }
+            }
+        };
+
+        match captured {
+            Ok((bytes, capture_timings)) => {
+                StageTimings::add(&timings.capture_ns, captured_at.elapsed());
+                timings.add_capture(capture_timings);
+                let bytes = Arc::new(bytes);
+                let sent = tokio::select! {
+                    _ = stop.cancelled() => break,
+                    sent = sender.send((next, bytes.clone())) => sent,
+                };
+                if sent.is_err() {
+                    break;
+                }
+                next += chunk.stride;
+                attempts = 0;
+                timeouts = 0;
+                last_frame = Some(bytes);
+            }
+            Err(error) => {
+                attempts += 1;
+                if attempts <= config.frame_retries && current.is_alive() {
+                    warn!("frame {next} failed ({error}), retrying");
+                    continue;
+                }
+
+                attempts = 0;
+                if let Some(dead) = session.take() {
+                    dead.close().await;
+                }
+                if *launches > config.max_relaunches {
+                    failure = Some(format!("frame {next}: {error}").into());
+                    break;
+                }
+                warn!("frame {next} failed ({error}), relaunching browser");
+            }
+        }
+    }
+
+    (next, failure)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params() -> Vec<(&'static str, String)> {
+        vec![
+            ("workerId", "2".to_string()),
+            ("startFrame", "60".to_string()),
+        ]
+    }
+
+    #[test]
+    fn params_start_a_query_string() {
+        assert_eq!(
+            with_query("http://localhost:5174/render", &params()),
+            "http://localhost:5174/render?workerId=2&startFrame=60"
+        );
+        assert_eq!(
+            with_query("https://example.com/render", &params()),
+            "https://example.com/render?workerId=2&startFrame=60"
+        );
+        assert_eq!(
+            with_query("file:///C:/app/dist-render/render.html", &params()),
+            "file:///C:/app/dist-render/render.html?workerId=2&startFrame=60"
+        );
+    }
+
+    #[test]
+    fn params_join_an_existing_query_string() {
+        assert_eq!(
+            with_query("https://example.com/render?project=demo", &params()),
+            "https://example.com/render?project=demo&workerId=2&startFrame=60"
+        );
+        assert_eq!(
+            with_query("file:///app/render.html?debug=1", &params()),
+            "file:///app/render.html?debug=1&workerId=2&startFrame=60"
+        );
+        assert_eq!(
+            with_query("http://localhost:5174/render?", &params()),
+            "http://localhost:5174/render?workerId=2&startFrame=60"
+        );
+        assert_eq!(
+            with_query("http://localhost:5174/render?project=demo&", &params()),
+            "http://localhost:5174/render?project=demo&workerId=2&startFrame=60"
+        );
+    }
+
+    #[test]
+    fn params_go_before_the_fragment() {
+        assert_eq!(
+            with_query("http://localhost:5174/#/render", &params()),
+            "http://localhost:5174/?workerId=2&startFrame=60#/render"
+        );
+        assert_eq!(
+            with_query("file:///app/render.html?debug=1#/render?x=1", &params()),
+            "file:///app/render.html?debug=1&workerId=2&startFrame=60#/render?x=1"
+        );
+    }
+}
@@ -24,6 +24,7 @@ $env:RENDER_PROGRESS_URL = "http://127.0.0.1:3000/render_progress"
 $env:RENDER_CANCEL_URL = "http://127.0.0.1:3000/is_canceled"
 $env:RENDER_RESET_URL = "http://127.0.0.1:3000/reset"
 $env:RENDER_AUDIO_PLAN_URL = "http://127.0.0.1:3000/render_audio_plan"
+$env:RENDER_OVERWRITE = "1"
 
 $ready = $false
 for ($i = 0; $i -lt 60; $i += 1) {
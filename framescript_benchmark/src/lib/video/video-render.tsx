@@ -206,7 +206,9 @@ export const VideoCanvasRender = ({ video, style, trimStartFrames = 0, trimEndFr
         const width = view.getUint32(0, true);
         const height = view.getUint32(4, true);
         const frameIndex = view.getUint32(8, true);
-        const rgba = new Uint8ClampedArray(buffer, 12);
+        // bit 0: hardware decoded, bit 1: fallback frame, bit 2: software forced
+        const flags = view.getUint32(12, true);
+        const rgba = new Uint8ClampedArray(buffer, 16);
 
         if (width * height * 4 !== rgba.length) {
           rejectPendingRequests(new Error("frame size mismatch"));
@@ -220,6 +222,9 @@ export const VideoCanvasRender = ({ video, style, trimStartFrames = 0, trimEndFr
 
         const imageData = new ImageData(rgba, width, height);
         ctx.putImageData(imageData, 0, 0);
+        // which decoder produced what is on screen, for corruption reports
+        canvas.dataset.decodeBackend =
+          flags & 2 ? "fallback" : flags & 1 ? "hardware" : "software";
 
         const pending = pendingMapRef.current.get(frameIndex);
         const projectFrame =